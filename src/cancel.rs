@@ -0,0 +1,85 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{Error, Result};
+
+/// Cancels long operations from another thread or once a deadline passes,
+/// see `Db::with_cancel` and `Range::cancel_on`.
+///
+/// Operations check the token between the pages they read and fail with
+/// `Error::Cancelled` once it is cancelled. Clones share the cancellation.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token that cancels on its own once `deadline` has passed.
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            deadline: Some(deadline),
+        }
+    }
+
+    /// A token that cancels on its own `timeout` from now.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::with_deadline(Instant::now() + timeout)
+    }
+
+    /// Cancel the operations using this token or any of its clones.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Fail with `Error::Cancelled` if the token is cancelled.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(())
+    }
+}
+
+/// Check `token`, if there is one.
+pub(crate) fn check(token: Option<&CancelToken>) -> Result<()> {
+    token.map_or(Ok(()), CancelToken::check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(check(Some(&clone)).is_ok());
+
+        token.cancel();
+        assert!(matches!(clone.check(), Err(Error::Cancelled)));
+        assert!(check(None).is_ok());
+
+        let past = CancelToken::with_deadline(Instant::now());
+        assert!(past.is_cancelled());
+        assert!(!CancelToken::with_timeout(Duration::from_secs(60)).is_cancelled());
+    }
+}
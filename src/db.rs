@@ -0,0 +1,861 @@
+mod batch;
+mod locks;
+mod sampler;
+mod txn;
+
+use std::{
+    num::{NonZeroU64, NonZeroUsize},
+    ops::RangeBounds,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    cancel::CancelToken,
+    pager::{
+        CacheConfig, CacheUsage, CommitInfo, CommitPolicy, CommitStats, DWALPager, File,
+        FreeListStats, MemoryFile, RepairPolicy, Version, WriteBackStats,
+    },
+    progress::Progress,
+    tree::{
+        CacheMisses, ConflictPolicy, Cursor, FanoutStats, FreezeGuard, Range, ReaderCounts,
+        Snapshot, Tree, TreeHash, ValueGuard, DUP_SORT,
+    },
+    Error, Result,
+};
+
+pub use self::{
+    batch::WriteBatch,
+    locks::{KeyLocks, KeyRangeGuard},
+    txn::WriteTxn,
+};
+
+use self::sampler::ReadSampler;
+
+/// An on disk key value store.
+///
+/// Every `put` and `delete` is committed on its own but only becomes durable
+/// once `flush` returns. The pages they free are reused after that too,
+/// commits sync on their own once enough are waiting.
+pub struct Db {
+    tree: Tree,
+    locks: KeyLocks,
+    sampler: Option<ReadSampler>,
+}
+
+/// Options for opening a `Db`, in the spirit of `std::fs::OpenOptions`.
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    create: bool,
+    read_only: bool,
+    zero_fill: bool,
+    scrub_freed_pages: bool,
+    deterministic: bool,
+    cache_budget: Option<usize>,
+    cache_config: Option<CacheConfig>,
+    dirty_watermarks: Option<(usize, usize)>,
+    verify_root_path: bool,
+    commit_policy: CommitPolicy,
+    dup_sort: bool,
+    bitmap_allocator: bool,
+    shadow_paging: bool,
+    delta_updates: bool,
+    wal: bool,
+    sampler: Option<ReadSampler>,
+    max_snapshots: Option<usize>,
+    reuse_delay: u64,
+    repair_policy: RepairPolicy,
+}
+
+impl Db {
+    /// Open the database at `path`, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        OpenOptions::new().open_pager(DWALPager::open(path)?)
+    }
+
+    /// Open a new database kept entirely in memory.
+    pub fn open_in_memory() -> Result<Self> {
+        OpenOptions::new().open_file(MemoryFile::new())
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.tree.get(key)?;
+        sample(&mut self.sampler, key, value.is_some());
+
+        Ok(value)
+    }
+
+    /// Whether `key` is present, without reading its value.
+    pub fn contains(&mut self, key: &[u8]) -> Result<bool> {
+        let found = self.tree.contains(key)?;
+        sample(&mut self.sampler, key, found);
+
+        Ok(found)
+    }
+
+    /// Look up `key` without copying its value out of the page cache, see
+    /// `ValueGuard`.
+    pub fn get_ref(&mut self, key: &[u8]) -> Result<Option<ValueGuard<'_>>> {
+        let value = self.tree.get_ref(key)?;
+        sample(&mut self.sampler, key, value.is_some());
+
+        Ok(value)
+    }
+
+    /// All values of `key` in a database opened with
+    /// `OpenOptions::dup_sort`, in order.
+    pub fn get_all(&mut self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let values = self.tree.get_all(key)?;
+        sample(&mut self.sampler, key, !values.is_empty());
+
+        Ok(values)
+    }
+
+    /// Iterate over the entries with keys in `range`, in key order.
+    pub fn range<K: AsRef<[u8]> + ?Sized>(&mut self, range: impl RangeBounds<K>) -> Range<'_> {
+        self.tree.range(range)
+    }
+
+    /// Number of entries with keys in `range`, counted without reading
+    /// their values.
+    pub fn count_range<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        range: impl RangeBounds<K>,
+    ) -> Result<u64> {
+        self.tree.count_range(range)
+    }
+
+    /// A cursor over the entries, it starts out not positioned on any
+    /// entry.
+    pub fn cursor(&mut self) -> Cursor<'_> {
+        self.tree.cursor()
+    }
+
+    /// A read only view of the entries as it is now, unaffected by later
+    /// updates.
+    ///
+    /// Fails with `Error::TooManySnapshots` once as many snapshots are open
+    /// as `OpenOptions::max_snapshots` allows.
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        self.tree.snapshot()
+    }
+
+    /// Advisory locks on ranges of keys for writers that need to coordinate,
+    /// see `KeyLocks`.
+    pub fn key_locks(&self) -> KeyLocks {
+        self.locks.clone()
+    }
+
+    /// Reject updates and commits with `Error::Frozen` until the returned
+    /// guard is dropped, reads are unaffected. See `FreezeGuard`.
+    pub fn freeze(&self) -> FreezeGuard {
+        self.tree.freeze()
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.tree.is_frozen()
+    }
+
+    /// Run `f` with the long operations it makes on the database failing
+    /// with `Error::Cancelled` once `token` is cancelled.
+    ///
+    /// Range scans, `root_hash`, `hashes_at_depth`,
+    /// `retain`, `merge_from`, `replace_range` and `snapshot_to_path` check
+    /// the token between the pages they read. Updates a cancelled operation had made are rolled
+    /// back, earlier commits stay.
+    pub fn with_cancel<T>(
+        &mut self,
+        token: &CancelToken,
+        f: impl FnOnce(&mut Db) -> Result<T>,
+    ) -> Result<T> {
+        let outer = self.tree.set_cancel(Some(token.clone()));
+        let res = f(self);
+        self.tree.set_cancel(outer);
+
+        if let Err(Error::Cancelled) = res {
+            self.tree.rollback()?;
+        }
+
+        res
+    }
+
+    /// Run `f` with the long operations it makes on the database calling
+    /// `progress` as they go, to show how far they are or to decide to
+    /// cancel them through a `CancelToken`.
+    ///
+    /// `snapshot_to_path` reports the pages of the file copied,
+    /// `root_hash` and `hashes_at_depth` the tree nodes hashed and
+    /// `merge_from` the leaves of the other database read.
+    pub fn with_progress<T>(
+        &mut self,
+        progress: impl Fn(Progress) + Send + Sync + 'static,
+        f: impl FnOnce(&mut Db) -> Result<T>,
+    ) -> Result<T> {
+        let outer = self.tree.set_progress(Some(Arc::new(progress)));
+        let res = f(self);
+        self.tree.set_progress(outer);
+
+        res
+    }
+
+    /// Start a write transaction, its updates become visible atomically
+    /// when it is committed.
+    pub fn begin(&mut self) -> WriteTxn<'_> {
+        WriteTxn::new(&mut self.tree)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.tree.put(key, value)?;
+        self.tree.commit()
+    }
+
+    /// Add `key` with an empty value, for using the database as a set of
+    /// keys. Returns false if it was already present, its value is left
+    /// alone then.
+    ///
+    /// Entries with empty values take up only the key and a length in
+    /// their leaf.
+    pub fn insert(&mut self, key: &[u8]) -> Result<bool> {
+        if self.tree.contains(key)? {
+            return Ok(false);
+        }
+
+        self.put(key, &[])?;
+
+        Ok(true)
+    }
+
+    /// Add `value` to the values of `key` in a database opened with
+    /// `OpenOptions::dup_sort`, returns false if it was already there.
+    pub fn put_dup(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let added = self.tree.put_dup(key, value)?;
+
+        if added {
+            self.tree.commit()?;
+        }
+
+        Ok(added)
+    }
+
+    /// Remove `value` from the values of `key`, and `key` once it has none
+    /// left, returns false if it wasn't there.
+    pub fn delete_dup(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let removed = self.tree.delete_dup(key, value)?;
+
+        if removed {
+            self.tree.commit()?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove `key`, returning its value if it was present.
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.tree.delete(key)?;
+
+        if value.is_some() {
+            self.tree.commit()?;
+        }
+
+        Ok(value)
+    }
+
+    /// Add `bytes` to the end of the value of `key`, returns false if it
+    /// isn't present.
+    ///
+    /// Of a value too large to be kept in its leaf only the last page is
+    /// rewritten, the rest stays where it is.
+    pub fn append(&mut self, key: &[u8], bytes: &[u8]) -> Result<bool> {
+        let appended = self.tree.append(key, bytes)?;
+
+        if appended {
+            self.tree.commit()?;
+        }
+
+        Ok(appended)
+    }
+
+    /// Overwrite the value of `key` with `bytes` starting at `offset`,
+    /// growing it if they run past its end. Returns false if `key` isn't
+    /// present and fails with `Error::OffsetOverflow` if `offset` is past
+    /// the end of the value.
+    ///
+    /// Of a value too large to be kept in its leaf only the pages holding
+    /// the updated bytes are rewritten.
+    pub fn update_range(&mut self, key: &[u8], offset: usize, bytes: &[u8]) -> Result<bool> {
+        let updated = self.tree.update_range(key, offset, bytes)?;
+
+        if updated {
+            self.tree.commit()?;
+        }
+
+        Ok(updated)
+    }
+
+    /// Apply all operations of `batch` in a single commit, after a crash
+    /// either all of them are visible or none are.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut txn = self.begin();
+
+        for op in batch.into_ops() {
+            match op {
+                batch::Op::Put(key, value) => txn.put(&key, &value)?,
+                batch::Op::Delete(key) => {
+                    txn.delete(&key)?;
+                }
+            }
+        }
+
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Delete the entries in `range` for which `keep` returns false, looking
+    /// at no more than `budget` entries, and commit.
+    ///
+    /// Returns the key to resume from if the range wasn't scanned
+    /// completely, pass `key..end` as the range of the next call.
+    pub fn retain<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        keep: impl FnMut(&[u8], &[u8]) -> bool,
+        budget: NonZeroUsize,
+    ) -> Result<Option<Vec<u8>>> {
+        let resume = self.tree.retain(range, keep, budget)?;
+        self.tree.commit()?;
+
+        Ok(resume)
+    }
+
+    /// Add every entry of `other` in a single commit, a key present in both
+    /// keeps the value picked by `conflict`. Returns the number of entries
+    /// written.
+    ///
+    /// Useful to consolidate shards or load a database built offline,
+    /// `other` is read in key order so this is much faster than putting its
+    /// entries one by one.
+    pub fn merge_from(&mut self, other: &mut Db, conflict: ConflictPolicy) -> Result<u64> {
+        let written = self.tree.merge_from(&mut other.tree, conflict)?;
+        self.tree.commit()?;
+
+        Ok(written)
+    }
+
+    /// Replace the entries with keys in `range` by `entries` in a single
+    /// commit, for rebuilding derived data like an index. Returns the number
+    /// of entries written or deleted.
+    ///
+    /// `entries` have to be in key order and within `range`, otherwise
+    /// nothing is replaced and `Error::Unordered` is returned. Entries that
+    /// didn't change are left alone.
+    pub fn replace_range<K, I, EK, EV>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        entries: I,
+    ) -> Result<u64>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        I: IntoIterator<Item = (EK, EV)>,
+        EK: AsRef<[u8]>,
+        EV: AsRef<[u8]>,
+    {
+        let written = match self.tree.replace_range(range, entries) {
+            Ok(written) => written,
+            Err(err) => {
+                self.tree.rollback()?;
+                return Err(err);
+            }
+        };
+        self.tree.commit()?;
+
+        Ok(written)
+    }
+
+    /// Number of levels in the tree.
+    pub fn height(&mut self) -> Result<usize> {
+        self.tree.height()
+    }
+
+    /// Hash of all entries and the shape of the tree holding them, see
+    /// `Snapshot::root_hash` for one that stays put while writes continue.
+    pub fn root_hash(&mut self) -> Result<TreeHash> {
+        self.tree.root_hash()
+    }
+
+    /// Hashes of the tree nodes `depth` levels below the root, each with the
+    /// lowest key it can hold, to find which subtree two trees differ in.
+    pub fn hashes_at_depth(&mut self, depth: usize) -> Result<Vec<(Vec<u8>, TreeHash)>> {
+        self.tree.hashes_at_depth(depth)
+    }
+
+    /// Number of tree nodes on each level, starting with the root.
+    pub fn node_count_by_level(&mut self) -> Result<Vec<usize>> {
+        self.tree.node_count_by_level()
+    }
+
+    /// Up to `n - 1` keys dividing the database into `n` ranges of about
+    /// the same number of entries, for splitting work on it between threads
+    /// or processes. Found from the upper levels of the tree without
+    /// scanning the entries.
+    pub fn split_points(&mut self, n: usize) -> Result<Vec<Vec<u8>>> {
+        self.tree.split_points(n)
+    }
+
+    /// Fanout of the internal tree nodes, `None` while the tree is a single
+    /// leaf.
+    pub fn fanout_stats(&mut self) -> Result<Option<FanoutStats>> {
+        self.tree.fanout_stats()
+    }
+
+    /// Number of snapshots, ranges and cursors currently open.
+    ///
+    /// Every snapshot keeps the pages of its version from being reused, a
+    /// count that keeps growing points at readers that are never dropped.
+    pub fn reader_counts(&self) -> ReaderCounts {
+        self.tree.reader_counts()
+    }
+
+    /// Memory used by the page cache.
+    pub fn cache_usage(&self) -> CacheUsage {
+        self.tree.cache_usage()
+    }
+
+    /// Page cache misses since the database was opened, by the level of the
+    /// tree the page was on. Misses on internal nodes are the sign of a
+    /// cache too small for the database, see `CacheMisses`.
+    pub fn cache_misses(&self) -> CacheMisses {
+        self.tree.cache_misses()
+    }
+
+    /// Free the memory the page cache allocated but doesn't use, to call
+    /// when the database is idle. Returns the number of bytes freed.
+    ///
+    /// Cached pages are moved together so the memory they don't fill can be
+    /// freed, the cache keeps what it holds. A cache sized with
+    /// `OpenOptions::cache_budget` does this on its own as it shrinks.
+    pub fn release_memory(&mut self) -> usize {
+        self.tree.release_memory()
+    }
+
+    /// How the pages updated since opening were written back to the file,
+    /// updates to a page that is still waiting to be written don't write it
+    /// again.
+    pub fn write_back_stats(&self) -> WriteBackStats {
+        self.tree.write_back_stats()
+    }
+
+    /// Which pages the last commit made since opening wrote, to track down
+    /// write amplification.
+    pub fn last_commit_stats(&self) -> Option<CommitStats> {
+        self.tree.last_commit_stats()
+    }
+
+    /// How many freed pages are waiting to be reused, and the oldest version
+    /// their pages are kept for.
+    pub fn free_list_stats(&self) -> FreeListStats {
+        self.tree.free_list_stats()
+    }
+
+    /// Number of pages repaired from the write-ahead log since opening, see
+    /// `OpenOptions::repair_policy`.
+    pub fn repaired_pages(&self) -> u64 {
+        self.tree.repaired_pages()
+    }
+
+    /// The tag `version` was committed with by `WriteTxn::set_tag`.
+    ///
+    /// Only the 64 most recent tagged commits are remembered, older ones and
+    /// untagged commits return `None`.
+    pub fn commit_info(&self, version: Version) -> Option<CommitInfo> {
+        self.tree.commit_info(version)
+    }
+
+    /// Catch up with a database opened read only that another process is
+    /// still writing to, returns whether it committed anything new.
+    ///
+    /// Until then reads see the database as it was when it was opened or
+    /// last refreshed. Snapshots taken before should be dropped, the writer
+    /// doesn't know about them and may reuse the pages they read.
+    pub fn refresh(&mut self) -> Result<bool> {
+        self.tree.refresh()
+    }
+
+    /// Wait for the commit that returned `version` to be durable, syncing
+    /// unless a later commit already has.
+    pub fn wait_for_durable(&mut self, version: Version) -> Result<()> {
+        self.tree.wait_for_durable(version)
+    }
+
+    /// Write a copy of the database as of the last commit to a new file at
+    /// `path`, which another process can open, read only or not.
+    ///
+    /// On filesystems that support it, like btrfs and XFS, the copy is a
+    /// copy-on-write clone that takes no time and no space up front.
+    /// Elsewhere the file is read out and written to the copy. Fails if
+    /// `path` already exists.
+    pub fn snapshot_to_path(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let dest = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        self.tree.copy_to(&dest)
+    }
+
+    /// Wait for all updates so far to be durable on disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.tree.flush()
+    }
+
+    /// Commit and sync whatever is left, then close the database.
+    ///
+    /// The deferred frees and remap cleanups no snapshot holds back are run
+    /// and free pages at the end of the file are cut off. The file is
+    /// marked as cleanly closed, the next open skips `verify_root_path`.
+    /// Dropping a `Db` instead leaves it as it was after the last commit.
+    pub fn close(mut self) -> Result<()> {
+        self.tree.close()
+    }
+
+    /// Whether the database was closed with `close` the last time it was
+    /// written, rather than dropped or interrupted by a crash.
+    pub fn was_closed_cleanly(&self) -> bool {
+        self.tree.was_closed_cleanly()
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self {
+            create: true,
+            read_only: false,
+            zero_fill: false,
+            scrub_freed_pages: false,
+            deterministic: false,
+            cache_budget: None,
+            cache_config: None,
+            dirty_watermarks: None,
+            verify_root_path: false,
+            commit_policy: CommitPolicy::EveryCommit,
+            dup_sort: false,
+            bitmap_allocator: false,
+            shadow_paging: false,
+            delta_updates: false,
+            wal: false,
+            sampler: None,
+            max_snapshots: None,
+            reuse_delay: 0,
+            repair_policy: RepairPolicy::Fail,
+        }
+    }
+
+    /// Create the database if it does not exist, defaults to `true`.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Open the database without ever writing to it, defaults to `false`.
+    ///
+    /// Any update returns `Error::ReadOnly`.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Zero newly allocated pages instead of lazily initializing them,
+    /// defaults to `false`.
+    ///
+    /// Page buffers are reused, without this stale bytes of other pages can
+    /// end up in the unused parts of a page written to the file.
+    pub fn zero_fill(&mut self, zero_fill: bool) -> &mut Self {
+        self.zero_fill = zero_fill;
+        self
+    }
+
+    /// Overwrite freed pages with zeroes, so deleted data doesn't linger in
+    /// the file, defaults to `false`.
+    pub fn scrub_freed_pages(&mut self, scrub_freed_pages: bool) -> &mut Self {
+        self.scrub_freed_pages = scrub_freed_pages;
+        self
+    }
+
+    /// Write the same bytes for the same sequence of updates, defaults to
+    /// `false`. Meant for golden files and reproducible simulations, a
+    /// file can then be diffed against the one from another run.
+    ///
+    /// This implies `zero_fill`. Page allocation, splits and the queues
+    /// are always deterministic, the random writer id recorded in the
+    /// version vector is derived from the committed version instead.
+    pub fn deterministic(&mut self, deterministic: bool) -> &mut Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Size the page cache by its hit rate, using at most `max_bytes` of
+    /// memory for cached pages. By default the cache holds a fixed number
+    /// of pages.
+    ///
+    /// The cache grows while it misses often and slowly shrinks again once
+    /// the workload fits in fewer pages.
+    pub fn cache_budget(&mut self, max_bytes: usize) -> &mut Self {
+        self.cache_budget = Some(max_bytes);
+        self.cache_config = None;
+        self
+    }
+
+    /// Hold a fixed amount of pages in the page cache, see `CacheConfig`.
+    /// This replaces a `cache_budget`.
+    pub fn cache_config(&mut self, config: CacheConfig) -> &mut Self {
+        self.cache_config = Some(config);
+        self.cache_budget = None;
+        self
+    }
+
+    /// Write back the least recently updated pages ahead of the commit once
+    /// `high_bytes` of updates are waiting to be written, until `low_bytes`
+    /// are left. By default these are half the cache, up to 4MiB, and half
+    /// of that.
+    ///
+    /// This bounds the work left to a commit after a burst of updates much
+    /// larger than the cache, while pages updated over and over stay in
+    /// memory. The high watermark is capped at half the cache.
+    pub fn dirty_watermarks(&mut self, low_bytes: usize, high_bytes: usize) -> &mut Self {
+        self.dirty_watermarks = Some((low_bytes, high_bytes));
+        self
+    }
+
+    /// Check the root of the tree and the nodes one level below it when
+    /// opening, defaults to `false`.
+    ///
+    /// Gross corruption, like a root pointing at pages that aren't nodes,
+    /// then fails the open with `Error::Corruption` instead of a later
+    /// read. The cost is bounded by one level of the tree. The queues in
+    /// the header are always checked.
+    ///
+    /// A database that was closed with `Db::close` is not checked again.
+    pub fn verify_root_path(&mut self, verify_root_path: bool) -> &mut Self {
+        self.verify_root_path = verify_root_path;
+        self
+    }
+
+    /// When transactions and batches sync, defaults to
+    /// `CommitPolicy::EveryCommit`.
+    pub fn commit_policy(&mut self, policy: CommitPolicy) -> &mut Self {
+        self.commit_policy = policy;
+        self
+    }
+
+    /// Let keys hold any number of values, defaults to `false`. Only used
+    /// when the database is created, an existing one keeps its mode.
+    ///
+    /// Values are added and removed with `put_dup` and `delete_dup` and
+    /// kept sorted within the key's value. `get`, `range` and cursors return
+    /// them packed, `dup_values` unpacks them. Meant for index trees mapping
+    /// a key to many row ids, a key's values are rewritten on every update.
+    pub fn dup_sort(&mut self, dup_sort: bool) -> &mut Self {
+        self.dup_sort = dup_sort;
+        self
+    }
+
+    /// Keep track of free pages in a bitmap instead of a queue, defaults to
+    /// `false`. A queue of free pages is cheap to keep up but can't be
+    /// searched for runs of adjacent free pages in a large file.
+    ///
+    /// An existing file is converted by its next commit, after which it
+    /// always uses the bitmap.
+    pub fn bitmap_allocator(&mut self, bitmap_allocator: bool) -> &mut Self {
+        self.bitmap_allocator = bitmap_allocator;
+        self
+    }
+
+    /// Create the database with shadow paging, defaults to `false`. Ignored
+    /// for existing databases, they keep the paging they were created with.
+    ///
+    /// Pages updated by a commit stay on the copies it made of them rather
+    /// than being copied back later. There is less to keep track of but the
+    /// original pages stay taken, and every commit writes out where all the
+    /// copied pages are. Meant for small databases.
+    pub fn shadow_paging(&mut self, shadow_paging: bool) -> &mut Self {
+        self.shadow_paging = shadow_paging;
+        self
+    }
+
+    /// Keep small updates of values that don't change their length as
+    /// deltas next to their leaf, defaults to `false`.
+    ///
+    /// Otherwise every commit that updates a leaf copies it, and the copies
+    /// stay until no snapshot can read them. A counter updated on every
+    /// commit while a snapshot is open then takes a page per commit, with
+    /// deltas only the bytes that changed. Reads of the leaf apply them
+    /// until they are folded into it, once they are synced and no snapshot
+    /// needs the leaf without them.
+    pub fn delta_updates(&mut self, delta_updates: bool) -> &mut Self {
+        self.delta_updates = delta_updates;
+        self
+    }
+
+    /// Log pages to a write-ahead log next to the database before writing
+    /// them in place, defaults to `false`. Ignored when opening read only.
+    ///
+    /// The log is named after the database with `-wal` appended. A crash
+    /// while pages are written in place can otherwise leave them torn, the
+    /// next open writes them again from the log. See
+    /// `open_file_with_wal` for databases that aren't opened from a path.
+    pub fn wal(&mut self, wal: bool) -> &mut Self {
+        self.wal = wal;
+        self
+    }
+
+    /// Call `callback` with the key of one in every `every` reads by `get`,
+    /// `get_ref`, `get_all` and `contains`, along with whether the key was
+    /// found. By default reads aren't sampled.
+    ///
+    /// Meant for building a cache or heat map of keys in the application
+    /// without wrapping every read. Reads are counted by each `Db` on its
+    /// own, ranges, cursors and snapshots aren't sampled.
+    pub fn sample_reads(
+        &mut self,
+        every: NonZeroU64,
+        callback: impl Fn(&[u8], bool) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.sampler = Some(ReadSampler::new(every, Arc::new(callback)));
+        self
+    }
+
+    /// Most snapshots that can be open at once, `Db::snapshot` fails with
+    /// `Error::TooManySnapshots` past it. Unlimited by default.
+    ///
+    /// Snapshots hold on to the pages of their version, so code that leaks
+    /// them grows the file without bound. A limit turns that into an error.
+    /// Cloning a snapshot can't fail, clones count towards the limit but
+    /// aren't refused.
+    pub fn max_snapshots(&mut self, max: usize) -> &mut Self {
+        self.max_snapshots = Some(max);
+        self
+    }
+
+    /// Keep the pages of the last `versions` commits from being reused even
+    /// if no snapshot pins them, defaults to 0.
+    ///
+    /// Pages freed by a commit are otherwise reused as soon as no snapshot
+    /// can read them. See `Db::free_list_stats` for the pages held back.
+    pub fn reuse_delay(&mut self, versions: u64) -> &mut Self {
+        self.reuse_delay = versions;
+        self
+    }
+
+    /// What to do with pages of the tree read from the file that fail to
+    /// decode, defaults to `RepairPolicy::Fail`. Repairing needs the
+    /// write-ahead log, see `wal`.
+    pub fn repair_policy(&mut self, policy: RepairPolicy) -> &mut Self {
+        self.repair_policy = policy;
+        self
+    }
+
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<Db> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!self.read_only)
+            .create(self.create && !self.read_only)
+            .truncate(false)
+            .open(path)?;
+
+        if self.wal && !self.read_only {
+            let mut wal_path = path.as_os_str().to_owned();
+            wal_path.push("-wal");
+
+            let wal = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(wal_path)?;
+
+            return self.open_file_with_wal(file, wal);
+        }
+
+        self.open_file(file)
+    }
+
+    /// Open the database stored in `file`, an empty file creates a new one
+    /// regardless of `create`.
+    pub fn open_file(&self, file: impl File + 'static) -> Result<Db> {
+        let pager = if self.read_only {
+            DWALPager::recover_read_only(file)?
+        } else {
+            DWALPager::recover(file)?
+        };
+
+        self.open_pager(pager)
+    }
+
+    /// Open the database stored in `file` with its write-ahead log in
+    /// `wal`, see `wal`.
+    pub fn open_file_with_wal(
+        &self,
+        file: impl File + 'static,
+        wal: impl File + 'static,
+    ) -> Result<Db> {
+        if self.read_only {
+            return self.open_file(file);
+        }
+
+        self.open_pager(DWALPager::recover_with_wal(file, wal)?)
+    }
+
+    fn open_pager(&self, mut pager: DWALPager) -> Result<Db> {
+        pager.set_zero_fill(self.zero_fill);
+        pager.set_scrub_freed(self.scrub_freed_pages);
+        pager.set_deterministic(self.deterministic);
+        if let Some(max_bytes) = self.cache_budget {
+            pager.set_cache_budget(max_bytes);
+        }
+        if let Some(config) = self.cache_config {
+            pager.set_cache_config(config);
+        }
+        if let Some((low_bytes, high_bytes)) = self.dirty_watermarks {
+            pager.set_dirty_watermarks(low_bytes, high_bytes);
+        }
+        pager.set_commit_policy(self.commit_policy);
+        pager.set_reuse_delay(self.reuse_delay);
+        pager.set_repair_policy(self.repair_policy);
+        pager.set_delta_updates(self.delta_updates);
+        if self.bitmap_allocator && !self.read_only {
+            pager.use_bitmap_allocator()?;
+        }
+        if self.dup_sort && pager.root_page().is_none() {
+            pager.set_tree_flags(DUP_SORT);
+        }
+        if self.shadow_paging && !self.read_only && pager.root_page().is_none() {
+            pager.use_shadow_paging()?;
+        }
+
+        let mut tree = Tree::open(pager)?;
+        tree.set_max_snapshots(self.max_snapshots);
+        if self.verify_root_path && !tree.was_closed_cleanly() {
+            tree.verify_root_path()?;
+        }
+
+        Ok(Db {
+            tree,
+            locks: KeyLocks::new(),
+            sampler: self.sampler.clone(),
+        })
+    }
+}
+
+fn sample(sampler: &mut Option<ReadSampler>, key: &[u8], hit: bool) {
+    if let Some(sampler) = sampler {
+        sampler.record(key, hit);
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
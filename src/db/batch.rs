@@ -0,0 +1,47 @@
+/// A set of puts and deletes that `Db::write` applies atomically.
+///
+/// Operations are applied in the order they were added, so a later put or
+/// delete of the same key wins.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<Op>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(Op::Put(key.to_vec(), value.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(Op::Delete(key.to_vec()));
+        self
+    }
+
+    /// Number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub(super) fn into_ops(self) -> Vec<Op> {
+        self.ops
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+};
+
+/// Advisory locks on ranges of keys, shared by everything in the process
+/// that writes to a `Db`.
+///
+/// Nothing in the database checks these, they only serialize the callers
+/// that take them. Locks on overlapping ranges exclude each other, locks on
+/// disjoint ranges are held at the same time. Handles are cheap to clone and
+/// can be sent to other threads.
+#[derive(Debug, Clone, Default)]
+pub struct KeyLocks {
+    inner: Arc<Inner>,
+}
+
+/// A held lock on a range of keys, it is released on drop.
+#[derive(Debug)]
+pub struct KeyRangeGuard {
+    inner: Arc<Inner>,
+    id: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    held: Mutex<Held>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Held {
+    next_id: u64,
+    ranges: Vec<(u64, KeyRange)>,
+}
+
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+impl KeyLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lock `range`, blocking until no overlapping range is locked.
+    pub fn lock<K: AsRef<[u8]> + ?Sized>(&self, range: impl RangeBounds<K>) -> KeyRangeGuard {
+        let range = to_owned(range);
+        let mut held = self.held();
+
+        while held.overlaps(&range) {
+            held = self
+                .inner
+                .released
+                .wait(held)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+
+        self.insert(held, range)
+    }
+
+    /// Lock `range` if no overlapping range is locked.
+    pub fn try_lock<K: AsRef<[u8]> + ?Sized>(
+        &self,
+        range: impl RangeBounds<K>,
+    ) -> Option<KeyRangeGuard> {
+        let range = to_owned(range);
+        let held = self.held();
+
+        if held.overlaps(&range) {
+            return None;
+        }
+
+        Some(self.insert(held, range))
+    }
+
+    fn insert(&self, mut held: MutexGuard<'_, Held>, range: KeyRange) -> KeyRangeGuard {
+        let id = held.next_id;
+        held.next_id += 1;
+        held.ranges.push((id, range));
+
+        KeyRangeGuard {
+            inner: self.inner.clone(),
+            id,
+        }
+    }
+
+    fn held(&self) -> MutexGuard<'_, Held> {
+        // The lock is only held for bookkeeping that can't panic halfway.
+        self.inner.held.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for KeyRangeGuard {
+    fn drop(&mut self) {
+        let mut held = self.inner.held.lock().unwrap_or_else(|e| e.into_inner());
+        held.ranges.retain(|(id, _)| *id != self.id);
+        drop(held);
+
+        self.inner.released.notify_all();
+    }
+}
+
+impl Held {
+    fn overlaps(&self, range: &KeyRange) -> bool {
+        self.ranges.iter().any(|(_, held)| overlaps(held, range))
+    }
+}
+
+fn to_owned<K: AsRef<[u8]> + ?Sized>(range: impl RangeBounds<K>) -> KeyRange {
+    (
+        range.start_bound().map(|key| key.as_ref().to_vec()),
+        range.end_bound().map(|key| key.as_ref().to_vec()),
+    )
+}
+
+fn overlaps(a: &KeyRange, b: &KeyRange) -> bool {
+    !ends_before(&a.1, &b.0) && !ends_before(&b.1, &a.0)
+}
+
+/// Whether a range ending at `end` lies entirely before one starting at
+/// `start`.
+fn ends_before(end: &Bound<Vec<u8>>, start: &Bound<Vec<u8>>) -> bool {
+    match (end, start) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(end), Bound::Included(start)) => end < start,
+        (Bound::Included(end), Bound::Excluded(start))
+        | (Bound::Excluded(end), Bound::Included(start))
+        | (Bound::Excluded(end), Bound::Excluded(start)) => end <= start,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn overlapping_ranges() {
+        let locks = KeyLocks::new();
+
+        let ab = locks.lock(b"a".as_ref()..b"b".as_ref());
+
+        // Ranges that only touch `a..b` at an excluded bound are disjoint.
+        let bc = locks.try_lock(b"b".as_ref()..=b"c".as_ref()).unwrap();
+        assert!(locks.try_lock(..b"a".as_ref()).is_some());
+
+        assert!(locks.try_lock(b"a".as_ref()..=b"a".as_ref()).is_none());
+        assert!(locks.try_lock(b"c".as_ref()..).is_none());
+        assert!(locks.try_lock::<[u8]>(..).is_none());
+
+        drop(bc);
+        assert!(locks.try_lock(b"c".as_ref()..).is_some());
+
+        drop(ab);
+        assert!(locks.try_lock::<[u8]>(..).is_some());
+    }
+
+    #[test]
+    fn lock_blocks() {
+        let locks = KeyLocks::new();
+        let guard = locks.lock(b"k".as_ref()..=b"k".as_ref());
+
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn({
+            let locks = locks.clone();
+            move || {
+                let _guard = locks.lock(b"a".as_ref()..b"z".as_ref());
+                tx.send(()).unwrap();
+            }
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        drop(guard);
+        rx.recv().unwrap();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+use std::{fmt, num::NonZeroU64, sync::Arc};
+
+/// Called with the key of a sampled read and whether it was found.
+type Callback = Arc<dyn Fn(&[u8], bool) + Send + Sync>;
+
+/// Passes one in every `every` key reads of a `Db` to a callback, set up
+/// with `OpenOptions::sample_reads`.
+#[derive(Clone)]
+pub(super) struct ReadSampler {
+    every: NonZeroU64,
+    /// Reads since the last one sampled.
+    skipped: u64,
+    callback: Callback,
+}
+
+impl ReadSampler {
+    pub(super) fn new(every: NonZeroU64, callback: Callback) -> Self {
+        Self {
+            every,
+            skipped: 0,
+            callback,
+        }
+    }
+
+    /// Count a read of `key`, `hit` if it was found.
+    pub(super) fn record(&mut self, key: &[u8], hit: bool) {
+        self.skipped += 1;
+
+        if self.skipped == self.every.get() {
+            self.skipped = 0;
+            (self.callback)(key, hit);
+        }
+    }
+}
+
+impl fmt::Debug for ReadSampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadSampler")
+            .field("every", &self.every)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn one_in_every() {
+        let sampled = Arc::new(Mutex::new(Vec::new()));
+        let sink = sampled.clone();
+        let mut sampler = ReadSampler::new(
+            NonZeroU64::new(3).unwrap(),
+            Arc::new(move |key: &[u8], hit| sink.lock().unwrap().push((key[0], hit))),
+        );
+
+        for i in 0..10u8 {
+            sampler.record(&[i], i % 2 == 0);
+        }
+
+        assert_eq!(
+            *sampled.lock().unwrap(),
+            vec![(2, true), (5, false), (8, true)]
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use crate::{tree::Tree, Result, Version};
+
+/// A write transaction, started with `Db::begin`.
+///
+/// Puts and deletes are written against the uncommitted version and are only
+/// visible to the transaction itself, and snapshots won't see them, until
+/// `commit`. Dropping the transaction without committing rolls it back.
+pub struct WriteTxn<'a> {
+    tree: &'a mut Tree,
+    done: bool,
+}
+
+impl<'a> WriteTxn<'a> {
+    pub(super) fn new(tree: &'a mut Tree) -> Self {
+        Self { tree, done: false }
+    }
+
+    /// Look up `key`, including the updates made by this transaction.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.get(key)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.tree.put(key, value)
+    }
+
+    /// Remove `key`, returning its value if it was present.
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.delete(key)
+    }
+
+    /// Tag the commit with `tag`, like a hybrid logical clock timestamp or
+    /// the id of the upstream transaction it applies. `Db::commit_info`
+    /// returns it for the committed version.
+    pub fn set_tag(&mut self, tag: [u8; 16]) {
+        self.tree.set_commit_tag(tag);
+    }
+
+    /// Commit all updates of the transaction at once.
+    ///
+    /// With the default `CommitPolicy` they are durable when this returns,
+    /// otherwise pass the returned version to `Db::wait_for_durable` to
+    /// wait for the sync that covers it.
+    pub fn commit(mut self) -> Result<Version> {
+        self.done = true;
+
+        self.tree.commit_grouped()
+    }
+
+    /// Discard all updates of the transaction.
+    pub fn rollback(mut self) -> Result<()> {
+        self.done = true;
+
+        self.tree.rollback()
+    }
+}
+
+impl Drop for WriteTxn<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            // A failed rollback poisons the pager, which is reported by the
+            // next update.
+            let _ = self.tree.rollback();
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! C API for embedding `treedb` from other languages.
+//!
+//! Enabled with the `ffi` feature. The library is built with
+//! `cargo rustc --release --features ffi --crate-type cdylib` and the header
+//! generated from this module with
+//! `cbindgen --config cbindgen.toml --output treedb.h`.
+//!
+//! Every function returns a `Status`. Buffers handed out by the library,
+//! like the values returned by `treedb_get`, are owned by the caller and
+//! have to be released with `treedb_free_buf`.
+
+use std::{ffi::CStr, ops::Bound, os::raw::c_char, ptr, slice};
+
+use crate::{Db, WriteTxn};
+
+/// Result of a call into the C API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    /// The key does not exist, or an iterator is exhausted.
+    NotFound = 1,
+    /// A pointer argument was null, or a path was not valid utf-8.
+    InvalidArgument = 2,
+    /// The underlying operation failed.
+    Error = 3,
+}
+
+/// An iterator over a range of keys.
+///
+/// It does not borrow the database, every call to `treedb_iter_next` seeks
+/// past the last key it returned. Updates made between calls are observed.
+pub struct Iter {
+    db: *mut Db,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+/// A write transaction, see `WriteTxn`.
+///
+/// The database can't be used, other than through the transaction, until it
+/// is committed or rolled back.
+pub struct Txn(WriteTxn<'static>);
+
+/// Open the database at the nul terminated `path`, creating it if it does
+/// not exist, and store it in `db`.
+///
+/// # Safety
+///
+/// `path` must be a valid nul terminated string and `db` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_open(path: *const c_char, db: *mut *mut Db) -> Status {
+    if path.is_null() || db.is_null() {
+        return Status::InvalidArgument;
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return Status::InvalidArgument,
+    };
+
+    match Db::open(path) {
+        Ok(opened) => {
+            *db = Box::into_raw(Box::new(opened));
+            Status::Ok
+        }
+        Err(_) => Status::Error,
+    }
+}
+
+/// Commit whatever is left, shut the database down cleanly and release
+/// it, even if closing fails. See `Db::close`.
+///
+/// # Safety
+///
+/// `db` must come from `treedb_open`, it and any iterators over it can't be
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_close(db: *mut Db) -> Status {
+    if db.is_null() {
+        return Status::InvalidArgument;
+    }
+
+    match Box::from_raw(db).close() {
+        Ok(()) => Status::Ok,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Wait for every update committed so far to be durable on disk, see
+/// `Db::flush`.
+///
+/// # Safety
+///
+/// `db` must be an open database.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_flush(db: *mut Db) -> Status {
+    let db = match db.as_mut() {
+        Some(db) => db,
+        None => return Status::InvalidArgument,
+    };
+
+    match db.flush() {
+        Ok(()) => Status::Ok,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Insert or replace the value of `key` and commit.
+///
+/// # Safety
+///
+/// `db` must be an open database and `key`/`value` valid for reads of their
+/// lengths.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_put(
+    db: *mut Db,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> Status {
+    let (db, key, value) = match (db.as_mut(), bytes(key, key_len), bytes(value, value_len)) {
+        (Some(db), Some(key), Some(value)) => (db, key, value),
+        _ => return Status::InvalidArgument,
+    };
+
+    match db.put(key, value) {
+        Ok(()) => Status::Ok,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Look up `key`, on success its value is stored in `value`/`value_len`.
+///
+/// # Safety
+///
+/// `db` must be an open database, `key` valid for reads of `key_len` and
+/// `value`/`value_len` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_get(
+    db: *mut Db,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> Status {
+    let (db, key) = match (db.as_mut(), bytes(key, key_len)) {
+        (Some(db), Some(key)) => (db, key),
+        _ => return Status::InvalidArgument,
+    };
+
+    if value.is_null() || value_len.is_null() {
+        return Status::InvalidArgument;
+    }
+
+    match db.get(key) {
+        Ok(Some(found)) => {
+            write_buf(found, value, value_len);
+            Status::Ok
+        }
+        Ok(None) => Status::NotFound,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Remove `key` and commit, returns `NotFound` if it didn't exist.
+///
+/// # Safety
+///
+/// `db` must be an open database and `key` valid for reads of `key_len`.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_delete(db: *mut Db, key: *const u8, key_len: usize) -> Status {
+    let (db, key) = match (db.as_mut(), bytes(key, key_len)) {
+        (Some(db), Some(key)) => (db, key),
+        _ => return Status::InvalidArgument,
+    };
+
+    match db.delete(key) {
+        Ok(Some(_)) => Status::Ok,
+        Ok(None) => Status::NotFound,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Start a write transaction and store it in `txn`.
+///
+/// # Safety
+///
+/// `db` must be an open database that outlives the transaction and `txn`
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_txn_begin(db: *mut Db, txn: *mut *mut Txn) -> Status {
+    let db = match db.as_mut() {
+        Some(db) if !txn.is_null() => db,
+        _ => return Status::InvalidArgument,
+    };
+
+    *txn = Box::into_raw(Box::new(Txn(db.begin())));
+
+    Status::Ok
+}
+
+/// Insert or replace the value of `key` within the transaction.
+///
+/// # Safety
+///
+/// `txn` must come from `treedb_txn_begin` and `key`/`value` be valid for
+/// reads of their lengths.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_txn_put(
+    txn: *mut Txn,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> Status {
+    let (txn, key, value) = match (txn.as_mut(), bytes(key, key_len), bytes(value, value_len)) {
+        (Some(txn), Some(key), Some(value)) => (txn, key, value),
+        _ => return Status::InvalidArgument,
+    };
+
+    match txn.0.put(key, value) {
+        Ok(()) => Status::Ok,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Remove `key` within the transaction, returns `NotFound` if it didn't
+/// exist.
+///
+/// # Safety
+///
+/// `txn` must come from `treedb_txn_begin` and `key` be valid for reads of
+/// `key_len`.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_txn_delete(
+    txn: *mut Txn,
+    key: *const u8,
+    key_len: usize,
+) -> Status {
+    let (txn, key) = match (txn.as_mut(), bytes(key, key_len)) {
+        (Some(txn), Some(key)) => (txn, key),
+        _ => return Status::InvalidArgument,
+    };
+
+    match txn.0.delete(key) {
+        Ok(Some(_)) => Status::Ok,
+        Ok(None) => Status::NotFound,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Commit the transaction and release it, even if the commit fails.
+///
+/// # Safety
+///
+/// `txn` must come from `treedb_txn_begin`, it can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_txn_commit(txn: *mut Txn) -> Status {
+    if txn.is_null() {
+        return Status::InvalidArgument;
+    }
+
+    match Box::from_raw(txn).0.commit() {
+        Ok(_) => Status::Ok,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Discard the updates of the transaction and release it.
+///
+/// # Safety
+///
+/// `txn` must come from `treedb_txn_begin`, it can't be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_txn_rollback(txn: *mut Txn) -> Status {
+    if txn.is_null() {
+        return Status::InvalidArgument;
+    }
+
+    match Box::from_raw(txn).0.rollback() {
+        Ok(()) => Status::Ok,
+        Err(_) => Status::Error,
+    }
+}
+
+/// Release a buffer handed out by the library, null is ignored.
+///
+/// # Safety
+///
+/// `buf` and `len` must be exactly as returned by the library.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_free_buf(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)));
+    }
+}
+
+/// Create an iterator over the keys in `start..end` and store it in `iter`.
+///
+/// A null `start` or `end` leaves that side of the range unbounded.
+///
+/// # Safety
+///
+/// `db` must be an open database that outlives the iterator, `start`/`end`
+/// null or valid for reads of their lengths and `iter` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_iter_new(
+    db: *mut Db,
+    start: *const u8,
+    start_len: usize,
+    end: *const u8,
+    end_len: usize,
+    iter: *mut *mut Iter,
+) -> Status {
+    if db.is_null() || iter.is_null() {
+        return Status::InvalidArgument;
+    }
+
+    let bound = |key: *const u8, len, bound: fn(Vec<u8>) -> Bound<Vec<u8>>| {
+        if key.is_null() {
+            Bound::Unbounded
+        } else {
+            bound(slice::from_raw_parts(key, len).to_vec())
+        }
+    };
+
+    *iter = Box::into_raw(Box::new(Iter {
+        db,
+        start: bound(start, start_len, Bound::Included),
+        end: bound(end, end_len, Bound::Excluded),
+    }));
+
+    Status::Ok
+}
+
+/// Advance the iterator, on success the next entry is stored in
+/// `key`/`key_len` and `value`/`value_len`. Returns `NotFound` once there
+/// are no entries left.
+///
+/// # Safety
+///
+/// `iter` must come from `treedb_iter_new` and the out pointers be valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_iter_next(
+    iter: *mut Iter,
+    key: *mut *mut u8,
+    key_len: *mut usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> Status {
+    let iter = match iter.as_mut() {
+        Some(iter) => iter,
+        None => return Status::InvalidArgument,
+    };
+
+    if key.is_null() || key_len.is_null() || value.is_null() || value_len.is_null() {
+        return Status::InvalidArgument;
+    }
+
+    let db = &mut *iter.db;
+    let range = (
+        iter.start.as_ref().map(Vec::as_slice),
+        iter.end.as_ref().map(Vec::as_slice),
+    );
+
+    let next = db.range::<[u8]>(range).next();
+
+    match next {
+        Some(Ok((found_key, found_value))) => {
+            iter.start = Bound::Excluded(found_key.clone());
+
+            write_buf(found_key, key, key_len);
+            write_buf(found_value, value, value_len);
+            Status::Ok
+        }
+        None => Status::NotFound,
+        Some(Err(_)) => Status::Error,
+    }
+}
+
+/// Release an iterator, null is ignored.
+///
+/// # Safety
+///
+/// `iter` must come from `treedb_iter_new`.
+#[no_mangle]
+pub unsafe extern "C" fn treedb_iter_free(iter: *mut Iter) {
+    if !iter.is_null() {
+        drop(Box::from_raw(iter));
+    }
+}
+
+/// Borrow `len` bytes at `data`, an empty slice may be passed as null.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(data, len)),
+    }
+}
+
+/// Hand ownership of `bytes` to the caller, see `treedb_free_buf`.
+unsafe fn write_buf(bytes: Vec<u8>, buf: *mut *mut u8, len: *mut usize) {
+    *len = bytes.len();
+    *buf = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    unsafe fn take(buf: *mut u8, len: usize) -> Vec<u8> {
+        let bytes = slice::from_raw_parts(buf, len).to_vec();
+        treedb_free_buf(buf, len);
+        bytes
+    }
+
+    #[test]
+    fn roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = CString::new(dir.path().join("db").to_str().unwrap()).unwrap();
+
+        unsafe {
+            let mut db = ptr::null_mut();
+            assert_eq!(treedb_open(path.as_ptr(), &mut db), Status::Ok);
+
+            for key in [b"a", b"b", b"c"] {
+                assert_eq!(
+                    treedb_put(db, key.as_ptr(), 1, b"v".as_ptr(), 1),
+                    Status::Ok
+                );
+            }
+            assert_eq!(treedb_delete(db, b"b".as_ptr(), 1), Status::Ok);
+            assert_eq!(treedb_delete(db, b"b".as_ptr(), 1), Status::NotFound);
+
+            let mut txn = ptr::null_mut();
+            assert_eq!(treedb_txn_begin(db, &mut txn), Status::Ok);
+            assert_eq!(
+                treedb_txn_put(txn, b"d".as_ptr(), 1, b"v".as_ptr(), 1),
+                Status::Ok
+            );
+            assert_eq!(treedb_txn_rollback(txn), Status::Ok);
+
+            assert_eq!(treedb_txn_begin(db, &mut txn), Status::Ok);
+            assert_eq!(
+                treedb_txn_put(txn, b"e".as_ptr(), 1, b"v".as_ptr(), 1),
+                Status::Ok
+            );
+            assert_eq!(treedb_txn_delete(txn, b"a".as_ptr(), 1), Status::Ok);
+            assert_eq!(treedb_txn_commit(txn), Status::Ok);
+
+            let (mut value, mut value_len) = (ptr::null_mut(), 0);
+            assert_eq!(
+                treedb_get(db, b"c".as_ptr(), 1, &mut value, &mut value_len),
+                Status::Ok
+            );
+            assert_eq!(take(value, value_len), b"v");
+            assert_eq!(
+                treedb_get(db, b"b".as_ptr(), 1, &mut value, &mut value_len),
+                Status::NotFound
+            );
+
+            let mut iter = ptr::null_mut();
+            assert_eq!(
+                treedb_iter_new(db, ptr::null(), 0, ptr::null(), 0, &mut iter),
+                Status::Ok
+            );
+
+            let mut keys = Vec::new();
+            let (mut key, mut key_len) = (ptr::null_mut(), 0);
+            while treedb_iter_next(iter, &mut key, &mut key_len, &mut value, &mut value_len)
+                == Status::Ok
+            {
+                keys.push(take(key, key_len));
+                take(value, value_len);
+            }
+            assert_eq!(keys, [b"c", b"e"]);
+
+            treedb_iter_free(iter);
+            assert_eq!(treedb_close(db), Status::Ok);
+        }
+    }
+
+    #[test]
+    fn reopen_after_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = CString::new(dir.path().join("db").to_str().unwrap()).unwrap();
+
+        unsafe {
+            let mut db = ptr::null_mut();
+            assert_eq!(treedb_open(path.as_ptr(), &mut db), Status::Ok);
+            assert_eq!(
+                treedb_put(db, b"a".as_ptr(), 1, b"1".as_ptr(), 1),
+                Status::Ok
+            );
+            assert_eq!(treedb_flush(db), Status::Ok);
+            assert_eq!(
+                treedb_put(db, b"b".as_ptr(), 1, b"2".as_ptr(), 1),
+                Status::Ok
+            );
+            assert_eq!(treedb_close(db), Status::Ok);
+            assert_eq!(treedb_close(ptr::null_mut()), Status::InvalidArgument);
+            assert_eq!(treedb_flush(ptr::null_mut()), Status::InvalidArgument);
+
+            let reopened = Db::open(dir.path().join("db")).unwrap();
+            assert!(reopened.was_closed_cleanly());
+            drop(reopened);
+
+            assert_eq!(treedb_open(path.as_ptr(), &mut db), Status::Ok);
+            for (key, value) in [(b"a", b"1"), (b"b", b"2")] {
+                let (mut found, mut found_len) = (ptr::null_mut(), 0);
+                assert_eq!(
+                    treedb_get(db, key.as_ptr(), 1, &mut found, &mut found_len),
+                    Status::Ok
+                );
+                assert_eq!(take(found, found_len), value);
+            }
+            assert_eq!(treedb_close(db), Status::Ok);
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! Order preserving key encodings.
+//!
+//! Keys in the tree are compared as raw bytes. The encodings here produce
+//! bytes whose lexicographic order matches the natural order of the encoded
+//! values, so integers, floats, strings and tuples of them can be used as
+//! keys directly.
+//!
+//! - Unsigned integers are big endian, signed integers additionally have their
+//!   sign bit flipped.
+//! - Floats follow `total_cmp`: `-NaN < -inf < -0.0 < 0.0 < inf < NaN`.
+//! - `None` sorts before any `Some`.
+//! - Byte strings are escaped (`0x00` becomes `0x00 0xff`) and terminated with
+//!   `0x00 0x01`, which makes them self delimiting so tuples can be composed by
+//!   concatenating their elements.
+
+use crate::{Error, Result};
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_NULL: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+/// A type that can be encoded into an order preserving key.
+pub trait KeyCodec: Sized {
+    /// Append the encoding of `self` to `out`.
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Decode a value from the front of `input`, advancing it past the bytes
+    /// that were consumed.
+    fn decode_key(input: &mut &[u8]) -> Result<Self>;
+}
+
+/// Encode `key` into a new buffer.
+pub fn encode<K: KeyCodec>(key: &K) -> Vec<u8> {
+    let mut out = Vec::new();
+    key.encode_key(&mut out);
+    out
+}
+
+/// Decode a key, failing if `bytes` is not exactly one encoded `K`.
+pub fn decode<K: KeyCodec>(mut bytes: &[u8]) -> Result<K> {
+    let key = K::decode_key(&mut bytes)?;
+
+    if !bytes.is_empty() {
+        return Err(Error::InvalidKeyEncoding);
+    }
+
+    Ok(key)
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(Error::InvalidKeyEncoding);
+    }
+
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+
+    Ok(head)
+}
+
+macro_rules! unsigned {
+    ($($ty:ty),*) => {
+        $(
+            impl KeyCodec for $ty {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_key(input: &mut &[u8]) -> Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$ty>()];
+                    bytes.copy_from_slice(take(input, std::mem::size_of::<$ty>())?);
+                    Ok(<$ty>::from_be_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+unsigned!(u8, u16, u32, u64, u128);
+
+macro_rules! signed {
+    ($($ty:ty => $unsigned:ty),*) => {
+        $(
+            impl KeyCodec for $ty {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    // Flipping the sign bit moves negative numbers below
+                    // positive ones while keeping two's complement order.
+                    let flipped = (*self as $unsigned) ^ (1 << (<$unsigned>::BITS - 1));
+                    flipped.encode_key(out);
+                }
+
+                fn decode_key(input: &mut &[u8]) -> Result<Self> {
+                    let flipped = <$unsigned>::decode_key(input)?;
+                    Ok((flipped ^ (1 << (<$unsigned>::BITS - 1))) as $ty)
+                }
+            }
+        )*
+    };
+}
+
+signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+macro_rules! float {
+    ($($ty:ty => $unsigned:ty),*) => {
+        $(
+            impl KeyCodec for $ty {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    let bits = self.to_bits();
+                    let sign = 1 << (<$unsigned>::BITS - 1);
+
+                    // Negative floats have all bits flipped so larger
+                    // magnitudes sort first, positive ones only the sign bit.
+                    let bits = if bits & sign != 0 { !bits } else { bits | sign };
+                    bits.encode_key(out);
+                }
+
+                fn decode_key(input: &mut &[u8]) -> Result<Self> {
+                    let bits = <$unsigned>::decode_key(input)?;
+                    let sign = 1 << (<$unsigned>::BITS - 1);
+
+                    let bits = if bits & sign != 0 { bits & !sign } else { !bits };
+                    Ok(<$ty>::from_bits(bits))
+                }
+            }
+        )*
+    };
+}
+
+float!(f32 => u32, f64 => u64);
+
+impl KeyCodec for bool {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        (*self as u8).encode_key(out);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<Self> {
+        match u8::decode_key(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidKeyEncoding),
+        }
+    }
+}
+
+impl KeyCodec for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        for byte in self {
+            out.push(*byte);
+
+            if *byte == ESCAPE {
+                out.push(ESCAPED_NULL);
+            }
+        }
+
+        out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<Self> {
+        let mut bytes = Vec::new();
+
+        loop {
+            let byte = take(input, 1)?[0];
+
+            if byte != ESCAPE {
+                bytes.push(byte);
+                continue;
+            }
+
+            match take(input, 1)?[0] {
+                ESCAPED_NULL => bytes.push(ESCAPE),
+                TERMINATOR => return Ok(bytes),
+                _ => return Err(Error::InvalidKeyEncoding),
+            }
+        }
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        // Utf-8 byte order matches code point order.
+        self.as_bytes().to_vec().encode_key(out);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(Vec::decode_key(input)?).map_err(|_| Error::InvalidKeyEncoding)
+    }
+}
+
+impl<T: KeyCodec> KeyCodec for Option<T> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode_key(out);
+            }
+        }
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<Self> {
+        match take(input, 1)?[0] {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode_key(input)?)),
+            _ => Err(Error::InvalidKeyEncoding),
+        }
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident),*) => {
+        impl<$($name: KeyCodec),*> KeyCodec for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                let ($($name,)*) = self;
+                $($name.encode_key(out);)*
+            }
+
+            fn decode_key(input: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_key(input)?,)*))
+            }
+        }
+    };
+}
+
+tuple!(A);
+tuple!(A, B);
+tuple!(A, B, C);
+tuple!(A, B, C, D);
+tuple!(A, B, C, D, E);
+
+#[cfg(test)]
+mod tests {
+    use std::{cmp::Ordering, fmt::Debug};
+
+    use super::*;
+
+    /// Small xorshift so the property tests are deterministic without
+    /// pulling in a rng crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn bytes(&mut self) -> Vec<u8> {
+            let len = self.next() % 6;
+            // A small alphabet including the escape bytes makes shared
+            // prefixes and escapes common.
+            (0..len)
+                .map(|_| [0x00, 0x01, 0x02, 0xff][self.next() as usize % 4])
+                .collect()
+        }
+
+        fn float(&mut self) -> f64 {
+            match self.next() % 8 {
+                0 => f64::NAN,
+                1 => -f64::NAN,
+                2 => f64::INFINITY,
+                3 => f64::NEG_INFINITY,
+                4 => 0.0,
+                5 => -0.0,
+                _ => f64::from_bits(self.next()),
+            }
+        }
+    }
+
+    fn check<K: KeyCodec + Debug>(a: &K, b: &K, natural: Ordering) {
+        let (ea, eb) = (encode(a), encode(b));
+        assert_eq!(ea.cmp(&eb), natural, "{:?} vs {:?}", a, b);
+    }
+
+    fn roundtrip<K: KeyCodec + Debug + PartialEq>(key: K) {
+        assert_eq!(decode::<K>(&encode(&key)).unwrap(), key);
+    }
+
+    #[test]
+    fn integers() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..10_000 {
+            let (a, b) = (rng.next() as i64, rng.next() as i64);
+            check(&a, &b, a.cmp(&b));
+            roundtrip(a);
+
+            let (a, b) = (rng.next() as i8, rng.next() as i8);
+            check(&a, &b, a.cmp(&b));
+            roundtrip(a);
+
+            let (a, b) = (rng.next() as u32, rng.next() as u32);
+            check(&a, &b, a.cmp(&b));
+            roundtrip(a);
+        }
+
+        check(&i64::MIN, &-1, Ordering::Less);
+        check(&-1i64, &0, Ordering::Less);
+        check(&0i64, &i64::MAX, Ordering::Less);
+    }
+
+    #[test]
+    fn floats() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..10_000 {
+            let (a, b) = (rng.float(), rng.float());
+            check(&a, &b, a.total_cmp(&b));
+
+            let decoded = decode::<f64>(&encode(&a)).unwrap();
+            assert_eq!(decoded.to_bits(), a.to_bits());
+
+            let (a, b) = (a as f32, b as f32);
+            check(&a, &b, a.total_cmp(&b));
+        }
+
+        check(&-0.0f64, &0.0, Ordering::Less);
+        check(&f64::NEG_INFINITY, &f64::MIN, Ordering::Less);
+        check(&f64::MAX, &f64::INFINITY, Ordering::Less);
+    }
+
+    #[test]
+    fn bytes_and_options() {
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+
+        for _ in 0..10_000 {
+            let (a, b) = (rng.bytes(), rng.bytes());
+            check(&a, &b, a.cmp(&b));
+            roundtrip(a.clone());
+
+            let a = if rng.next() % 3 == 1 { None } else { Some(a) };
+            let b = if rng.next() % 3 == 1 { None } else { Some(b) };
+            check(&a, &b, a.cmp(&b));
+            roundtrip(a);
+        }
+
+        check(&String::from("a"), &String::from("a\0"), Ordering::Less);
+        check(&String::from("ab"), &String::from("b"), Ordering::Less);
+        roundtrip(String::from("hello\0world"));
+    }
+
+    #[test]
+    fn tuples() {
+        let mut rng = Rng(0x0123_4567_89ab_cdef);
+
+        for _ in 0..10_000 {
+            let a = (rng.bytes(), rng.next() as i16 % 4, Some(rng.float() > 0.0));
+            let b = (rng.bytes(), rng.next() as i16 % 4, Some(rng.float() > 0.0));
+            check(&a, &b, a.cmp(&b));
+            roundtrip(a);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(decode::<u32>(&[0, 0, 0]).is_err());
+        assert!(decode::<u8>(&[0, 0]).is_err());
+        assert!(decode::<bool>(&[2]).is_err());
+        assert!(decode::<Vec<u8>>(&[1, 2, 3]).is_err());
+        assert!(decode::<Vec<u8>>(&[0, 7]).is_err());
+        assert!(decode::<Option<u8>>(&[2, 0]).is_err());
+    }
+}
@@ -1,9 +1,32 @@
 //! `treedb` is an on disk b-tree
 
+mod cancel;
+mod db;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod keys;
 mod pager;
-// pub mod tree;
+mod progress;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tree;
+
+pub use cancel::CancelToken;
+pub use db::{Db, KeyLocks, KeyRangeGuard, OpenOptions, WriteBatch, WriteTxn};
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use pager::UringFile;
+pub use pager::{
+    CacheConfig, CacheUsage, CommitInfo, CommitPolicy, CommitStats, File, FreeListStats,
+    MemoryFile, RepairPolicy, Version, WriteBackStats,
+};
+pub use progress::Progress;
+pub use tree::{
+    dup_values, CacheMisses, ConflictPolicy, Cursor, DupValues, FanoutStats, FreezeGuard,
+    IntoRange, Range, ReaderCounts, Snapshot, TreeHash, ValueGuard,
+};
 
 use pager::LogicalPageId;
+use zerocopy::{CastError, SizeError};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -13,4 +36,138 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("index `{0}` out of bounds")]
     IndexOutofBounds(LogicalPageId),
+    #[error("offset overflow computing {0}")]
+    OffsetOverflow(&'static str),
+    #[error("page `{page}` is corrupted: {reason}")]
+    Corruption {
+        page: LogicalPageId,
+        reason: &'static str,
+    },
+    #[error("invalid key encoding")]
+    InvalidKeyEncoding,
+    #[error("page is full")]
+    PageFull,
+    #[error("key of {0} bytes is too large")]
+    KeyTooLarge(usize),
+    #[error("state of queue `{0}` is corrupted")]
+    QueueStateCorrupted(u8),
+    #[error("database is opened read-only")]
+    ReadOnly,
+    #[error("a commit failed, the database must be reopened")]
+    Poisoned,
+    #[error("version `{0}` has not been committed")]
+    VersionNotCommitted(Version),
+    #[error("version `{0}` is older than the oldest version kept")]
+    VersionTooOld(Version),
+    #[error("out of memory for the page cache")]
+    CacheExhausted,
+    #[error("not a treedb database")]
+    InvalidDatabase,
+    #[error("no copy of the header is intact")]
+    CorruptHeader,
+    #[error("unsupported database format version `{0}`")]
+    UnsupportedVersion(u16),
+    #[error("unsupported page size `{0}`")]
+    UnsupportedPageSize(u32),
+    #[error("file is truncated, {file_len} bytes cannot hold {page_count} pages")]
+    Truncated { page_count: u64, file_len: u64 },
+    #[error("the header is ahead of its pages, only versions up to `{0}` were synced")]
+    RecoveryNeeded(Version),
+    #[error("database is frozen")]
+    Frozen,
+    #[error("database is opened for writing elsewhere")]
+    DatabaseLocked,
+    #[error("{0} snapshots are already open")]
+    TooManySnapshots(usize),
+    #[error("database was not created with dup sort")]
+    NotDupSort,
+    #[error("bytes do not fit the layout of `{0}`")]
+    Layout(&'static str),
+    #[error("entries are out of order or outside their range")]
+    Unordered,
+    #[error("operation was cancelled")]
+    Cancelled,
+}
+
+/// What kind of failure an `Error` is, for mapping errors to the error
+/// types of other languages without matching every variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Reading or writing the file failed.
+    Io,
+    /// The file isn't a treedb database this version can open.
+    InvalidDatabase,
+    /// The file is damaged, see `Error::is_fatal`.
+    Corruption,
+    /// The arguments can't be used, like a key that is too large or a
+    /// version that can't be read.
+    InvalidArgument,
+    /// The database can't do this right now, like writing while frozen or
+    /// read only, or opening one more snapshot than allowed.
+    Unavailable,
+    /// A `CancelToken` cancelled the operation.
+    Cancelled,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(_) => ErrorKind::Io,
+            Error::InvalidDatabase
+            | Error::UnsupportedVersion(_)
+            | Error::UnsupportedPageSize(_) => ErrorKind::InvalidDatabase,
+            Error::IndexOutofBounds(_)
+            | Error::OffsetOverflow(_)
+            | Error::Corruption { .. }
+            | Error::QueueStateCorrupted(_)
+            | Error::CorruptHeader
+            | Error::Truncated { .. }
+            | Error::RecoveryNeeded(_)
+            | Error::Layout(_) => ErrorKind::Corruption,
+            Error::InvalidKeyEncoding
+            | Error::PageFull
+            | Error::KeyTooLarge(_)
+            | Error::VersionNotCommitted(_)
+            | Error::VersionTooOld(_)
+            | Error::NotDupSort
+            | Error::Unordered => ErrorKind::InvalidArgument,
+            Error::ReadOnly
+            | Error::Poisoned
+            | Error::CacheExhausted
+            | Error::Frozen
+            | Error::DatabaseLocked
+            | Error::TooManySnapshots(_) => ErrorKind::Unavailable,
+            Error::Cancelled => ErrorKind::Cancelled,
+        }
+    }
+
+    /// Whether the database can't be used as opened anymore, it has to be
+    /// reopened or repaired first. Other errors only fail the call that
+    /// returned them, which may succeed when tried again.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Error::Poisoned
+                | Error::Corruption { .. }
+                | Error::CorruptHeader
+                | Error::QueueStateCorrupted(_)
+                | Error::InvalidDatabase
+                | Error::UnsupportedVersion(_)
+                | Error::UnsupportedPageSize(_)
+                | Error::Truncated { .. }
+                | Error::RecoveryNeeded(_)
+        )
+    }
+}
+
+impl<Src, Dst: ?Sized> From<SizeError<Src, Dst>> for Error {
+    fn from(_: SizeError<Src, Dst>) -> Self {
+        Error::Layout(std::any::type_name::<Dst>())
+    }
+}
+
+impl<Src, Dst: ?Sized> From<CastError<Src, Dst>> for Error {
+    fn from(_: CastError<Src, Dst>) -> Self {
+        Error::Layout(std::any::type_name::<Dst>())
+    }
 }
@@ -1,133 +1,947 @@
 #![allow(dead_code)]
 
 #[cfg(test)]
-mod test;
+pub(crate) mod test;
 
 mod arena;
+mod bitmap;
 mod cache;
+mod chain;
+mod commit;
+mod crc;
+mod delta;
+mod file;
+mod jobs;
+mod memory;
 mod page;
+mod page_map;
 mod queue;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod wal;
 
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
     fmt,
+    io::{self, IoSlice},
+    ops::Bound,
+    path::Path,
 };
 
 use arena::Arena;
+use bitmap::BitmapAllocator;
 use bytes::BytesMut;
-use page::{PageBuf, PageBufMut};
+pub(crate) use crc::{crc32, crc32_update};
+pub(crate) use page::{PageBuf, PageBufMut, PageWriter, PAGE_CAPACITY};
 use zerocopy::{
     little_endian::{U16, U32, U64},
-    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+    FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout, Unaligned,
 };
 
-use crate::Result;
+use crate::{
+    cancel::{self, CancelToken},
+    progress::{self, ProgressFn},
+    Error, Result,
+};
 
-use self::{cache::Cache, queue::FIFOQueue};
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use self::uring::UringFile;
+pub use self::{commit::CommitPolicy, memory::MemoryFile, wal::RepairPolicy};
+
+use self::{
+    cache::Cache,
+    commit::GroupCommit,
+    delta::{Delta, Deltas, MAX_DELTA_LEN, MAX_PAGE_DELTAS},
+    jobs::{Job, JobQueue},
+    queue::{FIFOQueue, QueueState},
+    wal::Wal,
+};
 
-/// First version of this!
-const VERSION: u16 = 1;
+/// Identifies a treedb file, the first bytes of the header.
+const MAGIC: [u8; 8] = *b"treedb\0\0";
+/// Format version, 2 stores the keys of leaves prefix compressed, 3
+/// leaves out the length of empty values and 4 keeps two copies of the
+/// header.
+const VERSION: u16 = 4;
 /// 4kb page
-const PAGE_SIZE: usize = 4 * 1024;
-
-pub trait File {
+pub(crate) const PAGE_SIZE: usize = 4 * 1024;
+
+/// Commits write the header to these pages in turn, so a torn header write
+/// leaves the one of the commit before intact.
+const HEADER_SLOTS: usize = 2;
+
+const JOB_QUEUE_ID: u8 = 0;
+const FREE_LIST_ID: u8 = 1;
+
+/// Number of writers remembered in the header's version vector.
+const VERSION_VECTOR_LEN: usize = 4;
+/// Number of tagged commits remembered in the header.
+const COMMIT_TAGS_LEN: usize = 64;
+
+/// Pages the cache holds unless it is sized by a budget.
+const DEFAULT_CACHE_PAGES: usize = 1024;
+/// The arena grows by this many pages at a time, it is also the step by
+/// which a budgeted cache grows and shrinks.
+const CACHE_CHUNK_PAGES: usize = 256;
+/// Fewest pages a cache sized by `CacheConfig` holds.
+const MIN_CACHE_PAGES: usize = 16;
+/// Lookups between adjustments of a budgeted cache.
+const CACHE_SIZING_WINDOW: usize = 1024;
+/// Windows in a row with almost no misses before a budgeted cache shrinks.
+const CACHE_SHRINK_WINDOWS: usize = 8;
+/// Updated pages held in memory before they are written out ahead of the
+/// next commit. Never more than half the cache, dirty pages can't be evicted
+/// until they are written.
+const MAX_DIRTY_PAGES: usize = 1024;
+/// Written between adjacent pages, the file holds only `PAGE_CAPACITY` bytes
+/// of every `PAGE_SIZE` slot.
+const PAGE_GAP: [u8; PAGE_SIZE - PAGE_CAPACITY] = [0; PAGE_SIZE - PAGE_CAPACITY];
+/// Pages read at a time by `DWALPager::copy_to` when the file can't be
+/// cloned.
+const COPY_CHUNK_PAGES: usize = 64;
+/// Size the write-ahead log may grow to before a commit syncs the file so it
+/// can start over.
+const WAL_CHECKPOINT_BYTES: u64 = 64 * 1024 * 1024;
+/// Freed pages are held back from reuse until their commit is synced. Once
+/// more than an eighth of the file, and at least this many pages, are held
+/// a commit syncs anyway so the file doesn't keep growing in their place.
+const MIN_HELD_FREE_PAGES: usize = 64;
+
+/// Storage the pager reads and writes pages through, implemented for
+/// `std::fs::File` and `MemoryFile`. It is `Send` so the pager can be
+/// shared by snapshots on other threads.
+pub trait File: Send {
     fn len(&self) -> Result<usize>;
     fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
     fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize>;
     fn sync_data(&self) -> Result<()>;
+
+    /// Write `bufs` one after the other starting at `offset`.
+    ///
+    /// By default they are copied into one buffer so this is still a single
+    /// `write_at`.
+    fn write_vectored_at(&self, bufs: &[IoSlice<'_>], offset: u64) -> Result<usize> {
+        if let [buf] = bufs {
+            return self.write_at(buf, offset);
+        }
+
+        let mut joined = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+        for buf in bufs {
+            joined.extend_from_slice(buf);
+        }
+
+        self.write_at(&joined, offset)
+    }
+
+    /// Read into `buf` until it is full or the file ends, `read_at` may
+    /// stop short of either. Returns the bytes read, less than `buf.len()`
+    /// only when the file ended first.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(len) => read += len,
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(read)
+    }
+
+    /// Write all of `buf` at `offset`, `write_at` may write only part of it.
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        let mut written = 0;
+        while written < buf.len() {
+            match self.write_at(&buf[written..], offset + written as u64) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(len) => written += len,
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write all of `bufs` one after the other starting at `offset`. The
+    /// rest of a short `write_vectored_at` is written a buffer at a time.
+    fn write_all_vectored_at(&self, bufs: &[IoSlice<'_>], offset: u64) -> Result<()> {
+        let mut skip = self.write_vectored_at(bufs, offset)?;
+        let mut offset = offset + skip as u64;
+
+        for buf in bufs {
+            if skip >= buf.len() {
+                skip -= buf.len();
+                continue;
+            }
+
+            self.write_all_at(&buf[skip..], offset)?;
+            offset += (buf.len() - skip) as u64;
+            skip = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Write every run of buffers in `writes` at the offset paired with it,
+    /// like `write_all_vectored_at`. Pages are written back this way so an
+    /// implementation can submit all of the runs at once.
+    fn write_all_batch_at(&self, writes: &[(&[IoSlice<'_>], u64)]) -> Result<()> {
+        for (bufs, offset) in writes {
+            self.write_all_vectored_at(bufs, *offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Hint that `len` bytes at `offset` will be read soon so the
+    /// implementation can start fetching them in the background.
+    fn prefetch(&self, _offset: u64, _len: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Cut the file down to `len` bytes, used by `DWALPager::close` to give
+    /// back free pages at its end. By default the file keeps its length.
+    fn set_len(&self, _len: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Make the empty `dest` a copy-on-write clone of this file, returns
+    /// false if the file or its filesystem can't and it has to be copied
+    /// instead. By default files can't be cloned.
+    fn reflink_to(&self, _dest: &std::fs::File) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Lock the file against other writers until it is closed, returns
+    /// false if another writer holds the lock. By default files aren't
+    /// locked.
+    fn try_lock(&self) -> Result<bool> {
+        Ok(true)
+    }
 }
 
 #[derive(Debug, FromBytes, IntoBytes, KnownLayout, Unaligned, Immutable)]
 #[repr(C)]
 struct Header {
+    magic: [u8; 8],
     version: U16,
     page_size: U32,
     page_count: U64,
     commited_version: U64,
     oldest_version: U64,
+    job_queue: QueueState,
+    free_list: QueueState,
+    /// Root page of the tree, zero until one has been allocated.
+    root_page: U64,
+    /// The last version committed by each of the most recent writers, newest
+    /// first. Two copies of a file that diverged share a suffix of this, the
+    /// newest shared entry is their common ancestor.
+    version_vector: [WriterVersion; VERSION_VECTOR_LEN],
+    /// Flags of the tree, fixed when it is created. Files written before
+    /// these existed read as zero.
+    tree_flags: U64,
+    /// Tags of the most recent tagged commits, newest first.
+    commit_tags: [CommitTag; COMMIT_TAGS_LEN],
+    /// Directory page of the bitmap allocator, zero while free pages are
+    /// kept in the free list queue.
+    bitmap_directory: U64,
+    /// Nonzero if the file was last written by `close`, the first commit
+    /// after opening it again clears it.
+    clean_close: U64,
+    /// The newest version whose pages were synced before this header was
+    /// written, later ones were committed without syncing and the header
+    /// may have reached the disk without their pages.
+    synced_version: U64,
+    /// Nonzero once the file has switched to shadow paging, see
+    /// `DWALPager::use_shadow_paging`.
+    shadow_paging: U64,
+    /// First page of the page table written out for shadow paging, zero
+    /// while nothing is remapped.
+    page_map: U64,
+    /// First page of the deltas of patched pages, zero while there are
+    /// none.
+    deltas: U64,
+    /// Over the rest of the header, a copy that doesn't match was torn.
+    checksum: U32,
+}
+
+impl Header {
+    fn compute_checksum(&self) -> u32 {
+        let bytes = self.as_bytes();
+        crc32(&bytes[..bytes.len() - std::mem::size_of::<U32>()])
+    }
+
+    /// The page this header is written to, commits alternate between the
+    /// header slots.
+    fn slot(&self) -> PhysicalPageId {
+        PhysicalPageId(self.commited_version.get() as usize % HEADER_SLOTS)
+    }
+}
+
+/// An entry of the header's commit tags, a `version` of zero marks an unused
+/// entry.
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Unaligned, Immutable)]
+#[repr(C)]
+struct CommitTag {
+    version: U64,
+    tag: [u8; 16],
+}
+
+/// An entry of the header's version vector, a `writer_id` of zero marks an
+/// unused entry.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, FromBytes, IntoBytes, KnownLayout, Unaligned, Immutable,
+)]
+#[repr(C)]
+pub struct WriterVersion {
+    writer_id: U64,
+    version: U64,
 }
 
+/// The pages each remapped page was copied to by `atomic_update`, by the
+/// version that copied it.
+type PageTable = HashMap<LogicalPageId, BTreeMap<Version, PhysicalPageId>>;
+
 pub struct DWALPager {
     header: Header,
-    page_table: HashMap<LogicalPageId, BTreeMap<Version, PhysicalPageId>>,
+    /// The latest committed version that has been synced to disk.
+    durable_version: Version,
+    /// The header's `oldest_version` as of the last commit, reads of
+    /// versions before it are rejected.
+    oldest_version: Version,
+    /// Decides which commits made by `commit_grouped` sync.
+    group_commit: GroupCommit,
+    page_table: PageTable,
+    /// Pages the page table was last written to, with shadow paging.
+    page_map: Vec<PhysicalPageId>,
+    /// The page table changed since it was last written.
+    page_map_dirty: bool,
+    /// Updates of committed pages kept apart from the pages, see
+    /// `patch_page`.
+    deltas: Deltas,
+    /// Pages the deltas were last written to.
+    delta_pages: Vec<PhysicalPageId>,
+    /// The deltas changed since they were last written.
+    deltas_dirty: bool,
+    /// Keep small patches as deltas, see `set_delta_updates`.
+    delta_updates: bool,
     page_cache: PageCache,
-    remap_queue: FIFOQueue<RemappedPage>,
+    /// Remap cleanups and frees waiting for `oldest_version` to catch up
+    /// with the version they were queued at.
+    jobs: JobQueue,
+    /// Most jobs run by a commit, the rest wait for later commits or
+    /// `run_jobs`.
+    commit_job_budget: usize,
+    /// Pages that have been freed and can be handed out again by
+    /// `new_page_id`.
+    free_list: FIFOQueue<PhysicalPageId>,
+    /// Pages freed since the last sync, they are only handed out once it
+    /// is synced.
+    held_free_pages: usize,
+    /// Pages released since the last sync while `scrub_freed` is set, they
+    /// are zeroed once the header releasing them is synced. Until then the
+    /// last durable header may still read them.
+    unscrubbed: Vec<PhysicalPageId>,
+    /// Replaces the free list once a file has switched to it, see
+    /// `use_bitmap_allocator`.
+    bitmap: Option<BitmapAllocator>,
+    /// Pages handed out by `new_page_id` since the last commit, `rollback`
+    /// returns them to the free list.
+    uncommitted_pages: HashSet<PhysicalPageId>,
+    /// Pages written through `update_page` and `atomic_update` since the
+    /// last commit.
+    updated_pages: BTreeSet<usize>,
+    last_commit: Option<CommitStats>,
+    /// Versions pinned by readers along with how many times each is pinned,
+    /// `oldest_version` is held back to the first one.
+    pinned: BTreeMap<Version, usize>,
+    /// Committed versions `oldest_version` is held back by regardless of
+    /// pins, see `set_reuse_delay`.
+    reuse_delay: u64,
+    /// Zero page buffers handed out by `new_page_buffer`, otherwise they may
+    /// hold stale contents of other pages.
+    zero_fill: bool,
+    /// Overwrite pages with zeroes when they are released to the free list.
+    scrub_freed: bool,
+    /// Identifies this instance in the version vector, every open is a new
+    /// writer.
+    writer_id: u64,
+    /// The writer id used instead of `writer_id` while deterministic, see
+    /// `set_deterministic`. Page buffers are zero filled while it is set.
+    deterministic_writer_id: Option<u64>,
+    /// Tag for the next commit, set by `set_commit_tag`.
+    commit_tag: Option<[u8; 16]>,
+    /// Whether the header read when opening was written by `close`.
+    closed_cleanly: bool,
 }
 
 struct PageCache {
     file: Box<dyn File>,
+    /// Staging buffer for the header page. Header updates are copied in
+    /// here and written out as a single page-aligned write by
+    /// `flush_header` at commit, instead of a small write per update.
+    header_page: BytesMut,
+    /// Where `flush_header` writes the header page.
+    header_slot: PhysicalPageId,
+    header_dirty: bool,
+    read_only: bool,
+    /// Set when a commit fails partway, the in memory state may no longer
+    /// match the file so all further writes are rejected.
+    poisoned: bool,
+    /// Pages below this have been handed out, it is persisted as the
+    /// header's `page_count` and bounds every read.
     next_page_id: usize,
+    /// Length of the file as of our last write to it.
+    file_len: u64,
+    /// Bytes written since the file was last synced.
+    unsynced_bytes: u64,
     cache: Cache<LogicalPageId, PageCacheEntry>,
+    /// Number of pages the cache may hold before it evicts.
+    capacity: usize,
+    /// Set when the capacity follows the hit rate instead of being fixed.
+    sizing: Option<CacheSizing>,
+    /// Grows by a chunk at a time up to the capacity, buffers of replaced
+    /// and evicted pages are freed back to it to be handed out again.
     page_arena: Arena<std::alloc::System>,
+    /// Pages dropped from the cache while still referenced elsewhere, their
+    /// buffers go back to the arena once nothing else references them.
+    released: Vec<PageBuf>,
+    /// Pages updated since they were last written to the file, ordered by
+    /// page id so `flush_dirty` can coalesce adjacent ones.
+    dirty: BTreeMap<usize, DirtyPage>,
+    /// Updates since the last `flush_dirty`.
+    pending_updates: u64,
+    /// Counts every update, dirty pages are stamped with it to find the
+    /// coldest.
+    update_seq: u64,
+    /// Dirty page counts set by `set_dirty_watermarks`, `None` for the
+    /// defaults that follow the capacity.
+    dirty_watermarks: Option<(usize, usize)>,
+    /// Totals over every `flush_dirty` so far.
+    write_back: WriteBackStats,
+    /// Reads that weren't cached and went to the file.
+    misses: u64,
+    /// Pages are logged here before they are written to the file, see
+    /// `DWALPager::recover_with_wal`.
+    wal: Option<Wal>,
+    repair_policy: RepairPolicy,
+    /// Pages written over again from the log by `repair_page`.
+    repaired: u64,
+}
+
+/// A fixed size for the page cache, set with `OpenOptions::cache_config`.
+///
+/// By default the cache holds 1024 pages, 4MiB. The memory for pages is
+/// allocated in chunks of 256 pages as the cache fills up, a cache that
+/// never fills doesn't take up its whole size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Memory for cached pages, rounded down to whole pages and to at least
+    /// 16 of them.
+    pub max_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_CACHE_PAGES * PAGE_SIZE,
+        }
+    }
+}
+
+/// Memory used by the page cache, see `Db::cache_usage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheUsage {
+    /// Pages held by the cache.
+    pub cached_bytes: usize,
+    /// Updated pages not written to the file yet, these are also counted in
+    /// `cached_bytes` unless they were evicted.
+    pub dirty_bytes: usize,
+    /// Memory allocated for pages, cached or not.
+    pub allocated_bytes: usize,
+    /// What the cache may hold before it evicts.
+    pub max_bytes: usize,
+}
+
+/// How updated pages were written back to the file, returned by
+/// `flush_dirty` and summed up by `Db::write_back_stats`.
+///
+/// A page updated several times before it is written out is only written
+/// once, so `updates` minus `pages_written` is the number of page writes
+/// that were saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteBackStats {
+    /// Calls to `update_page`.
+    pub updates: u64,
+    /// Pages written to the file.
+    pub pages_written: u64,
+    /// Writes issued to the file, each covers a run of adjacent pages.
+    pub writes: u64,
+}
+
+/// What a commit wrote, returned by `Db::last_commit_stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitStats {
+    pub version: Version,
+    /// Ids of the pages of the tree the commit wrote, in order. A page
+    /// written several times is listed once.
+    pub pages: Vec<u64>,
+    /// Pages allocated by the commit, new or reused from the free list.
+    pub allocated_pages: usize,
+    /// Page updates made by the commit itself, for the job queue, the free
+    /// list and copying remapped pages back.
+    pub internal_updates: u64,
+}
+
+/// Pages on their way back to the free list, returned by
+/// `Db::free_list_stats`.
+///
+/// A page freed at a version waits in `waiting_jobs` until `oldest_version`
+/// has caught up with it, then in `held_pages` until the commit releasing
+/// it is synced, before it counts towards `free_pages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeListStats {
+    /// See `OpenOptions::reuse_delay`.
+    pub reuse_delay: u64,
+    /// The oldest version that can still be read, pages freed after it are
+    /// kept.
+    pub oldest_version: Version,
+    /// Frees and remap cleanups waiting for `oldest_version`.
+    pub waiting_jobs: u64,
+    /// Pages released since the last sync.
+    pub held_pages: u64,
+    /// Pages that can be handed out again.
+    pub free_pages: u64,
+}
+
+/// A tagged commit, returned by `Db::commit_info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub version: Version,
+    /// The tag passed to `WriteTxn::set_tag`.
+    pub tag: [u8; 16],
 }
 
 impl DWALPager {
     /// Recover a `VersionedPager`, if the file is empty it will create a new
     /// pager.
+    ///
+    /// The file opens at its last synced commit, later ones that weren't
+    /// synced are lost as if the pager crashed before writing them. If the
+    /// header of that commit has been written over the file needs recovery,
+    /// `Error::RecoveryNeeded`.
     pub fn recover(file: impl File + 'static) -> Result<Self> {
-        let file_size = file.len()?;
+        Self::open_file(file, false)
+    }
+
+    /// Recover the file at `path` through `std::fs::File`, creating it if it
+    /// does not exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        Self::recover(file)
+    }
+
+    /// Recover a `VersionedPager` that logs pages to `wal` before writing
+    /// them to `file`, after redoing the commits logged there.
+    ///
+    /// A crash while pages or the header are written in place can then
+    /// leave them torn, they are written again from the log. The log is
+    /// synced before every write to the file and starts over every time the
+    /// file is synced.
+    pub fn recover_with_wal(file: impl File + 'static, wal: impl File + 'static) -> Result<Self> {
+        // Redoing the log writes to the file, another writer can't be using
+        // it.
+        lock(&file)?;
+        let (wal, _) = Wal::recover(Box::new(wal), &file)?;
+
+        let mut pager = Self::open_file(file, false)?;
+        pager.page_cache.wal = Some(wal);
+
+        Ok(pager)
+    }
+
+    /// Recover a `VersionedPager` that never writes to `file`.
+    ///
+    /// This is meant for files that can't be written at all, like files on a
+    /// read-only filesystem. Any operation that would write returns
+    /// `Error::ReadOnly`.
+    pub fn recover_read_only(file: impl File + 'static) -> Result<Self> {
+        Self::open_file(file, true)
+    }
 
-        let page_table = HashMap::new();
+    fn open_file(file: impl File + 'static, read_only: bool) -> Result<Self> {
+        if !read_only {
+            lock(&file)?;
+        }
+
+        let file_size = file.len()?;
 
         let file = Box::new(file) as Box<dyn File>;
 
-        let header = if file_size > PAGE_SIZE {
-            let mut header_buf = BytesMut::zeroed(PAGE_SIZE);
-            // TODO: Probably need to make this read_exact?
-            file.read_at(&mut header_buf[..], 0)?;
-            let header_size = std::mem::size_of::<Header>();
-            Header::read_from_bytes(&header_buf[..header_size]).unwrap()
+        let mut header_page = BytesMut::zeroed(PAGE_SIZE);
+
+        let mut header = if file_size > 0 {
+            read_header(&*file, file_size, &mut header_page, !read_only)?
         } else {
             Header {
+                magic: MAGIC,
                 version: VERSION.into(),
                 page_size: (PAGE_SIZE as u32).into(),
-                // Start with 1, we could add a backup here.
-                page_count: 1.into(),
+                // The pages below are the header slots.
+                page_count: (HEADER_SLOTS as u64).into(),
                 commited_version: 1.into(),
                 oldest_version: 1.into(),
+                // Filled in from the queues below whenever the header is
+                // written.
+                job_queue: QueueState::new_zeroed(),
+                free_list: QueueState::new_zeroed(),
+                root_page: 0.into(),
+                version_vector: [WriterVersion::new_zeroed(); VERSION_VECTOR_LEN],
+                tree_flags: 0.into(),
+                commit_tags: [CommitTag::new_zeroed(); COMMIT_TAGS_LEN],
+                bitmap_directory: 0.into(),
+                clean_close: 0.into(),
+                synced_version: 1.into(),
+                shadow_paging: 0.into(),
+                page_map: 0.into(),
+                deltas: 0.into(),
+                checksum: 0.into(),
             }
         };
 
-        let mut page_cache = PageCache::new(file);
+        let mut page_cache = PageCache::new(file, header_page, read_only);
+        page_cache.next_page_id = usize::try_from(header.page_count.get())
+            .map_err(|_| Error::OffsetOverflow("page count"))?;
+        page_cache.file_len = file_size as u64;
+
+        // The queues of an existing file continue where its last commit left
+        // them, otherwise the jobs and free pages queued would be lost.
+        let (jobs, free_list) = if file_size > 0 {
+            let recover = |page_cache: &mut PageCache| {
+                Ok((
+                    JobQueue::recover(page_cache, &header.job_queue)?,
+                    FIFOQueue::recover(page_cache, &header.free_list)?,
+                ))
+            };
+
+            // Queue pages that don't hold what the header says they do were
+            // likely never written if the header got ahead of them.
+            recover(&mut page_cache).map_err(|err| match (err, unsynced(&header)) {
+                (Error::QueueStateCorrupted(_), Some(synced)) => Error::RecoveryNeeded(synced),
+                (err, _) => err,
+            })?
+        } else {
+            (
+                JobQueue::create(&mut page_cache, JOB_QUEUE_ID)?,
+                FIFOQueue::create(&mut page_cache, FREE_LIST_ID)?,
+            )
+        };
+
+        let (page_table, page_map) = load_page_table(&mut page_cache, &jobs, &header)?;
+        let (deltas, delta_pages) = load_deltas(&mut page_cache, &header)?;
+
+        let bitmap = match header.bitmap_directory.get() {
+            0 => None,
+            directory => Some(BitmapAllocator::load(
+                &mut page_cache,
+                PhysicalPageId(directory as usize),
+            )?),
+        };
 
-        let remap_queue = FIFOQueue::create(&mut page_cache, 0)?;
+        // Whatever version the header on disk claims is by definition durable.
+        let durable_version = Version(header.commited_version.get());
+        let closed_cleanly = header.clean_close.get() != 0;
+        header.clean_close = 0.into();
 
-        let pager = Self {
+        let mut pager = Self {
+            oldest_version: Version(header.oldest_version.get()),
             header,
+            durable_version,
+            group_commit: GroupCommit::new(CommitPolicy::default()),
             page_table,
+            page_map,
+            page_map_dirty: false,
+            deltas,
+            delta_pages,
+            deltas_dirty: false,
+            delta_updates: false,
             page_cache,
-            remap_queue,
+            jobs,
+            commit_job_budget: usize::MAX,
+            free_list,
+            held_free_pages: 0,
+            unscrubbed: Vec::new(),
+            bitmap,
+            uncommitted_pages: HashSet::new(),
+            updated_pages: BTreeSet::new(),
+            last_commit: None,
+            pinned: BTreeMap::new(),
+            reuse_delay: 0,
+            zero_fill: false,
+            scrub_freed: false,
+            writer_id: new_writer_id(),
+            deterministic_writer_id: None,
+            commit_tag: None,
+            closed_cleanly,
         };
 
-        pager.write_header()?;
+        if !read_only {
+            pager.write_header();
+
+            if file_size == 0 {
+                pager.write_initial_header()?;
+            }
+        }
 
         Ok(pager)
     }
 
-    pub fn new_page_id(&mut self) -> LogicalPageId {
-        // TODO: re-use freemap etc
-        let page_id = self.page_cache.new_last_page_id();
+    /// Write out the empty queues of a new file and its header to every
+    /// slot, then sync. Pages written before the first commit, when the
+    /// dirty pages outgrow the cache, would otherwise leave a file without
+    /// any header that can't be opened again after a crash.
+    fn write_initial_header(&mut self) -> Result<()> {
+        self.jobs.flush(&mut self.page_cache)?;
+        self.free_list.flush(&mut self.page_cache)?;
+        self.page_cache.flush_dirty()?;
+        self.page_cache.extend_file()?;
+        self.page_cache.sync_data()?;
+
+        self.write_header();
+        self.page_cache.flush_header_slots()?;
+        self.sync()
+    }
+
+    /// Allocate a page id, reusing a committed free page before growing the
+    /// file.
+    pub fn new_page_id(&mut self) -> Result<LogicalPageId> {
+        let free = match &mut self.bitmap {
+            Some(bitmap) => bitmap.alloc(),
+            None => self.free_list.pop(&mut self.page_cache)?,
+        };
+        let page_id = free.unwrap_or_else(|| self.page_cache.new_last_page_id());
+
+        self.uncommitted_pages.insert(page_id);
+
+        Ok(LogicalPageId(page_id.0))
+    }
+
+    /// Allocate `len` adjacent page ids, returning the first. With the bitmap
+    /// allocator they are the lowest run of free pages that is long enough,
+    /// otherwise, or if there is none, they are added at the end of the file.
+    pub fn new_page_run(&mut self, len: usize) -> Result<LogicalPageId> {
+        let first = match self
+            .bitmap
+            .as_mut()
+            .and_then(|bitmap| bitmap.alloc_run(len))
+        {
+            Some(first) => first,
+            None => {
+                let first = self.page_cache.new_last_page_id();
+                for _ in 1..len {
+                    self.page_cache.new_last_page_id();
+                }
+                first
+            }
+        };
+
+        for page_id in first.0..first.0 + len {
+            self.uncommitted_pages.insert(PhysicalPageId(page_id));
+        }
+
+        Ok(LogicalPageId(first.0))
+    }
+
+    /// Keep track of free pages in a bitmap instead of the free list queue,
+    /// so runs of adjacent free pages can be found with `new_page_run`. The
+    /// switch is persisted with the next commit and can't be undone, a file
+    /// that has switched always opens with the bitmap.
+    ///
+    /// The pages on the free list are moved to the bitmap by the next
+    /// commit, they can be handed out again after it.
+    pub fn use_bitmap_allocator(&mut self) -> Result<()> {
+        self.page_cache.check_writable()?;
+
+        if self.bitmap.is_none() {
+            self.bitmap = Some(BitmapAllocator::new());
+        }
+
+        Ok(())
+    }
+
+    /// Leave every page updated by `atomic_update` on the copy for good
+    /// instead of copying it back over the original, shadow paging. The
+    /// switch is persisted with the next commit and can't be undone, a file
+    /// that has switched always opens with shadow paging.
+    ///
+    /// No remap cleanups are queued, each commit that remaps pages writes
+    /// out the whole page table instead and frees the copies no reader can
+    /// see anymore. The original page stays taken as long as the page is
+    /// in use, its id is the one the page is known by. That is meant for
+    /// small files, where writing out the page table costs little and twice
+    /// the pages for the ones updated doesn't matter.
+    pub fn use_shadow_paging(&mut self) -> Result<()> {
+        self.page_cache.check_writable()?;
+
+        if !self.shadow_paging() {
+            self.header.shadow_paging = 1.into();
+            self.page_map_dirty = true;
+        }
+
+        Ok(())
+    }
 
-        LogicalPageId(page_id.0)
+    /// Whether the file uses shadow paging, see `use_shadow_paging`.
+    pub fn shadow_paging(&self) -> bool {
+        self.header.shadow_paging.get() != 0
     }
 
     /// Read a page at a specific version.
+    ///
+    /// Versions before the oldest one the last commit kept may have had
+    /// their pages reused, they are `Error::VersionTooOld`. Pages that were
+    /// never allocated, including the header slots, are
+    /// `Error::IndexOutofBounds`.
     // TODO: add `read` that can support optionally bypassing the cache.
     pub fn read_at(&mut self, id: LogicalPageId, version: Version) -> Result<PageBuf> {
+        if version < self.oldest_version {
+            return Err(Error::VersionTooOld(version));
+        }
+
+        // The header isn't a page, reading it through here would hand out
+        // its bytes as one.
+        if id.0 < HEADER_SLOTS {
+            return Err(Error::IndexOutofBounds(id));
+        }
+
         let page_id = self.get_physical_page_id(id, version);
 
+        // A read only pager knows the pages of the last header it read, the
+        // ones its queues allocated past those are never written.
+        if self.page_cache.read_only && page_id.0 as u64 >= self.header.page_count.get() {
+            return Err(Error::IndexOutofBounds(id));
+        }
+
         let page = self.page_cache.read_page(page_id)?;
 
-        Ok(page)
+        self.apply_deltas(id, version, page)
+    }
+
+    /// `page` with the deltas `id` has on top of it as of `version` applied,
+    /// see `patch_page`.
+    ///
+    /// The copy isn't cached, its buffer goes back to the cache once the
+    /// reader is done with it.
+    fn apply_deltas(
+        &mut self,
+        id: LogicalPageId,
+        version: Version,
+        page: PageBuf,
+    ) -> Result<PageBuf> {
+        let base = self.base_version(id, version);
+        let mut deltas = match self.deltas.get(&id) {
+            Some(versions) => versions
+                .range((Bound::Excluded(base), Bound::Included(version)))
+                .flat_map(|(_, deltas)| deltas)
+                .peekable(),
+            None => return Ok(page),
+        };
+
+        if deltas.peek().is_none() {
+            return Ok(page);
+        }
+
+        let mut patched = self.page_cache.new_page_buffer()?;
+        patched.copy_page(&page);
+        for delta in deltas {
+            delta.apply(patched.buf_mut());
+        }
+
+        let patched = patched.freeze();
+        self.page_cache.release_page_buffer(patched.clone());
+
+        Ok(patched)
+    }
+
+    /// Write the page `id` reads at `version` over again from the
+    /// write-ahead log, returns whether it was repaired.
+    ///
+    /// This is for pages read from the file that fail to decode, the cached
+    /// copy is replaced as well. Nothing is repaired unless the policy is
+    /// `RepairPolicy::Repair` and the log has an image of the page, a page
+    /// updated since it was last written isn't read from the file.
+    pub fn repair_page(&mut self, id: LogicalPageId, version: Version) -> Result<bool> {
+        if self.page_cache.repair_policy == RepairPolicy::Fail || version < self.oldest_version {
+            return Ok(false);
+        }
+
+        let page_id = self.get_physical_page_id(id, version);
+        self.page_cache.repair_page(page_id)
+    }
+
+    pub fn repair_policy(&self) -> RepairPolicy {
+        self.page_cache.repair_policy
+    }
+
+    /// Repair pages from the write-ahead log with `RepairPolicy::Repair`,
+    /// see `repair_page`. Pagers without a log can't repair any.
+    pub fn set_repair_policy(&mut self, policy: RepairPolicy) {
+        self.page_cache.repair_policy = policy;
+    }
+
+    /// Number of pages repaired so far.
+    pub fn repaired_pages(&self) -> u64 {
+        self.page_cache.repaired
+    }
+
+    /// Export the raw bytes of a page at `version`, header included, for
+    /// test harnesses and replication prototypes.
+    pub fn export_page(&mut self, id: LogicalPageId, version: Version) -> Result<Vec<u8>> {
+        Ok(self.read_at(id, version)?.export())
+    }
+
+    /// Write a page returned by `export_page` as `version` of `id`.
+    ///
+    /// Pages with the wrong size, an unknown version or page type, or a
+    /// checksum that doesn't match are rejected with `Error::Corruption`.
+    pub fn import_page(
+        &mut self,
+        id: LogicalPageId,
+        version: Version,
+        bytes: &[u8],
+    ) -> Result<LogicalPageId> {
+        self.page_cache.check_writable()?;
+
+        if !page::verify_export(bytes) {
+            return Err(Error::Corruption {
+                page: id,
+                reason: "exported page fails verification",
+            });
+        }
+
+        let mut page = self.new_page_buffer()?;
+        page.import(bytes);
+
+        self.atomic_update(id, version, page)
     }
 
     fn get_physical_page_id(&mut self, id: LogicalPageId, version: Version) -> PhysicalPageId {
         if let Some(remapped_pages) = self.page_table.get(&id) {
-            if let Some((_, page)) = remapped_pages
-                .range(..)
-                .filter(|(v, _)| *v <= &version)
-                .next_back()
-            {
+            if let Some((_, page)) = remapped_pages.range(..).rfind(|(v, _)| *v <= &version) {
                 return *page;
             }
         }
@@ -135,6 +949,76 @@ impl DWALPager {
         PhysicalPageId(id.0)
     }
 
+    /// The version the page `id` reads at `version` was copied at, zero if
+    /// it reads the page itself.
+    fn base_version(&self, id: LogicalPageId, version: Version) -> Version {
+        self.page_table
+            .get(&id)
+            .and_then(|versions| versions.range(..=version).next_back())
+            .map_or(Version(0), |(version, _)| *version)
+    }
+
+    /// Overwrite `bytes` at `offset` of the contents of the page `id`, which
+    /// reads as `page` at `version`.
+    ///
+    /// With delta updates a few bytes of a committed page are kept as a
+    /// delta that reads apply on top of the page, rather than a copy of the
+    /// whole page for every version that patches it. Longer patches, pages
+    /// copied or allocated at `version` and pages that have collected enough
+    /// deltas are copied with `atomic_update` instead.
+    pub fn patch_page(
+        &mut self,
+        id: LogicalPageId,
+        version: Version,
+        page: &PageBuf,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<()> {
+        self.page_cache.check_writable()?;
+
+        let base = self.base_version(id, version);
+        let deltas = self.deltas.get(&id).map_or(0, |versions| {
+            versions
+                .range((Bound::Excluded(base), Bound::Unbounded))
+                .map(|(_, deltas)| deltas.len())
+                .sum()
+        });
+
+        if self.delta_updates
+            && bytes.len() <= MAX_DELTA_LEN
+            && deltas < MAX_PAGE_DELTAS
+            && base < version
+            && !self.uncommitted_pages.contains(&PhysicalPageId(id.0))
+        {
+            self.updated_pages.insert(id.0);
+            self.deltas
+                .entry(id)
+                .or_default()
+                .entry(version)
+                .or_default()
+                .push(Delta::new(offset, bytes));
+            self.deltas_dirty = true;
+
+            return Ok(());
+        }
+
+        let mut updated = self.new_page_buffer()?;
+        updated.init();
+        updated.buf_mut().copy_from_slice(page.buf());
+        updated.buf_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+        self.atomic_update(id, version, updated)?;
+
+        Ok(())
+    }
+
+    /// Keep small patches of committed pages as deltas, see `patch_page`.
+    /// Defaults to `false`, every patch copies the page. Deltas already
+    /// made are kept either way.
+    pub fn set_delta_updates(&mut self, delta_updates: bool) {
+        self.delta_updates = delta_updates;
+    }
+
     /// Atomically update the page by creating a new page for the specified
     /// version.
     pub fn atomic_update(
@@ -143,206 +1027,2052 @@ impl DWALPager {
         version: Version,
         page: PageBufMut,
     ) -> Result<LogicalPageId> {
+        self.page_cache.check_writable()?;
+
         // Copy page
-        let new_page_id = self.new_page_id();
+        let new_page_id = self.new_page_id()?;
+        self.updated_pages.insert(page_id.0);
 
         self.page_cache.update_page(new_page_id, page)?;
 
-        let versions = self.page_table.entry(page_id).or_insert(BTreeMap::new());
+        // Queued to be un-mapped later, with shadow paging it stays.
+        if !self.shadow_paging() {
+            self.jobs.push(
+                version,
+                Job::RemapCleanup {
+                    original_page_id: page_id,
+                    new_page_id,
+                },
+            );
+        }
+        self.page_map_dirty = true;
 
-        // Pushed into the queue to be un-mapped later
-        // self.remap_queue.push_back(RemappedPage {
-        //     version,
-        //     original_page_id: page_id,
-        //     new_page_id,
-        // });
+        let versions = self.page_table.entry(page_id).or_default();
 
-        versions.insert(version, PhysicalPageId(new_page_id.0));
+        // Updating a page twice within a version replaces the earlier copy,
+        // which nothing can reference anymore.
+        if let Some(replaced) = versions.insert(version, PhysicalPageId(new_page_id.0)) {
+            self.uncommitted_pages.remove(&replaced);
+            self.release_page(replaced)?;
+        }
 
         Ok(new_page_id)
     }
 
+    /// Commit the current version and sync it to disk.
+    ///
+    /// The pages of the commit are synced before its header is written, so
+    /// a crash can't leave a header behind that points at pages that never
+    /// made it to disk.
     pub fn commit(&mut self) -> Result<()> {
-        self.header.commited_version += 1;
-
-        self.write_header()?;
-        self.page_cache.flush()?;
-
-        Ok(())
-    }
+        self.page_cache.check_writable()?;
 
-    pub fn new_page_buffer(&mut self) -> PageBufMut {
-        self.page_cache.new_page_buffer()
+        let res = self.try_commit(true);
+        self.page_cache.poison_on_err(res)
     }
 
-    pub fn update_page(&mut self, page_id: LogicalPageId, page: PageBufMut) -> Result<()> {
-        self.page_cache.update_page(page_id, page)
-    }
+    /// Commit the current version and sync it if the `CommitPolicy` says
+    /// it is time to, otherwise it is synced along with a later commit.
+    ///
+    /// Returns the committed version for `wait_for_durable`.
+    pub fn commit_grouped(&mut self) -> Result<Version> {
+        self.commit_unsynced()?;
 
-    fn write_page(&mut self, page_id: PhysicalPageId, page: &PageBuf) -> Result<()> {
-        self.page_cache.write_page(page_id, page)
-    }
+        if self.group_commit.sync_due(self.page_cache.unsynced_bytes) {
+            self.sync()?;
+        }
 
-    /// Free a page at the specified version.
-    pub fn free(&mut self, _page_id: LogicalPageId, _version: Version) {
-        // First check if this page id matches any "originally" remapped pages
-        // from the remapped_pages map. If it is an original page then add
-        // it to the back of the `remap_queue`. If the version is older than
-        // the last effective version we can add it to the freelist page,
-        // otherwise add it to the delayed free list queue.
-        todo!()
+        Ok(self.committed_version())
     }
 
-    fn current_version(&self) -> Version {
-        Version(self.header.commited_version.get() + 1)
+    /// Set when `commit_grouped` syncs, by default on every commit.
+    pub fn set_commit_policy(&mut self, policy: CommitPolicy) {
+        self.group_commit.policy = policy;
     }
 
-    fn write_header(&self) -> Result<()> {
-        self.page_cache.write_header(&self.header)
+    /// Commit the current version without syncing it to disk.
+    ///
+    /// The version becomes visible via `committed_version` right away but
+    /// only becomes durable once `wait_for_durable` (or a later `commit`)
+    /// syncs the file. Pages freed by unsynced commits are only reused after
+    /// that, once more than an eighth of the file is waiting the commit
+    /// syncs after all.
+    ///
+    /// If this fails the pager is poisoned and rejects all further writes
+    /// with `Error::Poisoned`, it has to be recovered again.
+    pub fn commit_unsynced(&mut self) -> Result<()> {
+        self.page_cache.check_writable()?;
+
+        let res = self.try_commit(false);
+        self.page_cache.poison_on_err(res)
     }
-}
 
-impl PageCache {
-    fn new(file: Box<dyn File>) -> Self {
-        let cache = Cache::new(1024);
-        let page_arena = Arena::new(std::alloc::System, PAGE_SIZE, 1024);
+    /// Commit the current version, with `sync` the pages are synced before
+    /// the header is written and the header after.
+    fn try_commit(&mut self, sync: bool) -> Result<()> {
+        let updates = self.page_cache.total_updates();
+        self.header.commited_version += 1;
+        // Never moves back, a longer delay only holds back later frees.
+        let delayed = Version(
+            self.header
+                .commited_version
+                .get()
+                .saturating_sub(self.reuse_delay),
+        )
+        .max(self.oldest_version);
+        self.header.oldest_version = match self.pinned.keys().next() {
+            Some(pinned) => pinned.min(&delayed).0.into(),
+            None => delayed.0.into(),
+        };
 
-        Self {
-            file,
-            cache,
-            page_arena,
-            // One because header page
-            next_page_id: 1,
+        self.jobs.flush(&mut self.page_cache)?;
+        self.try_run_jobs(self.commit_job_budget)?;
+        if self.shadow_paging() {
+            self.prune_page_table()?;
+            self.write_page_map()?;
         }
-    }
-
-    pub fn new_last_page_id(&mut self) -> PhysicalPageId {
-        // TODO: re-use freemap etc
-        let page_id = self.next_page_id;
-        self.next_page_id += 1;
-
-        PhysicalPageId(page_id)
-    }
-
-    fn new_page_buffer(&mut self) -> PageBufMut {
-        match self.alloc_page_buffer() {
-            Some(buf) => buf,
-            None => {
-                // TODO: handle allocation failed & evict failed
-                let (_page_id, page_buf) = self.cache.evict().unwrap();
-
-                // TODO: handle that this page_buf is currently has a ref outstanding
-                let page_buf_mut = page_buf.page.try_take().unwrap();
-
-                page_buf_mut
-            }
+        self.fold_deltas()?;
+        self.write_deltas()?;
+        self.flush_free_pages()?;
+
+        self.record_writer_version();
+        self.record_commit_tag();
+        self.last_commit = Some(CommitStats {
+            version: self.committed_version(),
+            pages: std::mem::take(&mut self.updated_pages)
+                .into_iter()
+                .map(|page_id| page_id as u64)
+                .collect(),
+            allocated_pages: self.uncommitted_pages.len(),
+            internal_updates: self.page_cache.total_updates() - updates,
+        });
+        self.page_cache.flush_dirty()?;
+        self.page_cache.extend_file()?;
+
+        // Logged pages are already synced to the log, otherwise without
+        // syncing the pages only the versions synced so far are known to be
+        // intact once the header is written.
+        let logged = self.page_cache.wal.is_some();
+        if sync && !logged {
+            self.page_cache.sync_data()?;
+        }
+        self.header.synced_version = if sync || logged {
+            self.header.commited_version
+        } else {
+            self.durable_version.0.into()
+        };
+        self.write_header();
+        self.page_cache.flush_header()?;
+        self.oldest_version = Version(self.header.oldest_version.get());
+
+        // Pages freed by this commit are handed out once the header that
+        // frees them is durable, before that a crash leaves the last
+        // durable header still using them. A logged header is durable once
+        // it is written, otherwise the sync below or a later one makes it.
+        if logged {
+            self.free_synced_pages()?;
+        }
+        self.uncommitted_pages.clear();
+
+        if sync
+            || self.held_free_pages > MIN_HELD_FREE_PAGES.max(self.page_cache.next_page_id / 8)
+            || self
+                .page_cache
+                .wal
+                .as_ref()
+                .is_some_and(|wal| wal.len() > WAL_CHECKPOINT_BYTES)
+        {
+            self.sync()?;
         }
-    }
 
-    fn alloc_page_buffer(&mut self) -> Option<PageBufMut> {
-        let ptr = self.page_arena.alloc().ok()?;
-        // let ptr = NonNull::slice_from_raw_parts(ptr, PAGE_SIZE);
+        Ok(())
+    }
 
-        Some(PageBufMut::new(ptr))
+    /// Discard everything written at the current version since the last
+    /// commit.
+    ///
+    /// Remapped and newly allocated pages go back to the free list, the jobs
+    /// queued for them and for frees are forgotten and the root page is
+    /// restored.
+    ///
+    /// If this fails the pager is poisoned, like a failed commit.
+    pub fn rollback(&mut self) -> Result<()> {
+        self.page_cache.check_writable()?;
+
+        let res = self.try_rollback();
+        self.page_cache.poison_on_err(res)
     }
 
-    fn read_page(&mut self, page_id: PhysicalPageId) -> Result<PageBuf> {
-        // TODO: figure out how to hand out pages
-        let logical_page_id = LogicalPageId(page_id.0);
+    fn try_rollback(&mut self) -> Result<()> {
+        let version = self.current_version();
 
-        if let Some(entry) = self.cache.get(&logical_page_id) {
-            Ok(entry.page.clone())
-        } else {
-            let mut page = self.new_page_buffer();
+        self.page_table.retain(|_, versions| {
+            versions.remove(&version);
+            !versions.is_empty()
+        });
+        self.deltas.retain(|_, versions| {
+            versions.remove(&version);
+            !versions.is_empty()
+        });
 
-            self.read_physical_page(page_id, &mut page)?;
+        self.jobs.discard_pending();
 
-            let page = page.freeze();
+        let mut pages = self.uncommitted_pages.drain().collect::<Vec<_>>();
+        pages.sort_unstable_by_key(|page_id| page_id.0);
 
-            let entry = PageCacheEntry { page: page.clone() };
+        for page_id in pages {
+            self.release_page(page_id)?;
+        }
 
-            self.cache.insert(logical_page_id, entry);
+        self.header.root_page = self.page_cache.staged_header().root_page;
+        self.commit_tag = None;
+        self.updated_pages.clear();
 
-            Ok(page)
-        }
+        Ok(())
     }
 
-    pub fn update_page(&mut self, page_id: LogicalPageId, page: PageBufMut) -> Result<()> {
-        let page = page.freeze();
-
-        if let Some(entry) = self.cache.get_mut(&page_id) {
-            entry.page = page.clone();
+    /// Copy the file as of the last commit to the empty `dest`, cloning it
+    /// when the filesystem supports that and reading it out otherwise.
+    ///
+    /// The copy opens at the last commit, updates since then went to pages
+    /// its header doesn't point at. It is synced before returning.
+    ///
+    /// A copy read out checks `cancel` and reports to `progress` between
+    /// chunks of pages, a clone is reported done at once.
+    pub fn copy_to(
+        &mut self,
+        dest: &std::fs::File,
+        cancel: Option<&CancelToken>,
+        progress: Option<&ProgressFn>,
+    ) -> Result<()> {
+        let file = &self.page_cache.file;
+        let len = file.len()? as u64;
+        let total = len.div_ceil(PAGE_SIZE as u64);
+
+        if !file.reflink_to(dest)? {
+            let mut buf = vec![0; COPY_CHUNK_PAGES * PAGE_SIZE];
+
+            let mut offset = 0;
+            while offset < len {
+                cancel::check(cancel)?;
+
+                let read = file.read_exact_at(&mut buf, offset)?;
+                if read == 0 {
+                    break;
+                }
+
+                File::write_all_at(dest, &buf[..read], offset)?;
+                offset += read as u64;
+
+                progress::report(progress, offset.div_ceil(PAGE_SIZE as u64), total);
+            }
         } else {
-            let entry = PageCacheEntry { page: page.clone() };
-
-            self.cache.insert(page_id, entry);
+            progress::report(progress, total, total);
         }
 
-        self.write_page(PhysicalPageId(page_id.0), &page)?;
+        Ok(dest.sync_all()?)
+    }
 
-        Ok(())
+    /// Commit whatever is left and shut down cleanly, the pager shouldn't
+    /// be written to after.
+    ///
+    /// Every job that is ready runs regardless of the commit job budget,
+    /// free pages at the end of the file are cut off and the header is
+    /// marked as cleanly closed, see `was_closed_cleanly`. Jobs held back
+    /// by pinned versions stay queued.
+    pub fn close(&mut self) -> Result<()> {
+        self.page_cache.check_writable()?;
+
+        let res = self.try_close();
+        self.page_cache.poison_on_err(res)
     }
 
-    fn read_physical_page(&self, page_id: PhysicalPageId, page: &mut PageBufMut) -> Result<()> {
-        let offset = page_id.0 * PAGE_SIZE;
-        self.file.read_at(page.buf_mut(), offset as u64)?;
+    fn try_close(&mut self) -> Result<()> {
+        // Synced so the pages freed by the jobs can be moved and cut off.
+        let budget = std::mem::replace(&mut self.commit_job_budget, usize::MAX);
+        self.try_commit(true)?;
+        self.commit_job_budget = budget;
+
+        // The pages freed by the jobs are free as of that commit. Cutting
+        // off the ones at the end takes two more, the pages of the free
+        // pages' own bookkeeping at the end have to move elsewhere first
+        // and can't be written over until the header moving them is.
+        let moved = self.truncate_free_tail()?;
+        self.try_commit(false)?;
+
+        match &mut self.bitmap {
+            Some(bitmap) => {
+                self.page_cache.next_page_id = bitmap.truncate(self.page_cache.next_page_id);
+            }
+            None => {
+                for page_id in moved {
+                    self.free_list.push_back(&mut self.page_cache, page_id)?;
+                }
+            }
+        }
 
-        Ok(())
-    }
+        self.header.clean_close = 1.into();
+        self.try_commit(true)?;
+        self.header.clean_close = 0.into();
 
-    fn write_page(&mut self, page_id: PhysicalPageId, page: &PageBuf) -> Result<()> {
-        let offset = page_id.0 * PAGE_SIZE;
-        self.file.write_at(page.buf(), offset as u64)?;
+        let file_len = min_file_len(self.page_cache.next_page_id)?;
+        if self.page_cache.file_len > file_len {
+            self.page_cache.file.set_len(file_len)?;
+            self.page_cache.file_len = file_len;
+            self.page_cache.flush()?;
+        }
 
         Ok(())
     }
 
-    fn write_header(&self, header: &Header) -> Result<()> {
-        let header = header.as_bytes();
+    /// Drop the committed free pages at the end of the file from the page
+    /// count.
+    ///
+    /// The free list, and the job queue if it is empty, are started over on
+    /// the lowest free pages. Along with their old pages the rest of the
+    /// free pages are returned to be pushed onto the free list once the
+    /// header no longer points at the old ones. With the bitmap, the pages
+    /// it is written to at the end are moved by the next commit instead.
+    fn truncate_free_tail(&mut self) -> Result<Vec<PhysicalPageId>> {
+        if let Some(bitmap) = &mut self.bitmap {
+            self.page_cache.next_page_id = bitmap.truncate(self.page_cache.next_page_id);
+            return Ok(Vec::new());
+        }
+
+        let mut free = BTreeSet::new();
+        while let Some(page_id) = self.free_list.pop(&mut self.page_cache)? {
+            free.insert(page_id.0);
+        }
+
+        let free_list_page = match free.pop_first() {
+            Some(page_id) => page_id,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut old_pages = self.free_list.take_released();
+        old_pages.push(self.free_list.tail_page());
+
+        // The job queue is usually empty by now, it may be at the end too.
+        let job_page = if self.jobs.is_empty() {
+            free.pop_first()
+        } else {
+            None
+        };
+        if job_page.is_some() {
+            old_pages.extend(self.jobs.take_released());
+            old_pages.push(self.jobs.tail_page());
+        }
+
+        free.extend(old_pages.iter().map(|page_id| page_id.0));
+
+        let last_kept = free_list_page.max(job_page.unwrap_or(0));
+        let mut end = self.page_cache.next_page_id;
+        while end - 1 > last_kept && free.remove(&(end - 1)) {
+            end -= 1;
+        }
+        self.page_cache.next_page_id = end;
+
+        self.free_list = FIFOQueue::create_at(
+            &mut self.page_cache,
+            FREE_LIST_ID,
+            PhysicalPageId(free_list_page),
+        )?;
+        if let Some(job_page) = job_page {
+            self.jobs =
+                JobQueue::create_at(&mut self.page_cache, JOB_QUEUE_ID, PhysicalPageId(job_page))?;
+        }
+
+        Ok(free.into_iter().map(PhysicalPageId).collect())
+    }
+
+    /// Whether the file was last written by `close`, rather than left
+    /// behind by a crash or a pager that was dropped.
+    pub fn was_closed_cleanly(&self) -> bool {
+        self.closed_cleanly
+    }
+
+    /// Make the pages freed since the last commit available after it.
+    fn flush_free_pages(&mut self) -> Result<()> {
+        if self.bitmap.is_none() {
+            self.release_queue_pages()?;
+            return self.free_list.flush(&mut self.page_cache);
+        }
+
+        // Whatever is still on the free list, from before the switch to the
+        // bitmap or pushed since, moves over.
+        self.free_list.flush(&mut self.page_cache)?;
+        let bitmap = self.bitmap.as_mut().unwrap();
+        while let Some(page_id) = self.free_list.pop(&mut self.page_cache)? {
+            bitmap.free(page_id);
+        }
+        self.release_queue_pages()?;
+
+        let bitmap = self.bitmap.as_mut().unwrap();
+        let directory = bitmap.commit(&mut self.page_cache)?;
+        self.header.bitmap_directory = (directory.0 as u64).into();
+
+        Ok(())
+    }
+
+    /// Move the queue pages read to the end onto the free list. The header of
+    /// this commit no longer points at them, so like the rest of the free
+    /// list they can be reused after it.
+    fn release_queue_pages(&mut self) -> Result<()> {
+        let mut pages = self.jobs.take_released();
+        pages.extend(self.free_list.take_released());
+
+        for page_id in pages {
+            self.release_page(page_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the committed version for this writer in the version vector,
+    /// pushing out the oldest writer on its first commit.
+    fn record_writer_version(&mut self) {
+        let entry = WriterVersion {
+            writer_id: self
+                .deterministic_writer_id
+                .unwrap_or(self.writer_id)
+                .into(),
+            version: self.header.commited_version,
+        };
+
+        let vector = &mut self.header.version_vector;
+
+        if vector[0].writer_id != entry.writer_id {
+            vector.rotate_right(1);
+        }
+
+        vector[0] = entry;
+    }
+
+    /// The version vector as of the last commit, newest writer first.
+    pub fn version_vector(&self) -> impl Iterator<Item = (u64, Version)> + '_ {
+        self.header
+            .version_vector
+            .iter()
+            .filter(|entry| entry.writer_id.get() != 0)
+            .map(|entry| (entry.writer_id.get(), Version(entry.version.get())))
+    }
+
+    /// Tag the next commit with `tag`, an id the application correlates it
+    /// with like a timestamp or the id of an upstream transaction. Rolling
+    /// back clears it.
+    pub fn set_commit_tag(&mut self, tag: [u8; 16]) {
+        self.commit_tag = Some(tag);
+    }
+
+    fn record_commit_tag(&mut self) {
+        if let Some(tag) = self.commit_tag.take() {
+            let tags = &mut self.header.commit_tags;
+
+            tags.rotate_right(1);
+            tags[0] = CommitTag {
+                version: self.header.commited_version,
+                tag,
+            };
+        }
+    }
+
+    /// The tag `version` was committed with, `None` if it wasn't tagged or
+    /// more than 64 tagged commits followed it.
+    pub fn commit_info(&self, version: Version) -> Option<CommitInfo> {
+        self.header
+            .commit_tags
+            .iter()
+            .find(|entry| entry.version.get() == version.0 && version.0 != 0)
+            .map(|entry| CommitInfo {
+                version,
+                tag: entry.tag,
+            })
+    }
+
+    /// Run up to `budget` deferred jobs that no reader can observe anymore,
+    /// returning how many ran. Commits already run as many as their budget
+    /// allows, this lets maintenance catch up in between.
+    ///
+    /// Pages released by the jobs can be reused once the next commit is
+    /// synced.
+    pub fn run_jobs(&mut self, budget: usize) -> Result<usize> {
+        self.page_cache.check_writable()?;
+
+        let res = self.try_run_jobs(budget);
+        self.page_cache.poison_on_err(res)
+    }
+
+    /// Limit how many deferred jobs a commit runs, by default all that are
+    /// ready. Jobs beyond the budget wait for later commits or `run_jobs`.
+    pub fn set_commit_job_budget(&mut self, budget: usize) {
+        self.commit_job_budget = budget;
+    }
+
+    /// Keep the pages of the last `versions` committed versions from being
+    /// freed or remapped back, as if they were pinned. Defaults to 0, only
+    /// pinned versions are kept.
+    ///
+    /// A reader that didn't pin its version can keep reading it until
+    /// `versions` more commits are made, after that reads of it fail with
+    /// `Error::VersionTooOld` rather than see pages that were reused.
+    pub fn set_reuse_delay(&mut self, versions: u64) {
+        self.reuse_delay = versions;
+    }
+
+    /// How many pages are free and how many are still held back from reuse.
+    pub fn free_list_stats(&self) -> FreeListStats {
+        let free_pages = match &self.bitmap {
+            Some(bitmap) => bitmap.free_pages() as u64,
+            // Pushed since the last sync but not poppable yet.
+            None => self
+                .free_list
+                .len()
+                .saturating_sub(self.held_free_pages as u64),
+        };
+
+        FreeListStats {
+            reuse_delay: self.reuse_delay,
+            oldest_version: self.oldest_version,
+            waiting_jobs: self.jobs.len(),
+            held_pages: self.held_free_pages as u64,
+            free_pages,
+        }
+    }
+
+    fn try_run_jobs(&mut self, budget: usize) -> Result<usize> {
+        let oldest_version = Version(self.header.oldest_version.get());
+
+        let mut ran = 0;
+        while ran < budget {
+            let (version, job) = match self.jobs.pop_ready(&mut self.page_cache, oldest_version)? {
+                Some(job) => job,
+                None => break,
+            };
+
+            match job {
+                Job::FreePage { page_id } => self.release_freed_page(page_id)?,
+                Job::RemapCleanup {
+                    original_page_id,
+                    new_page_id,
+                } => self.remap_cleanup(version, original_page_id, new_page_id)?,
+            }
+
+            ran += 1;
+        }
+
+        Ok(ran)
+    }
+
+    /// Undo a remap that can no longer be observed.
+    ///
+    /// Remaps are cleaned up in version order. The latest remap of a page
+    /// visible to `oldest_version` is copied back over the original physical
+    /// page, older ones are dropped, and the remapped physical pages are
+    /// returned to the free list.
+    fn remap_cleanup(
+        &mut self,
+        version: Version,
+        original_page_id: LogicalPageId,
+        new_page_id: LogicalPageId,
+    ) -> Result<()> {
+        let oldest_version = Version(self.header.oldest_version.get());
+        let new_page_id = PhysicalPageId(new_page_id.0);
+
+        let versions = match self.page_table.get_mut(&original_page_id) {
+            // The page was freed, or updated again within the same
+            // version, after this remap so its copy is already gone.
+            Some(versions) if versions.get(&version) == Some(&new_page_id) => versions,
+            _ => return Ok(()),
+        };
+
+        // If a newer remap is also visible to the oldest version this one
+        // is obsolete, otherwise it holds the contents the original page
+        // should have.
+        let obsolete = versions
+            .range(..=oldest_version)
+            .next_back()
+            .is_some_and(|(newer, _)| *newer > version);
+
+        versions.remove(&version);
+        self.page_map_dirty = true;
+
+        if versions.is_empty() {
+            self.page_table.remove(&original_page_id);
+        }
+
+        if !obsolete {
+            let page = self.page_cache.read_page(new_page_id)?;
+
+            let mut original = self.page_cache.new_page_buffer()?;
+            original.buf_mut().copy_from_slice(page.buf());
+
+            self.page_cache.update_page(original_page_id, original)?;
+
+            // The original holds them now.
+            if let Some(versions) = self.deltas.get_mut(&original_page_id) {
+                *versions = versions.split_off(&Version(version.0 + 1));
+                self.deltas_dirty = true;
+            }
+        }
+
+        self.release_page(new_page_id)
+    }
+
+    /// Move a freed page that no pinned version can observe anymore onto
+    /// the free list, along with any pages it was still remapped to.
+    fn release_freed_page(&mut self, page_id: LogicalPageId) -> Result<()> {
+        if self.deltas.remove(&page_id).is_some() {
+            self.deltas_dirty = true;
+        }
+
+        if let Some(remapped_pages) = self.page_table.remove(&page_id) {
+            self.page_map_dirty = true;
+
+            for physical_page_id in remapped_pages.into_values() {
+                self.release_page(physical_page_id)?;
+            }
+        }
+
+        self.release_page(PhysicalPageId(page_id.0))
+    }
+
+    /// Drop the remaps of the page table no reader can see anymore, this
+    /// takes the place of remap cleanups with shadow paging. The newest remap
+    /// of a page the oldest version reads stays, the pages of older ones are
+    /// released.
+    fn prune_page_table(&mut self) -> Result<()> {
+        let oldest_version = Version(self.header.oldest_version.get());
+        let mut released = Vec::new();
+
+        for versions in self.page_table.values_mut() {
+            let visible = match versions.range(..=oldest_version).next_back() {
+                Some((version, _)) => *version,
+                None => continue,
+            };
+
+            let kept = versions.split_off(&visible);
+            released.extend(std::mem::replace(versions, kept).into_values());
+        }
+
+        // Sorted so the free list doesn't follow the page table's order.
+        released.sort_unstable_by_key(|page_id| page_id.0);
+        self.page_map_dirty |= !released.is_empty();
+
+        for page_id in released {
+            self.release_page(page_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the page table out to new pages for the header to point at, if
+    /// it changed since the last commit.
+    fn write_page_map(&mut self) -> Result<()> {
+        if !std::mem::take(&mut self.page_map_dirty) {
+            return Ok(());
+        }
+
+        let contents = page_map::encode(&self.page_table);
+        let replaced = std::mem::take(&mut self.page_map);
+        self.page_map = self.replace_chain(replaced, &contents)?;
+        self.header.page_map = chain_head(&self.page_map);
+
+        Ok(())
+    }
+
+    /// Fold the deltas no reader needs apart anymore into the pages they
+    /// apply to, see `patch_page`.
+    ///
+    /// The oldest version that can be read applies the deltas made up to it
+    /// on top of the page it reads, so they can be written into that page
+    /// in place. Only those of synced commits are, the header a crash falls
+    /// back to has them too and applying them again writes the same bytes.
+    /// Deltas made before the page was copied are already on the copy and
+    /// are dropped.
+    fn fold_deltas(&mut self) -> Result<()> {
+        let oldest_version = Version(self.header.oldest_version.get());
+        let synced = oldest_version.min(self.durable_version);
+
+        let mut page_ids = self.deltas.keys().copied().collect::<Vec<_>>();
+        page_ids.sort_unstable_by_key(|page_id| page_id.0);
+
+        for page_id in page_ids {
+            let base = self.base_version(page_id, oldest_version);
+            let versions = self.deltas.get_mut(&page_id).unwrap();
+            let len = versions.len();
+
+            let mut folded = versions.split_off(&Version(base.0 + 1));
+            *versions = folded.split_off(&Version(synced.0 + 1));
+            self.deltas_dirty |= versions.len() != len;
+            if versions.is_empty() {
+                self.deltas.remove(&page_id);
+            }
+
+            if folded.is_empty() {
+                continue;
+            }
+
+            let physical_page_id = self.get_physical_page_id(page_id, oldest_version);
+            let page = self.page_cache.read_page(physical_page_id)?;
+
+            let mut patched = self.page_cache.new_page_buffer()?;
+            patched.copy_page(&page);
+            for delta in folded.values().flatten() {
+                delta.apply(patched.buf_mut());
+            }
+
+            self.page_cache
+                .update_page(LogicalPageId(physical_page_id.0), patched)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the deltas out to new pages for the header to point at, if they
+    /// changed since the last commit.
+    fn write_deltas(&mut self) -> Result<()> {
+        if !std::mem::take(&mut self.deltas_dirty) {
+            return Ok(());
+        }
+
+        let contents = delta::encode(&self.deltas);
+        let replaced = std::mem::take(&mut self.delta_pages);
+        self.delta_pages = self.replace_chain(replaced, &contents)?;
+        self.header.deltas = chain_head(&self.delta_pages);
+
+        Ok(())
+    }
+
+    /// Write `contents` to a chain of new pages, see `chain`, and release
+    /// the pages of the chain it replaces. Returns the new pages.
+    fn replace_chain(
+        &mut self,
+        replaced: Vec<PhysicalPageId>,
+        contents: &[Vec<u8>],
+    ) -> Result<Vec<PhysicalPageId>> {
+        let mut pages = Vec::with_capacity(contents.len());
+        for _ in contents {
+            pages.push(PhysicalPageId(self.new_page_id()?.0));
+        }
+
+        chain::write(&mut self.page_cache, &pages, contents)?;
+
+        for page_id in replaced {
+            self.release_page(page_id)?;
+        }
+
+        Ok(pages)
+    }
+
+    /// Pin `version` so that it stays readable across later commits, until
+    /// it is released again with `unpin`.
+    ///
+    /// Pins are counted, each call has to be matched by an `unpin`.
+    pub fn pin(&mut self, version: Version) {
+        *self.pinned.entry(version).or_default() += 1;
+    }
+
+    /// Release a pin taken with `pin`, the pages only it could observe are
+    /// cleaned up by the next commit.
+    pub fn unpin(&mut self, version: Version) {
+        if let Some(count) = self.pinned.get_mut(&version) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.pinned.remove(&version);
+            }
+        }
+    }
+
+    /// Block until `version` is durable on disk.
+    ///
+    /// Since all writes up to the committed version have already been
+    /// issued, this syncs the file if `version` is not yet durable.
+    pub fn wait_for_durable(&mut self, version: Version) -> Result<()> {
+        if version <= self.durable_version {
+            return Ok(());
+        }
+
+        if version > self.committed_version() {
+            return Err(Error::VersionNotCommitted(version));
+        }
+
+        self.sync()
+    }
+
+    /// The latest version that has been committed, it may not be durable
+    /// yet.
+    pub fn committed_version(&self) -> Version {
+        Version(self.header.commited_version.get())
+    }
+
+    /// The latest version that has been committed and synced to disk.
+    pub fn durable_version(&self) -> Version {
+        self.durable_version
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let res = self.page_cache.flush();
+        self.page_cache.poison_on_err(res)?;
+
+        self.durable_version = self.committed_version();
+        self.group_commit.synced();
+
+        let res = self.free_synced_pages();
+        self.page_cache.poison_on_err(res)
+    }
+
+    /// Let `new_page_id` hand out the pages freed by the last header
+    /// written, which is durable, and scrub them.
+    fn free_synced_pages(&mut self) -> Result<()> {
+        self.free_list.advance_barrier();
+        self.held_free_pages = 0;
+
+        if let Some(bitmap) = &mut self.bitmap {
+            bitmap.synced();
+        }
+
+        // Written with the next commit, unless they are reused first.
+        for page_id in std::mem::take(&mut self.unscrubbed) {
+            let mut page = self.page_cache.new_page_buffer()?;
+            page.zero();
+
+            self.page_cache
+                .update_page(LogicalPageId(page_id.0), page)?;
+        }
+
+        Ok(())
+    }
+
+    /// Zero page buffers on allocation instead of leaving whatever they
+    /// held before, defaults to `false`.
+    pub fn set_zero_fill(&mut self, zero_fill: bool) {
+        self.zero_fill = zero_fill;
+    }
+
+    /// Make the file a function of the updates made to it alone, defaults
+    /// to `false`. Two pagers making the same updates to the same file then
+    /// write the same bytes.
+    ///
+    /// Page buffers are zero filled, otherwise stale bytes left in them
+    /// depend on what the cache evicted. The writer id is derived from the
+    /// committed version instead of picked at random, so copies of a file
+    /// that are written to at the same version can't be told apart by
+    /// their version vectors.
+    ///
+    /// Turning it off goes back to the random writer id and to zero filling
+    /// only as set by `set_zero_fill`.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic_writer_id = if deterministic {
+            Some(deterministic_writer_id(self.header.commited_version.get()))
+        } else {
+            None
+        };
+    }
+
+    /// Overwrite pages with zeroes once they are freed, so deleted data
+    /// doesn't linger in the file, defaults to `false`. They are zeroed once
+    /// the commit freeing them is synced and written by the commit after.
+    pub fn set_scrub_freed(&mut self, scrub_freed: bool) {
+        self.scrub_freed = scrub_freed;
+    }
+
+    /// Let the page cache grow and shrink with its hit rate instead of
+    /// holding a fixed number of pages, using at most `max_bytes` for cached
+    /// pages. It never shrinks below one chunk of the arena.
+    pub fn set_cache_budget(&mut self, max_bytes: usize) {
+        self.page_cache.set_budget(max_bytes / PAGE_SIZE);
+    }
+
+    /// Hold a fixed number of pages in the cache, replacing a budget set
+    /// with `set_cache_budget`.
+    pub fn set_cache_config(&mut self, config: CacheConfig) {
+        self.page_cache.sizing = None;
+        self.page_cache
+            .resize((config.max_bytes / PAGE_SIZE).max(MIN_CACHE_PAGES));
+    }
+
+    /// Write back the coldest updated pages once `high_bytes` of them are
+    /// waiting to be written, until `low_bytes` are left. By default the
+    /// high watermark is half the cache, up to 4MiB, and the low one half
+    /// of that.
+    ///
+    /// The high watermark is capped at half the cache since updated pages
+    /// can't be evicted, the low one is kept below the high one. Lower
+    /// watermarks leave the commit less to write after a burst of updates
+    /// but write pages that are about to be updated again more often.
+    pub fn set_dirty_watermarks(&mut self, low_bytes: usize, high_bytes: usize) {
+        self.page_cache.dirty_watermarks = Some((low_bytes / PAGE_SIZE, high_bytes / PAGE_SIZE));
+    }
+
+    pub fn cache_usage(&self) -> CacheUsage {
+        self.page_cache.usage()
+    }
+
+    /// Give memory back that the page cache allocated but doesn't use, for
+    /// idle periods. Cached pages are moved into as few chunks of the arena
+    /// as they fit in, the chunks left empty are freed. Returns the number
+    /// of bytes freed.
+    ///
+    /// Pages that are being read or waiting to be written can't move, they
+    /// may hold on to a chunk until a later call.
+    pub fn release_memory(&mut self) -> usize {
+        self.page_cache.release_memory()
+    }
+
+    /// Number of pages the cache currently holds at most.
+    pub fn cache_capacity(&self) -> usize {
+        self.page_cache.capacity
+    }
+
+    /// A buffer to write a page into, `Error::CacheExhausted` if the page
+    /// cache has no memory left for one.
+    pub fn new_page_buffer(&mut self) -> Result<PageBufMut> {
+        let mut page = self.page_cache.new_page_buffer()?;
+
+        if self.zero_fill || self.deterministic_writer_id.is_some() {
+            page.zero();
+        }
+
+        Ok(page)
+    }
+
+    /// Push a page that nothing refers to anymore onto the free list.
+    fn release_page(&mut self, page_id: PhysicalPageId) -> Result<()> {
+        if self.scrub_freed {
+            self.unscrubbed.push(page_id);
+        }
+
+        self.held_free_pages += 1;
+
+        match &mut self.bitmap {
+            Some(bitmap) => {
+                bitmap.free(page_id);
+                Ok(())
+            }
+            None => self.free_list.push_back(&mut self.page_cache, page_id),
+        }
+    }
+
+    /// Overwrite a page in place.
+    ///
+    /// Pages allocated since the last commit may be written back ahead of
+    /// it, nothing committed refers to them. Committed pages are held in
+    /// the cache until the commit instead, otherwise a reader of the last
+    /// commit, or the file after a crash, would see the update early.
+    pub fn update_page(&mut self, page_id: LogicalPageId, page: PageBufMut) -> Result<()> {
+        self.updated_pages.insert(page_id.0);
+
+        if self.uncommitted_pages.contains(&PhysicalPageId(page_id.0)) {
+            self.page_cache.update_page(page_id, page)
+        } else {
+            self.page_cache.update_committed_page(page_id, page)
+        }
+    }
+
+    /// What the last commit since opening wrote.
+    pub fn last_commit_stats(&self) -> Option<&CommitStats> {
+        self.last_commit.as_ref()
+    }
+
+    fn write_page(&mut self, page_id: PhysicalPageId, page: &PageBuf) -> Result<()> {
+        self.page_cache.write_page(page_id, page)
+    }
+
+    /// Free a page at the specified version.
+    ///
+    /// Versions before `version` may still read the page, so it is only
+    /// pushed onto the free list, along with any pages it was remapped to,
+    /// once it is committed and no older version is pinned. It is handed
+    /// out again by `new_page_id` once the commit that did so is synced.
+    pub fn free(&mut self, page_id: LogicalPageId, version: Version) -> Result<()> {
+        self.page_cache.check_writable()?;
+
+        self.jobs.push(version, Job::FreePage { page_id });
+
+        Ok(())
+    }
+
+    /// Pick up the versions another process committed to the file since it
+    /// was opened or last refreshed, returns whether there were any.
+    ///
+    /// This is for read only pagers following a file that is still being
+    /// written. Pages past the page count of the last header read are
+    /// `Error::IndexOutofBounds` until a refresh covers them. Cached pages
+    /// are dropped since the writer may have reused them, versions pinned
+    /// before the refresh are not protected from that either. A pager that
+    /// writes is the only writer of its file, for it this does nothing.
+    pub fn refresh(&mut self) -> Result<bool> {
+        if !self.page_cache.read_only {
+            return Ok(false);
+        }
+
+        let file_size = self.page_cache.file.len()?;
+        let mut header_page = BytesMut::zeroed(PAGE_SIZE);
+        let header = read_header(&*self.page_cache.file, file_size, &mut header_page, false)?;
+
+        if header.commited_version.get() <= self.header.commited_version.get() {
+            return Ok(false);
+        }
+
+        let page_count = usize::try_from(header.page_count.get())
+            .map_err(|_| Error::OffsetOverflow("page count"))?;
+        self.page_cache.next_page_id = self.page_cache.next_page_id.max(page_count);
+        self.page_cache.file_len = file_size as u64;
+        self.page_cache.header_page = header_page;
+        self.page_cache.clear();
+
+        // The writer's remaps may have been added or cleaned up since.
+        let jobs = JobQueue::recover(&mut self.page_cache, &header.job_queue)?;
+        self.page_table = load_page_table(&mut self.page_cache, &jobs, &header)?.0;
+        self.deltas = load_deltas(&mut self.page_cache, &header)?.0;
+        self.durable_version = Version(header.commited_version.get());
+        self.oldest_version = Version(header.oldest_version.get());
+        self.header = header;
+
+        Ok(true)
+    }
+
+    /// The root page of the tree as of the last header write, if one has
+    /// been set.
+    pub fn root_page(&self) -> Option<LogicalPageId> {
+        match self.header.root_page.get() {
+            0 => None,
+            id => Some(LogicalPageId(id as usize)),
+        }
+    }
+
+    /// Set the root page of the tree, it is persisted with the next commit.
+    pub fn set_root_page(&mut self, page_id: LogicalPageId) {
+        self.header.root_page = (page_id.0 as u64).into();
+    }
+
+    /// How the pages updated so far were written back to the file.
+    pub fn write_back_stats(&self) -> WriteBackStats {
+        self.page_cache.write_back
+    }
+
+    /// Number of reads so far that missed the cache.
+    pub fn cache_misses(&self) -> u64 {
+        self.page_cache.misses
+    }
+
+    pub fn tree_flags(&self) -> u64 {
+        self.header.tree_flags.get()
+    }
+
+    /// Set the flags of the tree, they are persisted with the next commit.
+    pub fn set_tree_flags(&mut self, flags: u64) {
+        self.header.tree_flags = flags.into();
+    }
+
+    pub(crate) fn current_version(&self) -> Version {
+        Version(self.header.commited_version.get() + 1)
+    }
+
+    fn write_header(&mut self) {
+        self.header.job_queue = self.jobs.state();
+        self.header.free_list = self.free_list.state();
+        self.header.page_count = (self.page_cache.next_page_id as u64).into();
+        self.page_cache.write_header(&self.header)
+    }
+}
+
+impl Drop for DWALPager {
+    /// Make the last commit durable if it wasn't synced. The file is opened
+    /// again at the last header written after syncing, see `read_header`,
+    /// which would otherwise be an older one. Updates since the last commit
+    /// are dropped either way.
+    fn drop(&mut self) {
+        if self.page_cache.check_writable().is_ok() {
+            let res = self.page_cache.seal_header();
+            self.page_cache.poison_on_err(res).ok();
+        }
+    }
+}
+
+/// Rebuild the page table of a file from its job queue, and from the page
+/// map `header` points to with shadow paging. Returns the pages of the page
+/// map along with it.
+///
+/// Otherwise the page table isn't written to the file on its own. Every
+/// remap in it has a cleanup job queued that takes it out again, so the job
+/// queue logs the remaps added by each commit and popping their cleanups
+/// removes them, which bounds it to the remaps a pinned version or the
+/// commit job budget hold back. A page updated twice within a version is
+/// queued twice, the later copy replaces the earlier one like it did in
+/// memory.
+fn load_page_table(
+    page_cache: &mut PageCache,
+    jobs: &JobQueue,
+    header: &Header,
+) -> Result<(PageTable, Vec<PhysicalPageId>)> {
+    let mut page_table = PageTable::new();
+
+    jobs.scan(page_cache, |version, job| {
+        if let Job::RemapCleanup {
+            original_page_id,
+            new_page_id,
+        } = job
+        {
+            page_table
+                .entry(original_page_id)
+                .or_default()
+                .insert(version, PhysicalPageId(new_page_id.0));
+        }
+    })?;
+
+    let page_map = match header.page_map.get() {
+        0 => Vec::new(),
+        first => chain::read(
+            page_cache,
+            PhysicalPageId(first as usize),
+            "malformed page map",
+            |page_id, content| page_map::decode(page_id, content, &mut page_table),
+        )?,
+    };
+
+    Ok((page_table, page_map))
+}
+
+/// Read the deltas `header` points to, along with the pages they are on.
+fn load_deltas(
+    page_cache: &mut PageCache,
+    header: &Header,
+) -> Result<(Deltas, Vec<PhysicalPageId>)> {
+    let mut deltas = Deltas::new();
+
+    let pages = match header.deltas.get() {
+        0 => Vec::new(),
+        first => chain::read(
+            page_cache,
+            PhysicalPageId(first as usize),
+            "malformed page deltas",
+            |page_id, content| delta::decode(page_id, content, &mut deltas),
+        )?,
+    };
+
+    Ok((deltas, pages))
+}
+
+/// What the header points to for a chain written to `pages`, zero for an
+/// empty one.
+fn chain_head(pages: &[PhysicalPageId]) -> U64 {
+    pages.first().map_or(0, |page_id| page_id.0 as u64).into()
+}
+
+/// Read and validate the header of a file that is `file_size` bytes long
+/// into `header_page`, the newest of the header slots that holds a valid
+/// one.
+///
+/// With `durable` only a header whose versions were all synced before it
+/// was written is trusted. The newest one is passed over for the other slot
+/// if that holds the last synced version, otherwise the file is
+/// `Error::RecoveryNeeded`. Without it the newest header is taken as long as
+/// the file is long enough for it, for readers following a writer that
+/// hasn't synced yet.
+fn read_header(
+    file: &dyn File,
+    file_size: usize,
+    header_page: &mut [u8],
+    durable: bool,
+) -> Result<Header> {
+    // The header page is always written whole, anything shorter was not
+    // written by us.
+    if file_size < PAGE_SIZE {
+        return Err(Error::InvalidDatabase);
+    }
+
+    let mut slots = Vec::new();
+    let mut error = None;
+
+    for slot in 0..HEADER_SLOTS {
+        let offset = PhysicalPageId(slot).offset()?;
+        if (file_size as u64) < offset + PAGE_SIZE as u64 {
+            break;
+        }
+
+        let mut slot_page = vec![0; PAGE_SIZE];
+
+        // The file shrank since its length was taken.
+        if file.read_exact_at(&mut slot_page, offset)? < PAGE_SIZE {
+            break;
+        }
+
+        match read_slot(&slot_page) {
+            Ok(header) => slots.push((header, slot_page)),
+            // A slot that was never written says less about the file than
+            // one that was written and is damaged.
+            Err(err) if error.is_none() || matches!(error, Some(Error::InvalidDatabase)) => {
+                error = Some(err)
+            }
+            Err(_) => {}
+        }
+    }
+
+    slots.sort_by_key(|(header, _)| std::cmp::Reverse(header.commited_version.get()));
+
+    let newest = match slots.first() {
+        Some((header, _)) => header,
+        None => return Err(error.unwrap_or(Error::InvalidDatabase)),
+    };
+
+    let mut chosen = 0;
+    if let (true, Some(synced)) = (durable, unsynced(newest)) {
+        // The pages of the versions after the synced one may never have
+        // made it to disk, only a header written after syncing them can
+        // be trusted.
+        chosen = slots
+            .iter()
+            .position(|(header, _)| {
+                header.commited_version.get() == synced.0 && unsynced(header).is_none()
+            })
+            .ok_or(Error::RecoveryNeeded(synced))?;
+    }
+
+    let (header, slot_page) = slots.swap_remove(chosen);
+    header_page.copy_from_slice(&slot_page);
+
+    // Every page below `page_count` was in the file when the header was
+    // committed, a shorter file lost some of them.
+    let page_count = usize::try_from(header.page_count.get())
+        .map_err(|_| Error::OffsetOverflow("page count"))?;
+    if (file_size as u64) < min_file_len(page_count)? {
+        if let Some(synced) = unsynced(&header) {
+            return Err(Error::RecoveryNeeded(synced));
+        }
+
+        return Err(Error::Truncated {
+            page_count: header.page_count.get(),
+            file_len: file_size as u64,
+        });
+    }
+
+    Ok(header)
+}
+
+/// The last synced version if `header` was written without syncing the
+/// pages of the versions after it first. After a crash those may be missing
+/// even though the header made it.
+fn unsynced(header: &Header) -> Option<Version> {
+    let synced = header.synced_version.get();
+    (synced < header.commited_version.get()).then_some(Version(synced))
+}
+
+/// Validate the copy of the header in a header slot.
+fn read_slot(page: &[u8]) -> Result<Header> {
+    let header_size = std::mem::size_of::<Header>();
+    let header = Header::read_from_bytes(&page[..header_size])?;
+
+    if header.magic != MAGIC {
+        return Err(Error::InvalidDatabase);
+    }
+
+    if header.version.get() != VERSION {
+        return Err(Error::UnsupportedVersion(header.version.get()));
+    }
+
+    if header.page_size.get() as usize != PAGE_SIZE {
+        return Err(Error::UnsupportedPageSize(header.page_size.get()));
+    }
+
+    let page_count = header.page_count.get();
+    let queues = [
+        (JOB_QUEUE_ID, &header.job_queue),
+        (FREE_LIST_ID, &header.free_list),
+    ];
+    for (queue_id, state) in queues {
+        if !state.is_valid() || state.queue_id() != queue_id || !state.within(page_count) {
+            return Err(Error::QueueStateCorrupted(queue_id));
+        }
+    }
+
+    if header.checksum.get() != header.compute_checksum() {
+        return Err(Error::CorruptHeader);
+    }
+
+    Ok(header)
+}
+
+/// The shortest file holding `page_count` pages. `write_page` only writes
+/// the contents of a page, so the last one may end short of a full page.
+fn min_file_len(page_count: usize) -> Result<u64> {
+    let last_page_id = page_count.checked_sub(1).ok_or(Error::InvalidDatabase)?;
+
+    Ok(PhysicalPageId(last_page_id).offset()? + PAGE_CAPACITY as u64)
+}
+
+/// Take the writer lock on `file`, `Error::DatabaseLocked` if another
+/// writer has it. Readers don't lock, they may read while a writer commits.
+fn lock(file: &dyn File) -> Result<()> {
+    if file.try_lock()? {
+        Ok(())
+    } else {
+        Err(Error::DatabaseLocked)
+    }
+}
+
+/// A non-zero writer id for a writer that opened the file at
+/// `committed_version`, the same every time (SplitMix64).
+fn deterministic_writer_id(committed_version: u64) -> u64 {
+    let mut z = committed_version.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    (z ^ (z >> 31)).max(1)
+}
+
+/// Pick a random, non-zero writer id.
+fn new_writer_id() -> u64 {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+        time::SystemTime,
+    };
+
+    // `RandomState` is randomly seeded, mixing in the time and pid keeps ids
+    // apart even if the seeds ever repeat.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+
+    hasher.finish().max(1)
+}
+
+impl PageCache {
+    fn new(file: Box<dyn File>, header_page: BytesMut, read_only: bool) -> Self {
+        debug_assert_eq!(header_page.len(), PAGE_SIZE);
+
+        let cache = Cache::new(DEFAULT_CACHE_PAGES);
+        let page_arena = Arena::new(std::alloc::System, PAGE_SIZE, CACHE_CHUNK_PAGES);
+        page_arena.set_max_chunks(DEFAULT_CACHE_PAGES.div_ceil(CACHE_CHUNK_PAGES));
+
+        Self {
+            file,
+            header_page,
+            header_slot: PhysicalPageId(0),
+            header_dirty: false,
+            read_only,
+            poisoned: false,
+            cache,
+            capacity: DEFAULT_CACHE_PAGES,
+            sizing: None,
+            page_arena,
+            released: Vec::new(),
+            dirty: BTreeMap::new(),
+            pending_updates: 0,
+            update_seq: 0,
+            dirty_watermarks: None,
+            write_back: WriteBackStats::default(),
+            misses: 0,
+            // The header slots come first.
+            next_page_id: HEADER_SLOTS,
+            file_len: 0,
+            unsynced_bytes: 0,
+            wal: None,
+            repair_policy: RepairPolicy::Fail,
+            repaired: 0,
+        }
+    }
+
+    pub fn new_last_page_id(&mut self) -> PhysicalPageId {
+        // TODO: re-use freemap etc
+        let page_id = self.next_page_id;
+        self.next_page_id += 1;
+
+        PhysicalPageId(page_id)
+    }
+
+    /// Calls to `update_page` so far, written back or not.
+    fn total_updates(&self) -> u64 {
+        self.write_back.updates + self.pending_updates
+    }
+
+    /// A buffer for a page, `Error::CacheExhausted` if there is no memory
+    /// left for one.
+    fn new_page_buffer(&mut self) -> Result<PageBufMut> {
+        self.reclaim_released();
+
+        // The cache can still grow, otherwise make room by evicting.
+        if self.cache.len() < self.capacity {
+            if let Some(buf) = self.alloc_page_buffer() {
+                return Ok(buf);
+            }
+        }
+
+        // Shrink back to the capacity if the cache had to grow past it.
+        while self.cache.len() > self.capacity {
+            match self.evict_unpinned() {
+                Some(buf) => self.free_page_buffer(buf),
+                None => break,
+            }
+        }
+
+        if let Some(buf) = self.evict_unpinned() {
+            return Ok(buf);
+        }
+
+        // Everything is pinned, grow past the capacity instead of waiting
+        // for pages that may never be released.
+        if let Some(buf) = self.alloc_page_buffer() {
+            return Ok(buf);
+        }
+
+        self.page_arena
+            .add_chunk()
+            .map_err(|_| Error::CacheExhausted)?;
+        self.alloc_page_buffer().ok_or(Error::CacheExhausted)
+    }
+
+    /// Evict a page that isn't pinned and take its buffer. A pinned page is
+    /// still being read or waiting to be written, its buffer can't be
+    /// reused.
+    fn evict_unpinned(&mut self) -> Option<PageBufMut> {
+        let (_, entry) = self.cache.evict_where(|entry| entry.pins() == 0)?;
+
+        entry.page.try_take().ok()
+    }
+
+    /// A freed buffer or a new one, growing the arena up to the capacity.
+    fn alloc_page_buffer(&mut self) -> Option<PageBufMut> {
+        self.page_arena.alloc().ok().map(PageBufMut::new)
+    }
+
+    fn free_page_buffer(&mut self, buf: PageBufMut) {
+        // Safety: every buffer comes from `alloc_page_buffer` and is given
+        // up here.
+        unsafe { self.page_arena.free(buf.into_raw()) }
+    }
+
+    /// Free the buffer of a page dropped from the cache, or hold on to it
+    /// until the readers still referencing it are done.
+    fn release_page_buffer(&mut self, page: PageBuf) {
+        match page.try_take() {
+            Ok(buf) => self.free_page_buffer(buf),
+            Err(page) => self.released.push(page),
+        }
+    }
+
+    /// Free the buffers of released pages nothing references anymore.
+    fn reclaim_released(&mut self) {
+        for page in std::mem::take(&mut self.released) {
+            self.release_page_buffer(page);
+        }
+    }
+
+    fn usage(&self) -> CacheUsage {
+        CacheUsage {
+            cached_bytes: self.cache.len() * PAGE_SIZE,
+            dirty_bytes: self.dirty.len() * PAGE_SIZE,
+            allocated_bytes: self.page_arena.capacity() * PAGE_SIZE,
+            max_bytes: self.capacity * PAGE_SIZE,
+        }
+    }
+
+    /// Size the cache by its hit rate, holding at most `max_pages`.
+    fn set_budget(&mut self, max_pages: usize) {
+        let max_pages = max_pages.max(CACHE_CHUNK_PAGES);
+
+        self.sizing = Some(CacheSizing {
+            max_pages,
+            ..CacheSizing::default()
+        });
+        self.resize(self.capacity.min(max_pages));
+    }
+
+    /// Count a lookup towards the hit rate of a budgeted cache, adjusting
+    /// its capacity at the end of each window.
+    ///
+    /// A cache that misses a lot grows by a chunk, one that has barely
+    /// missed for a while shrinks by one to find out whether it needs all
+    /// of its pages.
+    fn record_lookup(&mut self, hit: bool) {
+        let sizing = match &mut self.sizing {
+            Some(sizing) => sizing,
+            None => return,
+        };
+
+        sizing.lookups += 1;
+        sizing.misses += usize::from(!hit);
+
+        if sizing.lookups < CACHE_SIZING_WINDOW {
+            return;
+        }
+
+        let (lookups, misses) = (sizing.lookups, sizing.misses);
+        sizing.lookups = 0;
+        sizing.misses = 0;
+
+        let capacity = if misses * 10 > lookups {
+            sizing.quiet_windows = 0;
+            (self.capacity + CACHE_CHUNK_PAGES).min(sizing.max_pages)
+        } else if misses * 100 < lookups {
+            sizing.quiet_windows += 1;
+
+            if sizing.quiet_windows < CACHE_SHRINK_WINDOWS {
+                return;
+            }
+
+            sizing.quiet_windows = 0;
+            self.capacity
+                .saturating_sub(CACHE_CHUNK_PAGES)
+                .max(CACHE_CHUNK_PAGES)
+        } else {
+            sizing.quiet_windows = 0;
+            return;
+        };
+
+        self.resize(capacity);
+    }
+
+    /// Change the capacity, evicting pages down to it. Evicted buffers go
+    /// back to the arena, when it shrinks the chunks that are no longer
+    /// needed are released.
+    fn resize(&mut self, capacity: usize) {
+        let shrink = capacity < self.capacity;
+        self.capacity = capacity;
+        self.cache.set_capacity(capacity);
+        self.page_arena
+            .set_max_chunks(capacity.div_ceil(CACHE_CHUNK_PAGES));
+
+        while self.cache.len() > self.capacity {
+            let (_, entry) = match self.cache.evict() {
+                Some(evicted) => evicted,
+                None => break,
+            };
+
+            self.release_page_buffer(entry.page);
+        }
+
+        if shrink {
+            self.release_memory();
+        }
+    }
+
+    /// See `DWALPager::release_memory`.
+    fn release_memory(&mut self) -> usize {
+        let drain = self.page_arena.plan_compaction();
+
+        let mut moved = Vec::new();
+        for entry in self.cache.values_mut() {
+            let draining = self
+                .page_arena
+                .chunk_of(entry.page.as_ptr())
+                .is_some_and(|chunk| drain[chunk]);
+            if !draining || entry.pins() > 0 {
+                continue;
+            }
+
+            let mut buf = match self.page_arena.alloc() {
+                Ok(ptr) => PageBufMut::new(ptr),
+                Err(_) => break,
+            };
+            buf.copy_page(&entry.page);
+
+            let page = std::mem::replace(&mut entry.page, buf.freeze());
+            moved.extend(page.try_take().ok());
+        }
+
+        // Only freed once everything has moved, `alloc` would hand them
+        // out again.
+        for buf in moved {
+            self.free_page_buffer(buf);
+        }
+
+        self.page_arena.release_empty_chunks() * CACHE_CHUNK_PAGES * PAGE_SIZE
+    }
+
+    fn read_page(&mut self, page_id: PhysicalPageId) -> Result<PageBuf> {
+        // TODO: figure out how to hand out pages
+        let logical_page_id = LogicalPageId(page_id.0);
+
+        if page_id.0 >= self.next_page_id {
+            return Err(Error::IndexOutofBounds(logical_page_id));
+        }
+
+        if let Some(entry) = self.cache.get(&logical_page_id) {
+            let page = entry.page.clone();
+            self.record_lookup(true);
+
+            Ok(page)
+        } else if let Some(dirty) = self.dirty.get(&page_id.0) {
+            // Evicted before it was written out.
+            let page = dirty.page.clone();
+            self.record_lookup(true);
+
+            Ok(page)
+        } else {
+            self.record_lookup(false);
+            self.misses += 1;
+
+            let mut page = self.new_page_buffer()?;
+
+            self.read_physical_page(page_id, &mut page)?;
+
+            let page = page.freeze();
+
+            let entry = PageCacheEntry { page: page.clone() };
+
+            self.cache.insert(logical_page_id, entry);
+
+            Ok(page)
+        }
+    }
+
+    pub fn update_page(&mut self, page_id: LogicalPageId, page: PageBufMut) -> Result<()> {
+        self.update(page_id, page, false)
+    }
+
+    /// Update a page the last commit can read, it is only written back by
+    /// `flush_dirty` and never ahead of the commit by `flush_coldest`.
+    pub fn update_committed_page(
+        &mut self,
+        page_id: LogicalPageId,
+        page: PageBufMut,
+    ) -> Result<()> {
+        self.update(page_id, page, true)
+    }
+
+    fn update(&mut self, page_id: LogicalPageId, page: PageBufMut, held: bool) -> Result<()> {
+        self.check_writable()?;
+
+        let page = page.freeze();
+        let held = held || self.dirty.get(&page_id.0).is_some_and(|dirty| dirty.held);
+
+        // Dropping the replaced copy first lets its buffer be reused below.
+        self.update_seq += 1;
+        let dirty = DirtyPage {
+            page: page.clone(),
+            updated: self.update_seq,
+            held,
+        };
+        self.dirty.insert(page_id.0, dirty);
+        self.pending_updates += 1;
+
+        if let Some(entry) = self.cache.get_mut(&page_id) {
+            let old = std::mem::replace(&mut entry.page, page.clone());
+            self.release_page_buffer(old);
+        } else {
+            let entry = PageCacheEntry { page: page.clone() };
+
+            self.cache.insert(page_id, entry);
+        }
+
+        let (low, high) = self.dirty_watermarks();
+        if self.dirty.len() >= high {
+            self.flush_coldest(low)?;
+        }
+
+        Ok(())
+    }
+
+    /// The low and high watermarks in pages, see
+    /// `DWALPager::set_dirty_watermarks`.
+    fn dirty_watermarks(&self) -> (usize, usize) {
+        let max = MAX_DIRTY_PAGES.min(self.capacity / 2);
+
+        match self.dirty_watermarks {
+            Some((low, high)) => {
+                let high = high.clamp(1, max);
+                (low.min(high - 1), high)
+            }
+            None => (max / 2, max),
+        }
+    }
+
+    /// Write out the dirty pages updated least recently until only `keep`
+    /// are left. Pages updated over and over, like the root or the tail of
+    /// a queue, stay in memory to be written once by the commit, as do
+    /// held pages.
+    fn flush_coldest(&mut self, keep: usize) -> Result<WriteBackStats> {
+        let mut page_ids: Vec<usize> = self
+            .dirty
+            .iter()
+            .filter(|(_, dirty)| !dirty.held)
+            .map(|(page_id, _)| *page_id)
+            .collect();
+        page_ids.sort_unstable_by_key(|page_id| self.dirty[page_id].updated);
+        page_ids.truncate(self.dirty.len().saturating_sub(keep));
+        page_ids.sort_unstable();
+
+        self.write_dirty(&page_ids)
+    }
+
+    /// Write out the dirty pages in file order, each run of adjacent pages
+    /// with a single vectored write.
+    ///
+    /// The pages stay dirty if a write fails.
+    fn flush_dirty(&mut self) -> Result<WriteBackStats> {
+        let page_ids: Vec<usize> = self.dirty.keys().copied().collect();
+        self.write_dirty(&page_ids)
+    }
+
+    /// Write out the dirty pages `page_ids`, in ascending order, like
+    /// `flush_dirty`.
+    fn write_dirty(&mut self, page_ids: &[usize]) -> Result<WriteBackStats> {
+        self.check_writable()?;
+
+        let mut stats = WriteBackStats {
+            updates: self.pending_updates,
+            pages_written: page_ids.len() as u64,
+            writes: 0,
+        };
+
+        let dirty = &self.dirty;
+        let page = |page_id: &usize| dirty[page_id].page.buf();
+
+        if let Some(wal) = &mut self.wal {
+            for page_id in page_ids {
+                wal.append_page(PhysicalPageId(*page_id), page(page_id));
+            }
+            wal.sync()?;
+        }
+
+        let mut runs = Vec::new();
+        let mut end = 0;
+        let mut len = 0;
+
+        let mut pages = page_ids.iter().peekable();
+        while let Some(&first) = pages.next() {
+            let mut bufs = vec![IoSlice::new(page(&first))];
+
+            let mut last = first;
+            while let Some(&next) = pages.next_if(|&&next| next == last + 1) {
+                bufs.push(IoSlice::new(&PAGE_GAP));
+                bufs.push(IoSlice::new(page(&next)));
+                last = next;
+            }
+
+            let offset = PhysicalPageId(first).offset()?;
+            let run_len = (last - first) * PAGE_SIZE + PAGE_CAPACITY;
+            end = offset + run_len as u64;
+            len += run_len as u64;
+
+            runs.push((bufs, offset));
+        }
+
+        let writes: Vec<_> = runs
+            .iter()
+            .map(|(bufs, offset)| (&bufs[..], *offset))
+            .collect();
+        self.file.write_all_batch_at(&writes)?;
+
+        // Runs are in file order, the last one ends furthest out.
+        self.file_len = self.file_len.max(end);
+        self.unsynced_bytes += len;
+        stats.writes += runs.len() as u64;
+
+        for page_id in page_ids {
+            self.dirty.remove(page_id);
+        }
+        self.pending_updates = 0;
+
+        self.write_back.updates += stats.updates;
+        self.write_back.pages_written += stats.pages_written;
+        self.write_back.writes += stats.writes;
+
+        Ok(stats)
+    }
+
+    /// Hint that `page_id` will be read soon, this is a no-op if the page is
+    /// already cached.
+    fn prefetch(&mut self, page_id: PhysicalPageId) -> Result<()> {
+        if self.cache.get(&LogicalPageId(page_id.0)).is_some()
+            || self.dirty.contains_key(&page_id.0)
+        {
+            return Ok(());
+        }
+
+        self.file.prefetch(page_id.offset()?, PAGE_SIZE)
+    }
+
+    fn read_physical_page(&self, page_id: PhysicalPageId, page: &mut PageBufMut) -> Result<()> {
+        let offset = page_id.offset()?;
+        let len = self.file.read_exact_at(page.buf_mut(), offset)?;
+
+        if len < PAGE_CAPACITY {
+            // A writer always extends the file to its page count before
+            // committing, a page it has written or a reader can see that
+            // comes up short was shrunk underneath.
+            if self.read_only || offset + PAGE_CAPACITY as u64 <= self.file_len {
+                return Err(Error::Truncated {
+                    page_count: self.next_page_id as u64,
+                    file_len: self.file.len()? as u64,
+                });
+            }
+
+            // Past the end of the file, never written since it was
+            // allocated.
+            page.buf_mut()[len..].fill(0);
+        }
+
+        Ok(())
+    }
+
+    /// Drop every cached page.
+    fn clear(&mut self) {
+        while let Some((_, entry)) = self.cache.evict() {
+            self.release_page_buffer(entry.page);
+        }
+    }
+
+    /// Write the last image of `page_id` in the log over it, if the page
+    /// isn't waiting to be written anyway.
+    fn repair_page(&mut self, page_id: PhysicalPageId) -> Result<bool> {
+        if self.read_only || self.dirty.contains_key(&page_id.0) {
+            return Ok(false);
+        }
+
+        let image = match &self.wal {
+            Some(wal) => wal.find_page(page_id)?,
+            None => None,
+        };
+        let image = match image {
+            Some(image) if image.len() == PAGE_CAPACITY => image,
+            _ => return Ok(false),
+        };
+
+        self.check_writable()?;
+        self.write_at(&image, page_id.offset()?)?;
+
+        let mut page = self.new_page_buffer()?;
+        page.buf_mut().copy_from_slice(&image);
+        let page = page.freeze();
+
+        match self.cache.get_mut(&LogicalPageId(page_id.0)) {
+            Some(entry) => {
+                let old = std::mem::replace(&mut entry.page, page);
+                self.release_page_buffer(old);
+            }
+            None => {
+                self.cache
+                    .insert(LogicalPageId(page_id.0), PageCacheEntry { page });
+            }
+        }
+        self.repaired += 1;
+
+        Ok(true)
+    }
+
+    fn write_page(&mut self, page_id: PhysicalPageId, page: &PageBuf) -> Result<()> {
+        self.check_writable()?;
+
+        let offset = page_id.offset()?;
+        self.write_at(page.buf(), offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        self.file.write_all_at(buf, offset)?;
+        self.file_len = self.file_len.max(offset + buf.len() as u64);
+        self.unsynced_bytes += buf.len() as u64;
+
+        Ok(())
+    }
+
+    /// Grow the file to cover every page handed out so far, including ones
+    /// that were never written, so that recovery can tell when it has been
+    /// truncated.
+    fn extend_file(&mut self) -> Result<()> {
+        let last_page_id = PhysicalPageId(self.next_page_id - 1);
+        let offset = last_page_id.offset()?;
+
+        // Writing the last page would have grown the file past it.
+        if self.file_len < offset + PAGE_CAPACITY as u64 {
+            self.write_at(&[0; PAGE_CAPACITY], offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_header(&mut self, header: &Header) {
+        self.header_slot = header.slot();
+
+        let checksum = header.compute_checksum();
+        let header = header.as_bytes();
 
         debug_assert!(header.len() < PAGE_SIZE, "header must be below PAGE_SIZE");
 
-        self.file.write_at(header, 0)?;
+        self.header_page[..header.len()].copy_from_slice(header);
+        let checksum_offset = header.len() - std::mem::size_of::<U32>();
+        self.header_page[checksum_offset..header.len()].copy_from_slice(&checksum.to_le_bytes());
+        self.header_dirty = true;
+    }
+
+    /// The header as of the last `write_header`.
+    fn staged_header(&self) -> Header {
+        let (header, _) = Header::read_from_prefix(&self.header_page[..]).unwrap();
+        header
+    }
+
+    /// Sync the file and rewrite the staged header as synced, if it was
+    /// written without syncing first.
+    fn seal_header(&mut self) -> Result<()> {
+        let mut header = self.staged_header();
+        if unsynced(&header).is_none() {
+            return Ok(());
+        }
+
+        self.sync_data()?;
+        header.synced_version = header.commited_version;
+        self.write_header(&header);
+        self.flush_header()?;
+        self.flush()
+    }
+
+    /// Write the staged header to every slot, for a new file that has no
+    /// older header to fall back on.
+    fn flush_header_slots(&mut self) -> Result<()> {
+        let slot = self.header_slot;
+
+        for other in 0..HEADER_SLOTS {
+            self.header_slot = PhysicalPageId(other);
+            self.header_dirty = true;
+            self.flush_header()?;
+        }
+
+        self.header_slot = slot;
+
+        Ok(())
+    }
+
+    fn flush_header(&mut self) -> Result<()> {
+        self.check_writable()?;
+
+        if self.header_dirty {
+            if let Some(wal) = &mut self.wal {
+                wal.append_page(self.header_slot, &self.header_page);
+                wal.append_commit();
+                wal.sync()?;
+            }
+
+            let offset = self.header_slot.offset()?;
+            self.file.write_all_at(&self.header_page[..], offset)?;
+            self.file_len = self.file_len.max(offset + PAGE_SIZE as u64);
+            self.unsynced_bytes += PAGE_SIZE as u64;
+            self.header_dirty = false;
+        }
+
+        Ok(())
+    }
+
+    /// Sync everything written so far, without starting the log over.
+    fn sync_data(&mut self) -> Result<()> {
+        self.check_writable()?;
+
+        self.file.sync_data()?;
+        self.unsynced_bytes = 0;
 
         Ok(())
     }
 
     fn flush(&mut self) -> Result<()> {
-        self.file.sync_data()
+        self.sync_data()?;
+
+        // Everything logged is in the file now.
+        if let Some(wal) = &mut self.wal {
+            wal.reset()?;
+        }
+
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(Error::ReadOnly)
+        } else if self.poisoned {
+            Err(Error::Poisoned)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn poison_on_err<T>(&mut self, res: Result<T>) -> Result<T> {
+        if res.is_err() {
+            self.poisoned = true;
+        }
+
+        res
+    }
+}
+
+impl Drop for PageCache {
+    fn drop(&mut self) {
+        self.dirty.clear();
+        self.reclaim_released();
+
+        // Pages can outlive the pager, the memory they point into has to as
+        // well. It is leaked rather than freed under them.
+        if !self.released.is_empty() || self.cache.values_mut().any(|entry| entry.pins() > 0) {
+            self.page_arena.leak();
+        }
     }
 }
 
+/// A page updated since it was last written to the file.
+struct DirtyPage {
+    page: PageBuf,
+    /// `PageCache::update_seq` as of its last update.
+    updated: u64,
+    /// Overwrites a page the last commit can read, see
+    /// `update_committed_page`.
+    held: bool,
+}
+
 struct PageCacheEntry {
     page: PageBuf,
 }
 
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, FromBytes, IntoBytes, Immutable)]
+impl PageCacheEntry {
+    /// References to the page held outside of the cache entry, including
+    /// the dirty page map. A pinned entry is never reused by eviction.
+    fn pins(&self) -> usize {
+        self.page.ref_count() - 1
+    }
+}
+
+#[derive(Default)]
+struct CacheSizing {
+    max_pages: usize,
+    lookups: usize,
+    misses: usize,
+    /// Windows in a row that barely missed.
+    quiet_windows: usize,
+}
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, FromBytes, IntoBytes, KnownLayout, Immutable)]
 #[repr(C)]
 pub struct PhysicalPageId(usize);
 
 impl PhysicalPageId {
     const INVALID_ID: Self = PhysicalPageId(usize::MAX);
+
+    /// Byte offset of this page within the file.
+    fn offset(&self) -> Result<u64> {
+        self.0
+            .checked_mul(PAGE_SIZE)
+            .and_then(|offset| u64::try_from(offset).ok())
+            .ok_or(Error::OffsetOverflow("page offset"))
+    }
 }
 
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, IntoBytes, FromBytes, Immutable)]
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, IntoBytes, FromBytes, KnownLayout, Immutable)]
 pub struct LogicalPageId(usize);
 
-#[derive(Debug)]
-struct DelayedFreePage {
-    version: Version,
-    page_id: LogicalPageId,
-}
+/// Identifies a commit, every commit gets a higher version than the ones
+/// before it.
+#[derive(
+    Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, IntoBytes, FromBytes, KnownLayout, Immutable,
+)]
+pub struct Version(u64);
 
-#[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Debug)]
-struct RemappedPage {
-    version: Version,
-    original_page_id: LogicalPageId,
-    new_page_id: LogicalPageId,
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, IntoBytes, FromBytes, Immutable)]
-pub struct Version(u64);
-
 impl fmt::Display for LogicalPageId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -351,7 +3081,7 @@ impl fmt::Display for LogicalPageId {
 
 impl From<LogicalPageId> for usize {
     fn from(t: LogicalPageId) -> Self {
-        t.0 as usize
+        t.0
     }
 }
 
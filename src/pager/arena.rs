@@ -1,48 +1,231 @@
-use std::{alloc::Layout, cell::Cell, ops::Add, ptr::NonNull};
+use std::{
+    alloc::Layout,
+    cell::{Cell, RefCell},
+    ops::Add,
+    ptr::NonNull,
+};
 
 use allocator_api2::alloc::{AllocError, Allocator};
 
+/// Hands out pages from chunks of `num_pages` pages.
+///
+/// It starts with a single chunk and allocates more on demand, up to
+/// `max_chunks`, while `add_chunk` always adds one. Pages given back with
+/// `free` are handed out again before any new ones. Chunks none of whose
+/// pages are handed out are released by `release_empty_chunks`, the rest
+/// when the arena is dropped.
 pub struct Arena<A: Allocator> {
-    ptr: NonNull<u8>,
+    chunks: RefCell<Vec<NonNull<u8>>>,
+    /// Pages handed out from the last chunk.
     len: Cell<usize>,
+    /// Pages given back, handed out first.
+    free: RefCell<Vec<NonNull<u8>>>,
+    /// Chunks `alloc` may grow to on its own.
+    max_chunks: Cell<usize>,
     page_size: usize,
     num_pages: usize,
     alloc: A,
 }
 
+// SAFETY: The arena owns its chunks, the pointers to them are never shared
+// outside of the pages it hands out.
+unsafe impl<A: Allocator + Send> Send for Arena<A> {}
+
 impl<A: Allocator> Arena<A> {
     pub fn new(alloc: A, page_size: usize, num_pages: usize) -> Self {
         assert!(page_size.is_power_of_two());
         assert!(num_pages.is_power_of_two());
 
-        let size = page_size * num_pages;
-
-        let layout = Layout::from_size_align(size, 8).unwrap();
-        let ptr = alloc.allocate(layout).unwrap();
-
-        let ptr = unsafe { NonNull::new_unchecked(ptr.as_ptr().cast::<u8>()) };
-
-        Self {
-            ptr,
+        let mut arena = Self {
+            chunks: RefCell::new(Vec::new()),
             len: Cell::new(0),
+            free: RefCell::new(Vec::new()),
+            max_chunks: Cell::new(1),
             alloc,
             page_size,
             num_pages,
-        }
+        };
+        arena.add_chunk().unwrap();
+
+        arena
+    }
+
+    /// Let `alloc` grow the arena to `max_chunks` chunks, defaults to one.
+    /// Chunks already allocated are kept.
+    pub fn set_max_chunks(&self, max_chunks: usize) {
+        self.max_chunks.set(max_chunks);
     }
 
     pub fn alloc(&self) -> Result<NonNull<u8>, AllocError> {
-        let len = self.len.get();
+        if let Some(ptr) = self.free.borrow_mut().pop() {
+            return Ok(ptr);
+        }
 
-        if len >= self.num_pages {
-            return Err(AllocError);
+        if self.len.get() >= self.num_pages {
+            if self.chunks.borrow().len() >= self.max_chunks.get() {
+                return Err(AllocError);
+            }
+
+            self.grow()?;
         }
 
+        let len = self.len.get();
         let offset = len * self.page_size;
 
         self.len.set(len.add(1));
 
-        Ok(unsafe { self.ptr.add(offset) })
+        let chunks = self.chunks.borrow();
+        let chunk = chunks.last().ok_or(AllocError)?;
+        Ok(unsafe { chunk.add(offset) })
+    }
+
+    /// Give back a page to be handed out again by `alloc`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` of this arena and must not
+    /// be used or freed again afterwards.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        debug_assert!(self.owns(ptr), "page freed to the wrong arena");
+
+        self.free.borrow_mut().push(ptr);
+    }
+
+    /// Give up every chunk without freeing it, for when pages handed out
+    /// may still be in use after the arena is dropped.
+    pub fn leak(&mut self) {
+        self.chunks.get_mut().clear();
+        self.free.get_mut().clear();
+        self.len.set(self.num_pages);
+    }
+
+    /// Add another chunk of pages to allocate from, regardless of
+    /// `max_chunks`.
+    pub fn add_chunk(&mut self) -> Result<(), AllocError> {
+        self.grow()
+    }
+
+    /// Number of pages in all chunks, handed out or not.
+    pub fn capacity(&self) -> usize {
+        self.chunks.borrow().len() * self.num_pages
+    }
+
+    /// Number of pages given back and not handed out again yet.
+    pub fn free_len(&self) -> usize {
+        self.free.borrow().len()
+    }
+
+    /// Get ready to move the pages handed out into as few chunks as
+    /// possible, the fullest ones.
+    ///
+    /// Returns which chunks pages should be moved out of. Until anything
+    /// else is freed `alloc` hands out pages of the other chunks, there are
+    /// enough of them for every page to move.
+    pub fn plan_compaction(&mut self) -> Vec<bool> {
+        let used = self.used_pages();
+
+        let keep = used.iter().sum::<usize>().div_ceil(self.num_pages).max(1);
+        let mut fullest = (0..used.len()).collect::<Vec<_>>();
+        fullest.sort_by_key(|&chunk| std::cmp::Reverse(used[chunk]));
+
+        let mut drain = vec![true; used.len()];
+        for &chunk in &fullest[..keep.min(used.len())] {
+            drain[chunk] = false;
+        }
+
+        // The free pages of the kept chunks go on top to be handed out
+        // first.
+        let mut free = std::mem::take(self.free.get_mut());
+        free.sort_by_key(|ptr| self.chunk_of(*ptr).is_some_and(|chunk| !drain[chunk]));
+        *self.free.get_mut() = free;
+
+        drain
+    }
+
+    /// Release the chunks none of whose pages are handed out, always keeping
+    /// one. Returns the number of chunks released.
+    pub fn release_empty_chunks(&mut self) -> usize {
+        let used = self.used_pages();
+
+        let mut empty = used.iter().map(|used| *used == 0).collect::<Vec<_>>();
+        if empty.iter().all(|empty| *empty) {
+            if let Some(first) = empty.first_mut() {
+                *first = false;
+            }
+        }
+
+        let mut free = std::mem::take(self.free.get_mut());
+        free.retain(|ptr| self.chunk_of(*ptr).is_some_and(|chunk| !empty[chunk]));
+        *self.free.get_mut() = free;
+
+        let layout = self.chunk_layout();
+        let mut released = 0;
+        for chunk in (0..empty.len()).rev() {
+            if empty[chunk] {
+                let ptr = self.chunks.get_mut().remove(chunk);
+                unsafe { self.alloc.deallocate(ptr, layout) };
+                released += 1;
+            }
+        }
+
+        released
+    }
+
+    /// Pages handed out from each chunk.
+    ///
+    /// The pages of the last chunk that were never handed out are moved to
+    /// the free list first, so the free pages of every chunk are in one
+    /// place.
+    fn used_pages(&mut self) -> Vec<usize> {
+        if let Some(last) = self.chunks.get_mut().last().copied() {
+            let len = self.len.replace(self.num_pages);
+            for page in (len..self.num_pages).rev() {
+                let ptr = unsafe { last.add(page * self.page_size) };
+                self.free.get_mut().push(ptr);
+            }
+        }
+
+        let mut used = vec![self.num_pages; self.chunks.get_mut().len()];
+        for ptr in self.free.borrow().iter() {
+            if let Some(chunk) = self.chunk_of(*ptr) {
+                used[chunk] -= 1;
+            }
+        }
+
+        used
+    }
+
+    /// Index of the chunk `ptr` points into.
+    pub fn chunk_of(&self, ptr: NonNull<u8>) -> Option<usize> {
+        let chunk_size = self.page_size * self.num_pages;
+        let addr = ptr.as_ptr() as usize;
+
+        self.chunks.borrow().iter().position(|chunk| {
+            let start = chunk.as_ptr() as usize;
+            (start..start + chunk_size).contains(&addr)
+        })
+    }
+
+    fn grow(&self) -> Result<(), AllocError> {
+        let ptr = self.alloc.allocate(self.chunk_layout())?;
+
+        self.chunks
+            .borrow_mut()
+            .push(unsafe { NonNull::new_unchecked(ptr.as_ptr().cast::<u8>()) });
+        self.len.set(0);
+
+        Ok(())
+    }
+
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.chunk_of(ptr).is_some_and(|chunk| {
+            let start = self.chunks.borrow()[chunk].as_ptr() as usize;
+            (ptr.as_ptr() as usize - start) & (self.page_size - 1) == 0
+        })
+    }
+
+    fn chunk_layout(&self) -> Layout {
+        Layout::from_size_align(self.page_size * self.num_pages, 8).unwrap()
     }
 }
 
@@ -60,14 +243,18 @@ unsafe impl<A: Allocator> Allocator for Arena<A> {
         Ok(NonNull::slice_from_raw_parts(ptr, self.page_size))
     }
 
-    unsafe fn deallocate(&self, _: NonNull<u8>, _: std::alloc::Layout) {}
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _: std::alloc::Layout) {
+        self.free(ptr)
+    }
 }
 
 impl<A: Allocator> Drop for Arena<A> {
     fn drop(&mut self) {
-        let size = self.page_size * self.num_pages;
-        let layout = Layout::from_size_align(size, 8).unwrap();
-        unsafe { self.alloc.deallocate(self.ptr, layout) }
+        let layout = self.chunk_layout();
+
+        for chunk in self.chunks.get_mut().iter() {
+            unsafe { self.alloc.deallocate(*chunk, layout) }
+        }
     }
 }
 
@@ -84,6 +271,18 @@ mod tests {
         assert_eq!(arena.len.get(), 0);
     }
 
+    #[test]
+    fn leak() {
+        let mut arena = Arena::new(System, 4096, 4);
+        let ptr = arena.alloc().unwrap();
+        arena.leak();
+        assert_eq!(arena.capacity(), 0);
+        drop(arena);
+
+        // Still allocated, freed by hand to keep leak checkers quiet.
+        unsafe { std::alloc::dealloc(ptr.as_ptr(), Layout::from_size_align(4096 * 4, 8).unwrap()) };
+    }
+
     #[test]
     #[should_panic]
     fn test_arena_invalid_page_size() {
@@ -124,6 +323,69 @@ mod tests {
         assert!(arena.alloc().is_err());
     }
 
+    #[test]
+    fn test_arena_add_chunk() {
+        let mut arena = Arena::new(System, 4096, 2);
+        let first = [arena.alloc().unwrap(), arena.alloc().unwrap()];
+        assert!(arena.alloc().is_err());
+
+        arena.add_chunk().unwrap();
+        assert_eq!(arena.capacity(), 4);
+
+        let second = [arena.alloc().unwrap(), arena.alloc().unwrap()];
+        assert!(arena.alloc().is_err());
+        assert!(first.iter().all(|ptr| !second.contains(ptr)));
+    }
+
+    #[test]
+    fn test_arena_grow_on_demand() {
+        let arena = Arena::new(System, 4096, 2);
+        arena.set_max_chunks(2);
+
+        let pages = (0..4).map(|_| arena.alloc().unwrap()).collect::<Vec<_>>();
+        assert_eq!(arena.capacity(), 4);
+        assert!(arena.alloc().is_err());
+
+        // Freed pages are handed out again before anything else.
+        unsafe { arena.free(pages[1]) };
+        assert_eq!(arena.free_len(), 1);
+        assert_eq!(arena.alloc().unwrap(), pages[1]);
+        assert!(arena.alloc().is_err());
+    }
+
+    #[test]
+    fn test_arena_release_empty_chunks() {
+        let mut arena = Arena::new(System, 4096, 2);
+        arena.set_max_chunks(3);
+
+        let pages = (0..6).map(|_| arena.alloc().unwrap()).collect::<Vec<_>>();
+        assert_eq!(arena.capacity(), 6);
+
+        // One page left in each of the first two chunks, the last is empty.
+        for page in [pages[1], pages[2], pages[4], pages[5]] {
+            unsafe { arena.free(page) };
+        }
+        assert_eq!(arena.release_empty_chunks(), 1);
+        assert_eq!(arena.capacity(), 4);
+
+        // Moving the page out of the second chunk lets it go too.
+        let drain = arena.plan_compaction();
+        assert_eq!(drain, vec![false, true]);
+        let moved = arena.alloc().unwrap();
+        assert_eq!(arena.chunk_of(moved), Some(0));
+        unsafe { arena.free(pages[3]) };
+        assert_eq!(arena.release_empty_chunks(), 1);
+        assert_eq!(arena.capacity(), 2);
+
+        // The first chunk is always kept, and the arena grows again.
+        unsafe { arena.free(pages[0]) };
+        unsafe { arena.free(moved) };
+        assert_eq!(arena.release_empty_chunks(), 0);
+        let pages = (0..4).map(|_| arena.alloc().unwrap()).collect::<Vec<_>>();
+        assert_eq!(arena.capacity(), 4);
+        assert!(pages.iter().all(|page| arena.owns(*page)));
+    }
+
     #[test]
     fn test_arena_as_allocator() {
         let arena = Arena::new(System, 8, 4); // Small pages for testing
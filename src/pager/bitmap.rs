@@ -0,0 +1,438 @@
+//! A free page allocator keeping one bit per page, an alternative to the
+//! free list queue for large files where runs of adjacent free pages are
+//! wanted.
+//!
+//! The bitmap is held in memory and written out by `commit` to bitmap pages
+//! listed by a directory page, which the header points to. Both are copy on
+//! write, a commit writes the changed bitmap pages and the directory to
+//! pages that were already free as of the previous commit so the previous
+//! header stays intact until the new one is written. The pages they replace
+//! are freed by the commit after.
+
+use std::{collections::BTreeSet, convert::TryInto};
+
+use super::{LogicalPageId, PageCache, PhysicalPageId, PAGE_CAPACITY};
+use crate::{Error, Result};
+
+/// Words of the bitmap stored in a bitmap page.
+const WORDS_PER_PAGE: usize = PAGE_CAPACITY / 8;
+/// Bitmap pages the directory page has room for, after their count.
+const MAX_BITMAP_PAGES: usize = PAGE_CAPACITY / 8 - 1;
+
+pub struct BitmapAllocator {
+    /// One bit per page, set for pages that are free. Always a whole number
+    /// of bitmap pages long, pages past the end are in use.
+    words: Vec<u64>,
+    /// Where each bitmap page was written by the last commit,
+    /// `INVALID_ID` for pages that haven't been written yet.
+    locations: Vec<PhysicalPageId>,
+    directory: PhysicalPageId,
+    /// Bitmap pages changed since the last commit.
+    dirty: BTreeSet<usize>,
+    /// Pages freed since the last commit, like pushes onto the free list
+    /// they can only be handed out once it is committed.
+    pending: Vec<PhysicalPageId>,
+    /// Pages the last commit replaced, the header before it still pointed
+    /// at them.
+    replaced: Vec<PhysicalPageId>,
+    /// Pages marked free by commits that haven't been synced yet. They are
+    /// written out as free but not handed out until `synced`, the last
+    /// durable header may still use them.
+    held: Vec<PhysicalPageId>,
+    /// No word before this one has a free page.
+    hint: usize,
+}
+
+impl BitmapAllocator {
+    pub fn new() -> Self {
+        Self {
+            words: Vec::new(),
+            locations: Vec::new(),
+            directory: PhysicalPageId::INVALID_ID,
+            dirty: BTreeSet::new(),
+            pending: Vec::new(),
+            replaced: Vec::new(),
+            held: Vec::new(),
+            hint: 0,
+        }
+    }
+
+    /// Read the bitmap written by a commit that returned `directory`.
+    pub fn load(pager: &mut PageCache, directory: PhysicalPageId) -> Result<Self> {
+        let corrupt = |page_id: PhysicalPageId| Error::Corruption {
+            page: LogicalPageId(page_id.0),
+            reason: "malformed free page bitmap",
+        };
+
+        let page = pager.read_page(directory)?;
+        let mut ids = page.buf().chunks_exact(8).map(read_word);
+
+        let count = ids.next().ok_or_else(|| corrupt(directory))? as usize;
+        if count > MAX_BITMAP_PAGES {
+            return Err(corrupt(directory));
+        }
+
+        let mut bitmap = Self::new();
+        bitmap.directory = directory;
+
+        for id in ids.take(count) {
+            let location = PhysicalPageId(id as usize);
+            let page = pager.read_page(location)?;
+
+            bitmap.locations.push(location);
+            bitmap.words.extend(
+                page.buf()
+                    .chunks_exact(8)
+                    .take(WORDS_PER_PAGE)
+                    .map(read_word),
+            );
+        }
+
+        Ok(bitmap)
+    }
+
+    /// Take a free page, the lowest one.
+    pub fn alloc(&mut self) -> Option<PhysicalPageId> {
+        let idx = self.hint + self.words[self.hint..].iter().position(|word| *word != 0)?;
+        self.hint = idx;
+
+        let bit = self.words[idx].trailing_zeros() as usize;
+        let page_id = PhysicalPageId(idx * 64 + bit);
+        self.take(page_id, 1);
+
+        Some(page_id)
+    }
+
+    /// Take `len` adjacent free pages, the lowest such run. Words without a
+    /// free page and words of only free pages are skipped over whole.
+    pub fn alloc_run(&mut self, len: usize) -> Option<PhysicalPageId> {
+        debug_assert!(len > 0);
+
+        let mut start = 0;
+        let mut run = 0;
+
+        for idx in self.hint..self.words.len() {
+            let word = self.words[idx];
+
+            if word == 0 {
+                run = 0;
+                continue;
+            }
+
+            if word == u64::MAX {
+                if run == 0 {
+                    start = idx * 64;
+                }
+                run += 64;
+            } else {
+                for bit in 0..64 {
+                    if word & (1 << bit) == 0 {
+                        run = 0;
+                        continue;
+                    }
+
+                    if run == 0 {
+                        start = idx * 64 + bit;
+                    }
+                    run += 1;
+
+                    if run == len {
+                        break;
+                    }
+                }
+            }
+
+            if run >= len {
+                let page_id = PhysicalPageId(start);
+                self.take(page_id, len);
+
+                return Some(page_id);
+            }
+        }
+
+        None
+    }
+
+    /// Free `page_id`, it can be handed out again once the next commit is
+    /// synced.
+    pub fn free(&mut self, page_id: PhysicalPageId) {
+        self.pending.push(page_id);
+    }
+
+    /// Hand out the pages marked free by the commits so far, their headers
+    /// have been synced.
+    pub fn synced(&mut self) {
+        for page_id in self.held.drain(..) {
+            self.words[page_id.0 / 64] |= 1 << (page_id.0 % 64);
+            self.hint = self.hint.min(page_id.0 / 64);
+        }
+    }
+
+    /// Number of pages that can be handed out.
+    pub fn free_pages(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Take the free pages at the end of the `page_count` pages of the file,
+    /// returning the page count without them.
+    ///
+    /// The pages the last commit replaced are cut off along with them. If
+    /// the bitmap itself is in the way the next commit writes it elsewhere,
+    /// so it can be cut off by the commit after.
+    pub fn truncate(&mut self, mut page_count: usize) -> usize {
+        while let Some(page_id) = page_count.checked_sub(1) {
+            let page = PhysicalPageId(page_id);
+            let free = self
+                .words
+                .get(page_id / 64)
+                .is_some_and(|word| word & (1 << (page_id % 64)) != 0);
+
+            if free {
+                self.take(page, 1);
+            } else if let Some(idx) = self.replaced.iter().position(|id| *id == page) {
+                self.replaced.swap_remove(idx);
+            } else if let Some(idx) = self.held.iter().position(|id| *id == page) {
+                // Written out as free, the bitmap page has to be written
+                // again without it.
+                self.held.swap_remove(idx);
+                self.dirty.insert(page_id / (WORDS_PER_PAGE * 64));
+            } else {
+                if page == self.directory || self.locations.contains(&page) {
+                    self.dirty.extend(0..self.locations.len());
+                }
+                break;
+            }
+
+            page_count = page_id;
+        }
+
+        page_count
+    }
+
+    /// Write the changed bitmap pages and a new directory, returning where
+    /// the directory was written.
+    ///
+    /// They are written to pages that were free before, or past the end of
+    /// the file if there are none, and the pages freed since the last commit
+    /// are only marked free after so they can't be among them. Those are
+    /// held back along with the ones held already, see `synced`.
+    pub fn commit(&mut self, pager: &mut PageCache) -> Result<PhysicalPageId> {
+        let mut freed = std::mem::take(&mut self.pending);
+        freed.append(&mut self.replaced);
+
+        for page_id in &freed {
+            self.grow_to(*page_id);
+            self.dirty.insert(page_id.0 / (WORDS_PER_PAGE * 64));
+        }
+
+        if self.dirty.is_empty() && self.directory != PhysicalPageId::INVALID_ID {
+            return Ok(self.directory);
+        }
+
+        // Taking pages for the bitmap changes more of it, until every
+        // changed page has a place to go.
+        let directory = self.alloc_or_append(pager);
+        let mut targets = Vec::new();
+
+        while let Some(idx) = self
+            .dirty
+            .iter()
+            .copied()
+            .find(|idx| targets.iter().all(|(target, _)| target != idx))
+        {
+            let location = self.alloc_or_append(pager);
+            targets.push((idx, location));
+        }
+
+        if self.locations.len() > MAX_BITMAP_PAGES {
+            return Err(Error::OffsetOverflow("bitmap pages"));
+        }
+
+        self.held.append(&mut freed);
+        for page_id in &self.held {
+            self.words[page_id.0 / 64] |= 1 << (page_id.0 % 64);
+        }
+
+        for (idx, location) in targets {
+            let mut page = pager.new_page_buffer()?;
+            page.init();
+
+            let words = &self.words[idx * WORDS_PER_PAGE..(idx + 1) * WORDS_PER_PAGE];
+            for (word, bytes) in words.iter().zip(page.buf_mut().chunks_exact_mut(8)) {
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+
+            pager.update_page(LogicalPageId(location.0), page)?;
+
+            let replaced = std::mem::replace(&mut self.locations[idx], location);
+            if replaced != PhysicalPageId::INVALID_ID {
+                self.replaced.push(replaced);
+            }
+        }
+
+        let mut page = pager.new_page_buffer()?;
+        page.init();
+
+        let ids = std::iter::once(self.locations.len() as u64)
+            .chain(self.locations.iter().map(|location| location.0 as u64));
+        for (id, bytes) in ids.zip(page.buf_mut().chunks_exact_mut(8)) {
+            bytes.copy_from_slice(&id.to_le_bytes());
+        }
+
+        pager.update_page(LogicalPageId(directory.0), page)?;
+
+        for page_id in &self.held {
+            self.words[page_id.0 / 64] &= !(1 << (page_id.0 % 64));
+        }
+
+        if self.directory != PhysicalPageId::INVALID_ID {
+            self.replaced.push(self.directory);
+        }
+        self.directory = directory;
+        self.dirty.clear();
+
+        Ok(directory)
+    }
+
+    fn alloc_or_append(&mut self, pager: &mut PageCache) -> PhysicalPageId {
+        self.alloc().unwrap_or_else(|| pager.new_last_page_id())
+    }
+
+    /// Mark `len` pages from `page_id` on as in use.
+    fn take(&mut self, page_id: PhysicalPageId, len: usize) {
+        for page_id in page_id.0..page_id.0 + len {
+            self.words[page_id / 64] &= !(1 << (page_id % 64));
+            self.dirty.insert(page_id / (WORDS_PER_PAGE * 64));
+        }
+    }
+
+    /// Add bitmap pages until `page_id` is covered.
+    fn grow_to(&mut self, page_id: PhysicalPageId) {
+        while self.words.len() <= page_id.0 / 64 {
+            self.words.resize(self.words.len() + WORDS_PER_PAGE, 0);
+            self.locations.push(PhysicalPageId::INVALID_ID);
+        }
+    }
+}
+
+fn read_word(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, PAGE_SIZE};
+
+    fn page_cache(file: &MemoryFile) -> PageCache {
+        let mut pager = PageCache::new(Box::new(file.clone()), BytesMut::zeroed(PAGE_SIZE), false);
+        pager.next_page_id = 1;
+        pager
+    }
+
+    #[test]
+    fn free_after_commit() {
+        let file = MemoryFile::default();
+        let mut pager = page_cache(&file);
+        let mut bitmap = BitmapAllocator::new();
+
+        for page_id in 10..20 {
+            bitmap.free(PhysicalPageId(page_id));
+        }
+        assert_eq!(bitmap.alloc(), None);
+
+        let directory = bitmap.commit(&mut pager).unwrap();
+        // Not until the commit is synced.
+        assert_eq!(bitmap.alloc(), None);
+        bitmap.synced();
+        assert_eq!(bitmap.free_pages(), 10);
+        assert_eq!(bitmap.alloc(), Some(PhysicalPageId(10)));
+        assert_eq!(bitmap.alloc_run(3), Some(PhysicalPageId(11)));
+
+        pager.flush_dirty().unwrap();
+        let next_page_id = pager.next_page_id;
+
+        let mut pager = page_cache(&file);
+        pager.next_page_id = next_page_id;
+        let mut bitmap = BitmapAllocator::load(&mut pager, directory).unwrap();
+        assert_eq!(bitmap.free_pages(), 10);
+        assert_eq!(bitmap.alloc_run(10), Some(PhysicalPageId(10)));
+    }
+
+    #[test]
+    fn held_until_synced() {
+        let file = MemoryFile::default();
+        let mut pager = page_cache(&file);
+        let mut bitmap = BitmapAllocator::new();
+
+        bitmap.free(PhysicalPageId(10));
+        bitmap.commit(&mut pager).unwrap();
+        bitmap.free(PhysicalPageId(11));
+        let directory = bitmap.commit(&mut pager).unwrap();
+        assert_eq!(bitmap.alloc(), None);
+
+        // Held pages are written out as free all the same.
+        pager.flush_dirty().unwrap();
+        let next_page_id = pager.next_page_id;
+        let mut pager = page_cache(&file);
+        pager.next_page_id = next_page_id;
+        let loaded = BitmapAllocator::load(&mut pager, directory).unwrap();
+        assert_eq!(loaded.free_pages(), 2);
+
+        bitmap.synced();
+        assert_eq!(bitmap.alloc_run(2), Some(PhysicalPageId(10)));
+    }
+
+    #[test]
+    fn runs() {
+        let file = MemoryFile::default();
+        let mut pager = page_cache(&file);
+        let mut bitmap = BitmapAllocator::new();
+
+        // Free pages 100..400 but for a gap at 130 and at 250.
+        for page_id in (100..400).filter(|page_id| *page_id != 130 && *page_id != 250) {
+            bitmap.free(PhysicalPageId(page_id));
+        }
+        bitmap.commit(&mut pager).unwrap();
+        bitmap.synced();
+
+        assert_eq!(bitmap.alloc_run(200), None);
+        assert_eq!(bitmap.alloc_run(100), Some(PhysicalPageId(131)));
+        assert_eq!(bitmap.alloc_run(30), Some(PhysicalPageId(100)));
+        assert_eq!(bitmap.alloc_run(120), Some(PhysicalPageId(251)));
+        assert_eq!(bitmap.alloc_run(20), Some(PhysicalPageId(371)));
+        assert_eq!(bitmap.alloc_run(19), Some(PhysicalPageId(231)));
+        assert_eq!(bitmap.alloc_run(1), Some(PhysicalPageId(391)));
+    }
+
+    #[test]
+    fn replaced_pages_freed_by_next_commit() {
+        let file = MemoryFile::default();
+        let mut pager = page_cache(&file);
+        let mut bitmap = BitmapAllocator::new();
+
+        // Nothing is free yet, the bitmap goes past the end of the file.
+        bitmap.free(PhysicalPageId(5));
+        assert_eq!(bitmap.commit(&mut pager).unwrap(), PhysicalPageId(1));
+        assert_eq!(bitmap.locations, [PhysicalPageId(2)]);
+        bitmap.synced();
+
+        // Page 5 was free as of the last commit, page 6 only after this one.
+        bitmap.free(PhysicalPageId(6));
+        assert_eq!(bitmap.commit(&mut pager).unwrap(), PhysicalPageId(5));
+        assert_eq!(bitmap.locations, [PhysicalPageId(3)]);
+        bitmap.synced();
+        assert_eq!(bitmap.free_pages(), 1);
+
+        // The pages written by the first commit are free after the third.
+        assert_eq!(bitmap.commit(&mut pager).unwrap(), PhysicalPageId(6));
+        bitmap.synced();
+        assert_eq!(bitmap.free_pages(), 2);
+        assert_eq!(bitmap.alloc_run(2), Some(PhysicalPageId(1)));
+    }
+}
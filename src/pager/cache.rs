@@ -1,94 +1,182 @@
-//! This is a really funk weird cache that doesn't actually cache. It just allows you to evict and
-//! insert/get. At the moment, this just uses FIFO as the eviciton algorithim. In the future, this
-//! should be extended to use `s3-fifo`.
+//! An S3-FIFO cache. It doesn't evict on its own, the caller decides when to
+//! `evict` and the cache decides what.
+//!
+//! New entries go into a small FIFO queue, those that are hit again before
+//! they reach its end move on to the main queue while the rest are evicted
+//! and remembered in a ghost queue. An entry that comes back while its key
+//! is still a ghost is admitted straight into the main queue. The main
+//! queue is a CLOCK, an entry that was hit since it last reached the end is
+//! given another round instead of being evicted.
+//!
+//! A sequential scan only ever touches the small queue, so it can't flush
+//! out the pages that are hit over and over, like the upper levels of the
+//! tree.
 
-use std::{collections::HashMap, hash::Hash, ptr::NonNull};
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+};
+
+/// Hits counted per entry, more rounds in the main queue than this are not
+/// earned.
+const MAX_FREQ: u8 = 3;
 
 pub struct Cache<K: Hash + Eq + Copy, V> {
-    index: HashMap<K, NonNull<Node<K, V>>>,
-    head: Option<NonNull<Node<K, V>>>,
-    tail: Option<NonNull<Node<K, V>>>,
+    entries: HashMap<K, Entry<V>>,
+    small: VecDeque<K>,
+    main: VecDeque<K>,
+    /// Keys recently evicted from the small queue, each with the sequence
+    /// number it was added at so that stale copies left in `ghost_order`
+    /// after a re-admission can be told apart.
+    ghosts: HashMap<K, u64>,
+    ghost_order: VecDeque<(K, u64)>,
+    ghost_seq: u64,
+    capacity: usize,
 }
 
-struct Node<K, V> {
-    key: K,
+struct Entry<V> {
     val: V,
-    prev: Option<NonNull<Node<K, V>>>,
+    freq: u8,
 }
 
 impl<K: Hash + Eq + Copy, V> Cache<K, V> {
     pub fn new(capacity: usize) -> Self {
         Cache {
-            index: HashMap::with_capacity(capacity),
-            head: None,
-            tail: None,
+            entries: HashMap::with_capacity(capacity),
+            small: VecDeque::new(),
+            main: VecDeque::new(),
+            ghosts: HashMap::new(),
+            ghost_order: VecDeque::new(),
+            ghost_seq: 0,
+            capacity,
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Change the number of entries the caller keeps the cache at, this
+    /// sizes the small and ghost queues.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.trim_ghosts();
+    }
+
     pub fn get(&mut self, key: &K) -> Option<&V> {
-        self.index
-            .get_mut(key)
-            .map(|node| unsafe { &(*node.as_ptr()).val })
+        self.get_mut(key).map(|val| &*val)
     }
 
     pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        self.index
-            .get_mut(key)
-            .map(|node| unsafe { &mut (*node.as_ptr()).val })
+        self.entries.get_mut(key).map(|entry| {
+            entry.freq = (entry.freq + 1).min(MAX_FREQ);
+            &mut entry.val
+        })
     }
 
     pub fn insert(&mut self, key: K, val: V) -> Option<(K, V)> {
-        unsafe {
-            let new = NonNull::new_unchecked(Box::into_raw(Box::new(Node {
-                key,
-                val,
-                prev: None,
-            })));
-
-            if let Some(head) = &mut self.head {
-                head.as_mut().prev = Some(new);
-                self.head = Some(new);
-            } else {
-                self.head = Some(new);
-                self.tail = Some(new);
-            }
-
-            if let Some(_) = self.index.insert(key, new) {
-                todo!("inserted the same key over another");
-            }
+        if self.entries.insert(key, Entry { val, freq: 0 }).is_some() {
+            todo!("inserted the same key over another");
+        }
 
-            None
+        if self.ghosts.remove(&key).is_some() {
+            self.main.push_back(key);
+        } else {
+            self.small.push_back(key);
         }
+
+        None
+    }
+
+    /// Every entry, in no particular order. This doesn't count as a hit.
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.entries.values_mut().map(|entry| &mut entry.val)
     }
 
     pub fn evict(&mut self) -> Option<(K, V)> {
-        unsafe {
-            if let Some(tail) = &mut self.tail {
-                let prev = tail.as_mut().prev;
-                let key = tail.as_mut().key;
-
-                let node = self.index.remove(&key).unwrap();
-
-                if prev.is_none() {
-                    self.head = None;
-                    self.tail = None;
-                } else {
-                    self.tail = prev;
+        self.evict_where(|_| true)
+    }
+
+    /// Evict an entry for which `evictable` returns true, entries it
+    /// refuses are passed over like entries that were hit. Returns `None`
+    /// if it refuses all of them.
+    pub fn evict_where(&mut self, mut evictable: impl FnMut(&V) -> bool) -> Option<(K, V)> {
+        // Every entry loses a hit or moves on each time it is passed over,
+        // after this many steps all of them have been refused.
+        let mut steps = (self.small.len() + self.main.len()) * (MAX_FREQ as usize + 2);
+
+        loop {
+            steps = steps.checked_sub(1)?;
+
+            let from_small = self.small.len() >= self.small_capacity() || self.main.is_empty();
+
+            if from_small {
+                let key = self.small.pop_front()?;
+                let entry = self.entries.get_mut(&key).unwrap();
+
+                if entry.freq > 0 || !evictable(&entry.val) {
+                    entry.freq = 0;
+                    self.main.push_back(key);
+                    continue;
                 }
 
-                let node = Box::from_raw(node.as_ptr());
+                self.add_ghost(key);
+                let entry = self.entries.remove(&key).unwrap();
+
+                return Some((key, entry.val));
+            }
+
+            let key = self.main.pop_front()?;
+            let entry = self.entries.get_mut(&key).unwrap();
 
-                Some((node.key, node.val))
-            } else {
-                None
+            if entry.freq > 0 || !evictable(&entry.val) {
+                entry.freq = entry.freq.saturating_sub(1);
+                self.main.push_back(key);
+                continue;
             }
+
+            let entry = self.entries.remove(&key).unwrap();
+
+            return Some((key, entry.val));
         }
     }
-}
 
-impl<K: Hash + Eq + Copy, V> Drop for Cache<K, V> {
-    fn drop(&mut self) {
-        while let Some(_node) = self.evict() {}
+    /// The small queue gets a tenth of the capacity.
+    fn small_capacity(&self) -> usize {
+        (self.capacity / 10).max(1)
+    }
+
+    fn add_ghost(&mut self, key: K) {
+        self.ghost_seq += 1;
+        self.ghosts.insert(key, self.ghost_seq);
+        self.ghost_order.push_back((key, self.ghost_seq));
+
+        self.trim_ghosts();
+    }
+
+    /// Forget the oldest ghosts once there are more than the main queue
+    /// holds.
+    fn trim_ghosts(&mut self) {
+        let max_ghosts = self.capacity - self.small_capacity().min(self.capacity);
+
+        while self.ghosts.len() > max_ghosts {
+            let (key, seq) = match self.ghost_order.pop_front() {
+                Some(ghost) => ghost,
+                None => break,
+            };
+
+            if self.ghosts.get(&key) == Some(&seq) {
+                self.ghosts.remove(&key);
+            }
+        }
+
+        // Drop copies of keys that were re-admitted since.
+        while let Some((key, seq)) = self.ghost_order.front() {
+            if self.ghosts.get(key) == Some(seq) {
+                break;
+            }
+            self.ghost_order.pop_front();
+        }
     }
 }
 
@@ -111,13 +199,14 @@ mod tests {
         assert_eq!(cache.get(&3), None);
         assert_eq!(cache.insert(3, "three"), None);
 
-        // Test eviction
-        assert!(cache.evict().is_some());
+        // Test eviction, the entries that were hit move on to the main
+        // queue.
+        assert_eq!(cache.evict(), Some((3, "three")));
 
         // Verify state after eviction
-        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&1), Some(&"one"));
         assert_eq!(cache.get(&2), Some(&"two"));
-        assert_eq!(cache.get(&3), Some(&"three"));
+        assert_eq!(cache.get(&3), None);
     }
 
     #[test]
@@ -138,4 +227,62 @@ mod tests {
             cache.insert(i, format!("{}", i));
         }
     }
+
+    #[test]
+    fn scan_resistant() {
+        let capacity = 100;
+        let mut cache = Cache::new(capacity);
+
+        let insert = |cache: &mut Cache<u32, u32>, key| {
+            if cache.get(&key).is_none() {
+                if cache.len() == capacity {
+                    cache.evict().unwrap();
+                }
+                cache.insert(key, key);
+            }
+        };
+
+        // A few hot keys that are hit between the pages of a long scan.
+        for key in 1_000..10_000 {
+            for hot in 0..10 {
+                insert(&mut cache, hot);
+            }
+            insert(&mut cache, key);
+        }
+
+        for hot in 0..10 {
+            assert!(cache.get(&hot).is_some(), "{}", hot);
+        }
+        assert_eq!(cache.len(), capacity);
+    }
+
+    #[test]
+    fn evict_where() {
+        let mut cache = Cache::new(10);
+
+        for i in 0..5 {
+            cache.insert(i, i);
+        }
+
+        assert_eq!(cache.evict_where(|val| *val >= 3), Some((3, 3)));
+        assert_eq!(cache.evict_where(|val| *val >= 3), Some((4, 4)));
+        assert_eq!(cache.evict_where(|val| *val >= 3), None);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn ghost_readmission() {
+        let mut cache = Cache::new(10);
+
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        assert_eq!(cache.evict(), Some((1, 1)));
+
+        // Back while it's a ghost, it skips the small queue.
+        cache.insert(1, 1);
+        assert_eq!(cache.main, [1]);
+        assert_eq!(cache.evict(), Some((2, 2)));
+        assert_eq!(cache.evict(), Some((1, 1)));
+        assert!(cache.evict().is_none());
+    }
 }
@@ -0,0 +1,74 @@
+//! Chains of pages holding what a commit writes out whole, the page map of
+//! shadow paging and the deltas of patched pages.
+//!
+//! Every page starts with the id of the next one, zero on the last. A chain
+//! is written to pages that were free before the commit, the previous
+//! header stays intact until the new one is written and the pages of the
+//! chain it replaces are freed by the commit.
+
+use std::convert::TryInto;
+
+use super::{LogicalPageId, PageCache, PhysicalPageId, PAGE_CAPACITY};
+use crate::{Error, Result};
+
+/// Bytes of a chain page after the link to the next one.
+pub const CHAIN_PAGE_LEN: usize = PAGE_CAPACITY - 8;
+
+/// Write `contents`, at most `CHAIN_PAGE_LEN` bytes each, to `pages` in
+/// order.
+pub fn write(pager: &mut PageCache, pages: &[PhysicalPageId], contents: &[Vec<u8>]) -> Result<()> {
+    debug_assert_eq!(pages.len(), contents.len());
+
+    for (idx, content) in contents.iter().enumerate() {
+        let mut page = pager.new_page_buffer()?;
+        page.init();
+
+        let next = pages.get(idx + 1).map_or(0, |page_id| page_id.0 as u64);
+        let (link, rest) = page.buf_mut().split_at_mut(8);
+        link.copy_from_slice(&next.to_le_bytes());
+        rest[..content.len()].copy_from_slice(content);
+
+        pager.update_page(LogicalPageId(pages[idx].0), page)?;
+    }
+
+    Ok(())
+}
+
+/// Read the chain written from `first` on, passing the contents of each of
+/// its pages to `read`. Returns the pages of the chain.
+///
+/// A chain that doesn't end is `Error::Corruption` with `reason`.
+pub fn read(
+    pager: &mut PageCache,
+    first: PhysicalPageId,
+    reason: &'static str,
+    mut read: impl FnMut(PhysicalPageId, &[u8]) -> Result<()>,
+) -> Result<Vec<PhysicalPageId>> {
+    let mut pages = Vec::new();
+    let mut next = first;
+
+    loop {
+        // Longer than the file, the chain loops back on itself.
+        if pages.len() >= pager.next_page_id {
+            return Err(Error::Corruption {
+                page: LogicalPageId(next.0),
+                reason,
+            });
+        }
+
+        let page = pager.read_page(next)?;
+        pages.push(next);
+
+        let (link, content) = page.buf().split_at(8);
+        read(next, content)?;
+
+        match read_word(link) {
+            0 => return Ok(pages),
+            page_id => next = PhysicalPageId(page_id as usize),
+        }
+    }
+}
+
+pub fn read_word(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+/// When a durable commit syncs the file.
+///
+/// Syncing is by far the most expensive part of a commit. Instead of every
+/// transaction syncing on its own, the policies other than `EveryCommit`
+/// fold the commits made in between into the next sync. A commit that
+/// isn't synced yet is visible but lost on a crash, until
+/// `Db::wait_for_durable` or `Db::flush` is called for it or a later
+/// commit syncs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommitPolicy {
+    /// Sync on every commit, the default.
+    #[default]
+    EveryCommit,
+    /// Sync on the first commit once this long has passed since the last
+    /// sync. Nothing syncs in between commits, there is no timer.
+    Interval(Duration),
+    /// Sync on the first commit once this many bytes have been written
+    /// since the last sync.
+    Bytes(u64),
+}
+
+/// Tracks what a `CommitPolicy` needs to decide when to sync.
+pub struct GroupCommit {
+    pub policy: CommitPolicy,
+    last_sync: Instant,
+}
+
+impl GroupCommit {
+    pub fn new(policy: CommitPolicy) -> Self {
+        Self {
+            policy,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// Whether a commit should sync, with `unsynced_bytes` written since the
+    /// last sync.
+    pub fn sync_due(&self, unsynced_bytes: u64) -> bool {
+        match self.policy {
+            CommitPolicy::EveryCommit => true,
+            CommitPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            CommitPolicy::Bytes(bytes) => unsynced_bytes >= bytes,
+        }
+    }
+
+    pub fn synced(&mut self) {
+        self.last_sync = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_due() {
+        assert!(GroupCommit::new(CommitPolicy::EveryCommit).sync_due(0));
+
+        let bytes = GroupCommit::new(CommitPolicy::Bytes(100));
+        assert!(!bytes.sync_due(99));
+        assert!(bytes.sync_due(100));
+
+        let mut interval = GroupCommit::new(CommitPolicy::Interval(Duration::from_millis(20)));
+        assert!(!interval.sync_due(u64::MAX));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(interval.sync_due(0));
+        interval.synced();
+        assert!(!interval.sync_due(0));
+    }
+}
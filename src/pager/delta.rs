@@ -0,0 +1,145 @@
+//! Small updates of committed pages kept as deltas instead of copies of the
+//! page, see `DWALPager::patch_page`.
+//!
+//! A delta overwrites a few bytes of a page at a version, reads of later
+//! versions apply it on top of the page. Deltas are folded into the page
+//! once they are older than every version that can still be read and than
+//! the last synced commit. Applying one again after a crash writes the same
+//! bytes, so a page that was folded before the crash reads the same. Every
+//! commit that changes them writes all of the deltas out to a chain of
+//! pages the header points to.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+};
+
+use super::{
+    chain::{read_word, CHAIN_PAGE_LEN},
+    LogicalPageId, PhysicalPageId, Version, PAGE_CAPACITY,
+};
+use crate::{Error, Result};
+
+/// Longest update kept as a delta, longer ones copy the page.
+pub const MAX_DELTA_LEN: usize = 128;
+/// Most deltas a page collects on top of the page they apply to, the next
+/// update copies the page with them applied instead. This bounds the work
+/// of reading it.
+pub const MAX_PAGE_DELTAS: usize = 32;
+
+/// Bytes of a delta on a chain page before the bytes it writes, the page,
+/// the version, the offset and the length.
+const DELTA_HEADER_LEN: usize = 8 + 8 + 2 + 2;
+
+/// The deltas of each page, by the version that made them in the order they
+/// were made.
+pub type Deltas = HashMap<LogicalPageId, BTreeMap<Version, Vec<Delta>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    /// Where `bytes` go in the contents of the page.
+    offset: u16,
+    bytes: Vec<u8>,
+}
+
+impl Delta {
+    pub fn new(offset: usize, bytes: &[u8]) -> Self {
+        debug_assert!(bytes.len() <= MAX_DELTA_LEN && offset + bytes.len() <= PAGE_CAPACITY);
+
+        Self {
+            offset: offset as u16,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    /// Write the bytes over the contents of a page.
+    pub fn apply(&self, buf: &mut [u8]) {
+        let offset = self.offset as usize;
+        buf[offset..offset + self.bytes.len()].copy_from_slice(&self.bytes);
+    }
+}
+
+/// The contents of the chain pages `deltas` are written to, none if there
+/// are no deltas.
+///
+/// Pages are written in order, the deltas of a page and version in the
+/// order they were made.
+pub fn encode(deltas: &Deltas) -> Vec<Vec<u8>> {
+    let mut page_ids = deltas.keys().copied().collect::<Vec<_>>();
+    page_ids.sort_unstable_by_key(|page_id| page_id.0);
+
+    let mut pages = Vec::new();
+    let mut content = Vec::new();
+    let mut count = 0u64;
+
+    for page_id in page_ids {
+        for (version, deltas) in &deltas[&page_id] {
+            for delta in deltas {
+                if 8 + content.len() + DELTA_HEADER_LEN + delta.bytes.len() > CHAIN_PAGE_LEN {
+                    pages.push(finish_page(count, &mut content));
+                    count = 0;
+                }
+
+                content.extend_from_slice(&(page_id.0 as u64).to_le_bytes());
+                content.extend_from_slice(&version.0.to_le_bytes());
+                content.extend_from_slice(&delta.offset.to_le_bytes());
+                content.extend_from_slice(&(delta.bytes.len() as u16).to_le_bytes());
+                content.extend_from_slice(&delta.bytes);
+                count += 1;
+            }
+        }
+    }
+
+    if count > 0 {
+        pages.push(finish_page(count, &mut content));
+    }
+
+    pages
+}
+
+/// A chain page holding the `count` deltas in `content`, which is left
+/// empty for the next one.
+fn finish_page(count: u64, content: &mut Vec<u8>) -> Vec<u8> {
+    let mut page = count.to_le_bytes().to_vec();
+    page.append(content);
+    page
+}
+
+/// Add the deltas on the chain page `page_id` holding `content` to
+/// `deltas`.
+pub fn decode(page_id: PhysicalPageId, content: &[u8], deltas: &mut Deltas) -> Result<()> {
+    let corrupt = || Error::Corruption {
+        page: LogicalPageId(page_id.0),
+        reason: "malformed page deltas",
+    };
+
+    let count = read_word(content);
+    let mut rest = &content[8..];
+
+    for _ in 0..count {
+        if rest.len() < DELTA_HEADER_LEN {
+            return Err(corrupt());
+        }
+
+        let (header, tail) = rest.split_at(DELTA_HEADER_LEN);
+        let offset = u16::from_le_bytes(header[16..18].try_into().unwrap());
+        let len = u16::from_le_bytes(header[18..].try_into().unwrap()) as usize;
+        if len > MAX_DELTA_LEN || len > tail.len() || offset as usize + len > PAGE_CAPACITY {
+            return Err(corrupt());
+        }
+
+        deltas
+            .entry(LogicalPageId(read_word(header) as usize))
+            .or_default()
+            .entry(Version(read_word(&header[8..])))
+            .or_default()
+            .push(Delta {
+                offset,
+                bytes: tail[..len].to_vec(),
+            });
+
+        rest = &tail[len..];
+    }
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use crate::Result;
+
+use super::File;
+
+impl File for std::fs::File {
+    fn len(&self) -> Result<usize> {
+        Ok(self.metadata()?.len() as usize)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        Ok(std::os::unix::fs::FileExt::read_at(self, buf, offset)?)
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)?;
+        Ok(buf.len())
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        Ok(std::os::windows::fs::FileExt::seek_read(self, buf, offset)?)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        Ok(std::os::windows::fs::FileExt::seek_write(
+            self, buf, offset,
+        )?)
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(std::fs::File::sync_data(self)?)
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        Ok(std::fs::File::set_len(self, len)?)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reflink_to(&self, dest: &std::fs::File) -> Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        // Shares the extents of the file on filesystems like btrfs and XFS,
+        // others reject it and the file is copied.
+        let res = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, self.as_raw_fd()) };
+        if res == 0 {
+            return Ok(true);
+        }
+
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV | libc::EINVAL | libc::ENOSYS) => {
+                Ok(false)
+            }
+            _ => Err(err.into()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn try_lock(&self) -> Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        // Held by the open file, it is released when the file is closed.
+        let res = unsafe { libc::flock(self.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if res == 0 {
+            return Ok(true);
+        }
+
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Ok(false),
+            _ => Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Page work that has to wait until no reader can observe the version that
+//! made it necessary.
+//!
+//! Jobs are queued in version order in a single `FIFOQueue` and run from the
+//! front once `oldest_version` has caught up with them, so new kinds of
+//! deferred work only need a `Job` variant and a handler in the pager.
+
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use super::{queue::FIFOQueue, LogicalPageId, PageCache, PhysicalPageId, QueueState, Version};
+use crate::{Error, Result};
+
+/// A unit of deferred page work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Job {
+    /// `page_id` was freed, it goes to the free list along with any pages it
+    /// is still remapped to.
+    FreePage { page_id: LogicalPageId },
+    /// `original_page_id` was copied to `new_page_id` by `atomic_update`,
+    /// the copy is moved back over the original.
+    RemapCleanup {
+        original_page_id: LogicalPageId,
+        new_page_id: LogicalPageId,
+    },
+}
+
+const FREE_PAGE: u64 = 0;
+const REMAP_CLEANUP: u64 = 1;
+
+/// A `Job` as stored in the queue, `other_page_id` is unused by jobs that
+/// only refer to one page.
+#[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Debug)]
+#[repr(C)]
+struct JobRecord {
+    version: Version,
+    kind: u64,
+    page_id: LogicalPageId,
+    other_page_id: LogicalPageId,
+}
+
+pub struct JobQueue {
+    queue: FIFOQueue<JobRecord>,
+    queue_id: u8,
+    /// Jobs queued since the last `flush`, only added to the queue then so
+    /// that a rollback can drop them.
+    pending: Vec<(Version, Job)>,
+}
+
+impl JobQueue {
+    pub fn create(pager: &mut PageCache, queue_id: u8) -> Result<Self> {
+        Ok(Self {
+            queue: FIFOQueue::create(pager, queue_id)?,
+            queue_id,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Create an empty queue on the free page `page_id`, see
+    /// `FIFOQueue::create_at`.
+    pub fn create_at(pager: &mut PageCache, queue_id: u8, page_id: PhysicalPageId) -> Result<Self> {
+        Ok(Self {
+            queue: FIFOQueue::create_at(pager, queue_id, page_id)?,
+            queue_id,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Re-open the queue persisted as `state`, see `FIFOQueue::recover`.
+    pub fn recover(pager: &mut PageCache, state: &QueueState) -> Result<Self> {
+        Ok(Self {
+            queue: FIFOQueue::recover(pager, state)?,
+            queue_id: state.queue_id(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Queue `job` to run once `version` can no longer be observed.
+    pub fn push(&mut self, version: Version, job: Job) {
+        debug_assert!(self
+            .pending
+            .last()
+            .into_iter()
+            .all(|(last, _)| *last <= version));
+
+        self.pending.push((version, job));
+    }
+
+    /// Forget the jobs queued since the last `flush`.
+    pub fn discard_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Add the pending jobs to the queue, making them visible to `pop_ready`.
+    pub fn flush(&mut self, pager: &mut PageCache) -> Result<()> {
+        for (version, job) in self.pending.drain(..) {
+            let record = match job {
+                Job::FreePage { page_id } => JobRecord {
+                    version,
+                    kind: FREE_PAGE,
+                    page_id,
+                    other_page_id: LogicalPageId(0),
+                },
+                Job::RemapCleanup {
+                    original_page_id,
+                    new_page_id,
+                } => JobRecord {
+                    version,
+                    kind: REMAP_CLEANUP,
+                    page_id: original_page_id,
+                    other_page_id: new_page_id,
+                },
+            };
+
+            self.queue.push_back(pager, record)?;
+        }
+
+        self.queue.flush(pager)?;
+        // Jobs are run by the commit that flushes them, the header it writes
+        // records the new head and tail together.
+        self.queue.advance_barrier();
+
+        Ok(())
+    }
+
+    /// Pop the next job if it was queued at or before `oldest_version`.
+    pub fn pop_ready(
+        &mut self,
+        pager: &mut PageCache,
+        oldest_version: Version,
+    ) -> Result<Option<(Version, Job)>> {
+        match self.queue.peek(pager)? {
+            Some(record) if record.version <= oldest_version => {}
+            _ => return Ok(None),
+        }
+
+        match self.queue.pop(pager)? {
+            Some(record) => self.decode(record).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Call `f` with every queued job in order, ready or not, without
+    /// popping them.
+    pub fn scan(&self, pager: &mut PageCache, mut f: impl FnMut(Version, Job)) -> Result<()> {
+        let mut res = Ok(());
+        self.queue.scan(pager, |record| {
+            if res.is_ok() {
+                res = self.decode(record).map(|(version, job)| f(version, job));
+            }
+        })?;
+
+        res
+    }
+
+    fn decode(&self, record: JobRecord) -> Result<(Version, Job)> {
+        let job = match record.kind {
+            FREE_PAGE => Job::FreePage {
+                page_id: record.page_id,
+            },
+            REMAP_CLEANUP => Job::RemapCleanup {
+                original_page_id: record.page_id,
+                new_page_id: record.other_page_id,
+            },
+            _ => return Err(Error::QueueStateCorrupted(self.queue_id)),
+        };
+
+        Ok((record.version, job))
+    }
+
+    pub fn state(&self) -> QueueState {
+        self.queue.state()
+    }
+
+    /// Number of jobs queued or pending.
+    pub fn len(&self) -> u64 {
+        self.pending.len() as u64 + self.queue.len()
+    }
+
+    /// No jobs are queued or pending.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.queue.is_empty()
+    }
+
+    pub fn tail_page(&self) -> PhysicalPageId {
+        self.queue.tail_page()
+    }
+
+    /// Take the queue pages that have been read to the end, see
+    /// `FIFOQueue::take_released`.
+    pub fn take_released(&mut self) -> Vec<PhysicalPageId> {
+        self.queue.take_released()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, PAGE_SIZE};
+
+    #[test]
+    fn pop_in_version_order() {
+        let mut pager = PageCache::new(
+            Box::new(MemoryFile::default()),
+            BytesMut::zeroed(PAGE_SIZE),
+            false,
+        );
+
+        let mut jobs = JobQueue::create(&mut pager, 0).unwrap();
+
+        let free = Job::FreePage {
+            page_id: LogicalPageId(3),
+        };
+        let remap = Job::RemapCleanup {
+            original_page_id: LogicalPageId(4),
+            new_page_id: LogicalPageId(5),
+        };
+
+        jobs.push(Version(1), free);
+        jobs.push(Version(2), remap);
+
+        // Nothing is visible before a flush, and discarded jobs never are.
+        assert_eq!(jobs.pop_ready(&mut pager, Version(9)).unwrap(), None);
+        jobs.flush(&mut pager).unwrap();
+        jobs.push(Version(3), free);
+        jobs.discard_pending();
+        jobs.flush(&mut pager).unwrap();
+
+        assert_eq!(jobs.pop_ready(&mut pager, Version(0)).unwrap(), None);
+        assert_eq!(
+            jobs.pop_ready(&mut pager, Version(1)).unwrap(),
+            Some((Version(1), free))
+        );
+        assert_eq!(jobs.pop_ready(&mut pager, Version(1)).unwrap(), None);
+        assert_eq!(
+            jobs.pop_ready(&mut pager, Version(9)).unwrap(),
+            Some((Version(2), remap))
+        );
+        assert_eq!(jobs.pop_ready(&mut pager, Version(9)).unwrap(), None);
+        assert_eq!(jobs.state().num_entries(), 0);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    cmp,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use crate::Result;
+
+use super::File;
+
+/// A `File` kept entirely in memory.
+///
+/// Clones share the same bytes, so a database can be reopened from a clone
+/// of the file it was written to as long as one of them is alive.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFile {
+    data: Arc<RwLock<Vec<u8>>>,
+}
+
+impl MemoryFile {
+    pub fn new() -> Self {
+        MemoryFile::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        MemoryFile {
+            data: Arc::new(RwLock::new(Vec::with_capacity(capacity))),
+        }
+    }
+
+    /// A copy of the file's contents.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl File for MemoryFile {
+    fn len(&self) -> Result<usize> {
+        Ok(self
+            .data
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let data = self.data.read().unwrap_or_else(PoisonError::into_inner);
+        let offset = offset as usize;
+
+        // Reading at or past the end reads nothing, like a file.
+        if offset >= data.len() {
+            return Ok(0);
+        }
+
+        let len = cmp::min(data.len() - offset, buf.len());
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+
+        Ok(len)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let mut data = self.data.write().unwrap_or_else(PoisonError::into_inner);
+        let offset = offset as usize;
+
+        if offset + buf.len() > data.len() {
+            data.resize(offset + buf.len(), 0);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        self.data
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .resize(len as usize, 0);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_between_clones() {
+        let file = MemoryFile::new();
+        let clone = file.clone();
+
+        file.write_at(b"world", 6).unwrap();
+        clone.write_at(b"hello", 0).unwrap();
+        assert_eq!(file.to_vec(), b"hello\0world");
+
+        let mut buf = [0; 8];
+        assert_eq!(clone.read_at(&mut buf, 6).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(clone.read_at(&mut buf, 11).unwrap(), 0);
+        assert_eq!(file.len().unwrap(), 11);
+
+        // Usable from other threads.
+        std::thread::spawn(move || clone.write_at(b"!", 11).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(file.to_vec(), b"hello\0world!");
+    }
+}
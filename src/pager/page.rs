@@ -1,14 +1,17 @@
-use std::{ptr::NonNull, rc::Rc};
+use std::{ptr::NonNull, sync::Arc};
 
-use zerocopy::{FromBytes, FromZeros, IntoBytes, KnownLayout};
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 
-use crate::pager::VERSION;
+use crate::{pager::VERSION, Error};
 
-use super::PAGE_SIZE;
+use super::{crc::crc32, PAGE_SIZE};
 
 const PAGE_HEADER_SIZE: usize = std::mem::size_of::<PageHeader>();
 
-#[derive(FromBytes, IntoBytes, KnownLayout, Debug, Clone)]
+/// Bytes available to users of a page after the page header.
+pub const PAGE_CAPACITY: usize = PAGE_SIZE - PAGE_HEADER_SIZE;
+
+#[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Debug, Clone)]
 #[repr(C)]
 pub struct PageHeader {
     checksum: u32,
@@ -31,24 +34,38 @@ pub struct PageBufMut {
 
 #[derive(Debug, Clone)]
 pub struct PageBuf {
-    ptr: Rc<NonNull<u8>>,
+    ptr: Arc<NonNull<u8>>,
 }
 
+// SAFETY: A `PageBufMut` is the only handle to its page, like a `Box`.
+unsafe impl Send for PageBufMut {}
+
+// SAFETY: The page behind a `PageBuf` is never written to again, it is only
+// handed back out as a `PageBufMut` once the last reference is taken.
+unsafe impl Send for PageBuf {}
+unsafe impl Sync for PageBuf {}
+
 impl PageBufMut {
     pub(super) fn new(ptr: NonNull<u8>) -> Self {
         PageBufMut { ptr }
     }
 
     pub fn init(&mut self) {
-        let buf = self.buf_mut();
-
-        let (header, data) = PageHeader::mut_from_prefix(&mut buf[..]).unwrap();
+        let header = self.header_mut();
 
         header.version = VERSION as u8;
         header.page_type = 0;
         header.checksum = 0;
 
-        data.zero();
+        self.buf_mut().zero();
+    }
+
+    /// Copy in a whole page, header included, that passed `verify_export`.
+    pub fn import(&mut self, page: &[u8]) {
+        debug_assert!(verify_export(page));
+
+        let buf = unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), PAGE_SIZE) };
+        buf.copy_from_slice(page);
     }
 
     pub fn header_mut(&mut self) -> &mut PageHeader {
@@ -60,7 +77,12 @@ impl PageBufMut {
     }
 
     pub fn get_usable_size(&self) -> usize {
-        PAGE_SIZE - size_of::<PageHeader>()
+        PAGE_CAPACITY
+    }
+
+    /// Zero the whole page, header included.
+    pub fn zero(&mut self) {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), PAGE_SIZE) }.fill(0);
     }
 
     pub fn buf(&self) -> &[u8] {
@@ -85,9 +107,23 @@ impl PageBufMut {
         }
     }
 
+    /// Copy in a whole page, header included.
+    pub(super) fn copy_page(&mut self, page: &PageBuf) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(page.ptr.as_ptr(), self.ptr.as_ptr(), PAGE_SIZE);
+        }
+    }
+
+    /// Give up the buffer, to return it to the arena it came from.
+    pub(super) fn into_raw(self) -> NonNull<u8> {
+        self.ptr
+    }
+
+    // `PageBuf` is `Send` and `Sync` itself, the pointer never leaves it.
+    #[allow(clippy::arc_with_non_send_sync)]
     pub(super) fn freeze(self) -> PageBuf {
         PageBuf {
-            ptr: Rc::new(self.ptr),
+            ptr: Arc::new(self.ptr),
         }
     }
 }
@@ -104,14 +140,111 @@ impl PageBuf {
         }
     }
 
+    /// The whole page, header included, with the checksum filled in so it
+    /// can be checked by `verify_export` on the way back in.
+    pub fn export(&self) -> Vec<u8> {
+        let mut page =
+            unsafe { std::slice::from_raw_parts(self.ptr.as_ptr() as *const u8, PAGE_SIZE) }
+                .to_vec();
+
+        // The copy isn't necessarily aligned for `PageHeader`.
+        let (mut header, data) = PageHeader::read_from_prefix(&page).unwrap();
+        header.checksum = crc32(data);
+        header.write_to_prefix(&mut page[..]).unwrap();
+
+        page
+    }
+
+    /// Where the page is in memory, to tell which chunk of the arena it is
+    /// in.
+    pub(super) fn as_ptr(&self) -> NonNull<u8> {
+        *self.ptr
+    }
+
+    /// Number of references to this page, including this one.
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.ptr)
+    }
+
     pub fn try_take(self) -> Result<PageBufMut, PageBuf> {
-        match Rc::try_unwrap(self.ptr) {
+        match Arc::try_unwrap(self.ptr) {
             Ok(ptr) => Ok(PageBufMut { ptr }),
             Err(ptr) => Err(PageBuf { ptr }),
         }
     }
 }
 
+/// Check that `page` is a whole page produced by `PageBuf::export`, with a
+/// known version and page type and a matching checksum.
+pub fn verify_export(page: &[u8]) -> bool {
+    if page.len() != PAGE_SIZE {
+        return false;
+    }
+
+    let (header, data) = PageHeader::read_from_prefix(page).unwrap();
+
+    let known_type = [PageType::Queue, PageType::Btree]
+        .iter()
+        .any(|page_type| header.page_type == page_type.clone() as u8);
+
+    header.version == VERSION as u8 && known_type && header.checksum == crc32(data)
+}
+
+/// Encodes straight into the payload of a `PageBufMut`, checking the space
+/// left on every write so encoders never need an intermediate `Vec<u8>`.
+pub struct PageWriter<'a> {
+    buf: &'a mut [u8],
+    offset: usize,
+}
+
+impl<'a> PageWriter<'a> {
+    pub fn new(page: &'a mut PageBufMut) -> Self {
+        PageWriter {
+            buf: page.buf_mut(),
+            offset: 0,
+        }
+    }
+
+    /// Number of bytes written so far.
+    pub fn position(&self) -> usize {
+        self.offset
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    /// Reserve `len` bytes and return them to be filled in, this is useful
+    /// for headers that are only known once the rest has been written.
+    pub fn reserve(&mut self, len: usize) -> crate::Result<&mut [u8]> {
+        if len > self.remaining() {
+            return Err(Error::PageFull);
+        }
+
+        let start = self.offset;
+        self.offset += len;
+
+        Ok(&mut self.buf[start..self.offset])
+    }
+
+    pub fn put_slice(&mut self, src: &[u8]) -> crate::Result<()> {
+        self.reserve(src.len())?.copy_from_slice(src);
+        Ok(())
+    }
+
+    pub fn put<T: IntoBytes + Immutable>(&mut self, value: &T) -> crate::Result<()> {
+        self.put_slice(value.as_bytes())
+    }
+
+    pub fn put_u16(&mut self, value: u16) -> crate::Result<()> {
+        self.put_slice(&value.to_le_bytes())
+    }
+
+    pub fn put_u64(&mut self, value: u64) -> crate::Result<()> {
+        self.put_slice(&value.to_le_bytes())
+    }
+}
+
 // #[derive(FromBytes, Debug)]
 // #[repr(C)]
 // pub struct PageView<'a, T> {
@@ -140,3 +273,64 @@ impl PageBuf {
 //     next_page_id: u64,
 //     end_offset: u64,
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::System;
+
+    use super::*;
+    use crate::pager::arena::Arena;
+
+    #[test]
+    fn page_writer() {
+        let arena = Arena::new(System, PAGE_SIZE, 1);
+        let mut page = PageBufMut::new(arena.alloc().unwrap());
+        page.init();
+
+        let usable = page.get_usable_size();
+
+        let mut writer = PageWriter::new(&mut page);
+        writer.put_u16(0xbeef).unwrap();
+        writer.put_slice(b"hello").unwrap();
+
+        let len = writer.reserve(2).unwrap();
+        len.copy_from_slice(&7u16.to_le_bytes());
+
+        assert_eq!(writer.position(), 9);
+        assert_eq!(writer.remaining(), usable - 9);
+
+        // Writes that don't fit fail and leave the writer untouched.
+        assert!(matches!(
+            writer.put_slice(&vec![0; usable]),
+            Err(Error::PageFull)
+        ));
+        assert_eq!(writer.position(), 9);
+
+        assert_eq!(&page.buf()[..9], b"\xef\xbehello\x07\x00");
+    }
+
+    #[test]
+    fn export_import() {
+        let arena = Arena::new(System, PAGE_SIZE, 2);
+        let mut page = PageBufMut::new(arena.alloc().unwrap());
+        page.init();
+        page.buf_mut()[..5].copy_from_slice(b"hello");
+
+        let exported = page.freeze().export();
+        assert!(verify_export(&exported));
+
+        let mut copy = PageBufMut::new(arena.alloc().unwrap());
+        copy.import(&exported);
+        assert_eq!(&copy.buf()[..5], b"hello");
+
+        let mut corrupted = exported.clone();
+        corrupted[PAGE_HEADER_SIZE + 1] ^= 1;
+        assert!(!verify_export(&corrupted));
+
+        let mut unknown_type = exported.clone();
+        unknown_type[5] = 7;
+        assert!(!verify_export(&unknown_type));
+
+        assert!(!verify_export(&exported[..PAGE_SIZE - 1]));
+    }
+}
@@ -0,0 +1,70 @@
+//! The page table of a file that uses shadow paging, see
+//! `DWALPager::use_shadow_paging`.
+//!
+//! Otherwise every remap has a cleanup job queued, so the job queue doubles
+//! as a log of the page table. With shadow paging remaps stay for good, so
+//! every commit that changes the page table writes all of it out to a chain
+//! of pages the header points to.
+
+use super::{
+    chain::{read_word, CHAIN_PAGE_LEN},
+    LogicalPageId, PageTable, PhysicalPageId, Version,
+};
+use crate::{Error, Result};
+
+/// Words of an entry, the logical page, the version it was remapped at and
+/// the page it was remapped to.
+const ENTRY_WORDS: usize = 3;
+/// Entries on a page, after their count.
+const ENTRIES_PER_PAGE: usize = (CHAIN_PAGE_LEN / 8 - 1) / ENTRY_WORDS;
+
+/// The contents of the chain pages `page_table` is written to, none for an
+/// empty one.
+///
+/// Entries are written in order, the same table is always written the
+/// same.
+pub fn encode(page_table: &PageTable) -> Vec<Vec<u8>> {
+    let mut entries = page_table
+        .iter()
+        .flat_map(|(page_id, versions)| {
+            versions
+                .iter()
+                .map(move |(version, physical)| [page_id.0 as u64, version.0, physical.0 as u64])
+        })
+        .collect::<Vec<_>>();
+    entries.sort_unstable();
+
+    entries
+        .chunks(ENTRIES_PER_PAGE)
+        .map(|chunk| {
+            std::iter::once(chunk.len() as u64)
+                .chain(chunk.iter().flatten().copied())
+                .flat_map(u64::to_le_bytes)
+                .collect()
+        })
+        .collect()
+}
+
+/// Add the entries on the chain page `page_id` holding `content` to
+/// `page_table`.
+pub fn decode(page_id: PhysicalPageId, content: &[u8], page_table: &mut PageTable) -> Result<()> {
+    let count = read_word(content) as usize;
+    if count > ENTRIES_PER_PAGE {
+        return Err(Error::Corruption {
+            page: LogicalPageId(page_id.0),
+            reason: "malformed page map",
+        });
+    }
+
+    for entry in content[8..].chunks_exact(ENTRY_WORDS * 8).take(count) {
+        page_table
+            .entry(LogicalPageId(read_word(entry) as usize))
+            .or_default()
+            .insert(
+                Version(read_word(&entry[8..])),
+                PhysicalPageId(read_word(&entry[16..]) as usize),
+            );
+    }
+
+    Ok(())
+}
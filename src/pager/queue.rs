@@ -1,23 +1,47 @@
 mod cursor;
 
-use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+use std::convert::TryFrom;
 
-use super::{PageCache, PhysicalPageId};
-use crate::Result;
+use zerocopy::{
+    little_endian::{U32, U64},
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+};
+
+use super::{crc::crc32, PageCache, PhysicalPageId};
+use crate::{Error, Result};
 
 use cursor::{ReadCursor, WriteCursor};
 
 pub struct FIFOQueue<T> {
+    queue_id: u8,
+    num_entries: u64,
+    /// Entries pushed since the last `flush`.
+    unflushed_entries: u64,
     head_reader: ReadCursor<T>,
     head_writer: WriteCursor<T>,
     tail_writer: WriteCursor<T>,
+    /// Position up to which the tail has been flushed.
+    flushed: (PhysicalPageId, usize),
+    /// Position `pop` stops at, the tail as of the last `advance_barrier`.
+    /// Entries flushed by a commit are kept from being popped and acted upon
+    /// until it is complete.
+    barrier: (PhysicalPageId, usize),
 }
 
 impl<T: IntoBytes + FromBytes + KnownLayout + Immutable> FIFOQueue<T> {
-    pub fn create(pager: &mut PageCache, _queue_id: u8) -> Result<Self> {
+    pub fn create(pager: &mut PageCache, queue_id: u8) -> Result<Self> {
         let init_page_id = pager.new_last_page_id();
+        Self::create_at(pager, queue_id, init_page_id)
+    }
 
-        let head_reader = ReadCursor::init(pager, init_page_id, init_page_id)?;
+    /// Create an empty queue on the free page `init_page_id`, rather than
+    /// a new one at the end of the file.
+    pub fn create_at(
+        pager: &mut PageCache,
+        queue_id: u8,
+        init_page_id: PhysicalPageId,
+    ) -> Result<Self> {
+        let head_reader = ReadCursor::new(init_page_id, 0);
         let tail_writer = WriteCursor::init(pager, init_page_id, PhysicalPageId::INVALID_ID)?;
         let head_writer = WriteCursor::init(
             pager,
@@ -26,41 +50,385 @@ impl<T: IntoBytes + FromBytes + KnownLayout + Immutable> FIFOQueue<T> {
         )?;
 
         Ok(Self {
+            queue_id,
+            num_entries: 0,
+            unflushed_entries: 0,
             head_reader,
             head_writer,
             tail_writer,
+            flushed: (init_page_id, 0),
+            barrier: (init_page_id, 0),
         })
     }
 
-    pub fn recover(_page_id: PhysicalPageId) -> Result<Self> {
-        todo!()
+    /// Re-open a queue at the boundaries `state` persisted, the entries
+    /// that were flushed but not popped yet are popped again.
+    pub fn recover(pager: &mut PageCache, state: &QueueState) -> Result<Self> {
+        let queue_id = state.queue_id();
+        let corrupted = |_| Error::QueueStateCorrupted(queue_id);
+
+        let head_page = PhysicalPageId(usize::try_from(state.head_page.get()).map_err(corrupted)?);
+        let head_offset = usize::try_from(state.head_offset.get()).map_err(corrupted)?;
+        let tail_page = PhysicalPageId(usize::try_from(state.tail_page.get()).map_err(corrupted)?);
+        let tail_offset = usize::try_from(state.tail_offset.get()).map_err(corrupted)?;
+
+        let tail_writer =
+            WriteCursor::recover(pager, tail_page, tail_offset, PhysicalPageId::INVALID_ID)?
+                .ok_or(Error::QueueStateCorrupted(queue_id))?;
+        let head_writer = WriteCursor::init(
+            pager,
+            PhysicalPageId::INVALID_ID,
+            PhysicalPageId::INVALID_ID,
+        )?;
+
+        Ok(Self {
+            queue_id,
+            num_entries: state.num_entries(),
+            unflushed_entries: 0,
+            head_reader: ReadCursor::new(head_page, head_offset),
+            head_writer,
+            tail_writer,
+            flushed: (tail_page, tail_offset),
+            barrier: (tail_page, tail_offset),
+        })
     }
 
+    /// Push `value` to be popped next, ahead of everything queued. Unlike
+    /// `push_back` it is visible to `pop` right away.
     pub fn push_front(&mut self, pager: &mut PageCache, value: T) -> Result<()> {
-        self.head_writer.write(pager, value)?;
+        let head = self
+            .head_writer
+            .write_front(pager, value, self.head_reader.position())?;
+        self.head_reader
+            .seek(head, self.head_reader.exhausted_len());
+        self.num_entries += 1;
         Ok(())
     }
 
     pub fn push_back(&mut self, pager: &mut PageCache, value: T) -> Result<()> {
         self.tail_writer.write(pager, value)?;
+        self.num_entries += 1;
+        self.unflushed_entries += 1;
         Ok(())
     }
 
-    /// Only return the records that have been flushed, and pops from the front of the queue.
+    /// Pop from the front of the queue, only returning the records before
+    /// the barrier.
     pub fn pop(&mut self, pager: &mut PageCache) -> Result<Option<T>> {
-        self.head_reader.pop(pager)
+        let item = self.head_reader.pop(pager, self.barrier)?;
+
+        if item.is_some() {
+            self.num_entries -= 1;
+        }
+
+        Ok(item)
+    }
+
+    /// Return the next item `pop` would return without removing it.
+    pub fn peek(&mut self, pager: &mut PageCache) -> Result<Option<T>> {
+        let position = self.head_reader.position();
+        let exhausted = self.head_reader.exhausted_len();
+        let item = self.head_reader.pop(pager, self.barrier)?;
+        self.head_reader.seek(position, exhausted);
+
+        Ok(item)
+    }
+
+    /// Call `f` with every entry `pop` would return, in order, without
+    /// popping them.
+    pub fn scan(&self, pager: &mut PageCache, mut f: impl FnMut(T)) -> Result<()> {
+        let (page_id, offset) = self.head_reader.position();
+        let mut reader = ReadCursor::new(page_id, offset);
+
+        while let Some(entry) = reader.pop(pager, self.barrier)? {
+            f(entry);
+        }
+
+        Ok(())
     }
 
-    pub fn state(&self) -> &QueueState {
-        // &self.state
-        todo!()
+    /// Take the pages `pop` has read to the end since the last call. They
+    /// can be reused once the state past them has been persisted.
+    pub fn take_released(&mut self) -> Vec<PhysicalPageId> {
+        self.head_reader.take_exhausted()
+    }
+
+    /// Number of entries, including the ones past the barrier.
+    pub fn len(&self) -> u64 {
+        self.num_entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_entries == 0
+    }
+
+    /// The page the next `push_back` writes to, unless it is full.
+    pub fn tail_page(&self) -> PhysicalPageId {
+        self.tail_writer.position().0
+    }
+
+    /// Write out the tail page so that everything pushed so far is part of
+    /// the `state`. It is only popped after the next `advance_barrier`.
+    pub fn flush(&mut self, pager: &mut PageCache) -> Result<()> {
+        self.tail_writer.flush(pager)?;
+
+        self.flushed = self.tail_writer.position();
+        self.unflushed_entries = 0;
+
+        Ok(())
+    }
+
+    /// Let `pop` return everything flushed so far.
+    pub fn advance_barrier(&mut self) {
+        self.barrier = self.flushed;
+    }
+
+    /// Snapshot the flushed queue boundaries so they can be persisted in the
+    /// header.
+    pub fn state(&self) -> QueueState {
+        let (head_page, head_offset) = self.head_reader.position();
+        let (tail_page, tail_offset) = self.flushed;
+
+        let mut state = QueueState {
+            queue_id: (self.queue_id as u64).into(),
+            head_page: (head_page.0 as u64).into(),
+            head_offset: (head_offset as u64).into(),
+            tail_page: (tail_page.0 as u64).into(),
+            tail_offset: (tail_offset as u64).into(),
+            num_entries: (self.num_entries - self.unflushed_entries).into(),
+            checksum: 0.into(),
+        };
+
+        state.checksum = state.compute_checksum().into();
+
+        state
     }
 }
 
-#[derive(Debug, FromBytes, IntoBytes, Immutable)]
+/// The persisted boundaries of a queue, stored in the header and protected
+/// by its own checksum so a torn or corrupt copy is detected on recovery.
+#[derive(Debug, Clone, FromBytes, IntoBytes, KnownLayout, Unaligned, Immutable)]
+#[repr(C)]
 pub struct QueueState {
     // Only really need u8 but saving space for other things
-    queue_id: u64,
-    head_page: PhysicalPageId,
-    tail_page: PhysicalPageId,
+    queue_id: U64,
+    head_page: U64,
+    head_offset: U64,
+    tail_page: U64,
+    tail_offset: U64,
+    num_entries: U64,
+    checksum: U32,
+}
+
+impl QueueState {
+    pub fn queue_id(&self) -> u8 {
+        self.queue_id.get() as u8
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries.get()
+    }
+
+    /// Returns true if the head and tail are within the first `page_count`
+    /// pages, or not set.
+    pub fn within(&self, page_count: u64) -> bool {
+        [self.head_page.get(), self.tail_page.get()]
+            .iter()
+            .all(|page_id| *page_id < page_count || *page_id == PhysicalPageId::INVALID_ID.0 as u64)
+    }
+
+    /// Returns true if the stored checksum matches the state.
+    pub fn is_valid(&self) -> bool {
+        self.checksum.get() == self.compute_checksum()
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        let bytes = self.as_bytes();
+        crc32(&bytes[..bytes.len() - size_of::<U32>()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, PAGE_SIZE};
+
+    #[test]
+    fn state_checksum() {
+        let mut pager = PageCache::new(
+            Box::new(MemoryFile::default()),
+            BytesMut::zeroed(PAGE_SIZE),
+            false,
+        );
+
+        let mut queue = FIFOQueue::<PhysicalPageId>::create(&mut pager, 3).unwrap();
+        queue.push_back(&mut pager, PhysicalPageId(42)).unwrap();
+        queue.flush(&mut pager).unwrap();
+
+        let mut state = queue.state();
+        assert!(state.is_valid());
+        assert_eq!(state.queue_id(), 3);
+        assert_eq!(state.num_entries(), 1);
+        assert!(state.within(pager.next_page_id as u64));
+        assert!(!state.within(1));
+
+        state.tail_offset += 1;
+        assert!(!state.is_valid());
+    }
+
+    #[test]
+    fn pop_only_flushed() {
+        let mut pager = PageCache::new(
+            Box::new(MemoryFile::default()),
+            BytesMut::zeroed(PAGE_SIZE),
+            false,
+        );
+
+        let mut queue = FIFOQueue::<PhysicalPageId>::create(&mut pager, 0).unwrap();
+
+        queue.push_back(&mut pager, PhysicalPageId(1)).unwrap();
+        assert_eq!(queue.pop(&mut pager).unwrap(), None);
+        assert_eq!(queue.state().num_entries(), 0);
+
+        queue.flush(&mut pager).unwrap();
+        queue.push_back(&mut pager, PhysicalPageId(2)).unwrap();
+        assert_eq!(queue.state().num_entries(), 1);
+
+        // Flushed but not past the barrier yet.
+        assert_eq!(queue.pop(&mut pager).unwrap(), None);
+        queue.advance_barrier();
+
+        assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(1)));
+        assert_eq!(queue.pop(&mut pager).unwrap(), None);
+
+        queue.flush(&mut pager).unwrap();
+        queue.advance_barrier();
+        assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(2)));
+        assert_eq!(queue.pop(&mut pager).unwrap(), None);
+    }
+
+    #[test]
+    fn push_pop_many_pages() {
+        let mut pager = PageCache::new(
+            Box::new(MemoryFile::default()),
+            BytesMut::zeroed(PAGE_SIZE),
+            false,
+        );
+
+        let mut queue = FIFOQueue::<PhysicalPageId>::create(&mut pager, 0).unwrap();
+
+        // Enough entries to span a few pages.
+        for i in 0..2000 {
+            queue.push_back(&mut pager, PhysicalPageId(i)).unwrap();
+        }
+        queue.flush(&mut pager).unwrap();
+        queue.advance_barrier();
+
+        for i in 0..1000 {
+            assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(i)));
+        }
+
+        // Interleave pushes with pops on a partially read queue.
+        for i in 2000..3000 {
+            queue.push_back(&mut pager, PhysicalPageId(i)).unwrap();
+        }
+        queue.flush(&mut pager).unwrap();
+        queue.advance_barrier();
+
+        for i in 1000..3000 {
+            assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(i)));
+        }
+        assert_eq!(queue.pop(&mut pager).unwrap(), None);
+        assert_eq!(queue.state().num_entries(), 0);
+    }
+
+    #[test]
+    fn recover_from_state() {
+        let file = MemoryFile::default();
+        let mut pager = PageCache::new(Box::new(file.clone()), BytesMut::zeroed(PAGE_SIZE), false);
+
+        let mut queue = FIFOQueue::<PhysicalPageId>::create(&mut pager, 1).unwrap();
+
+        for i in 0..1000 {
+            queue.push_back(&mut pager, PhysicalPageId(i)).unwrap();
+        }
+        queue.flush(&mut pager).unwrap();
+        queue.advance_barrier();
+        for i in 0..10 {
+            assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(i)));
+        }
+        // Not flushed, so lost like an uncommitted push.
+        queue.push_back(&mut pager, PhysicalPageId(5000)).unwrap();
+        pager.flush_dirty().unwrap();
+
+        let state = queue.state();
+        let next_page_id = pager.next_page_id;
+
+        let mut pager = PageCache::new(Box::new(file), BytesMut::zeroed(PAGE_SIZE), false);
+        pager.next_page_id = next_page_id;
+        let mut queue = FIFOQueue::<PhysicalPageId>::recover(&mut pager, &state).unwrap();
+        assert_eq!(queue.state().num_entries(), 990);
+
+        // Appends continue on the recovered tail page.
+        queue.push_back(&mut pager, PhysicalPageId(1000)).unwrap();
+        queue.flush(&mut pager).unwrap();
+        queue.advance_barrier();
+        assert_eq!(pager.next_page_id, next_page_id);
+
+        for i in 10..=1000 {
+            assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(i)));
+        }
+        assert_eq!(queue.pop(&mut pager).unwrap(), None);
+    }
+
+    #[test]
+    fn push_front() {
+        let file = MemoryFile::default();
+        let mut pager = PageCache::new(Box::new(file.clone()), BytesMut::zeroed(PAGE_SIZE), false);
+
+        let mut queue = FIFOQueue::<PhysicalPageId>::create(&mut pager, 0).unwrap();
+
+        for i in 0..10 {
+            queue.push_back(&mut pager, PhysicalPageId(i)).unwrap();
+        }
+        queue.flush(&mut pager).unwrap();
+        queue.advance_barrier();
+        assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(0)));
+
+        // Enough to need a second page in front, the last one pushed comes
+        // out first.
+        for i in 0..1000 {
+            queue
+                .push_front(&mut pager, PhysicalPageId(2000 + i))
+                .unwrap();
+        }
+        assert_eq!(queue.state().num_entries(), 1009);
+        assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(2999)));
+
+        // A popped slot isn't written over, the push goes to a new page.
+        let next_page_id = pager.next_page_id;
+        queue.push_front(&mut pager, PhysicalPageId(1)).unwrap();
+        assert_eq!(pager.next_page_id, next_page_id + 1);
+
+        pager.flush_dirty().unwrap();
+        let state = queue.state();
+
+        let mut pager = PageCache::new(Box::new(file), BytesMut::zeroed(PAGE_SIZE), false);
+        pager.next_page_id = next_page_id + 1;
+        let mut queue = FIFOQueue::<PhysicalPageId>::recover(&mut pager, &state).unwrap();
+
+        assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(1)));
+        for i in (0..999).rev() {
+            assert_eq!(
+                queue.pop(&mut pager).unwrap(),
+                Some(PhysicalPageId(2000 + i))
+            );
+        }
+        for i in 1..10 {
+            assert_eq!(queue.pop(&mut pager).unwrap(), Some(PhysicalPageId(i)));
+        }
+        assert_eq!(queue.pop(&mut pager).unwrap(), None);
+    }
 }
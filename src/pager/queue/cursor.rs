@@ -1,22 +1,17 @@
-use std::marker::PhantomData;
+use std::{convert::TryFrom, marker::PhantomData};
 
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
 use crate::{
     pager::{
-        page::{PageBuf, PageBufMut},
-        PageCache, PhysicalPageId, PAGE_SIZE,
+        page::{PageBuf, PageBufMut, PageHeader},
+        LogicalPageId, PageCache, PhysicalPageId, PAGE_SIZE,
     },
-    Result,
+    Error, Result,
 };
 
 // #[derive(IntoBytes, FromBytes, Immutable, KnownLayout, Debug, Unaligned)]
 // #[repr(C)]
-struct QueuePage<'a> {
-    header: &'a QueuePageHeader,
-    data: &'a [u8],
-}
-
 struct QueuePageMut<'a> {
     header: &'a mut QueuePageHeader,
     data: &'a mut [u8],
@@ -32,96 +27,115 @@ struct QueuePageHeader {
     _pad2: [u64; 2],
 }
 
+/// Space for items in a queue page, after both the page and queue headers.
+const ITEM_SPACE: usize = PAGE_SIZE - size_of::<PageHeader>() - size_of::<QueuePageHeader>();
+/// Largest item a queue holds, a page fits at least 16 of them.
+///
+/// Items don't have to divide `ITEM_SPACE` evenly, the rest of a page is
+/// left unused. Requiring them to would rule out the items queued on disk
+/// today: the job queue's 32 byte `JobRecord` doesn't divide the 4056 bytes
+/// of item space, and changing its size would change the file format.
+const MAX_ITEM_SIZE: usize = ITEM_SPACE / 16;
+
+/// Checks the size of the items of a queue when it is compiled. A zero sized
+/// item would never advance the cursors, one too large to fit a page would
+/// fail every write. The cursors evaluate `CHECK` when they are created.
+struct ItemSize<T>(PhantomData<T>);
+
+impl<T> ItemSize<T> {
+    const CHECK: () = assert!(
+        size_of::<T>() > 0 && size_of::<T>() <= MAX_ITEM_SIZE,
+        "queue items must be between 1 and MAX_ITEM_SIZE bytes"
+    );
+}
+
 pub(crate) struct ReadCursor<T> {
     page_id: PhysicalPageId,
-    page: Option<PageBuf>,
-
-    next_page_id: PhysicalPageId,
-    end_page_id: PhysicalPageId,
-
     offset: usize,
+    /// Pages read to the end and left behind, nothing links to them anymore
+    /// once the position past them is persisted.
+    exhausted: Vec<PhysicalPageId>,
 
     _pd: PhantomData<fn(T)>,
 }
 
 impl<T: FromBytes + KnownLayout + Immutable> ReadCursor<T> {
-    pub(crate) fn init(
-        pager: &mut PageCache,
-        init_page_id: PhysicalPageId,
-        end_page_id: PhysicalPageId,
-    ) -> Result<Self> {
-        let (next_page_id, page) = if init_page_id != end_page_id {
-            let buf = pager.read_page(init_page_id)?;
-            (init_page_id, Some(buf))
-        } else {
-            (PhysicalPageId::INVALID_ID, None)
-        };
-
-        Ok(Self {
-            page_id: init_page_id,
-            page,
-            next_page_id,
-            end_page_id,
+    pub(crate) fn new(page_id: PhysicalPageId, offset: usize) -> Self {
+        let () = ItemSize::<T>::CHECK;
 
-            offset: size_of::<QueuePageHeader>(),
+        Self {
+            page_id,
+            offset,
+            exhausted: Vec::new(),
 
             _pd: PhantomData,
-        })
-    }
-
-    pub(crate) fn pop(&mut self, pager: &mut PageCache) -> Result<Option<T>> {
-        if self.page_id == PhysicalPageId::INVALID_ID || self.page_id == self.end_page_id {
-            return Ok(None);
         }
+    }
 
-        if self.page.is_none() {
-            // If our current page != next_page_id then we must load it so
-            // that we can read the next_page's header to get its linked next page.
-            if self.page_id != self.next_page_id {
-                self.load_next_page(pager, self.page_id)?;
+    /// Pop the next item, never reading at or past `limit`.
+    ///
+    /// The page is re-read from the page cache on every call, since the tail
+    /// page keeps growing as the writer flushes it.
+    pub(crate) fn pop(
+        &mut self,
+        pager: &mut PageCache,
+        limit: (PhysicalPageId, usize),
+    ) -> Result<Option<T>> {
+        loop {
+            if self.page_id == PhysicalPageId::INVALID_ID || self.position() == limit {
+                return Ok(None);
             }
 
-            let page = self.page().unwrap();
+            let page = pager.read_page(self.page_id)?;
+            let (header, data) = QueuePageHeader::ref_from_prefix(page.buf())?;
 
-            if page.header.next_page_id != self.end_page_id {
-                self.load_next_page(pager, page.header.next_page_id)?;
-            } else {
-                self.next_page_id = PhysicalPageId::INVALID_ID;
-            }
-        }
+            let end_offset = (header.end_offset as usize).min(data.len());
 
-        let page = self.page().unwrap();
+            if self.offset < end_offset {
+                let item_end =
+                    advance(self.offset, size_of::<T>(), end_offset, "queue read offset")?;
 
-        let (item, _) = T::read_from_prefix(&page.data[self.offset..]).unwrap();
+                let (item, _) = T::read_from_prefix(&data[self.offset..item_end])?;
 
-        let item_size = size_of::<T>();
+                self.offset = item_end;
 
-        // if we have reached the end of the page, start reading the next page.
-        if self.offset + item_size == page.header.end_offset as usize {
-            todo!("start reading the next page");
-        }
+                return Ok(Some(item));
+            }
 
-        // Ensure we updated the offset, this was moved down here since
-        // I got a borrow issue in the if statement to read the next page.
-        self.offset += item_size;
+            // Everything on this page has been read, move on to the next one.
+            if header.next_page_id == PhysicalPageId::INVALID_ID {
+                return Ok(None);
+            }
+
+            self.exhausted.push(self.page_id);
+            self.page_id = header.next_page_id;
+            self.offset = header.next_offset as usize;
 
-        Ok(Some(item))
+            read_queue_page(pager, self.page_id)?;
+        }
     }
 
-    fn page(&self) -> Option<QueuePage<'_>> {
-        self.page.as_ref().map(|p| {
-            let (header, data) = QueuePageHeader::ref_from_prefix(p.buf()).unwrap();
-            QueuePage { header, data }
-        })
+    /// The page and offset of the next item to be read.
+    pub(crate) fn position(&self) -> (PhysicalPageId, usize) {
+        (self.page_id, self.offset)
     }
 
-    fn load_next_page(&mut self, pager: &mut PageCache, page_id: PhysicalPageId) -> Result<()> {
-        self.next_page_id = page_id;
+    /// Move back to a position previously returned by `position`, along
+    /// with the number of exhausted pages at the time.
+    pub(crate) fn seek(&mut self, (page_id, offset): (PhysicalPageId, usize), exhausted: usize) {
+        self.page_id = page_id;
+        self.offset = offset;
+        self.exhausted.truncate(exhausted);
+    }
 
-        let page = pager.read_page(page_id)?;
-        self.page = Some(page);
+    /// Number of pages left behind that haven't been taken yet.
+    pub(crate) fn exhausted_len(&self) -> usize {
+        self.exhausted.len()
+    }
 
-        Ok(())
+    /// Take the pages left behind since the last call.
+    pub(crate) fn take_exhausted(&mut self) -> Vec<PhysicalPageId> {
+        std::mem::take(&mut self.exhausted)
     }
 }
 
@@ -143,13 +157,15 @@ impl<T: IntoBytes + Immutable> WriteCursor<T> {
         init_page_id: PhysicalPageId,
         end_page_id: PhysicalPageId,
     ) -> Result<Self> {
+        let () = ItemSize::<T>::CHECK;
+
         let mut me = Self {
             page_id: PhysicalPageId::INVALID_ID,
             page: None,
             next_page_id: PhysicalPageId::INVALID_ID,
             end_page_id,
 
-            offset: size_of::<QueuePageHeader>(),
+            offset: 0,
 
             _pd: PhantomData,
         };
@@ -161,12 +177,51 @@ impl<T: IntoBytes + Immutable> WriteCursor<T> {
         Ok(me)
     }
 
+    /// Continue writing to `page_id` at `offset`, where a previous writer
+    /// flushed up to. Anything written past `offset` is discarded.
+    pub(crate) fn recover(
+        pager: &mut PageCache,
+        page_id: PhysicalPageId,
+        offset: usize,
+        end_page_id: PhysicalPageId,
+    ) -> Result<Option<Self>> {
+        let () = ItemSize::<T>::CHECK;
+
+        let page = read_queue_page(pager, page_id)?;
+
+        let mut copy = pager.new_page_buffer()?;
+        copy.buf_mut().copy_from_slice(page.buf());
+
+        let (header, _) = QueuePageHeader::mut_from_prefix(copy.buf_mut())?;
+
+        if header.item_space as usize != ITEM_SPACE || offset > header.end_offset as usize {
+            return Ok(None);
+        }
+
+        header.next_page_id = PhysicalPageId::INVALID_ID;
+        header.end_offset = to_u16(offset, "queue page end offset")?;
+
+        Ok(Some(Self {
+            page_id,
+            page: Some(copy),
+            next_page_id: PhysicalPageId::INVALID_ID,
+            end_page_id,
+
+            offset,
+
+            _pd: PhantomData,
+        }))
+    }
+
     pub fn write(&mut self, pager: &mut PageCache, item: T) -> Result<()> {
         let bytes_needed = size_of::<T>();
 
         // Check if we need a new page
         if self.page_id == PhysicalPageId::INVALID_ID
-            || self.offset + bytes_needed > self.page().unwrap().header.item_space as usize
+            || !matches!(
+                self.offset.checked_add(bytes_needed),
+                Some(end) if end <= self.page().unwrap().header.item_space as usize
+            )
         {
             // TODO: this should probably pull a free page from the original pager, but how?
             let new_page_id = pager.new_last_page_id();
@@ -176,19 +231,80 @@ impl<T: IntoBytes + Immutable> WriteCursor<T> {
         let offset = self.offset;
         let page = self.page().unwrap();
 
-        item.write_to(&mut page.data[offset as usize..bytes_needed])
-            .unwrap();
+        let new_offset = advance(offset, bytes_needed, page.data.len(), "queue write offset")?;
 
-        let new_offset = offset + bytes_needed;
+        item.write_to(&mut page.data[offset..new_offset])?;
 
-        page.header.end_offset = new_offset as u16;
+        page.header.end_offset = to_u16(new_offset, "queue page end offset")?;
         self.offset = new_offset;
 
-        // TODO: add queue numEntries++
+        Ok(())
+    }
+
+    /// Write `item` ahead of the reader at `head`, returning where the
+    /// reader continues from to read it first.
+    ///
+    /// Items are written from the end of the page towards its start, so the
+    /// last one written is read first. The page is only continued while the
+    /// reader is still at the last item written to it, the space in front of
+    /// items that have been popped may still be read after a crash.
+    pub(crate) fn write_front(
+        &mut self,
+        pager: &mut PageCache,
+        item: T,
+        head: (PhysicalPageId, usize),
+    ) -> Result<(PhysicalPageId, usize)> {
+        let bytes_needed = size_of::<T>();
+
+        if self.page.is_none() || head != self.position() || self.offset < bytes_needed {
+            let end_offset = ITEM_SPACE / bytes_needed * bytes_needed;
+
+            let mut page = pager.new_page_buffer()?;
+            page.init();
+
+            let (header, _) = QueuePageHeader::mut_from_prefix(page.buf_mut())?;
+            header.next_page_id = head.0;
+            header.next_offset = to_u16(head.1, "queue page next offset")?;
+            header.end_offset = to_u16(end_offset, "queue page end offset")?;
+            header.item_space = ITEM_SPACE as u16;
+
+            self.page = Some(page);
+            self.page_id = pager.new_last_page_id();
+            self.offset = end_offset;
+        }
+
+        self.offset -= bytes_needed;
+
+        let offset = self.offset;
+        let page = self.page().unwrap();
+        item.write_to(&mut page.data[offset..offset + bytes_needed])?;
+
+        // The reader goes through the page cache, it sees the item right
+        // away.
+        self.flush(pager)?;
+
+        Ok(self.position())
+    }
+
+    /// Write the current page through the page cache so readers can see
+    /// everything written so far. The writer keeps its own copy to continue
+    /// appending to.
+    pub(crate) fn flush(&mut self, pager: &mut PageCache) -> Result<()> {
+        if let Some(page) = &self.page {
+            let mut copy = pager.new_page_buffer()?;
+            copy.buf_mut().copy_from_slice(page.buf());
+
+            pager.update_page(LogicalPageId(self.page_id.0), copy)?;
+        }
 
         Ok(())
     }
 
+    /// The page and offset the next item will be written at.
+    pub(crate) fn position(&self) -> (PhysicalPageId, usize) {
+        (self.page_id, self.offset)
+    }
+
     fn add_new_page(
         &mut self,
         pager: &mut PageCache,
@@ -197,26 +313,26 @@ impl<T: IntoBytes + Immutable> WriteCursor<T> {
         init_page: bool,
     ) -> Result<()> {
         if let Some(mut page) = self.page.take() {
-            let (mut queue_page_header, _) =
-                QueuePageHeader::read_from_prefix(page.buf_mut()).unwrap();
+            let (queue_page_header, _) = QueuePageHeader::mut_from_prefix(page.buf_mut())?;
 
             queue_page_header.next_page_id = new_page_id;
-            queue_page_header.next_offset = new_offset as u16;
+            queue_page_header.next_offset = to_u16(new_offset, "queue page next offset")?;
 
-            pager.write_page(self.page_id, &page.freeze())?;
+            pager.update_page(LogicalPageId(self.page_id.0), page)?;
         }
 
         self.page_id = new_page_id;
         self.offset = new_offset;
 
         if init_page {
-            let page = self.page.insert(pager.new_page_buffer());
+            let page = self.page.insert(pager.new_page_buffer()?);
             page.init();
 
-            let (header, _data) = QueuePageHeader::mut_from_prefix(page.buf_mut()).unwrap();
+            let (header, _data) = QueuePageHeader::mut_from_prefix(page.buf_mut())?;
 
+            header.next_page_id = PhysicalPageId::INVALID_ID;
             header.end_offset = 0;
-            header.item_space = (PAGE_SIZE - size_of::<QueuePageHeader>()) as u16;
+            header.item_space = ITEM_SPACE as u16;
         }
 
         // TODO: clear the page if here we didn't init the page
@@ -224,10 +340,8 @@ impl<T: IntoBytes + Immutable> WriteCursor<T> {
         Ok(())
     }
 
-    fn set_next(&mut self, page_id: PhysicalPageId, offset: usize) {
-        let page = self.page().unwrap();
-        page.header.next_page_id = page_id;
-        page.header.next_offset = offset as u16;
+    fn set_next(&mut self, page_id: PhysicalPageId, offset: usize) -> Result<()> {
+        self.page().unwrap().set_next(page_id, offset)
     }
 
     fn page(&mut self) -> Option<QueuePageMut<'_>> {
@@ -239,8 +353,189 @@ impl<T: IntoBytes + Immutable> WriteCursor<T> {
 }
 
 impl<'a> QueuePageMut<'a> {
-    fn set_next(&mut self, page_id: PhysicalPageId, offset: usize) {
+    fn set_next(&mut self, page_id: PhysicalPageId, offset: usize) -> Result<()> {
         self.header.next_page_id = page_id;
-        self.header.next_offset = offset as u16;
+        self.header.next_offset = to_u16(offset, "queue page next offset")?;
+        Ok(())
+    }
+}
+
+/// Read a queue page and hint the page cache about the page linked after it,
+/// since a cursor walking the queue is going to need it next.
+fn read_queue_page(pager: &mut PageCache, page_id: PhysicalPageId) -> Result<PageBuf> {
+    let page = pager.read_page(page_id)?;
+
+    let (header, _) = QueuePageHeader::ref_from_prefix(page.buf())?;
+
+    if header.next_page_id != PhysicalPageId::INVALID_ID {
+        pager.prefetch(header.next_page_id)?;
+    }
+
+    Ok(page)
+}
+
+/// Advance `offset` by `len`, failing if it would wrap or land past `bound`.
+fn advance(offset: usize, len: usize, bound: usize, what: &'static str) -> Result<usize> {
+    offset
+        .checked_add(len)
+        .filter(|end| *end <= bound)
+        .ok_or(Error::OffsetOverflow(what))
+}
+
+fn to_u16(offset: usize, what: &'static str) -> Result<u16> {
+    u16::try_from(offset).map_err(|_| Error::OffsetOverflow(what))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::pager::test::mock::MemoryFile;
+
+    /// Small xorshift so the fuzz loop is deterministic without pulling in a
+    /// rng crate.
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn advance_bounds() {
+        assert_eq!(advance(0, 8, 8, "test").unwrap(), 8);
+        assert!(advance(1, 8, 8, "test").is_err());
+        assert!(advance(usize::MAX, 1, usize::MAX, "test").is_err());
+        assert!(to_u16(u16::MAX as usize + 1, "test").is_err());
+    }
+
+    #[test]
+    fn fuzz_advance() {
+        let mut state = 0x2545_f491_4f6c_dd1d;
+
+        for _ in 0..100_000 {
+            let offset = xorshift(&mut state) as usize >> (xorshift(&mut state) % 64);
+            let len = xorshift(&mut state) as usize >> (xorshift(&mut state) % 64);
+            let bound = xorshift(&mut state) as usize >> (xorshift(&mut state) % 64);
+
+            let expected = offset as u128 + len as u128;
+
+            match advance(offset, len, bound, "fuzz") {
+                Ok(end) => {
+                    assert_eq!(end as u128, expected);
+                    assert!(end <= bound);
+                }
+                Err(_) => assert!(expected > bound as u128),
+            }
+
+            match to_u16(offset, "fuzz") {
+                Ok(v) => assert_eq!(v as usize, offset),
+                Err(_) => assert!(offset > u16::MAX as usize),
+            }
+        }
+    }
+
+    #[test]
+    fn spill_links_previous_page() {
+        let mut pager = PageCache::new(
+            Box::new(MemoryFile::default()),
+            BytesMut::zeroed(PAGE_SIZE),
+            false,
+        );
+
+        let first_page_id = pager.new_last_page_id();
+        let mut writer =
+            WriteCursor::init(&mut pager, first_page_id, PhysicalPageId::INVALID_ID).unwrap();
+
+        let per_page = ITEM_SPACE / size_of::<PhysicalPageId>();
+        for i in 0..=per_page {
+            writer.write(&mut pager, PhysicalPageId(i)).unwrap();
+        }
+        let (second_page_id, _) = writer.position();
+        assert_ne!(second_page_id, first_page_id);
+
+        // The full page went out with its header pointing at the next one,
+        // not a copy of the header from before it was linked.
+        let page = pager.read_page(first_page_id).unwrap();
+        let (header, _) = QueuePageHeader::ref_from_prefix(page.buf()).unwrap();
+        assert_eq!(header.next_page_id, second_page_id);
+        assert_eq!(header.next_offset, 0);
+        assert_eq!(
+            header.end_offset as usize,
+            per_page * size_of::<PhysicalPageId>()
+        );
+    }
+
+    #[test]
+    fn largest_items() {
+        let mut pager = PageCache::new(
+            Box::new(MemoryFile::default()),
+            BytesMut::zeroed(PAGE_SIZE),
+            false,
+        );
+
+        let first_page_id = pager.new_last_page_id();
+        let mut writer = WriteCursor::<[u8; MAX_ITEM_SIZE]>::init(
+            &mut pager,
+            first_page_id,
+            PhysicalPageId::INVALID_ID,
+        )
+        .unwrap();
+
+        // Enough to fill two pages, with space left over at the end of each.
+        assert_ne!(ITEM_SPACE % MAX_ITEM_SIZE, 0);
+        let items = ITEM_SPACE / MAX_ITEM_SIZE * 2 + 1;
+        for i in 0..items {
+            writer.write(&mut pager, [i as u8; MAX_ITEM_SIZE]).unwrap();
+        }
+        writer.flush(&mut pager).unwrap();
+
+        let mut reader = ReadCursor::<[u8; MAX_ITEM_SIZE]>::new(first_page_id, 0);
+        for i in 0..items {
+            let item = reader.pop(&mut pager, writer.position()).unwrap();
+            assert_eq!(item, Some([i as u8; MAX_ITEM_SIZE]));
+        }
+        assert_eq!(reader.pop(&mut pager, writer.position()).unwrap(), None);
+        assert_eq!(reader.exhausted_len(), 2);
+    }
+
+    #[test]
+    fn read_ahead_next_page() {
+        let file = MemoryFile::default();
+        let mut pager = PageCache::new(Box::new(file.clone()), BytesMut::zeroed(PAGE_SIZE), false);
+
+        let first_page_id = pager.new_last_page_id();
+        let mut writer =
+            WriteCursor::init(&mut pager, first_page_id, PhysicalPageId::INVALID_ID).unwrap();
+
+        // Fill two pages and spill into a third one, linking and writing out
+        // the first two.
+        let per_page = ITEM_SPACE / size_of::<PhysicalPageId>();
+        for i in 0..=per_page * 2 {
+            writer.write(&mut pager, PhysicalPageId(i)).unwrap();
+        }
+        writer.flush(&mut pager).unwrap();
+        pager.flush_dirty().unwrap();
+
+        let (last_page_id, _) = writer.position();
+
+        // Re-open the file so that reads have to go to the file.
+        let mut pager = PageCache::new(Box::new(file.clone()), BytesMut::zeroed(PAGE_SIZE), false);
+        pager.next_page_id = last_page_id.0 + 1;
+        let mut reader = ReadCursor::<PhysicalPageId>::new(first_page_id, 0);
+
+        for i in 0..per_page {
+            let item = reader.pop(&mut pager, writer.position()).unwrap();
+            assert_eq!(item, Some(PhysicalPageId(i)));
+        }
+        assert!(file.prefetches().is_empty());
+
+        // Crossing into the second page hints the third.
+        reader.pop(&mut pager, writer.position()).unwrap();
+        assert_eq!(
+            file.prefetches(),
+            vec![(last_page_id.offset().unwrap(), PAGE_SIZE)]
+        );
     }
 }
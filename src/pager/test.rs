@@ -1,4 +1,4 @@
-use mock::MemoryFile;
+use mock::{FaultyFile, MemoryFile, ReadOnlyFile, ShortFile};
 
 use super::*;
 
@@ -9,12 +9,12 @@ fn update() {
 
     let mut pager = DWALPager::recover(file).unwrap();
 
-    let page1_id = pager.new_page_id();
-    let mut page1 = pager.new_page_buffer();
+    let page1_id = pager.new_page_id().unwrap();
+    let mut page1 = pager.new_page_buffer().unwrap();
 
     // This is higher because we use a couple pages upfront for internal
     // tracking.
-    assert_eq!(page1_id, LogicalPageId(2));
+    assert_eq!(page1_id, LogicalPageId(4));
 
     let page1_buf = page1.buf_mut();
     page1_buf.fill(42);
@@ -39,18 +39,16 @@ fn update() {
 }
 
 #[test]
-// TODO: re-enable this page once we have the API figured out. This currently fails under miri as
-// expected.
-#[ignore]
 fn read_dropped_page() {
     let file = MemoryFile::default();
 
     let mut pager = DWALPager::recover(file).unwrap();
 
-    let page1_id = pager.new_page_id();
-    let mut page1 = pager.new_page_buffer();
+    let page1_id = pager.new_page_id().unwrap();
+    let mut page1 = pager.new_page_buffer().unwrap();
 
-    assert_eq!(page1_id, LogicalPageId(1));
+    // The pages before it hold the job queue and free list.
+    assert_eq!(page1_id, LogicalPageId(4));
 
     let page1_buf = page1.buf_mut();
     page1_buf.fill(42);
@@ -67,9 +65,35 @@ fn read_dropped_page() {
 
     drop(pager);
 
+    // The arena is kept alive for pages read before the pager was dropped.
     assert!(page1_read.buf().iter().all(|x| *x == 42));
 }
 
+#[test]
+fn read_replaced_page() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    let page_id = pager.new_page_id().unwrap();
+
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    let old = pager.read_at(page_id, pager.current_version()).unwrap();
+
+    // Replacing the page can't hand out the buffer still being read.
+    for fill in [2, 3] {
+        let mut page = pager.new_page_buffer().unwrap();
+        page.buf_mut().fill(fill);
+        pager.update_page(page_id, page).unwrap();
+    }
+    assert!(old.buf().iter().all(|&b| b == 1));
+    assert_eq!(pager.page_cache.released.len(), 1);
+
+    // Once it is dropped its buffer is reused.
+    drop(old);
+    pager.new_page_buffer().unwrap();
+    assert!(pager.page_cache.released.is_empty());
+}
+
 #[test]
 fn multiple_pages() {
     let file = MemoryFile::default();
@@ -78,8 +102,8 @@ fn multiple_pages() {
     // Create and write multiple pages
     let page_ids: Vec<_> = (0..3)
         .map(|i| {
-            let page_id = pager.new_page_id();
-            let mut page = pager.new_page_buffer();
+            let page_id = pager.new_page_id().unwrap();
+            let mut page = pager.new_page_buffer().unwrap();
             page.buf_mut().fill(i as u8);
             let page = page.freeze();
             pager.write_page(PhysicalPageId(page_id.0), &page).unwrap();
@@ -102,8 +126,8 @@ fn page_updates() {
     let mut pager = DWALPager::recover(file).unwrap();
 
     // Create initial page
-    let page_id = pager.new_page_id();
-    let mut page = pager.new_page_buffer();
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
     page.buf_mut().fill(1);
     let version1 = pager.current_version();
     pager.update_page(page_id, page).unwrap();
@@ -111,7 +135,7 @@ fn page_updates() {
     pager.commit().unwrap();
 
     // Update the same page
-    let mut page = pager.new_page_buffer();
+    let mut page = pager.new_page_buffer().unwrap();
     page.buf_mut().fill(2);
     let version2 = pager.current_version();
     pager.atomic_update(page_id, version2, page).unwrap();
@@ -133,8 +157,8 @@ fn recovery_after_crash() {
     let mut pager = DWALPager::recover(file).unwrap();
     let page_ids: Vec<_> = (0..3)
         .map(|i| {
-            let page_id = pager.new_page_id();
-            let mut page = pager.new_page_buffer();
+            let page_id = pager.new_page_id().unwrap();
+            let mut page = pager.new_page_buffer().unwrap();
             page.buf_mut().fill(i as u8);
             let page = page.freeze();
             pager.write_page(PhysicalPageId(page_id.0), &page).unwrap();
@@ -155,122 +179,1990 @@ fn recovery_after_crash() {
 }
 
 #[test]
-#[ignore]
-fn read_nonexistent_page() {
+fn durable_version_watermark() {
     let file = MemoryFile::default();
     let mut pager = DWALPager::recover(file).unwrap();
-    let version = pager.current_version();
 
-    // Try reading a page ID that was never created
-    let nonexistent_id = LogicalPageId(999);
-    let result = pager.read_at(nonexistent_id, version);
-    assert!(result.is_err());
+    assert_eq!(pager.committed_version(), Version(1));
+    assert_eq!(pager.durable_version(), Version(1));
 
-    // Create a page, then try reading a different one
-    let page_id = pager.new_page_id();
-    let page = pager.new_page_buffer();
-    let page = page.freeze();
-    pager.write_page(PhysicalPageId(page_id.0), &page).unwrap();
+    pager.commit_unsynced().unwrap();
 
-    let another_nonexistent_id = LogicalPageId(page_id.0 + 1);
-    let result = pager.read_at(another_nonexistent_id, version);
-    assert!(result.is_err());
+    assert_eq!(pager.committed_version(), Version(2));
+    assert_eq!(pager.durable_version(), Version(1));
+
+    // Can't wait on a version that was never committed.
+    assert!(matches!(
+        pager.wait_for_durable(Version(3)),
+        Err(Error::VersionNotCommitted(Version(3)))
+    ));
+
+    pager.wait_for_durable(Version(2)).unwrap();
+    assert_eq!(pager.durable_version(), Version(2));
+
+    pager.commit().unwrap();
+
+    assert_eq!(pager.committed_version(), Version(3));
+    assert_eq!(pager.durable_version(), Version(3));
 }
 
 #[test]
-// TODO: verify behavior in these test cases
-#[ignore]
-fn read_invalid_version() {
+fn header_written_once_per_commit() {
     let file = MemoryFile::default();
+    let file2 = file.clone();
+
+    let header_writes = |writes: &[(u64, usize)]| -> Vec<(u64, usize)> {
+        writes
+            .iter()
+            .copied()
+            .filter(|(offset, _)| *offset < (HEADER_SLOTS * PAGE_SIZE) as u64)
+            .collect()
+    };
+
     let mut pager = DWALPager::recover(file).unwrap();
 
-    // Create a page
-    let page_id = pager.new_page_id();
-    let page = pager.new_page_buffer();
-    let page = page.freeze();
-    pager.write_page(PhysicalPageId(page_id.0), &page).unwrap();
-    let current_version = pager.current_version();
+    // A new file gets its header in every slot right away.
+    let initial = file2.writes();
+    assert_eq!(
+        header_writes(&initial),
+        vec![(0, PAGE_SIZE), (PAGE_SIZE as u64, PAGE_SIZE)]
+    );
 
-    // Try reading with a future version
-    let future_version = current_version.0 + 1;
-    let result = pager.read_at(page_id, Version(future_version));
-    assert!(result.is_err());
+    pager.commit().unwrap();
+    pager.commit_unsynced().unwrap();
 
-    // Try reading with an invalid past version (0 or very old)
-    let past_version = 0;
-    let result = pager.read_at(page_id, Version(past_version));
-    assert!(result.is_err());
+    // Each commit is a single page aligned write of the header page, to
+    // the header slots in turn.
+    assert_eq!(
+        header_writes(&file2.writes()[initial.len()..]),
+        vec![(0, PAGE_SIZE), (PAGE_SIZE as u64, PAGE_SIZE)]
+    );
+
+    drop(pager);
+
+    let pager = DWALPager::recover(file2).unwrap();
+    assert_eq!(pager.committed_version(), Version(3));
 }
 
-// Mock in-memory file implementation for testing
-mod mock {
-    use std::cell::RefCell;
-    use std::cmp;
-    use std::rc::Rc;
+#[test]
+fn pages_synced_before_header() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
 
-    use super::{File, Result};
+    let page_id = pager.new_page_id().unwrap();
+    let page = pager.new_page_buffer().unwrap();
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
 
-    #[derive(Clone, Default)]
-    pub struct MemoryFile {
-        data: Rc<RefCell<Vec<u8>>>,
+    // The pages are synced, then the header is written and synced on its
+    // own.
+    let writes = file.writes();
+    let (header, _) = writes.last().unwrap();
+    assert!(*header < (HEADER_SLOTS * PAGE_SIZE) as u64);
+    let syncs = file.syncs();
+    assert_eq!(syncs[syncs.len() - 2..], [writes.len() - 1, writes.len()]);
+}
+
+#[test]
+fn new_file_survives_crash_before_first_commit() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    // Enough dirty pages that some are written out ahead of any commit.
+    for _ in 0..DEFAULT_CACHE_PAGES {
+        let page_id = pager.new_page_id().unwrap();
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(page_id, page).unwrap();
     }
+    assert!(file.len().unwrap() > HEADER_SLOTS * PAGE_SIZE);
+    drop(pager);
 
-    impl MemoryFile {
-        pub fn new() -> Self {
-            MemoryFile {
-                data: Rc::new(RefCell::new(Vec::new())),
-            }
-        }
+    // The header written when the file was created is still there.
+    let pager = DWALPager::recover(file).unwrap();
+    assert_eq!(pager.committed_version(), Version(1));
+    assert_eq!(pager.root_page(), None);
+}
 
-        pub fn with_capacity(capacity: usize) -> Self {
-            MemoryFile {
-                data: Rc::new(RefCell::new(Vec::with_capacity(capacity))),
-            }
-        }
+#[test]
+fn unsynced_header_needs_recovery() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    pager.commit().unwrap();
+    let synced = pager.committed_version();
+
+    for _ in 0..3 {
+        pager.new_page_id().unwrap();
     }
+    pager.commit_unsynced().unwrap();
+    let page_count = pager.page_cache.next_page_id;
+    // Crash, dropping the pager would sync.
+    std::mem::forget(pager);
+
+    // As if the header made it to disk but extending the file didn't.
+    let copy = MemoryFile::default();
+    let mut data = vec![0; (page_count - 1) * PAGE_SIZE];
+    file.read_at(&mut data, 0).unwrap();
+    copy.write_at(&data, 0).unwrap();
+
+    // Following the newest header, like a reader does, runs into the end
+    // of the file.
+    assert!(matches!(
+        DWALPager::recover_read_only(copy.clone()),
+        Err(Error::RecoveryNeeded(version)) if version == synced
+    ));
+
+    // A writer passes over it for the synced one.
+    let pager = DWALPager::recover(copy).unwrap();
+    assert_eq!(pager.committed_version(), synced);
+}
 
-    impl File for MemoryFile {
-        fn len(&self) -> Result<usize> {
-            Ok(self.data.borrow().len())
-        }
+#[test]
+fn unsynced_header_passed_over() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let synced = pager.committed_version();
 
-        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-            let data = self.data.borrow();
-            let offset = offset as usize;
+    let mut synced_file = vec![0; file.len().unwrap()];
+    file.read_at(&mut synced_file, 0).unwrap();
 
-            // If offset is beyond data length, return 0 bytes read
-            if offset >= data.len() {
-                return Ok(0);
-            }
+    // As if the header of each unsynced commit made it to disk but the
+    // pages it wrote didn't.
+    let crash = |pager: DWALPager| {
+        std::mem::forget(pager);
 
-            // Calculate how many bytes we can actually read
-            let available = data.len() - offset;
-            let to_read = cmp::min(available, buf.len());
+        let mut data = vec![0; file.len().unwrap()];
+        file.read_at(&mut data, 0).unwrap();
+        data[HEADER_SLOTS * PAGE_SIZE..synced_file.len()]
+            .copy_from_slice(&synced_file[HEADER_SLOTS * PAGE_SIZE..]);
 
-            // Copy the data
-            buf[..to_read].copy_from_slice(&data[offset..offset + to_read]);
+        let copy = MemoryFile::default();
+        copy.write_at(&data, 0).unwrap();
+        copy
+    };
 
-            Ok(to_read)
-        }
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(2);
+    pager
+        .atomic_update(page_id, pager.current_version(), page)
+        .unwrap();
+    pager.commit_unsynced().unwrap();
+
+    // The other slot holds the synced version, the file opens at it.
+    let copy = crash(pager);
+    let mut pager = DWALPager::recover(copy.clone()).unwrap();
+    assert_eq!(pager.committed_version(), synced);
+    let page = pager.read_at(page_id, synced).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 1));
+
+    // Two unsynced commits later neither slot does.
+    pager.commit_unsynced().unwrap();
+    pager.commit_unsynced().unwrap();
+    std::mem::forget(pager);
+    assert!(matches!(
+        DWALPager::recover(copy.clone()),
+        Err(Error::RecoveryNeeded(version)) if version == synced
+    ));
+
+    // Read only pagers follow the newest header regardless.
+    let pager = DWALPager::recover_read_only(copy).unwrap();
+    assert_eq!(pager.committed_version(), Version(synced.0 + 2));
+}
 
-        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
-            let mut data = self.data.borrow_mut();
-            let offset = offset as usize;
+#[test]
+fn version_vector() {
+    let file = MemoryFile::default();
+    let file2 = file.clone();
 
-            // Ensure the internal buffer is large enough
-            if offset + buf.len() > data.len() {
-                data.resize(offset + buf.len(), 0);
-            }
+    let mut pager = DWALPager::recover(file).unwrap();
+    assert_eq!(pager.version_vector().count(), 0);
 
-            // Write the data
-            data[offset..offset + buf.len()].copy_from_slice(buf);
+    pager.commit().unwrap();
+    pager.commit().unwrap();
 
-            Ok(buf.len())
-        }
+    let first: Vec<_> = pager.version_vector().collect();
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0].1, Version(3));
 
-        fn sync_data(&self) -> Result<()> {
-            // No-op for in-memory implementation
-            Ok(())
+    drop(pager);
+
+    // Every reopen is a new writer, the oldest ones eventually fall off.
+    let mut writers = vec![first[0].0];
+    for _ in 0..VERSION_VECTOR_LEN {
+        let mut pager = DWALPager::recover(file2.clone()).unwrap();
+        pager.commit().unwrap();
+
+        let vector: Vec<_> = pager.version_vector().collect();
+        assert_eq!(vector[0].1, pager.committed_version());
+        assert!(!writers.contains(&vector[0].0));
+
+        writers.insert(0, vector[0].0);
+        writers.truncate(VERSION_VECTOR_LEN);
+        let ids: Vec<_> = vector.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, writers);
+    }
+
+    // Read-only opens don't show up.
+    let pager = DWALPager::recover_read_only(ReadOnlyFile(file2)).unwrap();
+    let ids: Vec<_> = pager.version_vector().map(|(id, _)| id).collect();
+    assert_eq!(ids, writers);
+}
+
+#[test]
+fn deterministic_off() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    pager.commit().unwrap();
+    let random_id = pager.version_vector().next().unwrap().0;
+
+    pager.set_deterministic(true);
+    pager.commit().unwrap();
+    assert_ne!(pager.version_vector().next().unwrap().0, random_id);
+
+    // Replacing a cached page recycles its buffer, stale contents included,
+    // which only zero filling hides.
+    let page_id = pager.new_page_id().unwrap();
+    for fill in [7, 8] {
+        let mut page = pager.new_page_buffer().unwrap();
+        assert!(page.buf().iter().all(|&b| b == 0));
+        page.buf_mut().fill(fill);
+        pager.update_page(page_id, page).unwrap();
+    }
+
+    // Turning it off stops zero filling and restores the random writer id.
+    pager.set_deterministic(false);
+    let page = pager.new_page_buffer().unwrap();
+    assert!(page.buf().iter().all(|&b| b == 7));
+    pager.update_page(page_id, page).unwrap();
+
+    pager.commit().unwrap();
+    assert_eq!(pager.version_vector().next().unwrap().0, random_id);
+}
+
+#[test]
+fn commit_tags() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    pager.set_commit_tag([1; 16]);
+    pager.commit().unwrap();
+    let tagged = pager.committed_version();
+
+    pager.commit().unwrap();
+    let untagged = pager.committed_version();
+
+    // A rolled back tag isn't used by the next commit.
+    pager.set_commit_tag([2; 16]);
+    pager.rollback().unwrap();
+    pager.commit().unwrap();
+
+    assert_eq!(pager.commit_info(tagged).unwrap().tag, [1; 16]);
+    assert!(pager.commit_info(untagged).is_none());
+    assert!(pager.commit_info(pager.committed_version()).is_none());
+    drop(pager);
+
+    let mut pager = DWALPager::recover(file).unwrap();
+    assert_eq!(pager.commit_info(tagged).unwrap().tag, [1; 16]);
+
+    for i in 0..COMMIT_TAGS_LEN {
+        pager.set_commit_tag([i as u8; 16]);
+        pager.commit().unwrap();
+    }
+
+    assert!(pager.commit_info(tagged).is_none());
+    let last = pager.commit_info(pager.committed_version()).unwrap();
+    assert_eq!(last.tag, [COMMIT_TAGS_LEN as u8 - 1; 16]);
+}
+
+#[test]
+fn poisoned_after_failed_commit() {
+    let file = FaultyFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+
+    // Syncing the pages of the commit fails.
+    file.fail_syncs(true);
+    assert!(matches!(pager.commit(), Err(Error::Io(_))));
+    file.fail_syncs(false);
+
+    assert!(matches!(pager.commit(), Err(Error::Poisoned)));
+    let page = pager.new_page_buffer().unwrap();
+    assert!(matches!(
+        pager.atomic_update(page_id, pager.current_version(), page),
+        Err(Error::Poisoned)
+    ));
+    assert!(matches!(
+        pager.free(page_id, pager.current_version()),
+        Err(Error::Poisoned)
+    ));
+
+    // Reads still work.
+    let page = pager.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|b| *b == 1));
+
+    drop(pager);
+
+    // Reopening recovers, writes failing halfway through a commit poison it
+    // too.
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    let page = pager.new_page_buffer().unwrap();
+    pager
+        .atomic_update(page_id, pager.current_version(), page)
+        .unwrap();
+
+    file.fail_writes(true);
+    assert!(matches!(pager.commit_unsynced(), Err(Error::Io(_))));
+    file.fail_writes(false);
+
+    assert!(matches!(pager.commit_unsynced(), Err(Error::Poisoned)));
+    assert!(matches!(
+        pager.wait_for_durable(pager.committed_version()),
+        Err(Error::Poisoned)
+    ));
+
+    let pager = DWALPager::recover(file).unwrap();
+    assert!(pager.committed_version() >= version);
+}
+
+/// Allocate a page, write it and commit, then free it at the next version.
+fn freed_page(pager: &mut DWALPager) -> LogicalPageId {
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(5);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+
+    pager.free(page_id, pager.current_version()).unwrap();
+    page_id
+}
+
+#[test]
+fn freed_page_reused_after_header() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    let page_id = freed_page(&mut pager);
+
+    pager.commit().unwrap();
+    assert_eq!(pager.new_page_id().unwrap(), page_id);
+}
+
+#[test]
+fn freed_page_held_until_header() {
+    let file = FaultyFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    let page_id = freed_page(&mut pager);
+    let version = pager.committed_version();
+
+    // The commit puts the page on the free list and writes the list out,
+    // then fails syncing right before its header is written.
+    file.fail_syncs(true);
+    assert!(matches!(pager.commit(), Err(Error::Io(_))));
+    file.fail_syncs(false);
+
+    // It isn't handed out, the header that frees it was never written.
+    assert_ne!(pager.new_page_id().unwrap(), page_id);
+    drop(pager);
+
+    // Recovered as of the last header the page is still in use.
+    let mut pager = DWALPager::recover(file).unwrap();
+    assert_eq!(pager.committed_version(), version);
+    assert!(pager
+        .read_at(page_id, version)
+        .unwrap()
+        .buf()
+        .iter()
+        .all(|&b| b == 5));
+    for _ in 0..8 {
+        assert_ne!(pager.new_page_id().unwrap(), page_id);
+    }
+}
+
+#[test]
+fn freed_page_held_until_synced() {
+    for bitmap in [false, true] {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+        if bitmap {
+            pager.use_bitmap_allocator().unwrap();
+        }
+        let page_id = freed_page(&mut pager);
+
+        // The header freeing it is written but the last durable one still
+        // uses it, as do the ones of later unsynced commits.
+        pager.commit_unsynced().unwrap();
+        pager.commit_unsynced().unwrap();
+        for _ in 0..8 {
+            assert_ne!(pager.new_page_id().unwrap(), page_id, "{}", bitmap);
         }
+
+        pager.wait_for_durable(pager.committed_version()).unwrap();
+        assert_eq!(pager.new_page_id().unwrap(), page_id, "{}", bitmap);
+    }
+}
+
+#[test]
+fn open_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("pager");
+
+    let mut pager = DWALPager::open(&path).unwrap();
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(3);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+    drop(pager);
+
+    let mut pager = DWALPager::open(&path).unwrap();
+    let page = pager.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 3));
+}
+
+#[test]
+fn export_import_page() {
+    let mut source = DWALPager::recover(MemoryFile::default()).unwrap();
+
+    let page_id = source.new_page_id().unwrap();
+    let mut page = source.new_page_buffer().unwrap();
+    page.init();
+    page.buf_mut().fill(9);
+    source.update_page(page_id, page).unwrap();
+    source.commit().unwrap();
+
+    let exported = source
+        .export_page(page_id, source.committed_version())
+        .unwrap();
+
+    // Replicate the page into another pager.
+    let mut replica = DWALPager::recover(MemoryFile::default()).unwrap();
+    let replica_id = replica.new_page_id().unwrap();
+    replica
+        .import_page(replica_id, replica.current_version(), &exported)
+        .unwrap();
+    replica.commit().unwrap();
+
+    let page = replica
+        .read_at(replica_id, replica.committed_version())
+        .unwrap();
+    assert!(page.buf().iter().all(|b| *b == 9));
+
+    let mut corrupted = exported.clone();
+    corrupted[100] ^= 0xff;
+    assert!(matches!(
+        replica.import_page(replica_id, replica.current_version(), &corrupted),
+        Err(Error::Corruption { page, .. }) if page == replica_id
+    ));
+}
+
+#[test]
+fn read_only() {
+    let file = MemoryFile::default();
+    let file2 = file.clone();
+
+    let mut pager = DWALPager::recover(file).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(7);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+
+    drop(pager);
+
+    let writes = file2.writes().len();
+
+    let mut pager = DWALPager::recover_read_only(ReadOnlyFile(file2.clone())).unwrap();
+
+    let page = pager.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|b| *b == 7));
+
+    let page = pager.new_page_buffer().unwrap();
+    assert!(matches!(
+        pager.update_page(page_id, page),
+        Err(Error::ReadOnly)
+    ));
+
+    let page = pager.new_page_buffer().unwrap();
+    assert!(matches!(
+        pager.atomic_update(page_id, pager.current_version(), page),
+        Err(Error::ReadOnly)
+    ));
+
+    assert!(matches!(pager.commit(), Err(Error::ReadOnly)));
+    assert_eq!(pager.committed_version(), version);
+
+    assert_eq!(file2.writes().len(), writes);
+}
+
+#[test]
+fn refresh_read_only() {
+    let file = MemoryFile::default();
+    let mut writer = DWALPager::recover(file.clone()).unwrap();
+
+    let fill = |pager: &mut DWALPager, page_id, byte| {
+        let mut page = pager.new_page_buffer().unwrap();
+        page.buf_mut().fill(byte);
+        pager.update_page(page_id, page).unwrap();
+    };
+
+    let first = writer.new_page_id().unwrap();
+    fill(&mut writer, first, 1);
+    writer.commit().unwrap();
+
+    let mut reader = DWALPager::recover_read_only(ReadOnlyFile(file.clone())).unwrap();
+    let version = reader.current_version();
+    assert!(reader.read_at(first, version).unwrap().buf()[0] == 1);
+    assert!(!reader.refresh().unwrap());
+
+    let second = writer.new_page_id().unwrap();
+    fill(&mut writer, second, 2);
+    fill(&mut writer, first, 3);
+    writer.commit().unwrap();
+
+    // Nothing changes for the reader until it refreshes.
+    assert!(matches!(
+        reader.read_at(second, version),
+        Err(Error::IndexOutofBounds(page_id)) if page_id == second
+    ));
+    assert!(reader.read_at(first, version).unwrap().buf()[0] == 1);
+
+    assert!(reader.refresh().unwrap());
+    assert_eq!(reader.committed_version(), writer.committed_version());
+    let version = reader.current_version();
+    assert!(reader.read_at(second, version).unwrap().buf()[0] == 2);
+    assert!(reader.read_at(first, version).unwrap().buf()[0] == 3);
+
+    // The writer owns its file, there is nothing to refresh.
+    assert!(!writer.refresh().unwrap());
+}
+
+#[test]
+fn remaps_survive_reopen() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+
+    // The pin holds back the cleanup, the new contents are only on the
+    // copy when the pager goes away.
+    pager.pin(pager.committed_version());
+    for fill in [2, 3] {
+        let mut page = pager.new_page_buffer().unwrap();
+        page.buf_mut().fill(fill);
+        pager
+            .atomic_update(page_id, pager.current_version(), page)
+            .unwrap();
+    }
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+    drop(pager);
+
+    let mut reader = DWALPager::recover_read_only(ReadOnlyFile(file.clone())).unwrap();
+    assert_eq!(reader.read_at(page_id, version).unwrap().buf()[0], 3);
+
+    // Without the pin the cleanup copies it back over the original.
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    assert_eq!(pager.read_at(page_id, version).unwrap().buf()[0], 3);
+    pager.commit().unwrap();
+    assert!(pager.page_table.is_empty());
+    let version = pager.committed_version();
+    assert_eq!(pager.read_at(page_id, version).unwrap().buf()[0], 3);
+
+    assert!(reader.refresh().unwrap());
+    assert!(reader.page_table.is_empty());
+    assert_eq!(reader.read_at(page_id, version).unwrap().buf()[0], 3);
+}
+
+#[test]
+fn version_too_old() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let page = pager.new_page_buffer().unwrap();
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+
+    // Pinned, the version stays readable across commits.
+    pager.pin(version);
+    let page = pager.new_page_buffer().unwrap();
+    pager
+        .atomic_update(page_id, pager.current_version(), page)
+        .unwrap();
+    pager.commit().unwrap();
+    pager.read_at(page_id, version).unwrap();
+
+    // Once released the next commit may reuse its pages.
+    pager.unpin(version);
+    pager.commit().unwrap();
+    let err = pager.read_at(page_id, version).unwrap_err();
+    assert!(matches!(err, Error::VersionTooOld(v) if v == version));
+    assert!(!err.is_fatal());
+}
+
+#[test]
+fn reuse_delay() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    pager.set_reuse_delay(2);
+    let page_id = freed_page(&mut pager);
+    let version = pager.committed_version();
+
+    // The free waits for two more commits without a pin.
+    pager.commit().unwrap();
+    let stats = pager.free_list_stats();
+    assert_eq!(stats.reuse_delay, 2);
+    assert_eq!(stats.waiting_jobs, 1);
+    assert!(stats.oldest_version <= version);
+    assert_eq!(pager.read_at(page_id, version).unwrap().buf()[0], 5);
+    assert_ne!(pager.new_page_id().unwrap(), page_id);
+
+    pager.commit().unwrap();
+    assert!(pager.read_at(page_id, version).is_ok());
+
+    // Released by the commit past the delay and handed out once synced.
+    pager.commit().unwrap();
+    assert!(matches!(
+        pager.read_at(page_id, version),
+        Err(Error::VersionTooOld(_))
+    ));
+    let stats = pager.free_list_stats();
+    assert_eq!(stats.waiting_jobs, 0);
+    assert!(stats.free_pages >= 1);
+    assert_eq!(pager.new_page_id().unwrap(), page_id);
+
+    // A longer delay never moves the oldest version back.
+    let oldest = pager.free_list_stats().oldest_version;
+    pager.set_reuse_delay(10);
+    pager.commit().unwrap();
+    assert_eq!(pager.free_list_stats().oldest_version, oldest);
+}
+
+#[test]
+fn corrupt_queue_state() {
+    let file = MemoryFile::default();
+    let file2 = file.clone();
+
+    let mut pager = DWALPager::recover(file).unwrap();
+    pager.commit().unwrap();
+    drop(pager);
+
+    // Flip a byte inside the persisted remap queue state, of both header
+    // slots so neither is left to fall back on.
+    for slot in 0..HEADER_SLOTS {
+        let offset = slot * PAGE_SIZE + std::mem::offset_of!(Header, job_queue) + 8;
+        let mut byte = [0];
+        file2.read_at(&mut byte, offset as u64).unwrap();
+        file2.write_at(&[!byte[0]], offset as u64).unwrap();
     }
+
+    assert!(matches!(
+        DWALPager::recover(file2),
+        Err(Error::QueueStateCorrupted(JOB_QUEUE_ID))
+    ));
+}
+
+#[test]
+fn invalid_header() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    pager.commit().unwrap();
+    drop(pager);
+
+    let corrupt = |offset: usize, bytes: &[u8]| {
+        let copy = MemoryFile::default();
+        let mut page = vec![0; file.len().unwrap()];
+        file.read_at(&mut page, 0).unwrap();
+        for slot in 0..HEADER_SLOTS {
+            let offset = slot * PAGE_SIZE + offset;
+            page[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        copy.write_at(&page, 0).unwrap();
+        DWALPager::recover(copy)
+    };
+
+    assert!(matches!(corrupt(0, b"sqlite"), Err(Error::InvalidDatabase)));
+    assert!(matches!(
+        corrupt(std::mem::offset_of!(Header, version), &7u16.to_le_bytes()),
+        Err(Error::UnsupportedVersion(7))
+    ));
+    assert!(matches!(
+        corrupt(
+            std::mem::offset_of!(Header, page_size),
+            &512u32.to_le_bytes()
+        ),
+        Err(Error::UnsupportedPageSize(512))
+    ));
+
+    // A short file that isn't empty is never treated as a new database.
+    let short = MemoryFile::default();
+    short.write_at(b"garbage", 0).unwrap();
+    assert!(matches!(
+        DWALPager::recover(short),
+        Err(Error::InvalidDatabase)
+    ));
+}
+
+#[test]
+fn truncated_file() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let page = pager.new_page_buffer().unwrap();
+    pager.update_page(page_id, page).unwrap();
+
+    // Pages that are allocated but never written still count.
+    for _ in 0..3 {
+        pager.new_page_id().unwrap();
+    }
+    pager.commit().unwrap();
+    let page_count = pager.page_cache.next_page_id;
+    drop(pager);
+
+    assert_eq!(
+        file.len().unwrap() as u64,
+        min_file_len(page_count).unwrap()
+    );
+    DWALPager::recover(file.clone()).unwrap();
+
+    let copy = MemoryFile::default();
+    let mut data = vec![0; (page_count - 1) * PAGE_SIZE];
+    file.read_at(&mut data, 0).unwrap();
+    copy.write_at(&data, 0).unwrap();
+
+    assert!(matches!(
+        DWALPager::recover(copy),
+        Err(Error::Truncated { page_count: count, file_len })
+            if count == page_count as u64 && file_len == data.len() as u64
+    ));
+}
+
+#[test]
+fn short_reads_and_writes() {
+    let file = ShortFile::default();
+    let wal = ShortFile::default();
+    let mut pager = DWALPager::recover_with_wal(file.clone(), wal.clone()).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(42);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+    drop(pager);
+
+    // Every page and the header went out in pieces, and come back whole.
+    let mut pager = DWALPager::recover_with_wal(file, wal).unwrap();
+    assert_eq!(pager.committed_version(), version);
+    let page = pager.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|x| *x == 42));
+}
+
+#[test]
+fn truncated_under_writer() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let page = pager.new_page_buffer().unwrap();
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+
+    // Shrunk by someone else, the page is read back half from the file
+    // instead of half garbage.
+    let physical_id = PhysicalPageId(page_id.0);
+    file.set_len(physical_id.offset().unwrap() + 100).unwrap();
+    pager.page_cache.clear();
+
+    assert!(matches!(
+        pager.read_at(page_id, version),
+        Err(Error::Truncated { .. })
+    ));
+}
+
+#[test]
+fn torn_header_falls_back() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    pager.commit().unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+    drop(pager);
+
+    // The newest copy is in the slot after the one before it, tearing it
+    // leaves the commit before.
+    let root_page = std::mem::offset_of!(Header, root_page);
+    let slot = (version.0 as usize % HEADER_SLOTS) * PAGE_SIZE;
+    file.write_at(&[0xff; 8], (slot + root_page) as u64)
+        .unwrap();
+
+    let pager = DWALPager::recover(file.clone()).unwrap();
+    assert_eq!(pager.committed_version(), Version(version.0 - 1));
+    drop(pager);
+
+    // With both copies torn there is nothing to recover from.
+    let other = ((version.0 as usize + 1) % HEADER_SLOTS) * PAGE_SIZE;
+    file.write_at(&[0xff; 8], (other + root_page) as u64)
+        .unwrap();
+    assert!(matches!(
+        DWALPager::recover(file),
+        Err(Error::CorruptHeader)
+    ));
+}
+
+#[test]
+fn adaptive_cache() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    pager.set_cache_budget(2 * DEFAULT_CACHE_PAGES * PAGE_SIZE);
+    assert_eq!(pager.cache_capacity(), DEFAULT_CACHE_PAGES);
+
+    let mut page_ids = Vec::new();
+    for _ in 0..3 * DEFAULT_CACHE_PAGES {
+        let page_id = pager.new_page_id().unwrap();
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(page_id, page).unwrap();
+        page_ids.push(page_id);
+    }
+    pager.commit().unwrap();
+    let version = pager.current_version();
+
+    // Cycling through more pages than fit misses on every read, the cache
+    // grows until it hits the budget.
+    for _ in 0..4 {
+        for page_id in &page_ids {
+            pager.read_at(*page_id, version).unwrap();
+        }
+    }
+    assert_eq!(pager.cache_capacity(), 2 * DEFAULT_CACHE_PAGES);
+
+    // Once the reads fit it shrinks a chunk at a time.
+    for _ in 0..(CACHE_SHRINK_WINDOWS + 1) * CACHE_SIZING_WINDOW {
+        pager.read_at(page_ids[0], version).unwrap();
+    }
+    assert_eq!(
+        pager.cache_capacity(),
+        2 * DEFAULT_CACHE_PAGES - CACHE_CHUNK_PAGES
+    );
+    assert!(pager.page_cache.cache.len() <= pager.cache_capacity());
+
+    // Lowering the budget takes effect right away.
+    let allocated = pager.cache_usage().allocated_bytes;
+    pager.set_cache_budget(0);
+    assert_eq!(pager.cache_capacity(), CACHE_CHUNK_PAGES);
+    assert!(pager.page_cache.cache.len() <= CACHE_CHUNK_PAGES);
+
+    // The pages left are moved together and the memory of the rest is
+    // freed. The queues hold on to a few buffers of their own besides the
+    // cached pages.
+    assert!(allocated > 2 * CACHE_CHUNK_PAGES * PAGE_SIZE);
+    assert!(pager.cache_usage().allocated_bytes <= 2 * CACHE_CHUNK_PAGES * PAGE_SIZE);
+    assert_eq!(pager.release_memory(), 0);
+    for page_id in &page_ids {
+        pager.read_at(*page_id, version).unwrap();
+    }
+}
+
+#[test]
+fn dirty_watermarks() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    pager.set_dirty_watermarks(4 * PAGE_SIZE, 8 * PAGE_SIZE);
+    let initial = file.writes().len();
+
+    // One page updated all the time among pages updated once.
+    let hot = pager.new_page_id().unwrap();
+    let mut cold = Vec::new();
+    for i in 0..40 {
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(hot, page).unwrap();
+
+        let page_id = pager.new_page_id().unwrap();
+        let mut page = pager.new_page_buffer().unwrap();
+        page.buf_mut().fill(i);
+        pager.update_page(page_id, page).unwrap();
+        cold.push(page_id);
+
+        assert!(pager.cache_usage().dirty_bytes < 8 * PAGE_SIZE);
+    }
+
+    // Written back a few at a time, the hot page never.
+    let written: Vec<_> = file.writes()[initial..]
+        .iter()
+        .map(|(offset, len)| (*offset as usize / PAGE_SIZE, len.div_ceil(PAGE_SIZE)))
+        .collect();
+    assert!(written.len() > 1);
+    assert!(written
+        .iter()
+        .all(|(first, len)| !(*first..first + len).contains(&hot.0)));
+    assert!(written.iter().map(|(_, len)| len).sum::<usize>() >= cold.len() - 4);
+
+    pager.commit().unwrap();
+    drop(pager);
+    let mut pager = DWALPager::recover(file).unwrap();
+    for (i, page_id) in cold.iter().enumerate() {
+        let page = pager.read_at(*page_id, pager.committed_version()).unwrap();
+        assert!(page.buf().iter().all(|&b| b == i as u8));
+    }
+}
+
+#[test]
+fn committed_pages_held_until_commit() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    pager.set_dirty_watermarks(2 * PAGE_SIZE, 4 * PAGE_SIZE);
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+
+    // Updated in place, then left to go cold behind new pages that are
+    // written back ahead of the commit.
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(2);
+    pager.update_page(page_id, page).unwrap();
+    let initial = file.writes().len();
+    for _ in 0..16 {
+        let new_page_id = pager.new_page_id().unwrap();
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(new_page_id, page).unwrap();
+    }
+    assert!(file.writes().len() > initial);
+
+    let mut reader = DWALPager::recover_read_only(file.clone()).unwrap();
+    let page = reader.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 1));
+
+    pager.commit().unwrap();
+    let mut reader = DWALPager::recover_read_only(file).unwrap();
+    let page = reader.read_at(page_id, reader.committed_version()).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 2));
+}
+
+#[test]
+fn coalesced_commit_writes() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    // The queue pages and header slots of the new file.
+    let initial = file.writes().len();
+
+    let run_len = |pages: usize| (pages - 1) * PAGE_SIZE + PAGE_CAPACITY;
+
+    // Once dirty pages take up half the cache the colder half of them is
+    // written out ahead of the commit, adjacent ones in a single write.
+    let half = DEFAULT_CACHE_PAGES / 2;
+    let cold = half / 2;
+    let mut page_ids = Vec::new();
+    for i in 0..half + 10 {
+        let page_id = pager.new_page_id().unwrap();
+        let mut page = pager.new_page_buffer().unwrap();
+        page.buf_mut().fill(i as u8);
+        pager.update_page(page_id, page).unwrap();
+        page_ids.push(page_id);
+
+        if i + 1 == half {
+            assert_eq!(
+                file.writes()[initial..],
+                [(4 * PAGE_SIZE as u64, run_len(cold))]
+            );
+        }
+    }
+
+    let version = pager.current_version();
+    for (i, page_id) in page_ids.iter().enumerate() {
+        assert!(pager.read_at(*page_id, version).unwrap().buf()[0] == i as u8);
+    }
+
+    // The rest goes out with the commit, after the queue pages and before
+    // the header.
+    pager.commit().unwrap();
+    let writes = file.writes();
+    assert_eq!(
+        writes[initial + 1..],
+        [
+            (2 * PAGE_SIZE as u64, run_len(2)),
+            (
+                (4 + cold) as u64 * PAGE_SIZE as u64,
+                run_len(half - cold + 10)
+            ),
+            (0, PAGE_SIZE)
+        ]
+    );
+
+    drop(pager);
+    let mut pager = DWALPager::recover(file).unwrap();
+    for (i, page_id) in page_ids.iter().enumerate() {
+        assert!(pager.read_at(*page_id, version).unwrap().buf()[0] == i as u8);
+    }
+}
+
+#[test]
+fn group_commit() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    pager.set_commit_policy(CommitPolicy::Bytes(64 * PAGE_SIZE as u64));
+
+    let durable = pager.durable_version();
+    let mut versions = Vec::new();
+    for _ in 0..3 {
+        let page_id = pager.new_page_id().unwrap();
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(page_id, page).unwrap();
+        versions.push(pager.commit_grouped().unwrap());
+    }
+
+    // Every commit wrote a few pages and the header, too little to sync.
+    assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(pager.durable_version(), durable);
+
+    pager.wait_for_durable(versions[0]).unwrap();
+    assert_eq!(pager.durable_version(), versions[2]);
+
+    // Enough pages in one commit sync it right away.
+    for _ in 0..64 {
+        let page_id = pager.new_page_id().unwrap();
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(page_id, page).unwrap();
+    }
+    let version = pager.commit_grouped().unwrap();
+    assert_eq!(pager.durable_version(), version);
+}
+
+#[test]
+fn zero_fill_and_scrub() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+
+    // Replacing a cached page recycles its buffer, stale contents included.
+    for fill in [7, 8] {
+        let mut page = pager.new_page_buffer().unwrap();
+        page.buf_mut().fill(fill);
+        pager.update_page(page_id, page).unwrap();
+    }
+
+    let page = pager.new_page_buffer().unwrap();
+    assert!(page.buf().iter().all(|&b| b == 7));
+    pager.page_cache.free_page_buffer(page);
+
+    pager.set_zero_fill(true);
+    let page = pager.new_page_buffer().unwrap();
+    assert!(page.buf().iter().all(|&b| b == 0));
+
+    pager.set_scrub_freed(true);
+    pager.free(page_id, pager.current_version()).unwrap();
+    pager.commit().unwrap();
+    // Zeroed once the commit freeing it is synced, written by the next.
+    pager.commit().unwrap();
+
+    let mut raw = vec![1; PAGE_CAPACITY];
+    file.read_at(&mut raw, (page_id.0 * PAGE_SIZE) as u64)
+        .unwrap();
+    assert!(raw.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn scrub_waits_for_header() {
+    let file = FaultyFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    pager.set_scrub_freed(true);
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+
+    // The remap outlives its commit while the version before is pinned.
+    let pinned = pager.committed_version();
+    pager.pin(pinned);
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(2);
+    pager
+        .atomic_update(page_id, pager.current_version(), page)
+        .unwrap();
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+    pager.unpin(pinned);
+
+    // The next commit copies the remapped page back and releases the copy,
+    // then fails syncing right before its header is written. The last
+    // header still reads the copy.
+    file.fail_syncs(true);
+    assert!(matches!(pager.commit(), Err(Error::Io(_))));
+    file.fail_syncs(false);
+    drop(pager);
+
+    let mut pager = DWALPager::recover(file).unwrap();
+    assert_eq!(pager.committed_version(), version);
+    let page = pager.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 2));
+}
+
+#[test]
+fn free_list_reuse() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file).unwrap();
+
+    let page1_id = pager.new_page_id().unwrap();
+    let page2_id = pager.new_page_id().unwrap();
+
+    pager.free(page1_id, pager.current_version()).unwrap();
+
+    // Frees only become reusable once they are committed.
+    let page3_id = pager.new_page_id().unwrap();
+    assert_ne!(page3_id, page1_id);
+
+    pager.commit().unwrap();
+
+    assert_eq!(pager.new_page_id().unwrap(), page1_id);
+
+    let page4_id = pager.new_page_id().unwrap();
+    assert!(page4_id.0 > page3_id.0);
+    assert_ne!(page4_id, page2_id);
+}
+
+#[test]
+fn free_list_recovered() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let page1_id = pager.new_page_id().unwrap();
+    let page2_id = pager.new_page_id().unwrap();
+    pager.commit().unwrap();
+
+    pager.free(page2_id, pager.current_version()).unwrap();
+    pager.commit().unwrap();
+
+    // This free can't run while the version before it is pinned, it stays
+    // in the job queue.
+    pager.pin(pager.committed_version());
+    pager.free(page1_id, pager.current_version()).unwrap();
+    pager.commit().unwrap();
+
+    let page_count = pager.header.page_count.get();
+    drop(pager);
+
+    // Both the free list and the job queue pick up where they were.
+    let mut pager = DWALPager::recover(file).unwrap();
+    assert_eq!(pager.new_page_id().unwrap(), page2_id);
+
+    pager.commit().unwrap();
+    assert_eq!(pager.new_page_id().unwrap(), page1_id);
+    assert_eq!(pager.header.page_count.get(), page_count);
+}
+
+#[test]
+fn exhausted_queue_pages_reused() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file).unwrap();
+
+    // Enough free pages for the free list to span a few queue pages.
+    let pages: Vec<_> = (0..2_000).map(|_| pager.new_page_id().unwrap()).collect();
+    pager.commit().unwrap();
+
+    for page_id in &pages {
+        pager.free(*page_id, pager.current_version()).unwrap();
+    }
+    pager.commit().unwrap();
+
+    for _ in &pages {
+        pager.new_page_id().unwrap();
+    }
+    pager.commit().unwrap();
+
+    // The free list pages that were read through are handed out next,
+    // instead of growing the file.
+    let page_count = pager.page_cache.next_page_id;
+    let reused = pager.new_page_id().unwrap();
+    assert!(!pages.contains(&reused));
+    assert_eq!(pager.page_cache.next_page_id, page_count);
+}
+
+#[test]
+fn bitmap_allocator() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let pages: Vec<_> = (0..100).map(|_| pager.new_page_id().unwrap()).collect();
+    pager.commit().unwrap();
+
+    for page_id in &pages[..50] {
+        pager.free(*page_id, pager.current_version()).unwrap();
+    }
+    pager.commit().unwrap();
+
+    // The free list moves over to the bitmap with the next commit.
+    pager.use_bitmap_allocator().unwrap();
+    pager.commit().unwrap();
+    assert_ne!(pager.header.bitmap_directory.get(), 0);
+    drop(pager);
+
+    let mut pager = DWALPager::recover(file).unwrap();
+    assert!(pager.bitmap.is_some());
+
+    let run = pager.new_page_run(40).unwrap();
+    assert_eq!(run, pages[0]);
+    assert_eq!(pager.new_page_id().unwrap(), pages[40]);
+
+    // No run of 20 is left, it goes past the end of the file.
+    let page_count = pager.page_cache.next_page_id;
+    assert_eq!(pager.new_page_run(20).unwrap().0, page_count);
+
+    // Rolled back pages are free again after the next commit, which writes
+    // the bitmap to pages that were free before it.
+    pager.rollback().unwrap();
+    pager.commit().unwrap();
+    assert_eq!(pager.new_page_run(40).unwrap(), pages[0]);
+}
+
+#[test]
+fn close_truncates_free_tail() {
+    for bitmap in [false, true] {
+        let file = MemoryFile::default();
+        let mut pager = DWALPager::recover(file.clone()).unwrap();
+        if bitmap {
+            pager.use_bitmap_allocator().unwrap();
+        }
+
+        let pages: Vec<_> = (0..100).map(|_| pager.new_page_id().unwrap()).collect();
+        pager.commit().unwrap();
+
+        // Frees queued by the last update only run once it is closed.
+        let page_count = pager.page_cache.next_page_id;
+        for page_id in pages[20..30].iter().chain(&pages[40..]) {
+            pager.free(*page_id, pager.current_version()).unwrap();
+        }
+        pager.close().unwrap();
+
+        let closed_page_count = pager.page_cache.next_page_id;
+        assert!(closed_page_count < page_count - 50, "{}", bitmap);
+        assert_eq!(
+            file.len().unwrap() as u64,
+            min_file_len(closed_page_count).unwrap()
+        );
+        drop(pager);
+
+        let mut pager = DWALPager::recover(file.clone()).unwrap();
+        assert!(pager.was_closed_cleanly());
+        assert_eq!(pager.page_cache.next_page_id, closed_page_count);
+
+        // The pages before the cut are still free.
+        for _ in 0..4 {
+            let page_id = pager.new_page_id().unwrap();
+            assert!(page_id.0 < closed_page_count, "{}", bitmap);
+            assert!(!pages[..20].contains(&page_id), "{}", bitmap);
+        }
+        pager.commit().unwrap();
+        drop(pager);
+
+        let pager = DWALPager::recover(file).unwrap();
+        assert!(!pager.was_closed_cleanly());
+    }
+}
+
+#[test]
+fn remap_cleanup() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+
+    let committed = pager.committed_version();
+    let version = pager.current_version();
+
+    // Update twice within the same version, the first copy is dropped.
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(2);
+    pager.atomic_update(page_id, version, page).unwrap();
+
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(3);
+    let remapped_id = pager.atomic_update(page_id, version, page).unwrap();
+
+    let page = pager.read_at(page_id, committed).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 1));
+    let page = pager.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 3));
+
+    pager.commit().unwrap();
+
+    // The remapped contents were copied back over the original page.
+    assert!(pager.page_table.is_empty());
+    assert_eq!(pager.get_physical_page_id(page_id, version).0, page_id.0);
+
+    let page = pager.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 3));
+
+    // Both remapped copies are free to be reused.
+    assert_eq!(pager.new_page_id().unwrap().0, remapped_id.0 - 1);
+    assert_eq!(pager.new_page_id().unwrap(), remapped_id);
+}
+
+#[test]
+fn shadow_paging() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    pager.use_shadow_paging().unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let first = pager.committed_version();
+    pager.pin(first);
+
+    // Updates stay on their copies, no cleanups are queued for them.
+    let mut copies = Vec::new();
+    for fill in [2, 3] {
+        let mut page = pager.new_page_buffer().unwrap();
+        page.buf_mut().fill(fill);
+        copies.push(
+            pager
+                .atomic_update(page_id, pager.current_version(), page)
+                .unwrap(),
+        );
+        pager.commit().unwrap();
+    }
+    assert_eq!(pager.free_list_stats().waiting_jobs, 0);
+    assert_eq!(pager.read_at(page_id, first).unwrap().buf()[0], 1);
+    let version = pager.committed_version();
+    assert_eq!(pager.read_at(page_id, version).unwrap().buf()[0], 3);
+    drop(pager);
+
+    // The page table is read back from the page map.
+    let mut reader = DWALPager::recover_read_only(ReadOnlyFile(file.clone())).unwrap();
+    assert!(reader.shadow_paging());
+    assert_eq!(reader.read_at(page_id, version).unwrap().buf()[0], 3);
+
+    // Without the pin the older copy is released, the newest one and the
+    // original page stay taken.
+    let mut pager = DWALPager::recover(file).unwrap();
+    assert!(pager.shadow_paging());
+    pager.commit().unwrap();
+    let version = pager.committed_version();
+    assert_eq!(pager.page_table[&page_id].len(), 1);
+    assert_eq!(pager.get_physical_page_id(page_id, version).0, copies[1].0);
+
+    let reused = (0..4)
+        .map(|_| pager.new_page_id().unwrap())
+        .collect::<Vec<_>>();
+    assert!(reused.contains(&copies[0]));
+    assert!(!reused.contains(&copies[1]) && !reused.contains(&page_id));
+
+    assert!(reader.refresh().unwrap());
+    assert_eq!(reader.read_at(page_id, version).unwrap().buf()[0], 3);
+}
+
+#[test]
+fn delta_updates() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+    pager.set_delta_updates(true);
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+    let first = pager.committed_version();
+    pager.pin(first);
+
+    // Patches are kept apart from the page, without copying it.
+    for fill in 2..5u8 {
+        let version = pager.current_version();
+        let page = pager.read_at(page_id, version).unwrap();
+        pager
+            .patch_page(page_id, version, &page, 8, &[fill; 8])
+            .unwrap();
+        pager.commit().unwrap();
+    }
+    assert!(pager.page_table.is_empty());
+    assert_eq!(pager.deltas[&page_id].len(), 3);
+
+    let page = pager.read_at(page_id, first).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 1));
+    for (version, fill) in (first.0 + 1..).zip(2..5u8) {
+        let page = pager.read_at(page_id, Version(version)).unwrap();
+        assert_eq!(&page.buf()[..24], [[1; 8], [fill; 8], [1; 8]].concat());
+    }
+
+    // Too long for a delta, the page is copied with the deltas applied.
+    let version = pager.current_version();
+    let page = pager.read_at(page_id, version).unwrap();
+    let long = [5; MAX_DELTA_LEN + 1];
+    pager.patch_page(page_id, version, &page, 0, &long).unwrap();
+    assert_eq!(pager.page_table[&page_id].len(), 1);
+    pager.rollback().unwrap();
+    let version = pager.committed_version();
+    drop(pager);
+
+    let mut reader = DWALPager::recover_read_only(ReadOnlyFile(file.clone())).unwrap();
+    assert_eq!(reader.read_at(page_id, version).unwrap().buf()[8], 4);
+
+    // Without the pin they are folded into the page, but only once the
+    // commits that made them are synced.
+    let mut pager = DWALPager::recover(file).unwrap();
+    assert_eq!(pager.deltas[&page_id].len(), 3);
+    let page = pager.read_at(page_id, version).unwrap();
+    pager.set_delta_updates(true);
+    pager
+        .patch_page(page_id, pager.current_version(), &page, 16, &[6; 8])
+        .unwrap();
+    pager.commit_unsynced().unwrap();
+    assert_eq!(pager.deltas[&page_id].len(), 1);
+    pager.commit().unwrap();
+    pager.commit().unwrap();
+    assert!(pager.deltas.is_empty());
+    let version = pager.committed_version();
+
+    let page = pager
+        .page_cache
+        .read_page(PhysicalPageId(page_id.0))
+        .unwrap();
+    assert_eq!(&page.buf()[..24], [[1; 8], [4; 8], [6; 8]].concat());
+    assert_eq!(pager.read_at(page_id, version).unwrap().buf()[16], 6);
+
+    assert!(reader.refresh().unwrap());
+    assert_eq!(reader.read_at(page_id, version).unwrap().buf()[16], 6);
+}
+
+#[test]
+fn job_budget() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    pager.set_commit_job_budget(0);
+
+    let mut page_ids = Vec::new();
+    for _ in 0..4 {
+        let page_id = pager.new_page_id().unwrap();
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(page_id, page).unwrap();
+        page_ids.push(page_id);
+    }
+    pager.commit().unwrap();
+
+    let version = pager.current_version();
+    for page_id in &page_ids {
+        let page = pager.new_page_buffer().unwrap();
+        pager.atomic_update(*page_id, version, page).unwrap();
+    }
+    pager.commit().unwrap();
+
+    // The remaps are ready but the commit wasn't allowed to clean them up.
+    assert_eq!(pager.page_table.len(), 4);
+
+    assert_eq!(pager.run_jobs(3).unwrap(), 3);
+    assert_eq!(pager.page_table.len(), 1);
+    assert_eq!(pager.run_jobs(usize::MAX).unwrap(), 1);
+    assert!(pager.page_table.is_empty());
+    assert_eq!(pager.run_jobs(usize::MAX).unwrap(), 0);
+}
+
+#[test]
+fn pinned_version() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.commit().unwrap();
+
+    let pinned = pager.committed_version();
+    pager.pin(pinned);
+
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(2);
+    pager
+        .atomic_update(page_id, pager.current_version(), page)
+        .unwrap();
+    pager.commit().unwrap();
+
+    pager.free(page_id, pager.current_version()).unwrap();
+    pager.commit().unwrap();
+
+    // Neither the remap nor the free can be cleaned up while the old
+    // version is pinned.
+    assert_eq!(pager.header.oldest_version.get(), pinned.0);
+    let page = pager.read_at(page_id, pinned).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 1));
+    assert!(pager.page_table.contains_key(&page_id));
+
+    let reused = pager.new_page_id().unwrap();
+    assert_ne!(reused, page_id);
+
+    pager.unpin(pinned);
+    pager.commit().unwrap();
+
+    assert_eq!(pager.header.oldest_version, pager.header.commited_version);
+    assert!(pager.page_table.is_empty());
+
+    // The remapped copy is freed first, then the page itself.
+    pager.new_page_id().unwrap();
+    assert_eq!(pager.new_page_id().unwrap(), page_id);
+}
+
+#[test]
+fn rollback() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(1);
+    pager.update_page(page_id, page).unwrap();
+    pager.set_root_page(page_id);
+    pager.commit().unwrap();
+
+    let version = pager.current_version();
+
+    let mut page = pager.new_page_buffer().unwrap();
+    page.buf_mut().fill(2);
+    let remapped_id = pager.atomic_update(page_id, version, page).unwrap();
+    let new_page_id = pager.new_page_id().unwrap();
+    pager.set_root_page(new_page_id);
+    pager.free(page_id, version).unwrap();
+
+    pager.rollback().unwrap();
+
+    assert_eq!(pager.current_version(), version);
+    assert_eq!(pager.root_page(), Some(page_id));
+    assert!(pager.page_table.is_empty());
+
+    let page = pager.read_at(page_id, version).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 1));
+
+    // The stale remap is skipped by the cleanup, and the freed page was
+    // forgotten.
+    pager.commit().unwrap();
+    let page = pager.read_at(page_id, pager.committed_version()).unwrap();
+    assert!(page.buf().iter().all(|&b| b == 1));
+
+    assert_eq!(pager.new_page_id().unwrap().0, remapped_id.0);
+    assert_eq!(pager.new_page_id().unwrap(), new_page_id);
+    assert!(pager.new_page_id().unwrap().0 > new_page_id.0);
+}
+
+#[test]
+fn layout_error() {
+    let err = Error::from(Header::read_from_bytes(&[0; 3][..]).unwrap_err());
+    assert!(matches!(err, Error::Layout(name) if name.ends_with("::Header")));
+
+    let err = Error::from(QueueState::ref_from_prefix(&[0; 3][..]).unwrap_err());
+    assert!(matches!(err, Error::Layout(name) if name.ends_with("::QueueState")));
+}
+
+#[test]
+fn page_offset_overflow() {
+    assert_eq!(PhysicalPageId(2).offset().unwrap(), 2 * PAGE_SIZE as u64);
+    assert!(matches!(
+        PhysicalPageId::INVALID_ID.offset(),
+        Err(Error::OffsetOverflow(_))
+    ));
+}
+
+#[test]
+fn read_nonexistent_page() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file).unwrap();
+    let version = pager.current_version();
+
+    // Try reading a page ID that was never created
+    let nonexistent_id = LogicalPageId(999);
+    let result = pager.read_at(nonexistent_id, version);
+    assert!(matches!(result, Err(Error::IndexOutofBounds(id)) if id == nonexistent_id));
+
+    // Create a page, then try reading a different one
+    let page_id = pager.new_page_id().unwrap();
+    let page = pager.new_page_buffer().unwrap();
+    let page = page.freeze();
+    pager.write_page(PhysicalPageId(page_id.0), &page).unwrap();
+
+    let another_nonexistent_id = LogicalPageId(page_id.0 + 1);
+    let result = pager.read_at(another_nonexistent_id, version);
+    assert!(matches!(result, Err(Error::IndexOutofBounds(id)) if id == another_nonexistent_id));
+
+    // Neither are the header slots.
+    for slot in 0..HEADER_SLOTS {
+        let result = pager.read_at(LogicalPageId(slot), version);
+        assert!(matches!(result, Err(Error::IndexOutofBounds(_))));
+    }
+}
+
+#[test]
+// TODO: verify behavior in these test cases
+#[ignore]
+fn read_invalid_version() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file).unwrap();
+
+    // Create a page
+    let page_id = pager.new_page_id().unwrap();
+    let page = pager.new_page_buffer().unwrap();
+    let page = page.freeze();
+    pager.write_page(PhysicalPageId(page_id.0), &page).unwrap();
+    let current_version = pager.current_version();
+
+    // Try reading with a future version
+    let future_version = current_version.0 + 1;
+    let result = pager.read_at(page_id, Version(future_version));
+    assert!(result.is_err());
+
+    // Try reading with an invalid past version (0 or very old)
+    let past_version = 0;
+    let result = pager.read_at(page_id, Version(past_version));
+    assert!(result.is_err());
+}
+
+// Mock in-memory file implementation for testing
+pub(crate) mod mock {
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use super::{File, Result};
+
+    /// The public `MemoryFile` that also records the writes and prefetch
+    /// hints issued against it.
+    #[derive(Clone, Default)]
+    pub struct MemoryFile {
+        file: crate::pager::MemoryFile,
+        // (offset, len) of every write issued against this file.
+        writes: Arc<Mutex<Vec<(u64, usize)>>>,
+        // (offset, len) of every prefetch hint issued against this file.
+        prefetches: Arc<Mutex<Vec<(u64, usize)>>>,
+        // Number of writes issued before each sync.
+        syncs: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl MemoryFile {
+        pub fn new() -> Self {
+            MemoryFile::default()
+        }
+
+        pub fn with_capacity(capacity: usize) -> Self {
+            MemoryFile {
+                file: crate::pager::MemoryFile::with_capacity(capacity),
+                ..Default::default()
+            }
+        }
+
+        pub fn writes(&self) -> Vec<(u64, usize)> {
+            self.writes.lock().unwrap().clone()
+        }
+
+        pub fn prefetches(&self) -> Vec<(u64, usize)> {
+            self.prefetches.lock().unwrap().clone()
+        }
+
+        pub fn syncs(&self) -> Vec<usize> {
+            self.syncs.lock().unwrap().clone()
+        }
+    }
+
+    impl File for MemoryFile {
+        fn len(&self) -> Result<usize> {
+            self.file.len()
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.file.read_at(buf, offset)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+            self.writes.lock().unwrap().push((offset, buf.len()));
+            self.file.write_at(buf, offset)
+        }
+
+        fn sync_data(&self) -> Result<()> {
+            self.syncs
+                .lock()
+                .unwrap()
+                .push(self.writes.lock().unwrap().len());
+            self.file.sync_data()
+        }
+
+        fn prefetch(&self, offset: u64, len: usize) -> Result<()> {
+            self.prefetches.lock().unwrap().push((offset, len));
+            Ok(())
+        }
+
+        fn set_len(&self, len: u64) -> Result<()> {
+            self.file.set_len(len)
+        }
+    }
+
+    /// Wraps a `MemoryFile` and fails every write like a read-only
+    /// filesystem would.
+    pub struct ReadOnlyFile(pub MemoryFile);
+
+    impl File for ReadOnlyFile {
+        fn len(&self) -> Result<usize> {
+            self.0.len()
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.0.read_at(buf, offset)
+        }
+
+        fn write_at(&self, _buf: &[u8], _offset: u64) -> Result<usize> {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied).into())
+        }
+
+        fn sync_data(&self) -> Result<()> {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied).into())
+        }
+    }
+
+    /// Wraps a `MemoryFile` and fails writes or syncs while told to,
+    /// to simulate io errors in the middle of a commit.
+    #[derive(Clone, Default)]
+    pub struct FaultyFile {
+        pub file: MemoryFile,
+        fail_writes: Arc<AtomicBool>,
+        fail_syncs: Arc<AtomicBool>,
+    }
+
+    impl FaultyFile {
+        pub fn fail_writes(&self, fail: bool) {
+            self.fail_writes.store(fail, Ordering::Relaxed);
+        }
+
+        pub fn fail_syncs(&self, fail: bool) {
+            self.fail_syncs.store(fail, Ordering::Relaxed);
+        }
+    }
+
+    impl File for FaultyFile {
+        fn len(&self) -> Result<usize> {
+            self.file.len()
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            self.file.read_at(buf, offset)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+            if self.fail_writes.load(Ordering::Relaxed) {
+                return Err(io::Error::from(io::ErrorKind::Other).into());
+            }
+
+            self.file.write_at(buf, offset)
+        }
+
+        fn sync_data(&self) -> Result<()> {
+            if self.fail_syncs.load(Ordering::Relaxed) {
+                return Err(io::Error::from(io::ErrorKind::Other).into());
+            }
+
+            self.file.sync_data()
+        }
+    }
+
+    /// Wraps a `MemoryFile` and reads or writes at most `CHUNK` bytes per
+    /// call, like a file on a network filesystem might.
+    #[derive(Clone, Default)]
+    pub struct ShortFile(pub MemoryFile);
+
+    impl ShortFile {
+        const CHUNK: usize = 1000;
+    }
+
+    impl File for ShortFile {
+        fn len(&self) -> Result<usize> {
+            self.0.len()
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+            let len = buf.len().min(Self::CHUNK);
+            self.0.read_at(&mut buf[..len], offset)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+            let len = buf.len().min(Self::CHUNK);
+            self.0.write_at(&buf[..len], offset)
+        }
+
+        fn sync_data(&self) -> Result<()> {
+            self.0.sync_data()
+        }
+
+        fn set_len(&self, len: u64) -> Result<()> {
+            self.0.set_len(len)
+        }
+    }
+}
+
+#[test]
+fn hot_page_written_once() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let page_id = pager.new_page_id().unwrap();
+    pager.commit().unwrap();
+    let before = pager.write_back_stats();
+    let writes = file.writes().len();
+
+    for i in 0..100u8 {
+        let mut page = pager.new_page_buffer().unwrap();
+        page.buf_mut().fill(i);
+        pager.update_page(page_id, page).unwrap();
+    }
+    pager.commit().unwrap();
+
+    let stats = pager.write_back_stats();
+    assert_eq!(stats.updates - before.updates, 100 + 2);
+    // The page goes out once, in the same write as the two queue pages
+    // before it, followed by the header.
+    assert_eq!(stats.pages_written - before.pages_written, 3);
+    assert_eq!(stats.writes - before.writes, 1);
+    assert_eq!(file.writes().len() - writes, 2);
+
+    let version = pager.committed_version();
+    assert_eq!(pager.read_at(page_id, version).unwrap().buf()[0], 99);
+}
+
+#[test]
+fn pinned_pages_not_evicted() {
+    let file = MemoryFile::default();
+    let mut pager = DWALPager::recover(file.clone()).unwrap();
+
+    let page_ids = (0..DEFAULT_CACHE_PAGES + 10)
+        .map(|i| {
+            let page_id = pager.new_page_id().unwrap();
+            let mut page = pager.new_page_buffer().unwrap();
+            page.buf_mut().fill(i as u8);
+            pager.update_page(page_id, page).unwrap();
+            page_id
+        })
+        .collect::<Vec<_>>();
+    pager.commit().unwrap();
+
+    // Holding on to more pages than the cache holds grows it instead of
+    // handing out one of their buffers again.
+    let mut pager = DWALPager::recover(file).unwrap();
+    let version = pager.committed_version();
+    let pages = page_ids
+        .iter()
+        .map(|page_id| pager.read_at(*page_id, version).unwrap())
+        .collect::<Vec<_>>();
+
+    pager.new_page_buffer().unwrap().buf_mut().fill(0xff);
+
+    for (i, page) in pages.iter().enumerate() {
+        assert!(page.buf().iter().all(|byte| *byte == i as u8));
+    }
+
+    // Once they are released the cache is back to its capacity.
+    drop(pages);
+    pager.new_page_buffer().unwrap();
+    assert!(pager.page_cache.cache.len() <= DEFAULT_CACHE_PAGES);
+}
+
+#[test]
+fn read_page_held_while_copying() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    pager.set_cache_config(CacheConfig {
+        max_bytes: MIN_CACHE_PAGES * PAGE_SIZE,
+    });
+
+    let page_ids = (0..MIN_CACHE_PAGES * 2)
+        .map(|i| {
+            let page_id = pager.new_page_id().unwrap();
+            let mut page = pager.new_page_buffer().unwrap();
+            page.buf_mut().fill(i as u8);
+            pager.update_page(page_id, page).unwrap();
+            page_id
+        })
+        .collect::<Vec<_>>();
+    pager.commit().unwrap();
+
+    // Like patching a leaf in place, every page is read and copied while
+    // it is still held, the least recently used page is often the one
+    // being copied.
+    for _ in 0..2 {
+        for (i, page_id) in page_ids.iter().enumerate() {
+            let page = pager.read_at(*page_id, pager.current_version()).unwrap();
+            let mut copy = pager.new_page_buffer().unwrap();
+            copy.buf_mut().copy_from_slice(page.buf());
+            assert!(page.buf().iter().all(|&b| b == i as u8));
+
+            pager
+                .atomic_update(*page_id, pager.current_version(), copy)
+                .unwrap();
+        }
+        pager.commit().unwrap();
+    }
+
+    for (i, page_id) in page_ids.iter().enumerate() {
+        let page = pager.read_at(*page_id, pager.committed_version()).unwrap();
+        assert!(page.buf().iter().all(|&b| b == i as u8));
+    }
+}
+
+#[test]
+fn cache_config() {
+    let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+    pager.set_cache_config(CacheConfig {
+        max_bytes: 32 * PAGE_SIZE + 100,
+    });
+    assert_eq!(pager.cache_capacity(), 32);
+
+    let mut page_ids = Vec::new();
+    for _ in 0..100 {
+        let page_id = pager.new_page_id().unwrap();
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(page_id, page).unwrap();
+        page_ids.push(page_id);
+    }
+    pager.commit().unwrap();
+
+    let version = pager.current_version();
+    for page_id in &page_ids {
+        pager.read_at(*page_id, version).unwrap();
+    }
+
+    let usage = pager.cache_usage();
+    assert_eq!(usage.max_bytes, 32 * PAGE_SIZE);
+    assert!(usage.cached_bytes <= usage.max_bytes);
+    assert_eq!(usage.dirty_bytes, 0);
+    // A single chunk of the arena is enough.
+    assert_eq!(usage.allocated_bytes, CACHE_CHUNK_PAGES * PAGE_SIZE);
+
+    // Growing the cache allocates more chunks as it fills up.
+    pager.set_cache_config(CacheConfig {
+        max_bytes: 1_000 * PAGE_SIZE,
+    });
+    for _ in 0..600 {
+        let page_id = pager.new_page_id().unwrap();
+        let page = pager.new_page_buffer().unwrap();
+        pager.update_page(page_id, page).unwrap();
+    }
+    pager.commit().unwrap();
+    assert_eq!(
+        pager.cache_usage().allocated_bytes,
+        3 * CACHE_CHUNK_PAGES * PAGE_SIZE
+    );
 }
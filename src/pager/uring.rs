@@ -0,0 +1,525 @@
+//! A `File` that does its reads, writes and syncs through an io_uring.
+//!
+//! The ring is set up with the raw system calls, there is no liburing
+//! underneath. Every call submits its operations and waits for them to
+//! complete before returning, what the ring saves is the system calls:
+//! `write_all_batch_at` submits every run of pages written back by a commit
+//! with a single `io_uring_enter` instead of a `pwritev` each.
+
+use std::{
+    convert::TryFrom,
+    io::{self, IoSlice},
+    os::unix::io::{AsRawFd, RawFd},
+    path::Path,
+    ptr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use super::File;
+use crate::Result;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_FSYNC_DATASYNC: u32 = 1;
+
+/// Entries of the submission queue, larger batches are submitted in parts.
+const RING_ENTRIES: u32 = 64;
+
+/// `struct io_sqring_offsets`
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_cqring_offsets`
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `struct io_uring_params`
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// `struct io_uring_sqe`, with the unions reduced to the fields used here.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// `struct io_uring_cqe`
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A mapping of part of the ring into memory.
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { ptr, len })
+    }
+
+    /// The value at `offset` bytes into the mapping.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+struct Ring {
+    fd: RawFd,
+    sq_ring: Mmap,
+    /// `None` if the completion queue shares the mapping of the submission
+    /// queue.
+    cq_ring: Option<Mmap>,
+    sqes: Mmap,
+    params: Params,
+}
+
+// Safety: the mappings are only accessed through `&mut Ring`, the kernel
+// side doesn't care which thread submits.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let single = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+
+        let mapped = (|| {
+            let sq_ring = Mmap::new(
+                fd,
+                if single { sq_len.max(cq_len) } else { sq_len },
+                IORING_OFF_SQ_RING,
+            )?;
+            let cq_ring = if single {
+                None
+            } else {
+                Some(Mmap::new(fd, cq_len, IORING_OFF_CQ_RING)?)
+            };
+            let sqes = Mmap::new(
+                fd,
+                params.sq_entries as usize * std::mem::size_of::<Sqe>(),
+                IORING_OFF_SQES,
+            )?;
+
+            Ok((sq_ring, cq_ring, sqes))
+        })();
+
+        match mapped {
+            Ok((sq_ring, cq_ring, sqes)) => Ok(Self {
+                fd,
+                sq_ring,
+                cq_ring,
+                sqes,
+                params,
+            }),
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    fn cq_ring(&self) -> &Mmap {
+        self.cq_ring.as_ref().unwrap_or(&self.sq_ring)
+    }
+
+    fn sq_entries(&self) -> usize {
+        self.params.sq_entries as usize
+    }
+
+    /// Submit `sqes`, no more than the queue holds, and wait for all of
+    /// them. Their results are stored in `results`, by position.
+    fn submit_and_wait(&mut self, sqes: &[Sqe], results: &mut [i32]) -> io::Result<()> {
+        debug_assert!(sqes.len() <= self.sq_entries());
+
+        let off = &self.params.sq_off;
+        let tail = unsafe { &*self.sq_ring.at::<AtomicU32>(off.tail) };
+        let mask = unsafe { *self.sq_ring.at::<u32>(off.ring_mask) };
+        let array = self.sq_ring.at::<u32>(off.array);
+        let entries = self.sqes.at::<Sqe>(0);
+
+        // Only this side moves the tail, the kernel moves the head once it
+        // has consumed the entries, which all calls wait for.
+        let mut next = tail.load(Ordering::Relaxed);
+        for (idx, sqe) in sqes.iter().enumerate() {
+            let slot = next & mask;
+            unsafe {
+                let mut sqe = *sqe;
+                sqe.user_data = idx as u64;
+                entries.add(slot as usize).write(sqe);
+                array.add(slot as usize).write(slot);
+            }
+            next = next.wrapping_add(1);
+        }
+        tail.store(next, Ordering::Release);
+
+        let mut submitted = 0;
+        let mut completed = 0;
+        while completed < sqes.len() {
+            let to_submit = (sqes.len() - submitted) as u32;
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd,
+                    to_submit,
+                    1u32,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                // Entries in flight point at the caller's buffers, they have
+                // to complete first. Once none are the ones the kernel
+                // hasn't taken are taken back, a later call would submit
+                // them otherwise.
+                if submitted == completed {
+                    let unsubmitted = (sqes.len() - submitted) as u32;
+                    tail.store(next.wrapping_sub(unsubmitted), Ordering::Release);
+                    return Err(err);
+                }
+            } else {
+                submitted += res as usize;
+            }
+
+            completed += self.reap(results);
+        }
+
+        Ok(())
+    }
+
+    /// Take the completions that are ready, returns how many there were.
+    fn reap(&mut self, results: &mut [i32]) -> usize {
+        let cq_ring = self.cq_ring();
+        let off = &self.params.cq_off;
+        let head = unsafe { &*cq_ring.at::<AtomicU32>(off.head) };
+        let tail = unsafe { &*cq_ring.at::<AtomicU32>(off.tail) };
+        let mask = unsafe { *cq_ring.at::<u32>(off.ring_mask) };
+        let cqes = cq_ring.at::<Cqe>(off.cqes);
+
+        let mut next = head.load(Ordering::Relaxed);
+        let end = tail.load(Ordering::Acquire);
+        let mut reaped = 0;
+        while next != end {
+            let cqe = unsafe { &*cqes.add((next & mask) as usize) };
+            results[cqe.user_data as usize] = cqe.res;
+            next = next.wrapping_add(1);
+            reaped += 1;
+        }
+        head.store(next, Ordering::Release);
+
+        reaped
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// A file read and written through an io_uring, enabled with the `uring`
+/// feature on Linux.
+///
+/// Open a database on it with `OpenOptions::open_file`. It behaves like a
+/// `std::fs::File`, the pages written back by a commit are submitted to the
+/// ring together.
+pub struct UringFile {
+    file: std::fs::File,
+    ring: Mutex<Ring>,
+}
+
+impl UringFile {
+    /// Do the I/O of `file` through a new ring. Fails if the kernel doesn't
+    /// support io_uring or it is disabled.
+    pub fn new(file: std::fs::File) -> Result<Self> {
+        Ok(Self {
+            file,
+            ring: Mutex::new(Ring::new(RING_ENTRIES)?),
+        })
+    }
+
+    /// Open the file at `path` for reading and writing, creating it if it
+    /// doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        Self::new(file)
+    }
+
+    fn sqe(&self, opcode: u8, iovecs: &[libc::iovec], offset: u64) -> Sqe {
+        Sqe {
+            opcode,
+            fd: self.file.as_raw_fd(),
+            off: offset,
+            addr: iovecs.as_ptr() as u64,
+            len: iovecs.len() as u32,
+            ..Sqe::default()
+        }
+    }
+
+    /// Submit `sqes` in batches the ring can hold and return their results,
+    /// failing with the first error.
+    fn submit(&self, sqes: &[Sqe]) -> Result<Vec<usize>> {
+        let mut ring = self.ring.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut results = vec![0; sqes.len()];
+
+        let batch = ring.sq_entries();
+        for (sqes, results) in sqes.chunks(batch).zip(results.chunks_mut(batch)) {
+            ring.submit_and_wait(sqes, results)?;
+        }
+
+        results
+            .into_iter()
+            .map(|res| match usize::try_from(res) {
+                Ok(len) => Ok(len),
+                Err(_) => Err(io::Error::from_raw_os_error(-res).into()),
+            })
+            .collect()
+    }
+}
+
+/// `IoSlice` has the layout of `iovec` on unix, but lends it only as a
+/// slice of bytes.
+fn iovecs(bufs: &[IoSlice<'_>]) -> Vec<libc::iovec> {
+    bufs.iter()
+        .take(libc::UIO_MAXIOV as usize)
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect()
+}
+
+impl File for UringFile {
+    fn len(&self) -> Result<usize> {
+        File::len(&self.file)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let iovec = [libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        }];
+
+        Ok(self.submit(&[self.sqe(IORING_OP_READV, &iovec, offset)])?[0])
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        self.write_vectored_at(&[IoSlice::new(buf)], offset)
+    }
+
+    /// Writes at most `UIO_MAXIOV` buffers, `write_all_vectored_at` writes
+    /// the rest.
+    fn write_vectored_at(&self, bufs: &[IoSlice<'_>], offset: u64) -> Result<usize> {
+        let iovecs = iovecs(bufs);
+
+        Ok(self.submit(&[self.sqe(IORING_OP_WRITEV, &iovecs, offset)])?[0])
+    }
+
+    fn write_all_batch_at(&self, writes: &[(&[IoSlice<'_>], u64)]) -> Result<()> {
+        let iovecs: Vec<_> = writes.iter().map(|(bufs, _)| iovecs(bufs)).collect();
+        let sqes: Vec<_> = writes
+            .iter()
+            .zip(&iovecs)
+            .map(|((_, offset), iovecs)| self.sqe(IORING_OP_WRITEV, iovecs, *offset))
+            .collect();
+
+        // Short writes are finished one at a time.
+        for ((bufs, offset), written) in writes.iter().zip(self.submit(&sqes)?) {
+            let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+            if written < len {
+                let rest: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter()).copied().collect();
+                self.write_all_at(&rest[written..], offset + written as u64)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        let sqe = Sqe {
+            opcode: IORING_OP_FSYNC,
+            fd: self.file.as_raw_fd(),
+            op_flags: IORING_FSYNC_DATASYNC,
+            ..Sqe::default()
+        };
+        self.submit(&[sqe])?;
+
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        File::set_len(&self.file, len)
+    }
+
+    fn reflink_to(&self, dest: &std::fs::File) -> Result<bool> {
+        File::reflink_to(&self.file, dest)
+    }
+
+    fn try_lock(&self) -> Result<bool> {
+        File::try_lock(&self.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OpenOptions;
+
+    #[test]
+    fn read_write_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = UringFile::open(dir.path().join("uring")).unwrap();
+
+        file.write_all_at(b"hello world", 0).unwrap();
+        let bufs = [IoSlice::new(b"12"), IoSlice::new(b"34")];
+        file.write_all_batch_at(&[(&bufs, 20), (&bufs[1..], 30)])
+            .unwrap();
+        file.sync_data().unwrap();
+
+        assert_eq!(File::len(&file).unwrap(), 32);
+        let mut buf = [0; 32];
+        assert_eq!(file.read_exact_at(&mut buf, 0).unwrap(), 32);
+        assert_eq!(&buf[..11], b"hello world");
+        assert_eq!(&buf[20..24], b"1234");
+        assert_eq!(&buf[30..], b"34");
+
+        // Reads stop at the end of the file.
+        assert_eq!(file.read_exact_at(&mut buf, 16).unwrap(), 16);
+    }
+
+    #[test]
+    fn database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+
+        let mut db = OpenOptions::new()
+            .open_file(UringFile::open(&path).unwrap())
+            .unwrap();
+        for i in 0..2_000u32 {
+            db.put(&i.to_be_bytes(), &[i as u8; 64]).unwrap();
+        }
+        db.close().unwrap();
+
+        let mut db = OpenOptions::new()
+            .open_file(UringFile::open(&path).unwrap())
+            .unwrap();
+        assert!(db.was_closed_cleanly());
+        for i in 0..2_000u32 {
+            assert_eq!(db.get(&i.to_be_bytes()).unwrap(), Some(vec![i as u8; 64]));
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! A redo log of the pages written in place, so a crash in the middle of
+//! writing them can't leave a torn page or header behind.
+//!
+//! Before the page cache writes pages to the file it appends their images
+//! to the log and syncs it, and a commit logs the header page followed by a
+//! commit record before writing the header. Recovery writes the pages of
+//! every commit that made it into the log over the file again, records
+//! after the last commit record are from a commit that never wrote its
+//! header and are dropped.
+//!
+//! Once the file has been synced the log is no longer needed and starts
+//! over. Each start picks a new salt that every record carries, records
+//! left behind from before, if the file couldn't be shrunk, don't match it.
+
+use std::convert::TryFrom;
+
+use zerocopy::{little_endian::U64, FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+use super::{crc::crc32, File, PhysicalPageId};
+use crate::{Error, Result};
+
+/// Identifies a treedb log, the first bytes of its header.
+const MAGIC: [u8; 8] = *b"treewal\0";
+
+/// A page image, written over the page `page_id` on recovery.
+const PAGE_RECORD: u64 = 0;
+/// Ends the records of a commit, its header was logged right before it.
+const COMMIT_RECORD: u64 = 1;
+
+/// What a read does with a page that fails to decode, set with
+/// `OpenOptions::repair_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RepairPolicy {
+    /// Fail the read with `Error::Corruption`, the default.
+    #[default]
+    Fail,
+    /// Write the page over again from its image in the write-ahead log and
+    /// read it once more, failing as before if the log has no image of it.
+    ///
+    /// The log only holds the pages written since the file was last
+    /// synced, older pages can't be repaired. See `Db::repaired_pages`.
+    Repair,
+}
+
+#[derive(FromBytes, IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+struct WalHeader {
+    magic: [u8; 8],
+    salt: U64,
+}
+
+#[derive(FromBytes, IntoBytes, KnownLayout, Immutable)]
+#[repr(C)]
+struct RecordHeader {
+    /// Log sequence number, one more than the record before it.
+    lsn: U64,
+    salt: U64,
+    kind: U64,
+    page_id: U64,
+    len: U64,
+    /// Over the record header, with this zeroed, followed by the payload.
+    checksum: U64,
+}
+
+pub struct Wal {
+    file: Box<dyn File>,
+    salt: u64,
+    next_lsn: u64,
+    /// Length of the log as of the last `sync`.
+    len: u64,
+    /// Records appended since the last `sync`.
+    buf: Vec<u8>,
+}
+
+impl Wal {
+    /// Write the pages of the commits in the log at `file` over `db`, then
+    /// start the log over. Returns the log along with the number of
+    /// commits that were redone.
+    pub fn recover(file: Box<dyn File>, db: &dyn File) -> Result<(Self, usize)> {
+        let mut salt = 0;
+        let mut commits = 0;
+
+        if let Some(header) = read_header(&*file)? {
+            salt = header.salt.get();
+
+            // Find the end of the last commit first, the pages after it are
+            // left alone.
+            let mut end = None;
+            for record in Records::new(&*file, salt) {
+                let (offset, header, _) = record?;
+                if header.kind.get() == COMMIT_RECORD {
+                    end = Some(offset);
+                    commits += 1;
+                }
+            }
+
+            if let Some(end) = end {
+                for record in Records::new(&*file, salt) {
+                    let (offset, header, payload) = record?;
+                    if offset > end {
+                        break;
+                    }
+
+                    if header.kind.get() == PAGE_RECORD {
+                        let page_id = usize::try_from(header.page_id.get())
+                            .map_err(|_| Error::OffsetOverflow("log page id"))?;
+                        db.write_all_at(&payload, PhysicalPageId(page_id).offset()?)?;
+                    }
+                }
+
+                db.sync_data()?;
+            }
+        }
+
+        let mut wal = Self {
+            file,
+            salt,
+            next_lsn: 1,
+            len: 0,
+            buf: Vec::new(),
+        };
+        wal.reset()?;
+
+        Ok((wal, commits))
+    }
+
+    /// Log the contents of `page_id`.
+    pub fn append_page(&mut self, page_id: PhysicalPageId, page: &[u8]) {
+        self.append(PAGE_RECORD, page_id.0 as u64, page);
+    }
+
+    /// End the records of a commit.
+    pub fn append_commit(&mut self) {
+        self.append(COMMIT_RECORD, 0, &[]);
+    }
+
+    /// Write out the records appended so far and sync the log, they are
+    /// recovered from here on.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        self.file.write_all_at(&self.buf, self.len)?;
+        self.file.sync_data()?;
+
+        self.len += self.buf.len() as u64;
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    /// The last image of `page_id` logged since the log started over,
+    /// which is what was last written to it in place.
+    pub fn find_page(&self, page_id: PhysicalPageId) -> Result<Option<Vec<u8>>> {
+        let mut image = None;
+
+        for record in Records::new(&*self.file, self.salt) {
+            let (_, header, payload) = record?;
+            if header.kind.get() == PAGE_RECORD && header.page_id.get() == page_id.0 as u64 {
+                image = Some(payload);
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// Bytes in the log, synced or not.
+    pub fn len(&self) -> u64 {
+        self.len + self.buf.len() as u64
+    }
+
+    /// Start the log over with a new salt, once everything logged so far is
+    /// synced to the database.
+    ///
+    /// This doesn't sync, if the new header is lost the old records are
+    /// redone again which writes the same pages.
+    pub fn reset(&mut self) -> Result<()> {
+        self.salt = self.salt.wrapping_add(1);
+        self.next_lsn = 1;
+        self.buf.clear();
+
+        let header = WalHeader {
+            magic: MAGIC,
+            salt: self.salt.into(),
+        };
+        self.file.write_all_at(header.as_bytes(), 0)?;
+        self.len = std::mem::size_of::<WalHeader>() as u64;
+        self.file.set_len(self.len)?;
+
+        Ok(())
+    }
+
+    fn append(&mut self, kind: u64, page_id: u64, payload: &[u8]) {
+        let mut header = RecordHeader {
+            lsn: self.next_lsn.into(),
+            salt: self.salt.into(),
+            kind: kind.into(),
+            page_id: page_id.into(),
+            len: (payload.len() as u64).into(),
+            checksum: 0.into(),
+        };
+        header.checksum = checksum(&header, payload).into();
+
+        self.buf.extend_from_slice(header.as_bytes());
+        self.buf.extend_from_slice(payload);
+        self.next_lsn += 1;
+    }
+}
+
+fn read_header(file: &dyn File) -> Result<Option<WalHeader>> {
+    let mut header = WalHeader::new_zeroed();
+    let len = file.read_exact_at(header.as_mut_bytes(), 0)?;
+
+    // A log that was never written, or whose header was torn, has nothing
+    // to redo. Its header is only written after the database is synced.
+    if len < std::mem::size_of::<WalHeader>() || header.magic != MAGIC {
+        return Ok(None);
+    }
+
+    Ok(Some(header))
+}
+
+fn checksum(header: &RecordHeader, payload: &[u8]) -> u64 {
+    let mut bytes = Vec::with_capacity(std::mem::size_of::<RecordHeader>() + payload.len());
+    bytes.extend_from_slice(header.as_bytes());
+
+    let checksum_offset = bytes.len() - std::mem::size_of::<U64>();
+    bytes[checksum_offset..].fill(0);
+    bytes.extend_from_slice(payload);
+
+    crc32(&bytes) as u64
+}
+
+/// Walks the records of a log that carry `salt`, in order, along with the
+/// offset each starts at. Stops at the first record that is torn, out of
+/// order or from before the log last started over.
+struct Records<'a> {
+    file: &'a dyn File,
+    salt: u64,
+    offset: u64,
+    next_lsn: u64,
+}
+
+impl<'a> Records<'a> {
+    fn new(file: &'a dyn File, salt: u64) -> Self {
+        Self {
+            file,
+            salt,
+            offset: std::mem::size_of::<WalHeader>() as u64,
+            next_lsn: 1,
+        }
+    }
+
+    fn read(&mut self) -> Result<Option<(u64, RecordHeader, Vec<u8>)>> {
+        let mut header = RecordHeader::new_zeroed();
+        let len = self
+            .file
+            .read_exact_at(header.as_mut_bytes(), self.offset)?;
+
+        if len < std::mem::size_of::<RecordHeader>()
+            || header.salt.get() != self.salt
+            || header.lsn.get() != self.next_lsn
+        {
+            return Ok(None);
+        }
+
+        let payload_len = match usize::try_from(header.len.get()) {
+            Ok(payload_len) if payload_len <= super::PAGE_SIZE => payload_len,
+            _ => return Ok(None),
+        };
+
+        let mut payload = vec![0; payload_len];
+        let payload_offset = self.offset + len as u64;
+        if self.file.read_exact_at(&mut payload, payload_offset)? < payload_len
+            || checksum(&header, &payload) != header.checksum.get()
+        {
+            return Ok(None);
+        }
+
+        let offset = self.offset;
+        self.offset = payload_offset + payload_len as u64;
+        self.next_lsn += 1;
+
+        Ok(Some((offset, header, payload)))
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<(u64, RecordHeader, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{MemoryFile, PAGE_SIZE};
+
+    fn page(fill: u8) -> Vec<u8> {
+        vec![fill; PAGE_SIZE]
+    }
+
+    fn read_page(db: &MemoryFile, page_id: usize) -> Vec<u8> {
+        let mut buf = vec![0; PAGE_SIZE];
+        db.read_at(&mut buf, (page_id * PAGE_SIZE) as u64).unwrap();
+        buf
+    }
+
+    #[test]
+    fn redo_committed_pages() {
+        let log = MemoryFile::default();
+        let db = MemoryFile::default();
+
+        let (mut wal, commits) = Wal::recover(Box::new(log.clone()), &db).unwrap();
+        assert_eq!(commits, 0);
+
+        wal.append_page(PhysicalPageId(1), &page(1));
+        wal.append_page(PhysicalPageId(2), &page(2));
+        wal.append_commit();
+
+        // Never committed, its pages aren't redone.
+        wal.append_page(PhysicalPageId(3), &page(3));
+        wal.sync().unwrap();
+        drop(wal);
+
+        let (wal, commits) = Wal::recover(Box::new(log.clone()), &db).unwrap();
+        assert_eq!(commits, 1);
+        assert_eq!(read_page(&db, 1), page(1));
+        assert_eq!(read_page(&db, 2), page(2));
+        assert_eq!(read_page(&db, 3), vec![0; PAGE_SIZE]);
+
+        // The log started over.
+        assert_eq!(wal.len(), std::mem::size_of::<WalHeader>() as u64);
+        assert_eq!(Wal::recover(Box::new(log), &db).unwrap().1, 0);
+    }
+
+    #[test]
+    fn torn_record() {
+        let log = MemoryFile::default();
+        let db = MemoryFile::default();
+
+        let (mut wal, _) = Wal::recover(Box::new(log.clone()), &db).unwrap();
+        wal.append_page(PhysicalPageId(1), &page(1));
+        wal.append_commit();
+        wal.append_page(PhysicalPageId(1), &page(2));
+        wal.append_commit();
+        wal.sync().unwrap();
+
+        // Tear the second image of page 1, only the first commit is redone.
+        let offset = wal.len() - 100;
+        log.write_at(&[0xff; 10], offset).unwrap();
+        drop(wal);
+
+        assert_eq!(Wal::recover(Box::new(log), &db).unwrap().1, 1);
+        assert_eq!(read_page(&db, 1), page(1));
+    }
+
+    #[test]
+    fn stale_records_ignored() {
+        let log = MemoryFile::default();
+        let db = MemoryFile::default();
+
+        let (mut wal, _) = Wal::recover(Box::new(log.clone()), &db).unwrap();
+        wal.append_page(PhysicalPageId(1), &page(1));
+        wal.append_commit();
+        wal.sync().unwrap();
+        let records = log.to_vec();
+
+        // Started over but left with the records of before, like a file
+        // that couldn't be shrunk.
+        wal.reset().unwrap();
+        log.write_at(&records[std::mem::size_of::<WalHeader>()..], wal.len())
+            .unwrap();
+        drop(wal);
+
+        assert_eq!(Wal::recover(Box::new(log), &db).unwrap().1, 0);
+        assert_eq!(read_page(&db, 1), vec![0; PAGE_SIZE]);
+    }
+}
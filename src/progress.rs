@@ -0,0 +1,21 @@
+use std::sync::Arc;
+
+/// How far a long operation got, passed to the callback set with
+/// `Db::with_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Pages processed so far.
+    pub processed: u64,
+    /// Pages the operation processes in all.
+    pub total: u64,
+}
+
+/// Called as a long operation makes progress.
+pub(crate) type ProgressFn = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Report `processed` out of `total` pages to `progress`, if there is one.
+pub(crate) fn report(progress: Option<&ProgressFn>, processed: u64, total: u64) {
+    if let Some(progress) = progress {
+        progress(Progress { processed, total });
+    }
+}
@@ -0,0 +1,305 @@
+//! Reproducible workloads for benchmarks and tests.
+//!
+//! Enabled with the `testing` feature. Everything is derived from a seed, so
+//! a workload can be described in an issue by its parameters alone and
+//! replayed exactly by anyone else.
+//!
+//! A `Workload` combines a key distribution with value sizes, for example
+//! `Workload::new(42, Keys::zipfian(1_000_000, 0.99), ValueSizes::Uniform(16, 256))`
+//! is an endless iterator of pairs to `put`.
+
+use crate::keys;
+
+/// A small deterministic random number generator (SplitMix64).
+///
+/// Its output only depends on the seed, unlike generators seeded from the
+/// OS, and it is stable across releases.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, `n` must not be zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        assert!(n > 0, "empty range");
+
+        // Multiply and shift, the bias is negligible for the ranges used
+        // here.
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+
+    /// A float in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// Ranks in `0..n` where rank `k` is drawn with probability proportional to
+/// `1 / (k + 1)^s`, so a few keys are hot and most are cold.
+///
+/// Sampled by rejection inversion, it needs no memory proportional to `n`.
+#[derive(Debug, Clone)]
+pub struct Zipf {
+    n: u64,
+    s: f64,
+    t: f64,
+}
+
+impl Zipf {
+    /// `s` is the skew, 0 is uniform and the commonly used 0.99 is heavily
+    /// skewed.
+    pub fn new(n: u64, s: f64) -> Self {
+        assert!(n > 0, "empty range");
+        assert!(s >= 0.0, "negative skew");
+
+        let n_f = n as f64;
+        let t = if s != 1.0 {
+            (n_f.powf(1.0 - s) - s) / (1.0 - s)
+        } else {
+            1.0 + n_f.ln()
+        };
+
+        Self { n, s, t }
+    }
+
+    pub fn sample(&self, rng: &mut Rng) -> u64 {
+        loop {
+            let inv_b = self.inv_cdf(rng.next_f64() * self.t);
+            let x = (inv_b + 1.0).floor();
+
+            let mut ratio = x.powf(-self.s);
+            if x > 1.0 {
+                ratio *= inv_b.powf(self.s);
+            }
+
+            if rng.next_f64() < ratio {
+                // Rounding can land a hair past the last rank.
+                return (x as u64).clamp(1, self.n) - 1;
+            }
+        }
+    }
+
+    fn inv_cdf(&self, p: f64) -> f64 {
+        if p <= 1.0 {
+            p
+        } else if self.s != 1.0 {
+            (p * (1.0 - self.s) + self.s).powf(1.0 / (1.0 - self.s))
+        } else {
+            (p - 1.0).exp()
+        }
+    }
+}
+
+/// How the keys of a `Workload` are chosen.
+#[derive(Debug, Clone)]
+pub enum Keys {
+    /// `0, 1, 2, ...`, the append only case.
+    Sequential,
+    /// Uniformly out of `0..n`.
+    Uniform(u64),
+    /// Out of `0..n` with a few hot keys, see `Zipf`.
+    Zipfian(Zipf),
+    /// `(series, timestamp)` keys of `series` interleaved series, each
+    /// advancing by about `interval` per point like sensors reporting on a
+    /// schedule with some jitter.
+    TimeSeries { series: u32, interval: u64 },
+}
+
+impl Keys {
+    pub fn zipfian(n: u64, s: f64) -> Self {
+        Keys::Zipfian(Zipf::new(n, s))
+    }
+}
+
+/// How the value sizes of a `Workload` are chosen.
+#[derive(Debug, Clone, Copy)]
+pub enum ValueSizes {
+    Fixed(usize),
+    /// Uniformly out of `min..=max`.
+    Uniform(usize, usize),
+    /// Mostly `small` with one in `one_in` values `large`, like rows with
+    /// an occasional blob.
+    Bimodal {
+        small: usize,
+        large: usize,
+        one_in: u64,
+    },
+}
+
+/// An endless, reproducible stream of key value pairs.
+///
+/// Integer keys are encoded with `keys::encode` so they sort numerically,
+/// time series keys as a `(u32, u64)` tuple.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    rng: Rng,
+    keys: Keys,
+    values: ValueSizes,
+    /// Keys generated so far.
+    count: u64,
+    /// Next timestamp of each time series.
+    timestamps: Vec<u64>,
+}
+
+impl Workload {
+    pub fn new(seed: u64, keys: Keys, values: ValueSizes) -> Self {
+        let timestamps = match &keys {
+            Keys::TimeSeries { series, .. } => vec![0; *series as usize],
+            _ => Vec::new(),
+        };
+
+        Self {
+            rng: Rng::new(seed),
+            keys,
+            values,
+            count: 0,
+            timestamps,
+        }
+    }
+
+    pub fn next_key(&mut self) -> Vec<u8> {
+        let count = self.count;
+        self.count += 1;
+
+        match &self.keys {
+            Keys::Sequential => keys::encode(&count),
+            Keys::Uniform(n) => keys::encode(&self.rng.below(*n)),
+            Keys::Zipfian(zipf) => keys::encode(&zipf.sample(&mut self.rng)),
+            Keys::TimeSeries { series, interval } => {
+                let (series, interval) = (*series, *interval);
+                let id = (count % series as u64) as u32;
+
+                let jitter = match interval / 10 {
+                    0 => 0,
+                    spread => self.rng.below(spread),
+                };
+                let timestamp = self.timestamps[id as usize];
+                self.timestamps[id as usize] = timestamp + interval + jitter;
+
+                keys::encode(&(id, timestamp))
+            }
+        }
+    }
+
+    pub fn next_value(&mut self) -> Vec<u8> {
+        let len = match self.values {
+            ValueSizes::Fixed(len) => len,
+            ValueSizes::Uniform(min, max) => {
+                assert!(min <= max, "empty value size range");
+                min + self.rng.below((max - min) as u64 + 1) as usize
+            }
+            ValueSizes::Bimodal {
+                small,
+                large,
+                one_in,
+            } => match self.rng.below(one_in) {
+                0 => large,
+                _ => small,
+            },
+        };
+
+        let mut value = vec![0; len];
+        self.rng.fill_bytes(&mut value);
+        value
+    }
+}
+
+impl Iterator for Workload {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some((self.next_key(), self.next_value()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reproducible() {
+        let workload = || Workload::new(7, Keys::Uniform(1_000), ValueSizes::Uniform(1, 64));
+
+        let a = workload().take(100).collect::<Vec<_>>();
+        assert_eq!(a, workload().take(100).collect::<Vec<_>>());
+        assert!(a.iter().all(|(_, value)| (1..=64).contains(&value.len())));
+
+        let other = Workload::new(8, Keys::Uniform(1_000), ValueSizes::Uniform(1, 64));
+        assert_ne!(a, other.take(100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zipf_skew() {
+        let mut rng = Rng::new(1);
+        let zipf = Zipf::new(1_000, 0.99);
+
+        let mut counts = vec![0usize; 1_000];
+        for _ in 0..100_000 {
+            counts[zipf.sample(&mut rng) as usize] += 1;
+        }
+
+        // The hottest key gets about 1 / H(1000, 0.99) ~ 13% of the draws,
+        // twice as many as the second and far more than the tail.
+        assert!((11_000..15_000).contains(&counts[0]));
+        assert!((counts[0] / 2).abs_diff(counts[1]) < 1_000);
+        assert!(counts[500..].iter().sum::<usize>() < counts[0]);
+
+        let uniform = Zipf::new(10, 0.0);
+        let mut counts = [0usize; 10];
+        for _ in 0..100_000 {
+            counts[uniform.sample(&mut rng) as usize] += 1;
+        }
+        assert!(counts.iter().all(|count| (9_000..11_000).contains(count)));
+    }
+
+    #[test]
+    fn time_series() {
+        let workload = Workload::new(
+            3,
+            Keys::TimeSeries {
+                series: 4,
+                interval: 1_000,
+            },
+            ValueSizes::Fixed(8),
+        );
+
+        let keys = workload
+            .take(400)
+            .map(|(key, _)| keys::decode::<(u32, u64)>(&key).unwrap())
+            .collect::<Vec<_>>();
+
+        for series in 0..4 {
+            let timestamps = keys
+                .iter()
+                .filter(|(id, _)| *id == series)
+                .map(|(_, timestamp)| *timestamp)
+                .collect::<Vec<_>>();
+
+            assert_eq!(timestamps.len(), 100);
+            assert!(timestamps
+                .windows(2)
+                .all(|pair| (1_000..1_100).contains(&(pair[1] - pair[0]))));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::{pager::LogicalPageId, Result};
+
+use super::{node::Node, Tree};
+
+impl Tree {
+    /// Number of keys in `range`, without reading any of their values.
+    ///
+    /// Only the leaves at the ends of the range have their keys compared,
+    /// the subtrees between them lie entirely within the range and their
+    /// leaves are counted from the number of keys in their headers.
+    pub(crate) fn count_range<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        range: impl RangeBounds<K>,
+    ) -> Result<u64> {
+        let start = range.start_bound().map(K::as_ref);
+        let end = range.end_bound().map(K::as_ref);
+
+        self.count_subtree(self.root_page_id, start, end, false)
+    }
+
+    /// Count the keys within `start..end` under `page_id`, all of them if it
+    /// is `covered` by the range.
+    fn count_subtree(
+        &mut self,
+        page_id: LogicalPageId,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        covered: bool,
+    ) -> Result<u64> {
+        let page = self.read_page(page_id)?;
+
+        if Node::is_leaf_page(&page) {
+            let count = if covered {
+                Node::count_keys(page_id, &page, Bound::Unbounded, Bound::Unbounded)?
+            } else {
+                Node::count_keys(page_id, &page, start, end)?
+            };
+
+            return Ok(count as u64);
+        }
+
+        let node = Node::decode(page_id, &page)?;
+        drop(page);
+
+        // Children strictly between the ones holding the ends of the range
+        // are separated from the ends by keys that lie within it.
+        let first = match start {
+            Bound::Included(key) | Bound::Excluded(key) if !covered => node.child_index(key),
+            _ => 0,
+        };
+        let last = match end {
+            Bound::Included(key) | Bound::Excluded(key) if !covered => node.child_index(key),
+            _ => node.children.len() - 1,
+        };
+
+        let mut count = 0;
+
+        for idx in first..=last {
+            let covered = covered
+                || ((idx > first || matches!(start, Bound::Unbounded))
+                    && (idx < last || matches!(end, Bound::Unbounded)));
+
+            count += self.count_subtree(node.children[idx], start, end, covered)?;
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
+
+    #[test]
+    fn count_range() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        assert_eq!(tree.count_range::<[u8]>(..).unwrap(), 0);
+
+        // Long keys keep the fanout small enough for three levels.
+        let key = |i: u64| {
+            let mut key = i.to_be_bytes().to_vec();
+            key.resize(256, 0);
+            key
+        };
+
+        for i in 0..5_000u64 {
+            tree.put(&key(i * 2), &[1; 64]).unwrap();
+        }
+        tree.commit().unwrap();
+        assert!(tree.height().unwrap() > 2);
+
+        let cases = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(key(100)), Bound::Excluded(key(9_000))),
+            (Bound::Excluded(key(100)), Bound::Included(key(9_000))),
+            (Bound::Included(key(101)), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Excluded(key(7))),
+            (Bound::Included(key(4_000)), Bound::Included(key(4_000))),
+            (Bound::Included(key(20_000)), Bound::Unbounded),
+            (Bound::Included(key(50)), Bound::Excluded(key(50))),
+        ];
+
+        let check = |tree: &mut Tree| {
+            for (start, end) in &cases {
+                let start = start.as_ref().map(|key| &key[..]);
+                let end = end.as_ref().map(|key| &key[..]);
+
+                let expected = tree.range::<[u8]>((start, end)).count() as u64;
+                assert_eq!(tree.count_range::<[u8]>((start, end)).unwrap(), expected);
+            }
+        };
+
+        check(&mut tree);
+        assert_eq!(tree.count_range::<[u8]>(..).unwrap(), 5_000);
+
+        // Deletes leave separators behind that are no longer keys.
+        for i in (0..5_000u64).step_by(3) {
+            tree.delete(&key(i * 2)).unwrap();
+        }
+
+        check(&mut tree);
+        assert_eq!(tree.count_range::<[u8]>(..).unwrap(), 3_333);
+    }
+}
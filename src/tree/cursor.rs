@@ -0,0 +1,284 @@
+use crate::{pager::LogicalPageId, Result};
+
+use super::{node::Node, readers::IteratorGuard, Tree};
+
+/// A position in a `Tree` that can be moved in either direction and used to
+/// update the entry it points at.
+///
+/// Unlike `Range` navigation and updates can be interleaved. Updates made
+/// through the cursor are committed right away, like `Db::put` and
+/// `Db::delete`.
+pub struct Cursor<'a> {
+    tree: &'a mut Tree,
+    /// The current leaf, its page id and index into it, `None` once the
+    /// cursor moved off either end.
+    leaf: Option<(LogicalPageId, Node, usize)>,
+    /// Set by `seek_prefix`, entries outside the prefix are treated as if the
+    /// cursor moved off the end.
+    prefix: Option<Vec<u8>>,
+    _guard: IteratorGuard,
+}
+
+impl<'a> Cursor<'a> {
+    pub(super) fn new(tree: &'a mut Tree) -> Self {
+        let guard = IteratorGuard::new(&tree.readers);
+
+        Self {
+            tree,
+            leaf: None,
+            prefix: None,
+            _guard: guard,
+        }
+    }
+
+    /// Move to the first entry with a key equal to or after `key`.
+    pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.prefix = None;
+        self.seek_to(key)
+    }
+
+    /// Move to the first entry with a key starting with `prefix`, the cursor
+    /// stays within the prefix until the next seek.
+    pub fn seek_prefix(&mut self, prefix: &[u8]) -> Result<()> {
+        self.prefix = Some(prefix.to_vec());
+        self.seek_to(prefix)
+    }
+
+    pub fn seek_first(&mut self) -> Result<()> {
+        self.seek(&[])
+    }
+
+    pub fn seek_last(&mut self) -> Result<()> {
+        self.prefix = None;
+
+        let (page_id, node) = self.tree.last_leaf()?;
+        let len = node.keys.len();
+
+        self.leaf = Some((page_id, node, len));
+        self.prev()
+    }
+
+    /// Move to the next entry.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<()> {
+        if let Some((_, _, idx)) = &mut self.leaf {
+            *idx += 1;
+        }
+
+        self.skip_forward()
+    }
+
+    /// Move to the previous entry.
+    pub fn prev(&mut self) -> Result<()> {
+        while let Some((_, node, idx)) = &mut self.leaf {
+            if *idx > 0 {
+                *idx -= 1;
+                return Ok(());
+            }
+
+            self.leaf = match node.metadata.prev_leaf_pos {
+                Some(page_id) => {
+                    let node = self.tree.read_node(page_id)?;
+                    let len = node.keys.len();
+                    Some((page_id, node, len))
+                }
+                None => None,
+            };
+        }
+
+        Ok(())
+    }
+
+    /// The key of the current entry, or `None` if the cursor is not on an
+    /// entry.
+    pub fn key(&self) -> Option<&[u8]> {
+        let (_, node, idx) = self.leaf.as_ref()?;
+        let key = &node.keys.get(*idx)?[..];
+
+        match &self.prefix {
+            Some(prefix) if !key.starts_with(prefix) => None,
+            _ => Some(key),
+        }
+    }
+
+    /// The value of the current entry, or `None` if the cursor is not on an
+    /// entry.
+    ///
+    /// Values too large to be kept in the leaf are read from their overflow
+    /// pages.
+    pub fn value(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.key().is_none() {
+            return Ok(None);
+        }
+
+        let (page_id, node, idx) = match &self.leaf {
+            Some(leaf) => leaf,
+            None => return Ok(None),
+        };
+
+        self.tree.load_value(*page_id, &node.values[*idx]).map(Some)
+    }
+
+    /// Replace the value of the current entry, this does nothing if the
+    /// cursor is not on an entry.
+    pub fn overwrite(&mut self, value: &[u8]) -> Result<()> {
+        let key = match self.key() {
+            Some(key) => key.to_vec(),
+            None => return Ok(()),
+        };
+
+        self.tree.put(&key, value)?;
+        self.tree.commit()?;
+
+        // The update may have split the leaf, find the entry again.
+        self.seek_to(&key)
+    }
+
+    /// Remove the current entry and move to the one after it, returning the
+    /// removed value.
+    pub fn delete(&mut self) -> Result<Option<Vec<u8>>> {
+        let key = match self.key() {
+            Some(key) => key.to_vec(),
+            None => return Ok(None),
+        };
+
+        let value = self.tree.delete(&key)?;
+        self.tree.commit()?;
+
+        self.seek_to(&key)?;
+
+        Ok(value)
+    }
+
+    fn seek_to(&mut self, key: &[u8]) -> Result<()> {
+        let (page_id, node) = self.tree.find_leaf(key)?;
+        let idx = node.keys.partition_point(|k| k[..] < *key);
+
+        self.leaf = Some((page_id, node, idx));
+        self.skip_forward()
+    }
+
+    /// Move forward past the end of the current leaf, and any empty ones.
+    fn skip_forward(&mut self) -> Result<()> {
+        while let Some((_, node, idx)) = &self.leaf {
+            if *idx < node.keys.len() {
+                break;
+            }
+
+            self.leaf = match node.metadata.next_leaf_pos {
+                Some(page_id) => Some((page_id, self.tree.read_node(page_id)?, 0)),
+                None => None,
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
+
+    fn tree() -> Tree {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for i in (0..2_000u64).step_by(2) {
+            tree.put(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+
+        tree
+    }
+
+    fn key(cursor: &Cursor<'_>) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(cursor.key()?);
+        Some(u64::from_be_bytes(bytes))
+    }
+
+    #[test]
+    fn navigate() {
+        let mut tree = tree();
+        let mut cursor = tree.cursor();
+
+        assert_eq!(key(&cursor), None);
+
+        cursor.seek(&11u64.to_be_bytes()).unwrap();
+        assert_eq!(key(&cursor), Some(12));
+        assert_eq!(cursor.value().unwrap(), Some(12u64.to_le_bytes().to_vec()));
+
+        cursor.next().unwrap();
+        assert_eq!(key(&cursor), Some(14));
+        cursor.prev().unwrap();
+        cursor.prev().unwrap();
+        assert_eq!(key(&cursor), Some(10));
+
+        // Walk the whole tree in both directions, across leaves.
+        cursor.seek_first().unwrap();
+        for i in (0..2_000).step_by(2) {
+            assert_eq!(key(&cursor), Some(i));
+            cursor.next().unwrap();
+        }
+        assert_eq!(key(&cursor), None);
+
+        cursor.seek_last().unwrap();
+        for i in (0..1_000).rev().map(|i| i * 2) {
+            assert_eq!(key(&cursor), Some(i));
+            cursor.prev().unwrap();
+        }
+        assert_eq!(key(&cursor), None);
+
+        cursor.seek(&5_000u64.to_be_bytes()).unwrap();
+        assert_eq!(key(&cursor), None);
+    }
+
+    #[test]
+    fn prefix() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for key in [&b"a"[..], b"ab", b"abc", b"abd", b"b", b"ba"] {
+            tree.put(key, b"").unwrap();
+        }
+
+        let mut cursor = tree.cursor();
+        cursor.seek_prefix(b"ab").unwrap();
+
+        let mut keys = Vec::new();
+        while let Some(key) = cursor.key() {
+            keys.push(key.to_vec());
+            cursor.next().unwrap();
+        }
+        assert_eq!(keys, vec![b"ab".to_vec(), b"abc".to_vec(), b"abd".to_vec()]);
+
+        cursor.seek_prefix(b"c").unwrap();
+        assert_eq!(cursor.key(), None);
+    }
+
+    #[test]
+    fn positioned_updates() {
+        let mut tree = tree();
+        let mut cursor = tree.cursor();
+
+        // Delete every other entry while walking forward, overwrite the rest.
+        cursor.seek_first().unwrap();
+        while let Some(i) = key(&cursor) {
+            if i % 4 == 0 {
+                assert_eq!(cursor.delete().unwrap(), Some(i.to_le_bytes().to_vec()));
+            } else {
+                cursor.overwrite(&[1; 100]).unwrap();
+                assert_eq!(key(&cursor), Some(i));
+                cursor.next().unwrap();
+            }
+        }
+
+        cursor.seek_first().unwrap();
+        for i in (2..2_000u64).step_by(4) {
+            assert_eq!(key(&cursor), Some(i));
+            assert_eq!(cursor.value().unwrap(), Some(vec![1; 100]));
+            cursor.next().unwrap();
+        }
+        assert_eq!(key(&cursor), None);
+
+        assert_eq!(cursor.delete().unwrap(), None);
+    }
+}
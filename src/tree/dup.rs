@@ -0,0 +1,185 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    iter::FusedIterator,
+};
+
+use crate::{Error, Result};
+
+use super::Tree;
+
+/// Tree flag for keys holding a sorted set of values instead of one value.
+pub(crate) const DUP_SORT: u64 = 1;
+
+/// The values of a key in a dup sort tree, as stored in its value.
+///
+/// Each value is prefixed with its length as a little endian u32, the values
+/// are sorted and unique. A set that doesn't fit next to the key moves to
+/// overflow pages like any large value.
+pub struct DupValues<'a> {
+    rest: &'a [u8],
+}
+
+/// Iterate over the values packed into `stored`, the value of a key in a
+/// database opened with `OpenOptions::dup_sort`, as returned by `range` or
+/// a `Cursor`.
+///
+/// Stops at the first value that is cut short.
+pub fn dup_values(stored: &[u8]) -> DupValues<'_> {
+    DupValues { rest: stored }
+}
+
+impl<'a> Iterator for DupValues<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (len, rest) = self.rest.split_at_checked(4)?;
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+
+        match rest.split_at_checked(len) {
+            Some((value, rest)) => {
+                self.rest = rest;
+                Some(value)
+            }
+            None => {
+                self.rest = &[];
+                None
+            }
+        }
+    }
+}
+
+impl FusedIterator for DupValues<'_> {}
+
+fn encode(values: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let mut stored = Vec::with_capacity(values.iter().map(|value| 4 + value.len()).sum());
+
+    for value in values {
+        let len = u32::try_from(value.len()).map_err(|_| Error::OffsetOverflow("dup value"))?;
+        stored.extend_from_slice(&len.to_le_bytes());
+        stored.extend_from_slice(value);
+    }
+
+    Ok(stored)
+}
+
+impl Tree {
+    pub(crate) fn dup_sort(&self) -> bool {
+        self.pager().tree_flags() & DUP_SORT != 0
+    }
+
+    /// All values of `key` in order, empty if it isn't present.
+    pub(crate) fn get_all(&mut self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.check_dup_sort()?;
+
+        Ok(match self.get(key)? {
+            Some(stored) => dup_values(&stored).map(<[u8]>::to_vec).collect(),
+            None => Vec::new(),
+        })
+    }
+
+    /// Add `value` to the values of `key`, returns false if it was already
+    /// one of them.
+    pub(crate) fn put_dup(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let mut values = self.get_all(key)?;
+
+        match values.binary_search_by(|probe| probe[..].cmp(value)) {
+            Ok(_) => Ok(false),
+            Err(idx) => {
+                values.insert(idx, value.to_vec());
+                self.put(key, &encode(&values)?)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Remove `value` from the values of `key`, and `key` itself once it has
+    /// none left. Returns false if it wasn't one of them.
+    pub(crate) fn delete_dup(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        let mut values = self.get_all(key)?;
+
+        match values.binary_search_by(|probe| probe[..].cmp(value)) {
+            Ok(idx) => {
+                values.remove(idx);
+
+                if values.is_empty() {
+                    self.delete(key)?;
+                } else {
+                    self.put(key, &encode(&values)?)?;
+                }
+
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn check_dup_sort(&self) -> Result<()> {
+        if self.dup_sort() {
+            Ok(())
+        } else {
+            Err(Error::NotDupSort)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
+
+    fn dup_sort_tree() -> Tree {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+        pager.set_tree_flags(DUP_SORT);
+
+        Tree::open(pager).unwrap()
+    }
+
+    #[test]
+    fn sorted_values() {
+        let mut tree = dup_sort_tree();
+
+        for value in [&b"c"[..], b"a", b"b", b"a"] {
+            tree.put_dup(b"key", value).unwrap();
+        }
+        tree.put_dup(b"other", b"z").unwrap();
+
+        assert_eq!(tree.get_all(b"key").unwrap(), vec![b"a", b"b", b"c"]);
+        assert!(!tree.put_dup(b"key", b"b").unwrap());
+
+        assert!(tree.delete_dup(b"key", b"b").unwrap());
+        assert!(!tree.delete_dup(b"key", b"b").unwrap());
+        assert_eq!(tree.get_all(b"key").unwrap(), vec![b"a", b"c"]);
+
+        assert!(tree.delete_dup(b"key", b"a").unwrap());
+        assert!(tree.delete_dup(b"key", b"c").unwrap());
+        assert!(tree.get(b"key").unwrap().is_none());
+        assert_eq!(tree.get_all(b"other").unwrap(), vec![b"z"]);
+    }
+
+    #[test]
+    fn many_values_overflow() {
+        let mut tree = dup_sort_tree();
+
+        for i in (0..2_000u32).rev() {
+            assert!(tree.put_dup(b"row ids", &i.to_be_bytes()).unwrap());
+        }
+        tree.commit().unwrap();
+
+        let values = tree.get_all(b"row ids").unwrap();
+        assert_eq!(values.len(), 2_000);
+        assert!(values.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let stored = tree.get(b"row ids").unwrap().unwrap();
+        assert_eq!(dup_values(&stored).count(), 2_000);
+        // A truncated set stops at the cut.
+        assert_eq!(dup_values(&stored[..4 + 4 + 2]).count(), 1);
+    }
+
+    #[test]
+    fn not_dup_sort() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        assert!(matches!(tree.get_all(b"key"), Err(Error::NotDupSort)));
+        assert!(matches!(tree.put_dup(b"key", b"a"), Err(Error::NotDupSort)));
+    }
+}
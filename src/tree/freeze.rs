@@ -0,0 +1,80 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::{Error, Result};
+
+use super::Tree;
+
+/// Keeps the `Db` it was taken from frozen, every update and commit fails
+/// with `Error::Frozen` while reads go on as usual.
+///
+/// Meant for backups, migrations and handing a database over to another
+/// process. The database is unfrozen once every guard taken from it has
+/// been dropped or `unfreeze`d. Guards can be sent to other threads.
+#[derive(Debug)]
+pub struct FreezeGuard {
+    frozen: Arc<AtomicUsize>,
+}
+
+impl FreezeGuard {
+    /// Let writes resume, the same as dropping the guard.
+    pub fn unfreeze(self) {}
+}
+
+impl Drop for FreezeGuard {
+    fn drop(&mut self) {
+        self.frozen.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl Tree {
+    pub(crate) fn freeze(&self) -> FreezeGuard {
+        self.frozen.fetch_add(1, Ordering::Acquire);
+
+        FreezeGuard {
+            frozen: self.frozen.clone(),
+        }
+    }
+
+    pub(crate) fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Acquire) > 0
+    }
+
+    pub(super) fn check_not_frozen(&self) -> Result<()> {
+        if self.is_frozen() {
+            Err(Error::Frozen)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
+
+    #[test]
+    fn freeze() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+        tree.put(b"before", b"1").unwrap();
+
+        let guard = tree.freeze();
+        let nested = tree.freeze();
+
+        // Updates made before the freeze can't be committed either.
+        assert!(matches!(tree.commit(), Err(Error::Frozen)));
+        assert!(matches!(tree.put(b"key", b"value"), Err(Error::Frozen)));
+        assert!(matches!(tree.delete(b"before"), Err(Error::Frozen)));
+        assert_eq!(tree.get(b"before").unwrap(), Some(b"1".to_vec()));
+
+        guard.unfreeze();
+        assert!(tree.is_frozen());
+        drop(nested);
+
+        tree.put(b"key", b"value").unwrap();
+        tree.commit().unwrap();
+    }
+}
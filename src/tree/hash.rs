@@ -0,0 +1,162 @@
+use crate::{pager::LogicalPageId, progress, Result};
+
+use super::{sha256::Sha256, Tree};
+
+/// A SHA-256 hash of the contents of a subtree.
+pub type TreeHash = [u8; 32];
+
+const LEAF: u8 = 0;
+const INTERNAL: u8 = 1;
+
+impl Tree {
+    /// Merkle hash of the whole tree.
+    ///
+    /// A leaf hashes its keys and values, whether values are stored inline
+    /// or in overflow pages doesn't matter. An internal node hashes its
+    /// separators and the hashes of its children. Trees with the same hash
+    /// hold the same entries in the same shape, trees with the same entries
+    /// but a different history of splits and merges hash differently.
+    ///
+    /// The hashes aren't stored, every call reads the whole tree.
+    pub(crate) fn root_hash(&mut self) -> Result<TreeHash> {
+        self.hash_tree(&mut |_, _, _| {})
+    }
+
+    /// Hashes of the nodes `depth` levels below the root in key order, each
+    /// along with the lowest key it can hold.
+    ///
+    /// Comparing these between two trees of the same shape narrows down
+    /// where they differ, or which subtree of one of them is corrupt.
+    pub(crate) fn hashes_at_depth(&mut self, depth: usize) -> Result<Vec<(Vec<u8>, TreeHash)>> {
+        let mut hashes = Vec::new();
+
+        self.hash_tree(&mut |node_depth, low, hash| {
+            if node_depth == depth {
+                hashes.push((low.to_vec(), hash));
+            }
+        })?;
+
+        Ok(hashes)
+    }
+
+    /// Hash the whole tree like `subtree_hash`, reporting the nodes hashed
+    /// so far as progress.
+    fn hash_tree(&mut self, visit: &mut dyn FnMut(usize, &[u8], TreeHash)) -> Result<TreeHash> {
+        let progress = self.progress.clone();
+        let total = match progress {
+            Some(_) => self.node_count_by_level()?.iter().sum::<usize>() as u64,
+            None => 0,
+        };
+
+        let mut hashed = 0;
+        self.subtree_hash(self.root_page_id, &[], 0, &mut |depth, low, hash| {
+            visit(depth, low, hash);
+
+            hashed += 1;
+            progress::report(progress.as_ref(), hashed, total);
+        })
+    }
+
+    /// Hash the subtree at `page_id`, calling `visit` with the depth, lowest
+    /// key and hash of every node in it after its children.
+    fn subtree_hash(
+        &mut self,
+        page_id: LogicalPageId,
+        low: &[u8],
+        depth: usize,
+        visit: &mut dyn FnMut(usize, &[u8], TreeHash),
+    ) -> Result<TreeHash> {
+        self.check_cancelled()?;
+        let node = self.read_node(page_id)?;
+        let mut hasher = Sha256::new();
+
+        if node.is_leaf() {
+            hasher.update(&[LEAF]);
+
+            for (key, stored) in node.keys.iter().zip(&node.values) {
+                let value = self.load_value(page_id, stored)?;
+
+                hasher.update(&(key.len() as u64).to_le_bytes());
+                hasher.update(key);
+                hasher.update(&(value.len() as u64).to_le_bytes());
+                hasher.update(&value);
+            }
+        } else {
+            hasher.update(&[INTERNAL]);
+
+            for key in &node.keys {
+                hasher.update(&(key.len() as u64).to_le_bytes());
+                hasher.update(key);
+            }
+
+            for (idx, child) in node.children.iter().enumerate() {
+                let child_low = match idx {
+                    0 => low,
+                    _ => &node.keys[idx - 1][..],
+                };
+
+                hasher.update(&self.subtree_hash(*child, child_low, depth + 1, visit)?);
+            }
+        }
+
+        let hash = hasher.finish();
+        visit(depth, low, hash);
+
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pager::{test::mock::MemoryFile, DWALPager, PAGE_CAPACITY};
+
+    use super::*;
+
+    fn tree() -> Tree {
+        Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn root_hash() {
+        let mut a = tree();
+        let mut b = tree();
+        assert_eq!(a.root_hash().unwrap(), b.root_hash().unwrap());
+
+        for i in 0..2_000u64 {
+            a.put(&i.to_be_bytes(), &[i as u8; 16]).unwrap();
+            b.put(&i.to_be_bytes(), &[i as u8; 16]).unwrap();
+        }
+        a.put(b"large", &[7; 3 * PAGE_CAPACITY]).unwrap();
+        b.put(b"large", &[7; 3 * PAGE_CAPACITY]).unwrap();
+        assert_eq!(a.root_hash().unwrap(), b.root_hash().unwrap());
+
+        // Only the leaf holding the changed entry hashes differently.
+        b.put(&1_234u64.to_be_bytes(), &[0; 16]).unwrap();
+        assert_ne!(a.root_hash().unwrap(), b.root_hash().unwrap());
+
+        let leaf_depth = a.height().unwrap() - 1;
+        assert_eq!(b.height().unwrap() - 1, leaf_depth);
+        let a_leaves = a.hashes_at_depth(leaf_depth).unwrap();
+        let b_leaves = b.hashes_at_depth(leaf_depth).unwrap();
+        assert_eq!(a_leaves.len(), b_leaves.len());
+        assert_eq!(
+            a_leaves.len(),
+            *a.node_count_by_level().unwrap().last().unwrap()
+        );
+
+        let differing = a_leaves
+            .iter()
+            .zip(&b_leaves)
+            .filter(|(a, b)| a != b)
+            .map(|(a, _)| a.0.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(differing.len(), 1);
+        assert!(differing[0][..] <= 1_234u64.to_be_bytes()[..]);
+
+        // Changing it back restores the hash.
+        b.put(&1_234u64.to_be_bytes(), &[1_234u64 as u8; 16])
+            .unwrap();
+        assert_eq!(a.root_hash().unwrap(), b.root_hash().unwrap());
+        assert!(a.hashes_at_depth(10).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use std::{fmt, ops::RangeBounds};
+
+use crate::{progress, Error, Result};
+
+use super::{range::Range, Tree};
+
+/// Picks the value to keep from the key, the existing value and the value
+/// of the tree merged from.
+type Resolver = Box<dyn FnMut(&[u8], &[u8], &[u8]) -> Vec<u8>>;
+
+/// Which value a merge keeps for a key present in both trees.
+pub enum ConflictPolicy {
+    /// Keep the value already in the tree merged into.
+    KeepExisting,
+    /// Take the value of the tree merged from.
+    PreferSource,
+    /// Take the value of whichever tree committed the later version, the
+    /// tree merged from on a tie. Versions only order the commits of copies
+    /// of the same database, like shards split off from it.
+    KeepNewest,
+    /// Call with the key, the existing value and the value of the tree
+    /// merged from, the value returned is kept.
+    Resolve(Resolver),
+}
+
+impl fmt::Debug for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::KeepExisting => f.write_str("KeepExisting"),
+            ConflictPolicy::PreferSource => f.write_str("PreferSource"),
+            ConflictPolicy::KeepNewest => f.write_str("KeepNewest"),
+            ConflictPolicy::Resolve(_) => f.write_str("Resolve(..)"),
+        }
+    }
+}
+
+impl Tree {
+    /// Add every entry of `other` to this tree, a key present in both keeps
+    /// the value picked by `conflict`. Returns the number of entries
+    /// written.
+    ///
+    /// `other` is read leaf by leaf in key order, so consecutive writes land
+    /// in the same leaves of this tree while they are still cached. Values
+    /// are merged as stored, the values of a key in a dup sort tree aren't
+    /// combined. Progress is reported in leaves of `other` read.
+    pub(crate) fn merge_from(
+        &mut self,
+        other: &mut Tree,
+        mut conflict: ConflictPolicy,
+    ) -> Result<u64> {
+        self.check_not_frozen()?;
+
+        if let ConflictPolicy::KeepNewest = conflict {
+            let ours = self.pager().committed_version();
+            let theirs = other.pager().committed_version();
+
+            conflict = if ours > theirs {
+                ConflictPolicy::KeepExisting
+            } else {
+                ConflictPolicy::PreferSource
+            };
+        }
+
+        // Progress is counted in leaves of `other`.
+        let progress = self.progress.clone();
+        let total = match progress {
+            Some(_) => other
+                .node_count_by_level()?
+                .last()
+                .map_or(0, |&leaves| leaves as u64),
+            None => 0,
+        };
+
+        // The scan of `other` is cancelled along with this tree's updates.
+        let mut range = other.range::<[u8]>(..);
+        if let Some(token) = self.cancel.clone() {
+            range = range.cancel_on(token);
+        }
+
+        let mut written = 0;
+        let mut leaves_read = 0;
+        while let Some(entry) = range.next() {
+            let (key, value) = entry?;
+
+            if range.leaves_read() != leaves_read {
+                leaves_read = range.leaves_read();
+                progress::report(progress.as_ref(), leaves_read, total);
+            }
+
+            let value = match &mut conflict {
+                ConflictPolicy::PreferSource => value,
+                ConflictPolicy::KeepExisting => {
+                    if self.contains(&key)? {
+                        continue;
+                    }
+                    value
+                }
+                ConflictPolicy::Resolve(resolve) => match self.get(&key)? {
+                    Some(existing) => {
+                        let resolved = resolve(&key, &existing, &value);
+                        if resolved == existing {
+                            continue;
+                        }
+                        resolved
+                    }
+                    None => value,
+                },
+                ConflictPolicy::KeepNewest => unreachable!("resolved to a side above"),
+            };
+
+            self.put(&key, &value)?;
+            written += 1;
+        }
+
+        Ok(written)
+    }
+
+    /// Replace the entries with keys in `range` by `entries`, which have to
+    /// be in key order and within `range`. Returns the number of entries
+    /// written or deleted.
+    ///
+    /// The old and new entries are walked side by side, entries whose value
+    /// is unchanged are left alone so only the leaves holding changes are
+    /// rewritten. Entries out of order or outside `range` fail with
+    /// `Error::Unordered` once the entries before them were applied, the
+    /// caller rolls back to undo those.
+    pub(crate) fn replace_range<K, I, EK, EV>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        entries: I,
+    ) -> Result<u64>
+    where
+        K: AsRef<[u8]> + ?Sized,
+        I: IntoIterator<Item = (EK, EV)>,
+        EK: AsRef<[u8]>,
+        EV: AsRef<[u8]>,
+    {
+        self.check_not_frozen()?;
+
+        let start = range.start_bound().map(|key| key.as_ref());
+        let end = range.end_bound().map(|key| key.as_ref());
+
+        // Keys can't be deleted while the range borrows the tree, so collect
+        // them first.
+        let mut old = Vec::new();
+        for entry in Range::new(self, start, end) {
+            old.push(entry?.0);
+        }
+        let mut old = old.into_iter().peekable();
+
+        let mut written = 0;
+        let mut last: Option<Vec<u8>> = None;
+
+        for (key, value) in entries {
+            self.check_cancelled()?;
+            let (key, value) = (key.as_ref(), value.as_ref());
+
+            if last.as_deref().is_some_and(|last| last >= key)
+                || !RangeBounds::<[u8]>::contains(&(start, end), key)
+            {
+                return Err(Error::Unordered);
+            }
+            last = Some(key.to_vec());
+
+            while let Some(old_key) = old.next_if(|old_key| old_key.as_slice() < key) {
+                self.delete(&old_key)?;
+                written += 1;
+            }
+
+            if old.next_if(|old_key| old_key.as_slice() == key).is_some()
+                && self.get(key)?.as_deref() == Some(value)
+            {
+                continue;
+            }
+
+            self.put(key, value)?;
+            written += 1;
+        }
+
+        for old_key in old {
+            self.delete(&old_key)?;
+            written += 1;
+        }
+
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
+
+    fn tree(entries: &[(&[u8], &[u8])]) -> Tree {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+        for (key, value) in entries {
+            tree.put(key, value).unwrap();
+        }
+        tree.commit().unwrap();
+        tree
+    }
+
+    fn entries(tree: &mut Tree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        tree.range::<[u8]>(..).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn conflict_policies() {
+        let ours: &[(&[u8], &[u8])] = &[(b"a", b"1"), (b"b", b"1")];
+        let theirs: &[(&[u8], &[u8])] = &[(b"b", b"2"), (b"c", b"2")];
+
+        let merged = |conflict| {
+            let mut tree = tree(ours);
+            let written = tree.merge_from(&mut self::tree(theirs), conflict).unwrap();
+            (written, entries(&mut tree))
+        };
+        let expected = |b: &[u8]| {
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b.to_vec()),
+                (b"c".to_vec(), b"2".to_vec()),
+            ]
+        };
+
+        assert_eq!(merged(ConflictPolicy::KeepExisting), (1, expected(b"1")));
+        assert_eq!(merged(ConflictPolicy::PreferSource), (2, expected(b"2")));
+        // Both committed the same number of versions, the source wins.
+        assert_eq!(merged(ConflictPolicy::KeepNewest), (2, expected(b"2")));
+
+        let resolve = ConflictPolicy::Resolve(Box::new(|key, ours, theirs| {
+            assert_eq!(key, b"b");
+            [ours, theirs].concat()
+        }));
+        assert_eq!(merged(resolve), (2, expected(b"12")));
+    }
+
+    #[test]
+    fn keep_newest() {
+        let mut older = tree(&[(b"a", b"old")]);
+        let mut newer = tree(&[(b"a", b"new")]);
+        newer.commit().unwrap();
+
+        older
+            .merge_from(&mut newer, ConflictPolicy::KeepNewest)
+            .unwrap();
+        assert_eq!(older.get(b"a").unwrap(), Some(b"new".to_vec()));
+
+        let mut older = tree(&[(b"a", b"old")]);
+        newer
+            .merge_from(&mut older, ConflictPolicy::KeepNewest)
+            .unwrap();
+        assert_eq!(newer.get(b"a").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn replace_range() {
+        let mut tree = tree(&[]);
+        for i in 0..2_000u32 {
+            tree.put(&i.to_be_bytes(), &[i as u8; 64]).unwrap();
+        }
+        tree.commit().unwrap();
+
+        // Of 500..1500 multiples of four are dropped, other multiples of ten
+        // change and multiples of a hundred gain a key after them.
+        let mut new = Vec::new();
+        for i in 500..1_500u32 {
+            let key = i.to_be_bytes().to_vec();
+            match i {
+                _ if i % 4 == 0 => {}
+                _ if i % 10 == 0 => new.push((key.clone(), vec![0; 64])),
+                _ => new.push((key.clone(), vec![i as u8; 64])),
+            }
+            if i % 100 == 0 {
+                new.push(([&key[..], &[1]].concat(), vec![1; 64]));
+            }
+        }
+
+        let mut expected = entries(&mut tree);
+        expected.retain(|(key, _)| key[..] < 500u32.to_be_bytes()[..]);
+        expected.extend(new.iter().cloned());
+        expected.extend((1_500..2_000u32).map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 64])));
+
+        let (start, end) = (500u32.to_be_bytes(), 1_500u32.to_be_bytes());
+        let written = tree
+            .replace_range(start.to_vec()..end.to_vec(), new)
+            .unwrap();
+        tree.commit().unwrap();
+
+        assert_eq!(written, 250 + 50 + 10);
+        assert_eq!(entries(&mut tree), expected);
+
+        // Nothing changes when the entries are the same.
+        let same = expected.clone();
+        assert_eq!(tree.replace_range::<[u8], _, _, _>(.., same).unwrap(), 0);
+
+        let unordered = vec![(b"b", b"1"), (b"a", b"1")];
+        assert!(matches!(
+            tree.replace_range::<[u8], _, _, _>(.., unordered),
+            Err(Error::Unordered)
+        ));
+        let outside = vec![(b"b", b"1")];
+        assert!(matches!(
+            tree.replace_range(b"a".to_vec()..b"b".to_vec(), outside),
+            Err(Error::Unordered)
+        ));
+    }
+
+    #[test]
+    fn merge_many() {
+        let mut ours = tree(&[]);
+        let mut theirs = tree(&[]);
+
+        // Interleaved so both trees have many leaves to merge.
+        for i in 0..3_000u32 {
+            let tree = if i % 2 == 0 { &mut ours } else { &mut theirs };
+            tree.put(&i.to_be_bytes(), &[i as u8; 64]).unwrap();
+        }
+        ours.commit().unwrap();
+        theirs.commit().unwrap();
+
+        let written = ours
+            .merge_from(&mut theirs, ConflictPolicy::PreferSource)
+            .unwrap();
+        ours.commit().unwrap();
+
+        assert_eq!(written, 1_500);
+        let merged = entries(&mut ours);
+        assert_eq!(merged.len(), 3_000);
+        for (i, (key, value)) in merged.into_iter().enumerate() {
+            assert_eq!(key, (i as u32).to_be_bytes());
+            assert_eq!(value, vec![i as u8; 64]);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::Tree;
+
+/// Page cache misses of a `Db` by the level of the tree the page missed is
+/// on, see `Db::cache_misses`.
+///
+/// Misses on internal nodes mean the cache can't even hold the upper levels
+/// of the tree, every lookup then reads from the file more than once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheMisses {
+    pub root: u64,
+    /// Internal nodes other than the root.
+    pub internal: u64,
+    /// Leaves other than the root.
+    pub leaf: u64,
+    /// Pages of values too large to be kept in their leaf.
+    pub overflow: u64,
+}
+
+pub(super) enum Level {
+    Root,
+    Internal,
+    Leaf,
+    Overflow,
+}
+
+/// Counts of the misses of a tree, shared with its snapshots.
+#[derive(Debug, Default)]
+pub(super) struct MissCounters {
+    root: AtomicU64,
+    internal: AtomicU64,
+    leaf: AtomicU64,
+    overflow: AtomicU64,
+}
+
+impl MissCounters {
+    pub(super) fn count(&self, level: Level) {
+        let counter = match level {
+            Level::Root => &self.root,
+            Level::Internal => &self.internal,
+            Level::Leaf => &self.leaf,
+            Level::Overflow => &self.overflow,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Tree {
+    pub(crate) fn cache_misses(&self) -> CacheMisses {
+        CacheMisses {
+            root: self.misses.root.load(Ordering::Relaxed),
+            internal: self.misses.internal.load(Ordering::Relaxed),
+            leaf: self.misses.leaf.load(Ordering::Relaxed),
+            overflow: self.misses.overflow.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pager::{test::mock::MemoryFile, DWALPager},
+        tree::overflow::CHUNK_LEN,
+    };
+
+    #[test]
+    fn misses_by_level() {
+        let file = MemoryFile::default();
+        let mut tree = Tree::open(DWALPager::recover(file.clone()).unwrap()).unwrap();
+
+        for i in 0..2_000u32 {
+            tree.put(&i.to_be_bytes(), &[0; 64]).unwrap();
+        }
+        tree.put(b"large", &[1; 2 * CHUNK_LEN]).unwrap();
+        tree.close().unwrap();
+        drop(tree);
+
+        // Nothing is cached after reopening.
+        let mut tree = Tree::open(DWALPager::recover(file).unwrap()).unwrap();
+        assert!(tree.height().unwrap() >= 2);
+        let misses = tree.cache_misses();
+
+        tree.get(b"large").unwrap().unwrap();
+        let after = tree.cache_misses();
+        assert_eq!(after.root, misses.root);
+        assert_eq!(after.leaf, misses.leaf + 1);
+        assert_eq!(after.overflow, misses.overflow + 2);
+
+        // Cached now, and snapshots count towards the same misses.
+        let mut snapshot = tree.snapshot().unwrap();
+        snapshot.get(b"large").unwrap().unwrap();
+        assert_eq!(tree.cache_misses(), after);
+
+        snapshot.get(&1_000u32.to_be_bytes()).unwrap().unwrap();
+        assert_eq!(tree.cache_misses().leaf, after.leaf + 1);
+    }
+}
@@ -1,67 +1,1066 @@
+mod count;
+mod cursor;
+mod dup;
+mod freeze;
+mod hash;
+mod merge;
+mod misses;
 mod node;
-//
-// use crate::{
-//     page::{Kind, Page},
-//     pager::{PageId, Pager},
-//     Result,
-// };
-// use std::fs::OpenOptions;
-// use std::path::Path;
-
-// const PAGE_SIZE: usize = 4096;
-// const CACHE_SIZE: usize = 1024;
-
-// #[derive(Debug)]
-// pub struct Tree {
-//     pager: Pager,
-//     root_page_id: PageId,
-// }
-
-// impl Tree {
-//     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
-//         let file = OpenOptions::new()
-//             .create(true)
-//             .write(true)
-//             .read(true)
-//             .truncate(true)
-//             .open(path)?;
-
-//         let mut pager = Pager::new(PAGE_SIZE, file, CACHE_SIZE);
-
-//         let page = Page::new_leaf(PAGE_SIZE);
-//         let root_page_id = pager.alloc_page(page)?.id();
-
-//         Ok(Self {
-//             pager,
-//             root_page_id,
-//         })
-//     }
-
-//     pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-//         let root_page = self.pager.get(self.root_page_id)?;
-//         let root = root_page.page_mut();
-
-//         match &mut root.kind {
-//             Kind::Leaf(leaf) => leaf.put(key, value),
-//         }
-
-//         Ok(())
-//     }
-
-//     pub fn get(&mut self, key: &Vec<u8>) -> Result<Option<&(Vec<u8>, Vec<u8>)>> {
-//         let root_page = self.pager.get(self.root_page_id)?;
-//         let root = root_page.page();
-
-//         let val = match &root.kind {
-//             Kind::Leaf(leaf) => leaf.get(&key),
-//         };
-
-//         Ok(val)
-//     }
-
-//     pub fn flush_all(&mut self) -> Result<()> {
-//         self.pager.flush_all()?;
-
-//         Ok(())
-//     }
-// }
+mod overflow;
+mod range;
+mod readers;
+mod sha256;
+mod shape;
+mod snapshot;
+mod value;
+
+use std::{
+    num::NonZeroUsize,
+    ops::RangeBounds,
+    sync::{atomic::AtomicUsize, Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{
+    cancel::{self, CancelToken},
+    pager::{
+        CacheUsage, CommitInfo, CommitStats, DWALPager, FreeListStats, LogicalPageId, PageBuf,
+        RepairPolicy, Version, WriteBackStats, PAGE_CAPACITY,
+    },
+    progress::ProgressFn,
+    Error, Result,
+};
+
+use self::{
+    misses::{Level, MissCounters},
+    node::Node,
+    readers::Readers,
+};
+
+pub(crate) use self::dup::DUP_SORT;
+
+pub use self::{
+    cursor::Cursor,
+    dup::{dup_values, DupValues},
+    freeze::FreezeGuard,
+    hash::TreeHash,
+    merge::ConflictPolicy,
+    misses::CacheMisses,
+    range::Range,
+    readers::ReaderCounts,
+    shape::FanoutStats,
+    snapshot::{IntoRange, Snapshot},
+    value::ValueGuard,
+};
+
+/// Largest encoded entry that can be inserted. Keeping entries well below a
+/// page guarantees both halves of a split node fit in a page again.
+const MAX_ENTRY_SIZE: usize = PAGE_CAPACITY / 4;
+
+/// A value removed from a leaf, as stored, along with the leaf's page id.
+type Removed = (LogicalPageId, Vec<u8>);
+
+/// A b-tree stored in the pages of a `DWALPager`.
+///
+/// Every update writes a new copy of the nodes it touches via
+/// `atomic_update`, so a version only becomes visible once it is committed.
+pub(crate) struct Tree {
+    /// Shared with the snapshots taken of the tree, which may be on other
+    /// threads.
+    pager: Arc<Mutex<DWALPager>>,
+    root_page_id: LogicalPageId,
+    /// The root as of the last commit, which is what a snapshot sees.
+    committed_root_page_id: LogicalPageId,
+    /// The version reads are made at, `None` to read the latest, including
+    /// uncommitted updates. Only set for snapshots.
+    version: Option<Version>,
+    /// Number of `FreezeGuard`s alive, updates fail while there are any.
+    frozen: Arc<AtomicUsize>,
+    /// Open snapshots and iterators, shared with the snapshots.
+    readers: Arc<Readers>,
+    /// Cache misses by level, shared with the snapshots.
+    misses: Arc<MissCounters>,
+    /// Checked between pages by long operations, see `set_cancel`.
+    cancel: Option<CancelToken>,
+    /// Told about the progress of long operations, see `set_progress`.
+    progress: Option<ProgressFn>,
+}
+
+impl Tree {
+    /// Open the tree stored in `pager`, creating an empty one if the pager
+    /// has no root page yet.
+    pub(crate) fn open(mut pager: DWALPager) -> Result<Self> {
+        let root_page_id = match pager.root_page() {
+            Some(root_page_id) => root_page_id,
+            None => {
+                let root_page_id = pager.new_page_id()?;
+
+                let mut page = pager.new_page_buffer()?;
+                page.init();
+                Node::new_leaf().encode(&mut page)?;
+
+                pager.update_page(root_page_id, page)?;
+                pager.set_root_page(root_page_id);
+                pager.commit()?;
+
+                root_page_id
+            }
+        };
+
+        Ok(Self {
+            pager: Arc::new(Mutex::new(pager)),
+            root_page_id,
+            committed_root_page_id: root_page_id,
+            version: None,
+            frozen: Arc::default(),
+            readers: Arc::default(),
+            misses: Arc::default(),
+            cancel: None,
+            progress: None,
+        })
+    }
+
+    /// Check that the root and the nodes it points to decode, that the
+    /// root's keys are in order and that its children are all leaves or all
+    /// internal nodes.
+    ///
+    /// This catches gross corruption, like a root pointing at a page that
+    /// isn't a node, at the cost of reading one level of the tree.
+    pub(crate) fn verify_root_path(&mut self) -> Result<()> {
+        let root_page_id = self.root_page_id;
+        let root = self.read_node(root_page_id)?;
+
+        if root.keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(Error::Corruption {
+                page: root_page_id,
+                reason: "root keys out of order",
+            });
+        }
+
+        let mut child_is_leaf = None;
+        for child_page_id in &root.children {
+            self.check_cancelled()?;
+            let child = self.read_node(*child_page_id)?;
+
+            if *child_is_leaf.get_or_insert(child.is_leaf()) != child.is_leaf() {
+                return Err(Error::Corruption {
+                    page: *child_page_id,
+                    reason: "leaves and internal nodes mixed under the root",
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A read only view of the tree as of the last commit, see `Snapshot`.
+    ///
+    /// Fails with `Error::TooManySnapshots` if the limit set by
+    /// `set_max_snapshots` is reached.
+    pub(crate) fn snapshot(&mut self) -> Result<Snapshot> {
+        self.readers.open_snapshot()?;
+
+        let version = self.pager().committed_version();
+        self.pager().pin(version);
+
+        Ok(Snapshot::new(Tree {
+            pager: self.pager.clone(),
+            root_page_id: self.committed_root_page_id,
+            committed_root_page_id: self.committed_root_page_id,
+            version: Some(version),
+            frozen: self.frozen.clone(),
+            readers: self.readers.clone(),
+            misses: self.misses.clone(),
+            cancel: None,
+            progress: None,
+        }))
+    }
+
+    pub(crate) fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (page_id, node) = self.find_leaf(key)?;
+
+        match node.get(key) {
+            Some(stored) => Ok(Some(self.load_value(page_id, stored)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether `key` is present, without loading its value.
+    pub(crate) fn contains(&mut self, key: &[u8]) -> Result<bool> {
+        let (_, node) = self.find_leaf(key)?;
+
+        Ok(node.get(key).is_some())
+    }
+
+    /// Iterate over the entries with keys in `range`, in key order.
+    pub(crate) fn range<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        range: impl RangeBounds<K>,
+    ) -> Range<'_> {
+        let start = range.start_bound().map(K::as_ref);
+        let end = range.end_bound().map(K::as_ref);
+
+        Range::new(self, start, end)
+    }
+
+    /// Delete the entries in `range` for which `keep` returns false, looking
+    /// at no more than `budget` entries.
+    ///
+    /// If the range wasn't scanned completely the key to continue from is
+    /// returned, passing `key..end` as the range of the next call resumes the
+    /// scan. This keeps the work, and the size of the commit, of each call
+    /// bounded for incremental cleanups.
+    pub(crate) fn retain<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        range: impl RangeBounds<K>,
+        mut keep: impl FnMut(&[u8], &[u8]) -> bool,
+        budget: NonZeroUsize,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut doomed = Vec::new();
+        let mut resume = None;
+
+        // Entries can't be deleted while the range borrows the tree, so
+        // collect them first.
+        for (n, entry) in self.range(range).enumerate() {
+            let (key, value) = entry?;
+
+            if n == budget.get() {
+                resume = Some(key);
+                break;
+            }
+
+            if !keep(&key, &value) {
+                doomed.push(key);
+            }
+        }
+
+        for key in doomed {
+            self.delete(&key)?;
+        }
+
+        Ok(resume)
+    }
+
+    /// A cursor over the tree, it starts out not positioned on any entry.
+    pub(crate) fn cursor(&mut self) -> Cursor<'_> {
+        Cursor::new(self)
+    }
+
+    pub(crate) fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_not_frozen()?;
+
+        // Leaf entries are prefixed with a u16 length for both key and value,
+        // the key has to fit next to at least an overflow reference.
+        if 4 + key.len() + overflow::OVERFLOW_REF_LEN > MAX_ENTRY_SIZE {
+            return Err(Error::KeyTooLarge(key.len()));
+        }
+
+        let stored = self.store_value(key, value)?;
+
+        if let Some((separator, right_page_id)) = self.insert(self.root_page_id, key, &stored)? {
+            // The root split, grow the tree by a level. The old root keeps
+            // its page id and becomes the left child of the new root.
+            let root = Node::new_internal(vec![separator], vec![self.root_page_id, right_page_id]);
+
+            let root_page_id = self.pager().new_page_id()?;
+            self.write_new_node(root_page_id, &root)?;
+
+            self.pager().set_root_page(root_page_id);
+            self.root_page_id = root_page_id;
+        }
+
+        Ok(())
+    }
+
+    /// Remove `key`, returning its value if it was present.
+    ///
+    /// Nodes that become too empty borrow from or are merged with a sibling,
+    /// merged away pages are freed back to the pager.
+    pub(crate) fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_not_frozen()?;
+
+        let (stored, _) = self.remove(self.root_page_id, key)?;
+
+        let value = match stored {
+            Some((page_id, stored)) => {
+                let value = self.load_value(page_id, &stored)?;
+                self.free_value(page_id, &stored)?;
+                Some(value)
+            }
+            None => None,
+        };
+
+        if value.is_some() {
+            let root = self.read_node(self.root_page_id)?;
+
+            // The root lost its last separator, its only child becomes the
+            // new root and the tree shrinks by a level.
+            if !root.is_leaf() && root.keys.is_empty() {
+                let old_root_page_id = self.root_page_id;
+
+                self.root_page_id = root.children[0];
+                self.pager().set_root_page(self.root_page_id);
+
+                let version = self.pager().current_version();
+                self.pager().free(old_root_page_id, version)?;
+            }
+        }
+
+        Ok(value)
+    }
+
+    /// Remove `key` from the subtree rooted at `page_id`, returning its
+    /// stored value and also if the node underflowed and needs to be rebalanced by its parent.
+    fn remove(&mut self, page_id: LogicalPageId, key: &[u8]) -> Result<(Option<Removed>, bool)> {
+        let mut node = self.read_node(page_id)?;
+
+        let value = if node.is_leaf() {
+            match node.remove(key) {
+                Some(value) => (page_id, value),
+                None => return Ok((None, false)),
+            }
+        } else {
+            let idx = node.child_index(key);
+
+            match self.remove(node.children[idx], key)? {
+                (Some(value), true) => {
+                    self.rebalance(&mut node, idx)?;
+                    value
+                }
+                (value, _) => return Ok((value, false)),
+            }
+        };
+
+        self.write_node(page_id, &node)?;
+
+        Ok((Some(value), node.underflows()))
+    }
+
+    /// Fix up the underflowing child at `idx` of `parent` by merging it with
+    /// a sibling, or if the two don't fit in a page, by splitting the pair
+    /// evenly again.
+    fn rebalance(&mut self, parent: &mut Node, idx: usize) -> Result<()> {
+        // Prefer the left sibling, the leftmost child only has a right one.
+        let left_idx = idx.saturating_sub(1);
+
+        let left_page_id = parent.children[left_idx];
+        let right_page_id = parent.children[left_idx + 1];
+
+        let mut left = self.read_node(left_page_id)?;
+        let right = self.read_node(right_page_id)?;
+
+        let separator = parent.keys.remove(left_idx);
+        left.merge(separator, right);
+
+        if left.fits() {
+            parent.children.remove(left_idx + 1);
+
+            if left.is_leaf() {
+                if let Some(next) = left.metadata.next_leaf_pos {
+                    self.set_prev_leaf(next, left_page_id)?;
+                }
+            }
+
+            self.write_node(left_page_id, &left)?;
+
+            let version = self.pager().current_version();
+            self.pager().free(right_page_id, version)?;
+        } else {
+            let (separator, mut right) = left.split();
+
+            if left.is_leaf() {
+                left.metadata.next_leaf_pos = Some(right_page_id);
+                right.metadata.prev_leaf_pos = Some(left_page_id);
+            }
+
+            parent.keys.insert(left_idx, separator);
+
+            self.write_node(left_page_id, &left)?;
+            self.write_node(right_page_id, &right)?;
+        }
+
+        Ok(())
+    }
+
+    /// Insert into the subtree rooted at `page_id`, returning the separator
+    /// and page of the new right sibling if the node had to be split.
+    fn insert(
+        &mut self,
+        page_id: LogicalPageId,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<(Vec<u8>, LogicalPageId)>> {
+        let page = self.read_page(page_id)?;
+
+        // Replacing a value with one of the same length doesn't move any
+        // other entry, so the leaf is patched directly instead of being
+        // decoded, re-encoded and checked for a split.
+        if let Some((offset, len)) = Node::find_value(page_id, &page, key)? {
+            let old = &page.buf()[offset..offset + len];
+
+            if len == value.len() && overflow::is_inline(old) && overflow::is_inline(value) {
+                self.patch_leaf(page_id, &page, offset, value)?;
+                return Ok(None);
+            }
+        }
+
+        let mut node = Node::decode(page_id, &page)?;
+
+        if node.is_leaf() {
+            if let Some(old) = node.get(key) {
+                let old = old.to_vec();
+                self.free_value(page_id, &old)?;
+            }
+
+            node.insert(key, value);
+        } else {
+            let idx = node.child_index(key);
+
+            match self.insert(node.children[idx], key, value)? {
+                Some((separator, right_page_id)) => {
+                    node.insert_child(idx, separator, right_page_id)
+                }
+                // Children keep their logical page id when updated so the
+                // parent is unchanged.
+                None => return Ok(None),
+            }
+        }
+
+        if node.fits() {
+            self.write_node(page_id, &node)?;
+            return Ok(None);
+        }
+
+        let (separator, mut right) = node.split();
+        let right_page_id = self.pager().new_page_id()?;
+
+        if node.is_leaf() {
+            node.metadata.next_leaf_pos = Some(right_page_id);
+            right.metadata.prev_leaf_pos = Some(page_id);
+
+            if let Some(next) = right.metadata.next_leaf_pos {
+                self.set_prev_leaf(next, right_page_id)?;
+            }
+        }
+
+        // The right sibling is a brand new page that nothing points to until
+        // the parent is updated, so it doesn't need to be remapped.
+        self.write_new_node(right_page_id, &right)?;
+        self.write_node(page_id, &node)?;
+
+        Ok(Some((separator, right_page_id)))
+    }
+
+    /// Overwrite a value in the leaf `page` in place with one of the same
+    /// length, `offset` being where `Node::find_value` found it. Small
+    /// values of committed leaves may be kept as deltas, see
+    /// `DWALPager::patch_page`.
+    fn patch_leaf(
+        &mut self,
+        page_id: LogicalPageId,
+        page: &PageBuf,
+        offset: usize,
+        value: &[u8],
+    ) -> Result<()> {
+        let mut pager = self.pager();
+        let version = pager.current_version();
+
+        pager.patch_page(page_id, version, page, offset, value)
+    }
+
+    fn set_prev_leaf(&mut self, page_id: LogicalPageId, prev: LogicalPageId) -> Result<()> {
+        let mut leaf = self.read_node(page_id)?;
+        leaf.metadata.prev_leaf_pos = Some(prev);
+
+        self.write_node(page_id, &leaf)
+    }
+
+    /// Walk down to the rightmost leaf.
+    fn last_leaf(&mut self) -> Result<(LogicalPageId, Node)> {
+        let mut page_id = self.root_page_id;
+
+        loop {
+            let node = self.read_node(page_id)?;
+
+            match node.children.last() {
+                Some(child) if !node.is_leaf() => page_id = *child,
+                _ => return Ok((page_id, node)),
+            }
+        }
+    }
+
+    /// Walk down to the leaf that may contain `key`.
+    fn find_leaf(&mut self, key: &[u8]) -> Result<(LogicalPageId, Node)> {
+        let mut page_id = self.root_page_id;
+
+        loop {
+            let node = self.read_node(page_id)?;
+
+            if node.is_leaf() {
+                return Ok((page_id, node));
+            }
+
+            page_id = node.children[node.child_index(key)];
+        }
+    }
+
+    /// Commit all updates made so far without waiting for them to be
+    /// durable.
+    pub(crate) fn commit(&mut self) -> Result<()> {
+        self.check_not_frozen()?;
+        self.pager().commit_unsynced()?;
+        self.committed_root_page_id = self.root_page_id;
+
+        Ok(())
+    }
+
+    /// Commit all updates made so far, syncing as the pager's
+    /// `CommitPolicy` says.
+    pub(crate) fn commit_grouped(&mut self) -> Result<Version> {
+        self.check_not_frozen()?;
+        let version = self.pager().commit_grouped()?;
+        self.committed_root_page_id = self.root_page_id;
+
+        Ok(version)
+    }
+
+    /// Wait for `version` to be durable, syncing if it isn't yet.
+    pub(crate) fn wait_for_durable(&mut self, version: Version) -> Result<()> {
+        self.pager().wait_for_durable(version)
+    }
+
+    /// Discard all updates since the last commit.
+    pub(crate) fn rollback(&mut self) -> Result<()> {
+        self.pager().rollback()?;
+        self.root_page_id = self.committed_root_page_id;
+
+        Ok(())
+    }
+
+    /// Commit whatever is left and shut the pager down, see
+    /// `DWALPager::close`.
+    pub(crate) fn close(&mut self) -> Result<()> {
+        self.check_not_frozen()?;
+        self.pager().close()?;
+        self.committed_root_page_id = self.root_page_id;
+
+        Ok(())
+    }
+
+    pub(crate) fn was_closed_cleanly(&self) -> bool {
+        self.pager().was_closed_cleanly()
+    }
+
+    pub(crate) fn copy_to(&mut self, dest: &std::fs::File) -> Result<()> {
+        self.pager()
+            .copy_to(dest, self.cancel.as_ref(), self.progress.as_ref())
+    }
+
+    /// Have long operations, like scans, hashing, merges and copies, fail
+    /// with `Error::Cancelled` once `token` is cancelled. Returns the token
+    /// it replaces.
+    pub(crate) fn set_cancel(&mut self, token: Option<CancelToken>) -> Option<CancelToken> {
+        std::mem::replace(&mut self.cancel, token)
+    }
+
+    /// Report the progress of backups, hashing and merges to `progress`.
+    /// Returns the callback it replaces.
+    pub(crate) fn set_progress(&mut self, progress: Option<ProgressFn>) -> Option<ProgressFn> {
+        std::mem::replace(&mut self.progress, progress)
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        cancel::check(self.cancel.as_ref())
+    }
+
+    /// Wait for everything committed so far to be durable.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        let version = self.pager().committed_version();
+        self.pager().wait_for_durable(version)
+    }
+
+    pub(crate) fn cache_usage(&self) -> CacheUsage {
+        self.pager().cache_usage()
+    }
+
+    pub(crate) fn release_memory(&mut self) -> usize {
+        self.pager().release_memory()
+    }
+
+    pub(crate) fn write_back_stats(&self) -> WriteBackStats {
+        self.pager().write_back_stats()
+    }
+
+    pub(crate) fn last_commit_stats(&self) -> Option<CommitStats> {
+        self.pager().last_commit_stats().cloned()
+    }
+
+    pub(crate) fn free_list_stats(&self) -> FreeListStats {
+        self.pager().free_list_stats()
+    }
+
+    pub(crate) fn repaired_pages(&self) -> u64 {
+        self.pager().repaired_pages()
+    }
+
+    pub(crate) fn set_commit_tag(&mut self, tag: [u8; 16]) {
+        self.pager().set_commit_tag(tag);
+    }
+
+    pub(crate) fn commit_info(&self, version: Version) -> Option<CommitInfo> {
+        self.pager().commit_info(version)
+    }
+
+    /// Pick up commits another process made to a file opened read only,
+    /// see `DWALPager::refresh`.
+    pub(crate) fn refresh(&mut self) -> Result<bool> {
+        if !self.pager().refresh()? {
+            return Ok(false);
+        }
+
+        let root_page_id = self.pager().root_page();
+        if let Some(root_page_id) = root_page_id {
+            self.root_page_id = root_page_id;
+            self.committed_root_page_id = root_page_id;
+        }
+
+        Ok(true)
+    }
+
+    fn pager(&self) -> MutexGuard<'_, DWALPager> {
+        // A panic while holding the pager doesn't lock out the tree and its
+        // other snapshots, a commit cut short poisons the pager on its own.
+        self.pager.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn read_node(&mut self, page_id: LogicalPageId) -> Result<Node> {
+        let page = self.read_page(page_id)?;
+        Node::decode(page_id, &page)
+    }
+
+    fn read_page(&mut self, page_id: LogicalPageId) -> Result<PageBuf> {
+        self.read_page_counted(page_id, false)
+    }
+
+    fn read_overflow_page(&mut self, page_id: LogicalPageId) -> Result<PageBuf> {
+        self.read_page_counted(page_id, true)
+    }
+
+    /// Read a page, counting a cache miss by the level of the page.
+    ///
+    /// With `RepairPolicy::Repair` a node read from the file that fails to
+    /// decode is repaired from the write-ahead log if it can be, see
+    /// `DWALPager::repair_page`. Overflow pages can't be checked on their
+    /// own.
+    fn read_page_counted(&mut self, page_id: LogicalPageId, overflow: bool) -> Result<PageBuf> {
+        let version = self.version;
+        let mut pager = self.pager();
+        let version = version.unwrap_or_else(|| pager.current_version());
+
+        let misses = pager.cache_misses();
+        let mut page = pager.read_at(page_id, version)?;
+
+        if pager.cache_misses() != misses {
+            if !overflow
+                && pager.repair_policy() == RepairPolicy::Repair
+                && Node::decode(page_id, &page).is_err()
+                && pager.repair_page(page_id, version)?
+            {
+                page = pager.read_at(page_id, version)?;
+            }
+
+            let level = if overflow {
+                Level::Overflow
+            } else if page_id == self.root_page_id {
+                Level::Root
+            } else if Node::is_leaf_page(&page) {
+                Level::Leaf
+            } else {
+                Level::Internal
+            };
+
+            self.misses.count(level);
+        }
+
+        Ok(page)
+    }
+
+    fn write_new_node(&mut self, page_id: LogicalPageId, node: &Node) -> Result<()> {
+        let mut page = self.pager().new_page_buffer()?;
+        page.init();
+        node.encode(&mut page)?;
+
+        self.pager().update_page(page_id, page)
+    }
+
+    fn write_node(&mut self, page_id: LogicalPageId, node: &Node) -> Result<()> {
+        let mut page = self.pager().new_page_buffer()?;
+        page.init();
+        node.encode(&mut page)?;
+
+        let version = self.pager().current_version();
+        self.pager().atomic_update(page_id, version, page)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, CacheConfig, File, PAGE_SIZE};
+
+    impl Tree {
+        /// All keys in order, following the links between leaves. Also
+        /// checks that walking the links backwards gives the same keys.
+        fn leaf_keys(&mut self) -> Vec<Vec<u8>> {
+            let (first_page_id, _) = self.find_leaf(&[]).unwrap();
+            let (last_page_id, _) = self.last_leaf().unwrap();
+
+            let mut keys = Vec::new();
+            let mut page_id = Some(first_page_id);
+            while let Some(id) = page_id {
+                let leaf = self.read_node(id).unwrap();
+                keys.extend(leaf.keys.iter().cloned());
+                page_id = leaf.metadata.next_leaf_pos;
+            }
+
+            let mut rev_keys = Vec::new();
+            let mut page_id = Some(last_page_id);
+            while let Some(id) = page_id {
+                let leaf = self.read_node(id).unwrap();
+                rev_keys.extend(leaf.keys.iter().rev().cloned());
+                page_id = leaf.metadata.prev_leaf_pos;
+            }
+
+            rev_keys.reverse();
+            assert_eq!(keys, rev_keys);
+
+            keys
+        }
+    }
+
+    fn key(i: u64) -> [u8; 8] {
+        // Spread keys out so inserts land all over the tree.
+        i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes()
+    }
+
+    #[test]
+    fn split_and_reopen() {
+        let file = MemoryFile::default();
+        let mut tree = Tree::open(DWALPager::recover(file.clone()).unwrap()).unwrap();
+
+        let initial_root = tree.root_page_id;
+
+        for i in 0..5_000 {
+            tree.put(&key(i), &[i as u8; 32]).unwrap();
+
+            if i % 100 == 0 {
+                tree.commit().unwrap();
+            }
+        }
+        tree.commit().unwrap();
+
+        assert_ne!(tree.root_page_id, initial_root);
+        let root = tree.read_node(tree.root_page_id).unwrap();
+        assert!(!root.is_leaf());
+        assert!(root.children.len() > 2);
+
+        for i in 0..5_000 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(vec![i as u8; 32]));
+        }
+        assert_eq!(tree.get(b"missing").unwrap(), None);
+        drop(tree);
+
+        let mut tree = Tree::open(DWALPager::recover(file).unwrap()).unwrap();
+
+        for i in (0..5_000).step_by(7) {
+            assert_eq!(tree.delete(&key(i)).unwrap(), Some(vec![i as u8; 32]));
+        }
+
+        for i in 0..5_000 {
+            let expected = if i % 7 == 0 {
+                None
+            } else {
+                Some(vec![i as u8; 32])
+            };
+            assert_eq!(tree.get(&key(i)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn internal_splits() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        // Long keys that only differ at the end keep the separators long and
+        // the fanout small so internal nodes split too, large values make
+        // for many leaves.
+        let long_key = |i: u64| {
+            let mut long_key = vec![0; 248];
+            long_key.extend_from_slice(&key(i));
+            long_key
+        };
+
+        for i in 0..2_000 {
+            tree.put(&long_key(i), &[i as u8; 128]).unwrap();
+        }
+
+        let root = tree.read_node(tree.root_page_id).unwrap();
+        let child = tree.read_node(root.children[0]).unwrap();
+        assert!(!child.is_leaf());
+
+        for i in 0..2_000 {
+            assert_eq!(tree.get(&long_key(i)).unwrap(), Some(vec![i as u8; 128]));
+        }
+    }
+
+    #[test]
+    fn delete_rebalances() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        let value = |i: u64| vec![i as u8; 1 + (i % 64) as usize];
+
+        for i in 0..3_000 {
+            tree.put(&key(i), &value(i)).unwrap();
+        }
+        tree.commit().unwrap();
+
+        // Delete in a different order than inserted, checking the tree as it
+        // shrinks.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut order: Vec<u64> = (0..3_000).collect();
+        for i in (1..order.len()).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            order.swap(i, state as usize % (i + 1));
+        }
+
+        for (n, i) in order.iter().enumerate() {
+            assert_eq!(tree.delete(&key(*i)).unwrap(), Some(value(*i)));
+            assert_eq!(tree.delete(&key(*i)).unwrap(), None);
+
+            if n % 500 == 0 {
+                tree.commit().unwrap();
+
+                let remaining = tree.leaf_keys();
+                let mut expected: Vec<_> =
+                    order[n + 1..].iter().map(|i| key(*i).to_vec()).collect();
+                expected.sort();
+                assert_eq!(remaining, expected);
+            }
+        }
+
+        let root = tree.read_node(tree.root_page_id).unwrap();
+        assert!(root.is_leaf());
+        assert!(root.keys.is_empty());
+    }
+
+    #[test]
+    fn leaves_are_linked() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for i in 0..2_000u64 {
+            tree.put(&i.to_be_bytes(), &[0; 16]).unwrap();
+        }
+
+        let keys = tree.leaf_keys();
+        let expected = (0..2_000u64)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn retain() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for i in 0..1_000u64 {
+            tree.put(&i.to_be_bytes(), &(i % 3).to_be_bytes()).unwrap();
+        }
+
+        let end = 900u64.to_be_bytes();
+        let mut start = 100u64.to_be_bytes().to_vec();
+        let mut calls = 0;
+
+        // Drop every entry whose value is zero, 64 entries at a time.
+        loop {
+            calls += 1;
+
+            let resume = tree
+                .retain(
+                    &start[..]..&end[..],
+                    |_, value| value != 0u64.to_be_bytes(),
+                    NonZeroUsize::new(64).unwrap(),
+                )
+                .unwrap();
+
+            tree.commit().unwrap();
+
+            match resume {
+                Some(key) => start = key,
+                None => break,
+            }
+        }
+
+        assert_eq!(calls, (800 + 63) / 64);
+
+        for i in 0..1_000u64 {
+            let expected = if (100..900).contains(&i) && i % 3 == 0 {
+                None
+            } else {
+                Some((i % 3).to_be_bytes().to_vec())
+            };
+            assert_eq!(tree.get(&i.to_be_bytes()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn overwrite_in_place() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for i in 0..1_000u64 {
+            tree.put(&i.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let mut snapshot = tree.snapshot().unwrap();
+
+        // Same length values are patched into the leaf, other lengths take
+        // the regular path.
+        for i in 0..1_000u64 {
+            let value = if i % 3 == 0 { vec![2; 40] } else { vec![3; 32] };
+            tree.put(&i.to_be_bytes(), &value).unwrap();
+        }
+        tree.commit().unwrap();
+
+        for (i, entry) in tree.range::<[u8]>(..).enumerate() {
+            let (key, value) = entry.unwrap();
+            assert_eq!(key, (i as u64).to_be_bytes());
+            assert_eq!(value, if i % 3 == 0 { vec![2; 40] } else { vec![3; 32] });
+        }
+
+        assert_eq!(
+            snapshot.get(&1u64.to_be_bytes()).unwrap(),
+            Some(vec![1; 32])
+        );
+        assert_eq!(tree.leaf_keys().len(), 1_000);
+    }
+
+    #[test]
+    fn large_values() {
+        let file = MemoryFile::default();
+        let mut tree = Tree::open(DWALPager::recover(file.clone()).unwrap()).unwrap();
+
+        let value = |i: u64, len: usize| (0..len).map(|b| (b as u64 ^ i) as u8).collect::<Vec<_>>();
+        let len = |i: u64| [10, MAX_ENTRY_SIZE, 3 * PAGE_CAPACITY, 40_000][i as usize % 4];
+
+        for i in 0..200u64 {
+            tree.put(&i.to_be_bytes(), &value(i, len(i))).unwrap();
+            tree.commit().unwrap();
+        }
+
+        for i in 0..200u64 {
+            assert_eq!(tree.get(&i.to_be_bytes()).unwrap(), Some(value(i, len(i))));
+        }
+        for (i, entry) in tree.range::<[u8]>(..).rev().enumerate() {
+            let i = 199 - i as u64;
+            assert_eq!(entry.unwrap(), (i.to_be_bytes().to_vec(), value(i, len(i))));
+        }
+
+        // Replacing and deleting values frees their overflow pages, so once
+        // the sizes have settled doing it again doesn't grow the file.
+        let mut size = 0;
+        for round in 0..2u64 {
+            if round == 1 {
+                size = file.len().unwrap();
+            }
+            for i in 0..200u64 {
+                if i % 2 == 0 {
+                    tree.put(&i.to_be_bytes(), &value(i + round, len(i + 1)))
+                        .unwrap();
+                } else {
+                    assert_eq!(
+                        tree.delete(&i.to_be_bytes()).unwrap(),
+                        Some(value(i, len(i)))
+                    );
+                    tree.put(&i.to_be_bytes(), &value(i, len(i))).unwrap();
+                }
+                tree.commit().unwrap();
+            }
+        }
+        assert!(file.len().unwrap() < size + size / 20);
+
+        assert_eq!(
+            tree.get(&2u64.to_be_bytes()).unwrap(),
+            Some(value(3, len(3)))
+        );
+    }
+
+    #[test]
+    fn entry_too_large() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        let key = vec![0; MAX_ENTRY_SIZE];
+        assert!(matches!(
+            tree.put(&key, b"value"),
+            Err(Error::KeyTooLarge(len)) if len == MAX_ENTRY_SIZE
+        ));
+        assert_eq!(tree.get(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn verify_root_path() {
+        let file = MemoryFile::default();
+        let mut tree = Tree::open(DWALPager::recover(file.clone()).unwrap()).unwrap();
+
+        for i in 0..5_000 {
+            tree.put(&key(i), &[i as u8; 32]).unwrap();
+        }
+        tree.commit().unwrap();
+        tree.verify_root_path().unwrap();
+
+        let root = tree.read_node(tree.root_page_id).unwrap();
+        let child_page_id = root.children[root.children.len() / 2];
+        drop(tree);
+
+        let offset = usize::from(child_page_id) * PAGE_SIZE;
+        file.write_at(&[0xff; PAGE_CAPACITY], offset as u64)
+            .unwrap();
+
+        let mut tree = Tree::open(DWALPager::recover(file).unwrap()).unwrap();
+        assert!(matches!(
+            tree.verify_root_path(),
+            Err(Error::Corruption { page, .. }) if page == child_page_id
+        ));
+    }
+
+    #[test]
+    fn repair_from_wal() {
+        let file = MemoryFile::default();
+        let mut pager = DWALPager::recover_with_wal(file.clone(), MemoryFile::default()).unwrap();
+        pager.set_repair_policy(RepairPolicy::Repair);
+        let mut tree = Tree::open(pager).unwrap();
+
+        for i in 0..5_000 {
+            tree.put(&key(i), &[i as u8; 32]).unwrap();
+        }
+        // Written in place but not synced, the log still has every page.
+        tree.pager().commit_unsynced().unwrap();
+
+        let root = tree.read_node(tree.root_page_id).unwrap();
+        let child_page_id = root.children[root.children.len() / 2];
+        let offset = (usize::from(child_page_id) * PAGE_SIZE) as u64;
+        file.write_at(&[0xff; PAGE_CAPACITY], offset).unwrap();
+
+        // Small enough that the page is read from the file again.
+        tree.pager().set_cache_config(CacheConfig { max_bytes: 0 });
+        for i in 0..5_000 {
+            assert_eq!(tree.get(&key(i)).unwrap().unwrap(), [i as u8; 32]);
+        }
+        assert_eq!(tree.repaired_pages(), 1);
+
+        let mut buf = [0; PAGE_CAPACITY];
+        file.read_exact_at(&mut buf, offset).unwrap();
+        assert_ne!(buf, [0xff; PAGE_CAPACITY]);
+    }
+}
@@ -1,62 +1,59 @@
-use bincode::{deserialize, serialize};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{cmp::Ordering, io, sync::Arc};
-
-const PAGE_SIZE: usize = 4096;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct PageId(pub u64);
-
-#[derive(Debug)]
-pub struct Page {
-    pub id: PageId,
-    pub data: Vec<u8>,
-    pub dirty: bool,
+use std::{convert::TryFrom, ops::Bound};
+
+use zerocopy::{
+    little_endian::{U16, U64},
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+};
+
+use crate::{
+    pager::{LogicalPageId, PageBuf, PageBufMut, PageWriter, PAGE_CAPACITY},
+    Error, Result,
+};
+
+/// Set in the suffix length of a leaf entry whose value is empty, the value
+/// length is left out.
+const EMPTY_VALUE: u16 = 0x8000;
+
+#[derive(Debug, FromBytes, IntoBytes, KnownLayout, Unaligned, Immutable)]
+#[repr(C)]
+struct NodeHeader {
+    is_leaf: u8,
+    _pad: u8,
+    num_keys: U16,
+    next_leaf_pos: U64,
+    prev_leaf_pos: U64,
 }
 
-// Abstract page cache interface
-pub trait PageCache: Send + Sync {
-    fn get_page(&self, page_id: PageId) -> io::Result<Arc<Page>>;
-    fn flush_page(&self, page_id: PageId) -> io::Result<()>;
-    fn allocate_page(&self) -> io::Result<PageId>;
-    fn mark_dirty(&self, page_id: PageId) -> io::Result<()>;
-    fn flush_all(&self) -> io::Result<()>;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NodeMetadata {
+    pub(crate) is_leaf: bool,
+    pub(crate) next_leaf_pos: Option<LogicalPageId>,
+    pub(crate) prev_leaf_pos: Option<LogicalPageId>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct NodeMetadata {
-    is_leaf: bool,
-    num_keys: u16,
-    next_leaf_pos: PageId,
+/// A decoded tree node.
+///
+/// On disk a node is a `NodeHeader` followed, for leaves, by the
+/// `(prefix_len, prefix)` shared by all keys and then
+/// `(suffix_len, value_len, suffix, value)` entries, or just
+/// `(suffix_len | EMPTY_VALUE, suffix)` for empty values, and, for internal
+/// nodes, by `(key_len, key)` separators and then `num_keys + 1` child
+/// page ids. Keys are always whole once decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Node {
+    pub(crate) metadata: NodeMetadata,
+    pub(crate) keys: Vec<Vec<u8>>,
+    pub(crate) values: Vec<Vec<u8>>,
+    pub(crate) children: Vec<LogicalPageId>,
 }
 
-#[derive(Debug, Clone)]
-struct Node<K, V> {
-    metadata: NodeMetadata,
-    keys: Vec<K>,
-    values: Vec<Option<V>>,
-    children: Vec<PageId>,
-}
-
-impl<K, V> Node<K, V> {
-    fn encode(&self) -> Vec<u8> {
-        todo!()
-    }
-
-    fn decode(_: &Vec<u8>) -> io::Result<Node<K, V>> {
-        todo!()
-    }
-}
-
-impl<K: Ord + Serialize + for<'a> Deserialize<'a>, V: Serialize + for<'a> Deserialize<'a>>
-    Node<K, V>
-{
-    fn new_leaf() -> Self {
+impl Node {
+    pub(crate) fn new_leaf() -> Self {
         Self {
             metadata: NodeMetadata {
                 is_leaf: true,
-                num_keys: 0,
-                next_leaf_pos: PageId(0),
+                next_leaf_pos: None,
+                prev_leaf_pos: None,
             },
             keys: Vec::new(),
             values: Vec::new(),
@@ -64,296 +61,794 @@ impl<K: Ord + Serialize + for<'a> Deserialize<'a>, V: Serialize + for<'a> Deseri
         }
     }
 
-    fn new_internal() -> Self {
+    pub(crate) fn new_internal(keys: Vec<Vec<u8>>, children: Vec<LogicalPageId>) -> Self {
+        debug_assert_eq!(keys.len() + 1, children.len());
+
         Self {
             metadata: NodeMetadata {
                 is_leaf: false,
-                num_keys: 0,
-                next_leaf_pos: PageId(0),
+                next_leaf_pos: None,
+                prev_leaf_pos: None,
             },
-            keys: Vec::new(),
+            keys,
             values: Vec::new(),
-            children: Vec::new(),
+            children,
         }
     }
 
-    fn find_child(&self, key: &K) -> usize {
-        match self.keys.binary_search(key) {
-            Ok(pos) => pos + 1,
-            Err(pos) => pos,
-        }
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.metadata.is_leaf
     }
 
-    fn insert_into_leaf(&mut self, key: K, value: V) {
-        let pos = match self.keys.binary_search(&key) {
-            Ok(pos) => pos,
-            Err(pos) => pos,
-        };
+    /// Whether the node can be encoded into a single page.
+    pub(crate) fn fits(&self) -> bool {
+        self.encoded_len() <= PAGE_CAPACITY
+    }
 
-        self.keys.insert(pos, key);
-        self.values.insert(pos, Some(value));
-        self.metadata.num_keys += 1;
+    /// Whether the node is too empty and should be merged with, or borrow
+    /// from, a sibling.
+    pub(crate) fn underflows(&self) -> bool {
+        self.encoded_len() < PAGE_CAPACITY / 4
     }
-}
 
-pub struct BTree<K, V, C>
-where
-    K: Ord + Serialize + for<'a> Deserialize<'a>,
-    V: Serialize + for<'a> Deserialize<'a>,
-    C: PageCache,
-{
-    cache: C,
-    root_id: PageId,
-    max_keys: usize,
-    _phantom: std::marker::PhantomData<(K, V)>,
-}
+    /// Size of the node once encoded.
+    pub(crate) fn encoded_len(&self) -> usize {
+        let prefix_len = self.key_prefix().len();
 
-impl<K, V, C> BTree<K, V, C>
-where
-    K: Ord + Serialize + for<'a> Deserialize<'a> + Clone,
-    V: Serialize + for<'a> Deserialize<'a> + Clone,
-    C: PageCache,
-{
-    pub fn new(cache: C, root_id: PageId, max_keys: usize) -> Self {
-        Self {
-            cache,
-            root_id,
-            max_keys,
-            _phantom: std::marker::PhantomData,
+        self.prefix_encoded_len(prefix_len)
+            + (0..self.keys.len())
+                .map(|idx| self.entry_len(idx, prefix_len))
+                .sum::<usize>()
+    }
+
+    /// Encoded size of everything but the entries, the header and either
+    /// the shared key prefix of a leaf or the leftmost child of an internal
+    /// node.
+    fn prefix_encoded_len(&self, prefix_len: usize) -> usize {
+        if self.metadata.is_leaf {
+            size_of::<NodeHeader>() + 2 + prefix_len
+        } else {
+            size_of::<NodeHeader>() + 8
+        }
+    }
+
+    /// Encoded size of the entry at `idx`, internal nodes count the child to
+    /// the right of the separator and the leftmost child is left out.
+    fn entry_len(&self, idx: usize, prefix_len: usize) -> usize {
+        if self.metadata.is_leaf && self.values[idx].is_empty() {
+            2 + self.keys[idx].len() - prefix_len
+        } else if self.metadata.is_leaf {
+            4 + self.keys[idx].len() - prefix_len + self.values[idx].len()
+        } else {
+            2 + self.keys[idx].len() + 8
         }
     }
 
-    pub fn insert(&mut self, key: K, value: V) -> io::Result<()> {
-        let root_page = self.cache.get_page(self.root_id)?;
+    /// The prefix shared by every key of a leaf, it is only stored once.
+    /// Keys are sorted so it is the common prefix of the first and last.
+    fn key_prefix(&self) -> &[u8] {
+        match (self.keys.first(), self.keys.last()) {
+            (Some(first), Some(last)) if self.metadata.is_leaf => {
+                &first[..common_prefix_len(first, last)]
+            }
+            _ => &[],
+        }
+    }
+
+    /// Index of the child of an internal node that may contain `key`.
+    ///
+    /// Keys sort at or after the separator to the left of their child and
+    /// before the one to the right of it, separators copied up from leaves
+    /// don't have to be keys that exist.
+    pub(crate) fn child_index(&self, key: &[u8]) -> usize {
+        self.keys.partition_point(|k| k[..] <= *key)
+    }
 
-        let root: Node<K, V> = Node::decode(&root_page.data)?;
+    /// Insert a separator and the child to the right of it, used when the
+    /// child at `idx` has split.
+    pub(crate) fn insert_child(&mut self, idx: usize, key: Vec<u8>, child: LogicalPageId) {
+        self.keys.insert(idx, key);
+        self.children.insert(idx + 1, child);
+    }
 
-        if root.keys.len() >= self.max_keys {
-            self.split_root(key, value)?;
+    /// Split the node roughly in half by encoded size, keeping the lower
+    /// half. Returns the separator to insert into the parent along with the
+    /// upper half.
+    ///
+    /// Leaves copy up the shortest prefix of the first key of the upper half
+    /// that sorts after the last key of the lower half as the separator,
+    /// internal nodes move their middle key up.
+    pub(crate) fn split(&mut self) -> (Vec<u8>, Node) {
+        let min_keys = if self.metadata.is_leaf { 2 } else { 3 };
+        assert!(self.keys.len() >= min_keys, "node too small to split");
+
+        if self.metadata.is_leaf {
+            let mid = self.leaf_split_point();
+
+            let right = Node {
+                metadata: NodeMetadata {
+                    is_leaf: true,
+                    next_leaf_pos: self.metadata.next_leaf_pos,
+                    // Only the caller knows the page ids to link the two.
+                    prev_leaf_pos: None,
+                },
+                keys: self.keys.split_off(mid),
+                values: self.values.split_off(mid),
+                children: Vec::new(),
+            };
+
+            let last = self.keys.last().unwrap();
+            let first = &right.keys[0];
+            let separator = first[..common_prefix_len(last, first) + 1].to_vec();
+
+            (separator, right)
         } else {
-            self.insert_internal(self.root_id, key, value)?;
+            let half = self.encoded_len() / 2;
+
+            let mut len = self.prefix_encoded_len(0);
+            let mut mid = 0;
+            while mid < self.keys.len() && len < half {
+                len += self.entry_len(mid, 0);
+                mid += 1;
+            }
+
+            let mid = mid.clamp(1, self.keys.len() - 2);
+
+            let keys = self.keys.split_off(mid + 1);
+            let children = self.children.split_off(mid + 1);
+            let separator = self.keys.pop().unwrap();
+
+            (separator, Node::new_internal(keys, children))
         }
+    }
 
-        Ok(())
+    /// Index of the first key of the upper half when splitting a leaf,
+    /// picked so that the larger half is as small as possible.
+    ///
+    /// Each half stores its own key prefix, which may be a lot longer than
+    /// the prefix of the whole leaf. Splitting by the leaf's encoded size
+    /// doesn't work when a single key shares no prefix with the others.
+    fn leaf_split_point(&self) -> usize {
+        let num_keys = self.keys.len();
+
+        // Encoded sizes of the first `n` entries without a prefix.
+        let mut sums = Vec::with_capacity(num_keys + 1);
+        sums.push(0);
+        for idx in 0..num_keys {
+            sums.push(sums[idx] + self.entry_len(idx, 0));
+        }
+
+        let half_len = |start: usize, end: usize| {
+            let prefix_len = common_prefix_len(&self.keys[start], &self.keys[end - 1]);
+
+            self.prefix_encoded_len(prefix_len) + sums[end]
+                - sums[start]
+                - (end - start) * prefix_len
+        };
+
+        (1..num_keys)
+            .min_by_key(|&mid| half_len(0, mid).max(half_len(mid, num_keys)))
+            .unwrap()
     }
 
-    pub fn get(&self, key: &K) -> io::Result<Option<V>> {
-        let mut current_id = self.root_id;
+    /// Append the right sibling `right` to this node, the inverse of `split`.
+    ///
+    /// `separator` is the key between the two nodes in the parent, it is
+    /// dropped for leaves and pulled down into internal nodes.
+    pub(crate) fn merge(&mut self, separator: Vec<u8>, mut right: Node) {
+        debug_assert_eq!(self.metadata.is_leaf, right.metadata.is_leaf);
 
-        loop {
-            let page = self.cache.get_page(current_id)?;
+        if self.metadata.is_leaf {
+            self.values.append(&mut right.values);
+            self.metadata.next_leaf_pos = right.metadata.next_leaf_pos;
+        } else {
+            self.keys.push(separator);
+            self.children.append(&mut right.children);
+        }
 
-            let node: Node<K, V> = Node::decode(&page.data)?;
+        self.keys.append(&mut right.keys);
+    }
 
-            if node.metadata.is_leaf {
-                match node.keys.binary_search(key) {
-                    Ok(idx) => return Ok(node.values[idx].clone()),
-                    Err(_) => return Ok(None),
-                }
-            } else {
-                let child_idx = node.find_child(key);
-                current_id = node.children[child_idx];
+    /// Look up `key` in a leaf.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.search(key).ok().map(|idx| &self.values[idx][..])
+    }
+
+    /// Insert or overwrite `key` in a leaf.
+    pub(crate) fn insert(&mut self, key: &[u8], value: &[u8]) {
+        match self.search(key) {
+            Ok(idx) => self.values[idx] = value.to_vec(),
+            Err(idx) => {
+                self.keys.insert(idx, key.to_vec());
+                self.values.insert(idx, value.to_vec());
             }
         }
     }
 
-    fn insert_internal(
-        &mut self,
-        node_id: PageId,
-        key: K,
-        value: V,
-    ) -> io::Result<Option<(K, PageId)>> {
-        let page = self.cache.get_page(node_id)?;
-        let mut node: Node<K, V> = Node::decode(&page.data)?;
+    /// Remove `key` from a leaf, returning its value if it was present.
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let idx = self.search(key).ok()?;
 
-        if node.metadata.is_leaf {
-            node.insert_into_leaf(key, value);
-            self.write_node(node_id, &node)?;
+        self.keys.remove(idx);
+        Some(self.values.remove(idx))
+    }
 
-            if node.keys.len() > self.max_keys {
-                Ok(Some(self.split_leaf(node_id, &mut node)?))
-            } else {
-                Ok(None)
-            }
-        } else {
-            let child_idx = node.find_child(&key);
-            let child_id = node.children[child_idx];
+    fn search(&self, key: &[u8]) -> std::result::Result<usize, usize> {
+        self.keys.binary_search_by(|k| k[..].cmp(key))
+    }
 
-            if let Some((split_key, new_page_id)) = self.insert_internal(child_id, key, value)? {
-                node.keys.insert(child_idx, split_key);
-                node.children.insert(child_idx + 1, new_page_id);
-                node.metadata.num_keys += 1;
-                self.write_node(node_id, &node)?;
+    pub(crate) fn encode(&self, page: &mut PageBufMut) -> Result<()> {
+        let mut writer = PageWriter::new(page);
+
+        let num_keys = u16::try_from(self.keys.len()).map_err(|_| Error::PageFull)?;
+
+        writer.put(&NodeHeader {
+            is_leaf: self.metadata.is_leaf as u8,
+            _pad: 0,
+            num_keys: num_keys.into(),
+            next_leaf_pos: encode_page_id(self.metadata.next_leaf_pos),
+            prev_leaf_pos: encode_page_id(self.metadata.prev_leaf_pos),
+        })?;
+
+        if self.metadata.is_leaf {
+            let prefix = self.key_prefix();
+            writer.put_u16(len_u16(prefix)?)?;
+            writer.put_slice(prefix)?;
+
+            for (key, value) in self.keys.iter().zip(&self.values) {
+                let suffix = &key[prefix.len()..];
+                let suffix_len = len_u16(suffix)?;
+                if suffix_len & EMPTY_VALUE != 0 {
+                    return Err(Error::PageFull);
+                }
 
-                if node.keys.len() > self.max_keys {
-                    Ok(Some(self.split_internal(node_id, &mut node)?))
+                if value.is_empty() {
+                    writer.put_u16(suffix_len | EMPTY_VALUE)?;
+                    writer.put_slice(suffix)?;
                 } else {
-                    Ok(None)
+                    writer.put_u16(suffix_len)?;
+                    writer.put_u16(len_u16(value)?)?;
+                    writer.put_slice(suffix)?;
+                    writer.put_slice(value)?;
                 }
-            } else {
-                Ok(None)
+            }
+        } else {
+            for key in &self.keys {
+                writer.put_u16(len_u16(key)?)?;
+                writer.put_slice(key)?;
+            }
+
+            for child in &self.children {
+                writer.put_u64(usize::from(*child) as u64)?;
             }
         }
+
+        Ok(())
     }
 
-    fn split_leaf(&mut self, node_id: PageId, node: &mut Node<K, V>) -> io::Result<(K, PageId)> {
-        let split_point = (self.max_keys + 1) / 2;
-        let mut new_node = Node::new_leaf();
+    /// Whether an encoded node is a leaf, without decoding it.
+    pub(crate) fn is_leaf_page(page: &PageBuf) -> bool {
+        NodeHeader::ref_from_prefix(page.buf()).is_ok_and(|(header, _)| header.is_leaf == 1)
+    }
 
-        // Move half of the keys and values to the new node
-        new_node.keys = node.keys.split_off(split_point);
-        new_node.values = node.values.split_off(split_point);
-        new_node.metadata.num_keys = new_node.keys.len() as u16;
-        node.metadata.num_keys = node.keys.len() as u16;
+    /// Find the value of `key` in an encoded leaf without decoding it,
+    /// returning its offset within the page and its length.
+    ///
+    /// Returns `None` for internal nodes and leaves that don't hold `key`.
+    pub(crate) fn find_value(
+        page_id: LogicalPageId,
+        page: &PageBuf,
+        key: &[u8],
+    ) -> Result<Option<(usize, usize)>> {
+        let corrupt = || Error::Corruption {
+            page: page_id,
+            reason: "malformed leaf",
+        };
+
+        let (header, mut buf) = NodeHeader::read_from_prefix(page.buf()).map_err(|_| corrupt())?;
+
+        if header.is_leaf != 1 {
+            return Ok(None);
+        }
+
+        let len = page.buf().len();
 
-        // Update leaf node links
-        new_node.metadata.next_leaf_pos = node.metadata.next_leaf_pos;
-        let new_page_id = self.cache.allocate_page()?;
-        node.metadata.next_leaf_pos = new_page_id;
+        let prefix_len = read_u16(&mut buf).ok_or_else(corrupt)? as usize;
+        let prefix = take(&mut buf, prefix_len).ok_or_else(corrupt)?;
 
-        self.write_node(new_page_id, &new_node)?;
-        self.write_node(node_id, node)?;
+        // Every key in the leaf shares the prefix.
+        let suffix = match key.strip_prefix(prefix) {
+            Some(suffix) => suffix,
+            None => return Ok(None),
+        };
 
-        Ok((new_node.keys[0].clone(), new_page_id))
+        for _ in 0..header.num_keys.get() {
+            let (suffix_len, value_len) = read_entry_lens(&mut buf).ok_or_else(corrupt)?;
+            let entry_suffix = take(&mut buf, suffix_len).ok_or_else(corrupt)?;
+            let offset = len - buf.len();
+            take(&mut buf, value_len).ok_or_else(corrupt)?;
+
+            match entry_suffix.cmp(suffix) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => return Ok(Some((offset, value_len))),
+                // Entries are sorted, the key isn't in this leaf.
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+
+        Ok(None)
     }
 
-    fn split_internal(
-        &mut self,
-        node_id: PageId,
-        node: &mut Node<K, V>,
-    ) -> io::Result<(K, PageId)> {
-        let split_point = self.max_keys / 2;
-        let mut new_node = Node::new_internal();
+    /// Count the keys of an encoded leaf within `start..end` without
+    /// decoding it, values are skipped over and never copied.
+    pub(crate) fn count_keys(
+        page_id: LogicalPageId,
+        page: &PageBuf,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<usize> {
+        let corrupt = || Error::Corruption {
+            page: page_id,
+            reason: "malformed leaf",
+        };
+
+        let (header, mut buf) = NodeHeader::read_from_prefix(page.buf()).map_err(|_| corrupt())?;
 
-        // Move half of the keys and children to the new node
-        new_node.keys = node.keys.split_off(split_point + 1);
-        new_node.children = node.children.split_off(split_point + 1);
-        new_node.metadata.num_keys = new_node.keys.len() as u16;
-        node.metadata.num_keys = node.keys.len() as u16;
+        if header.is_leaf != 1 {
+            return Err(corrupt());
+        }
 
-        let split_key = node.keys.pop().unwrap();
-        node.metadata.num_keys -= 1;
+        let num_keys = header.num_keys.get() as usize;
 
-        let new_page_id = self.cache.allocate_page()?;
-        self.write_node(new_page_id, &new_node)?;
-        self.write_node(node_id, node)?;
+        if let (Bound::Unbounded, Bound::Unbounded) = (start, end) {
+            return Ok(num_keys);
+        }
+
+        let prefix_len = read_u16(&mut buf).ok_or_else(corrupt)? as usize;
+        let mut key = take(&mut buf, prefix_len).ok_or_else(corrupt)?.to_vec();
+
+        let mut count = 0;
+
+        for _ in 0..num_keys {
+            let (suffix_len, value_len) = read_entry_lens(&mut buf).ok_or_else(corrupt)?;
+            key.truncate(prefix_len);
+            key.extend_from_slice(take(&mut buf, suffix_len).ok_or_else(corrupt)?);
+            take(&mut buf, value_len).ok_or_else(corrupt)?;
+
+            let after_start = match start {
+                Bound::Included(start) => key[..] >= *start,
+                Bound::Excluded(start) => key[..] > *start,
+                Bound::Unbounded => true,
+            };
+            let before_end = match end {
+                Bound::Included(end) => key[..] <= *end,
+                Bound::Excluded(end) => key[..] < *end,
+                Bound::Unbounded => true,
+            };
+
+            if !before_end {
+                break;
+            }
+            if after_start {
+                count += 1;
+            }
+        }
 
-        Ok((split_key, new_page_id))
+        Ok(count)
     }
 
-    fn split_root(&mut self, key: K, value: V) -> io::Result<()> {
-        let old_root_id = self.root_id;
-        let new_root_id = self.cache.allocate_page()?;
+    pub(crate) fn decode(page_id: LogicalPageId, page: &PageBuf) -> Result<Self> {
+        let corrupt = || Error::Corruption {
+            page: page_id,
+            reason: "malformed node",
+        };
 
-        let mut new_root = Node::new_internal();
-        new_root.children.push(old_root_id);
+        let (header, mut buf) = NodeHeader::read_from_prefix(page.buf()).map_err(|_| corrupt())?;
+
+        let is_leaf = match header.is_leaf {
+            0 => false,
+            1 => true,
+            _ => return Err(corrupt()),
+        };
 
-        self.root_id = new_root_id;
-        self.write_node(new_root_id, &new_root)?;
+        let num_keys = header.num_keys.get() as usize;
 
-        if let Some((split_key, new_page_id)) = self.insert_internal(old_root_id, key, value)? {
-            new_root.keys.push(split_key);
-            new_root.children.push(new_page_id);
-            new_root.metadata.num_keys += 1;
-            self.write_node(new_root_id, &new_root)?;
+        let mut keys = Vec::with_capacity(num_keys);
+        let mut values = Vec::new();
+        let mut children = Vec::new();
+
+        if is_leaf {
+            values.reserve(num_keys);
+
+            let prefix_len = read_u16(&mut buf).ok_or_else(corrupt)? as usize;
+            let prefix = take(&mut buf, prefix_len).ok_or_else(corrupt)?;
+
+            for _ in 0..num_keys {
+                let (suffix_len, value_len) = read_entry_lens(&mut buf).ok_or_else(corrupt)?;
+                let suffix = take(&mut buf, suffix_len).ok_or_else(corrupt)?;
+
+                let mut key = Vec::with_capacity(prefix_len + suffix_len);
+                key.extend_from_slice(prefix);
+                key.extend_from_slice(suffix);
+                keys.push(key);
+
+                values.push(take(&mut buf, value_len).ok_or_else(corrupt)?.to_vec());
+            }
+        } else {
+            for _ in 0..num_keys {
+                let key_len = read_u16(&mut buf).ok_or_else(corrupt)? as usize;
+                keys.push(take(&mut buf, key_len).ok_or_else(corrupt)?.to_vec());
+            }
+
+            children.reserve(num_keys + 1);
+
+            for _ in 0..=num_keys {
+                let child = read_u64(&mut buf).ok_or_else(corrupt)?;
+                children.push(LogicalPageId::from(child as usize));
+            }
         }
 
-        Ok(())
+        Ok(Self {
+            metadata: NodeMetadata {
+                is_leaf,
+                next_leaf_pos: decode_page_id(header.next_leaf_pos),
+                prev_leaf_pos: decode_page_id(header.prev_leaf_pos),
+            },
+            keys,
+            values,
+            children,
+        })
     }
+}
 
-    fn write_node(&mut self, page_id: PageId, node: &Node<K, V>) -> io::Result<()> {
-        let data = node.encode();
-        let mut page = self.cache.get_page(page_id)?;
-        let page = Arc::get_mut(&mut page).unwrap();
-        page.data = data;
-        page.dirty = true;
-        self.cache.mark_dirty(page_id)?;
-        Ok(())
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Sibling links use zero for "none", page zero is the pager header.
+fn encode_page_id(page_id: Option<LogicalPageId>) -> U64 {
+    page_id.map_or(0, |id| usize::from(id) as u64).into()
+}
+
+fn decode_page_id(page_id: U64) -> Option<LogicalPageId> {
+    match page_id.get() {
+        0 => None,
+        id => Some(LogicalPageId::from(id as usize)),
     }
 }
 
+fn len_u16(bytes: &[u8]) -> Result<u16> {
+    u16::try_from(bytes.len()).map_err(|_| Error::PageFull)
+}
+
+/// Read the suffix and value lengths of a leaf entry.
+fn read_entry_lens(buf: &mut &[u8]) -> Option<(usize, usize)> {
+    let suffix_len = read_u16(buf)?;
+
+    if suffix_len & EMPTY_VALUE != 0 {
+        return Some(((suffix_len & !EMPTY_VALUE) as usize, 0));
+    }
+
+    Some((suffix_len as usize, read_u16(buf)? as usize))
+}
+
+fn read_u16(buf: &mut &[u8]) -> Option<u16> {
+    take(buf, 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u64(buf: &mut &[u8]) -> Option<u64> {
+    take(buf, 8).map(|bytes| {
+        let mut le = [0; 8];
+        le.copy_from_slice(bytes);
+        u64::from_le_bytes(le)
+    })
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+
+    Some(head)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
-    use std::sync::RwLock;
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
 
-    pub struct MemoryPageCache {
-        pages: RwLock<HashMap<PageId, Arc<Page>>>,
-        next_page_id: RwLock<u64>,
+    fn roundtrip(pager: &mut DWALPager, node: &Node) -> Node {
+        let mut page = pager.new_page_buffer().unwrap();
+        page.init();
+        node.encode(&mut page).unwrap();
+
+        let page_id = pager.new_page_id().unwrap();
+        pager.update_page(page_id, page).unwrap();
+
+        let page = pager.read_at(page_id, pager.current_version()).unwrap();
+        Node::decode(page_id, &page).unwrap()
     }
 
-    impl MemoryPageCache {
-        pub fn new() -> Self {
-            Self {
-                pages: RwLock::new(HashMap::new()),
-                next_page_id: RwLock::new(0),
-            }
+    #[test]
+    fn encode_decode() {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+
+        let mut leaf = Node::new_leaf();
+        leaf.insert(b"b", b"2");
+        leaf.insert(b"a", b"1");
+        leaf.insert(b"", b"");
+        leaf.metadata.next_leaf_pos = Some(LogicalPageId::from(7));
+        leaf.metadata.prev_leaf_pos = Some(LogicalPageId::from(5));
+        assert_eq!(roundtrip(&mut pager, &leaf), leaf);
+
+        let internal = Node {
+            metadata: NodeMetadata {
+                is_leaf: false,
+                next_leaf_pos: None,
+                prev_leaf_pos: None,
+            },
+            keys: vec![b"m".to_vec()],
+            values: Vec::new(),
+            children: vec![LogicalPageId::from(3), LogicalPageId::from(4)],
+        };
+        assert_eq!(roundtrip(&mut pager, &internal), internal);
+    }
+
+    #[test]
+    fn page_full() {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+
+        let mut leaf = Node::new_leaf();
+        leaf.insert(b"key", &[0; 4096]);
+
+        let mut page = pager.new_page_buffer().unwrap();
+        assert!(matches!(leaf.encode(&mut page), Err(Error::PageFull)));
+    }
+
+    #[test]
+    fn split() {
+        let mut leaf = Node::new_leaf();
+        for i in 0..10u8 {
+            leaf.insert(&[i], &[i; 8]);
         }
+        leaf.metadata.next_leaf_pos = Some(LogicalPageId::from(9));
+
+        let len = leaf.encoded_len();
+        let (separator, right) = leaf.split();
+
+        assert_eq!(separator, vec![5]);
+        assert_eq!(leaf.keys.len() + right.keys.len(), 10);
+        assert_eq!(right.keys[0], separator);
+        assert_eq!(right.metadata.next_leaf_pos, Some(LogicalPageId::from(9)));
+        assert!(leaf.encoded_len() <= len / 2 + leaf.entry_len(0, 0));
+
+        let keys = (0..5u8).map(|i| vec![i]).collect();
+        let children = (0..6).map(LogicalPageId::from).collect();
+        let mut internal = Node::new_internal(keys, children);
+
+        let (separator, right) = internal.split();
+
+        assert_eq!(separator, vec![2]);
+        assert_eq!(internal.keys, vec![vec![0], vec![1]]);
+        assert_eq!(internal.children.len(), 3);
+        assert_eq!(right.keys, vec![vec![3], vec![4]]);
+        assert_eq!(right.children.len(), 3);
+
+        assert_eq!(right.child_index(&[3]), 1);
+        assert_eq!(right.child_index(&[2]), 0);
+        assert_eq!(right.child_index(&[9]), 2);
     }
 
-    impl PageCache for MemoryPageCache {
-        fn get_page(&self, page_id: PageId) -> io::Result<Arc<Page>> {
-            let pages = self.pages.read().unwrap();
-            Ok(Arc::clone(pages.get(&page_id).ok_or_else(|| {
-                io::Error::new(io::ErrorKind::NotFound, "Page not found")
-            })?))
+    #[test]
+    fn separator_truncation() {
+        let mut leaf = Node::new_leaf();
+        for key in [&b"apple"[..], b"apricot", b"banana", b"blueberry"] {
+            leaf.insert(key, b"");
+        }
+
+        let (separator, right) = leaf.split();
+        assert_eq!(separator, b"b");
+        assert_eq!(right.keys[0], b"banana");
+
+        let internal = Node::new_internal(
+            vec![separator],
+            vec![LogicalPageId::from(1), LogicalPageId::from(2)],
+        );
+        assert_eq!(internal.child_index(b"apricot"), 0);
+        assert_eq!(internal.child_index(b"azzz"), 0);
+        assert_eq!(internal.child_index(b"b"), 1);
+        assert_eq!(internal.child_index(b"banana"), 1);
+
+        // A key that is a prefix of the next needs one more byte.
+        let mut leaf = Node::new_leaf();
+        for key in [&b"ab"[..], b"abc"] {
+            leaf.insert(key, b"");
         }
+        assert_eq!(leaf.split().0, b"abc");
+    }
 
-        fn flush_page(&self, _page_id: PageId) -> io::Result<()> {
-            Ok(())
+    #[test]
+    fn merge() {
+        let mut leaf = Node::new_leaf();
+        for i in 0..10u8 {
+            leaf.insert(&[i], &[i]);
         }
+        leaf.metadata.next_leaf_pos = Some(LogicalPageId::from(9));
+
+        let original = leaf.clone();
+        let (separator, right) = leaf.split();
+        leaf.merge(separator, right);
+        assert_eq!(leaf, original);
+
+        let keys = (0..5u8).map(|i| vec![i]).collect();
+        let children = (0..6).map(LogicalPageId::from).collect();
+        let mut internal = Node::new_internal(keys, children);
+
+        let original = internal.clone();
+        let (separator, right) = internal.split();
+        internal.merge(separator, right);
+        assert_eq!(internal, original);
+    }
+
+    #[test]
+    fn find_value() {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+
+        let mut leaf = Node::new_leaf();
+        leaf.insert(b"a", b"1");
+        leaf.insert(b"bb", b"22");
+        leaf.insert(b"d", b"");
+
+        let mut page = pager.new_page_buffer().unwrap();
+        page.init();
+        leaf.encode(&mut page).unwrap();
+        let page_id = pager.new_page_id().unwrap();
+        pager.update_page(page_id, page).unwrap();
+        let page = pager.read_at(page_id, pager.current_version()).unwrap();
+
+        let (offset, len) = Node::find_value(page_id, &page, b"bb").unwrap().unwrap();
+        assert_eq!(&page.buf()[offset..offset + len], b"22");
+        assert_eq!(
+            Node::find_value(page_id, &page, b"d")
+                .unwrap()
+                .map(|(_, len)| len),
+            Some(0)
+        );
+        assert_eq!(Node::find_value(page_id, &page, b"c").unwrap(), None);
+        assert_eq!(Node::find_value(page_id, &page, b"e").unwrap(), None);
+
+        let internal = Node::new_internal(vec![b"bb".to_vec()], vec![page_id, page_id]);
+        let mut page = pager.new_page_buffer().unwrap();
+        page.init();
+        internal.encode(&mut page).unwrap();
+        let page_id = pager.new_page_id().unwrap();
+        pager.update_page(page_id, page).unwrap();
+        let page = pager.read_at(page_id, pager.current_version()).unwrap();
+
+        assert_eq!(Node::find_value(page_id, &page, b"bb").unwrap(), None);
+    }
 
-        fn allocate_page(&self) -> io::Result<PageId> {
-            let mut next_id = self.next_page_id.write().unwrap();
-            let page_id = PageId(*next_id);
-            *next_id += 1;
+    #[test]
+    fn prefix_compression() {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
 
-            let page = Arc::new(Page {
-                id: page_id,
-                data: vec![0; PAGE_SIZE],
-                dirty: false,
-            });
+        let key = |i: u32| format!("users/0000000000/{:04}/name", i).into_bytes();
+        let mut leaf = Node::new_leaf();
+        for i in 0..100 {
+            leaf.insert(&key(i), b"v");
+        }
 
-            self.pages.write().unwrap().insert(page_id, page);
-            Ok(page_id)
+        // `users/0000000000/00` is stored once instead of in every entry.
+        assert_eq!(leaf.key_prefix(), b"users/0000000000/00");
+        let uncompressed = size_of::<NodeHeader>() + 100 * (4 + key(0).len() + 1);
+        assert_eq!(leaf.encoded_len(), uncompressed + 2 - 99 * 19);
+        assert!(leaf.fits());
+
+        assert_eq!(roundtrip(&mut pager, &leaf), leaf);
+
+        let mut page = pager.new_page_buffer().unwrap();
+        page.init();
+        leaf.encode(&mut page).unwrap();
+        let page_id = pager.new_page_id().unwrap();
+        pager.update_page(page_id, page).unwrap();
+        let page = pager.read_at(page_id, pager.current_version()).unwrap();
+
+        let (offset, len) = Node::find_value(page_id, &page, &key(42)).unwrap().unwrap();
+        assert_eq!(&page.buf()[offset..offset + len], b"v");
+        assert_eq!(Node::find_value(page_id, &page, b"users/1").unwrap(), None);
+        assert_eq!(Node::find_value(page_id, &page, b"users/").unwrap(), None);
+
+        // Splitting shares a longer prefix within each half.
+        let (_, right) = leaf.clone().split();
+        assert_eq!(right.key_prefix(), b"users/0000000000/00");
+        assert_eq!(roundtrip(&mut pager, &right), right);
+
+        // A key sharing nothing with the others takes the whole prefix away,
+        // the halves only fit again if they get their own prefixes.
+        while leaf.fits() {
+            let i = leaf.keys.len() as u32;
+            leaf.insert(&key(i), b"v");
         }
+        leaf.remove(&key(leaf.keys.len() as u32 - 1));
+        leaf.insert(b"a", b"v");
+        assert!(!leaf.fits());
 
-        fn mark_dirty(&self, page_id: PageId) -> io::Result<()> {
-            // let pages = self.pages.read().unwrap();
-            // if let Some(page) = pages.get_mut(&page_id) {
-            //     let mut page = Arc::get_mut(&mut page).unwrap();
-            //     page.dirty = true;
-            // }
-            // Ok(())
-            todo!()
+        let (_, right) = leaf.split();
+        assert!(leaf.fits());
+        assert!(right.fits());
+    }
+
+    #[test]
+    fn encoded_len() {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+
+        let mut leaf = Node::new_leaf();
+        leaf.insert(b"k", &[1]);
+        let value = vec![0; PAGE_CAPACITY - leaf.encoded_len() + 1];
+        leaf.insert(b"k", &value);
+
+        assert_eq!(leaf.encoded_len(), PAGE_CAPACITY);
+        assert!(leaf.fits());
+        leaf.encode(&mut pager.new_page_buffer().unwrap()).unwrap();
+
+        leaf.values[0].push(0);
+
+        assert!(!leaf.fits());
+        assert!(matches!(
+            leaf.encode(&mut pager.new_page_buffer().unwrap()),
+            Err(Error::PageFull)
+        ));
+
+        // Internal nodes count their leftmost child too.
+        let mut internal = Node::new_internal(Vec::new(), vec![LogicalPageId::from(1)]);
+        while internal.encoded_len() < PAGE_CAPACITY {
+            let key = (internal.keys.len() as u32).to_be_bytes().to_vec();
+            internal.insert_child(internal.keys.len(), key, LogicalPageId::from(1));
         }
+        assert_eq!(internal.encoded_len(), PAGE_CAPACITY);
+        internal
+            .encode(&mut pager.new_page_buffer().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn empty_values() {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
 
-        fn flush_all(&self) -> io::Result<()> {
-            Ok(())
+        let mut leaf = Node::new_leaf();
+        for key in [&b"apple"[..], b"banana", b"cherry"] {
+            leaf.insert(key, b"");
         }
+        let len = leaf.encoded_len();
+
+        assert_eq!(roundtrip(&mut pager, &leaf), leaf);
+
+        // Only the empty values save their length.
+        leaf.insert(b"banana", b"x");
+        assert_eq!(leaf.encoded_len(), len + 3);
+        assert_eq!(roundtrip(&mut pager, &leaf), leaf);
     }
 
     #[test]
-    #[ignore]
-    fn test_btree_operations() {
-        let cache = MemoryPageCache::new();
-        let root_id = cache.allocate_page().unwrap();
-        let mut tree = BTree::<i32, String, MemoryPageCache>::new(cache, root_id, 4);
-
-        // Initialize root node
-        let root = Node::new_leaf();
-        tree.write_node(root_id, &root).unwrap();
-
-        // Test insertions
-        tree.insert(1, "one".to_string()).unwrap();
-        tree.insert(2, "two".to_string()).unwrap();
-        tree.insert(3, "three".to_string()).unwrap();
-
-        // Test retrieval
-        assert_eq!(tree.get(&1).unwrap(), Some("one".to_string()));
-        assert_eq!(tree.get(&2).unwrap(), Some("two".to_string()));
-        assert_eq!(tree.get(&3).unwrap(), Some("three".to_string()));
-        assert_eq!(tree.get(&4).unwrap(), None);
+    fn corrupt() {
+        let mut pager = DWALPager::recover(MemoryFile::default()).unwrap();
+        let page_id = pager.new_page_id().unwrap();
+
+        let mut page = pager.new_page_buffer().unwrap();
+        page.init();
+        // A leaf claiming one entry with a key running off the page.
+        page.buf_mut()[..4].copy_from_slice(&[1, 0, 1, 0]);
+        let entry = size_of::<NodeHeader>();
+        page.buf_mut()[entry..entry + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        pager.update_page(page_id, page).unwrap();
+
+        let page = pager.read_at(page_id, pager.current_version()).unwrap();
+        assert!(matches!(
+            Node::decode(page_id, &page),
+            Err(Error::Corruption { page, .. }) if page == page_id
+        ));
     }
 }
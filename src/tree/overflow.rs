@@ -0,0 +1,480 @@
+use std::convert::TryFrom;
+
+use crate::{
+    pager::{crc32, crc32_update, LogicalPageId, PAGE_CAPACITY},
+    Error, Result,
+};
+
+use super::{node::Node, Tree, MAX_ENTRY_SIZE};
+
+/// Tags the values stored in leaves, either the value itself follows or a
+/// reference to the chain of overflow pages holding it.
+const INLINE: u8 = 0;
+/// References written before values had a checksum, they are still read.
+const OVERFLOW: u8 = 1;
+const OVERFLOW_CHECKED: u8 = 2;
+
+/// An overflow reference is the value length, the first page of the chain
+/// and a crc32 of the whole value. A chain that is cut short, points at the
+/// wrong page or has a damaged page is caught by the checksum no matter
+/// what the pages themselves look like.
+pub(super) const OVERFLOW_REF_LEN: usize = 1 + 8 + 8 + 4;
+
+/// Each overflow page starts with the id of the next page in the chain, zero
+/// for the last one.
+pub(super) const CHUNK_LEN: usize = PAGE_CAPACITY - 8;
+
+impl Tree {
+    /// Encode `value` for storing in a leaf next to `key`, values that don't
+    /// fit are written to a chain of overflow pages.
+    pub(super) fn store_value(&mut self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        // Empty values, like the keys of a set, are stored as nothing at all
+        // and leaves flag their entries instead of giving them a length.
+        if value.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if fits_inline(key, value) {
+            let mut stored = Vec::with_capacity(1 + value.len());
+            stored.push(INLINE);
+            stored.extend_from_slice(value);
+            return Ok(stored);
+        }
+
+        let first = self.write_chain(value)?;
+
+        Ok(encode_overflow(value.len(), first, Some(crc32(value))))
+    }
+
+    /// Write `value` to a new chain of overflow pages, returning its first
+    /// page.
+    fn write_chain(&mut self, value: &[u8]) -> Result<LogicalPageId> {
+        let mut page_ids = Vec::with_capacity(value.len().div_ceil(CHUNK_LEN));
+        for _ in 0..value.len().div_ceil(CHUNK_LEN) {
+            page_ids.push(self.pager().new_page_id()?);
+        }
+
+        for (idx, chunk) in value.chunks(CHUNK_LEN).enumerate() {
+            let next = page_ids
+                .get(idx + 1)
+                .map_or(0, |id| usize::from(*id) as u64);
+
+            let mut page = self.pager().new_page_buffer()?;
+            page.init();
+            page.buf_mut()[..8].copy_from_slice(&next.to_le_bytes());
+            page.buf_mut()[8..8 + chunk.len()].copy_from_slice(chunk);
+
+            // Nothing refers to the chain yet so it doesn't need remapping.
+            self.pager().update_page(page_ids[idx], page)?;
+        }
+
+        Ok(page_ids[0])
+    }
+
+    /// Add `bytes` to the end of the value of `key`, returns false if `key`
+    /// isn't present.
+    ///
+    /// Of an overflow value only the last page and the pages added after
+    /// it are written, see `update_range`.
+    pub(crate) fn append(&mut self, key: &[u8], bytes: &[u8]) -> Result<bool> {
+        self.patch_value(key, None, bytes)
+    }
+
+    /// Overwrite the value of `key` with `bytes` starting at `offset`,
+    /// growing the value if they run past its end. Returns false if `key`
+    /// isn't present and fails with `Error::OffsetOverflow` if `offset` is
+    /// past the end of the value.
+    ///
+    /// Only the overflow pages holding the updated bytes are written, the
+    /// rest of the chain is read to check and recompute the value's
+    /// checksum. Values kept inline are rewritten whole.
+    pub(crate) fn update_range(&mut self, key: &[u8], offset: usize, bytes: &[u8]) -> Result<bool> {
+        self.patch_value(key, Some(offset), bytes)
+    }
+
+    /// Write `bytes` into the value of `key` at `offset`, `None` for its
+    /// end.
+    fn patch_value(&mut self, key: &[u8], offset: Option<usize>, bytes: &[u8]) -> Result<bool> {
+        self.check_not_frozen()?;
+
+        let (leaf_page_id, leaf) = self.find_leaf(key)?;
+        let stored = match leaf.get(key) {
+            Some(stored) => stored.to_vec(),
+            None => return Ok(false),
+        };
+
+        let (len, first, checksum) = match decode(leaf_page_id, &stored)? {
+            Stored::Overflow(len, first, checksum) => (len, first, checksum),
+            Stored::Inline(value) => {
+                let (offset, end) = patch_bounds(value.len(), offset, bytes)?;
+
+                let mut value = value.to_vec();
+                value.resize(value.len().max(end), 0);
+                value[offset..end].copy_from_slice(bytes);
+
+                self.put(key, &value)?;
+                return Ok(true);
+            }
+        };
+
+        let (offset, end) = patch_bounds(len, offset, bytes)?;
+        if bytes.is_empty() {
+            return Ok(true);
+        }
+
+        let corrupt = || Error::Corruption {
+            page: leaf_page_id,
+            reason: "overflow value length or checksum mismatch",
+        };
+
+        let new_len = len.max(end);
+        let mut old_checksum = 0;
+        let mut new_checksum = 0;
+        let mut updated = Vec::new();
+
+        // The whole chain is walked, the checksum covers all of the value and
+        // growing it links new pages to the last one.
+        let mut start = 0;
+        let mut next = Some(first);
+        let mut last = first;
+        while start < len {
+            let overflow_page_id = next.ok_or_else(corrupt)?;
+            let page = self.read_overflow_page(overflow_page_id)?;
+            next = next_page(page.buf());
+            last = overflow_page_id;
+
+            let old_chunk_len = CHUNK_LEN.min(len - start);
+            let new_chunk_len = CHUNK_LEN.min(new_len - start);
+            old_checksum = crc32_update(old_checksum, &page.buf()[8..8 + old_chunk_len]);
+
+            let lo = offset.max(start);
+            let hi = end.min(start + new_chunk_len);
+
+            if lo < hi {
+                let mut copy = self.pager().new_page_buffer()?;
+                copy.init();
+                copy.buf_mut().copy_from_slice(page.buf());
+                copy.buf_mut()[8 + lo - start..8 + hi - start]
+                    .copy_from_slice(&bytes[lo - offset..hi - offset]);
+
+                new_checksum = crc32_update(new_checksum, &copy.buf()[8..8 + new_chunk_len]);
+                updated.push((overflow_page_id, copy));
+            } else {
+                new_checksum = crc32_update(new_checksum, &page.buf()[8..8 + new_chunk_len]);
+            }
+
+            start += CHUNK_LEN;
+        }
+
+        if checksum.is_some_and(|checksum| checksum != old_checksum) {
+            return Err(corrupt());
+        }
+
+        // Bytes past the end of the last page go to a new chain linked to it.
+        if new_len > start {
+            let tail = &bytes[start - offset..];
+            new_checksum = crc32_update(new_checksum, tail);
+            let tail_first = self.write_chain(tail)?;
+
+            if updated.last().map(|(page_id, _)| *page_id) != Some(last) {
+                let page = self.read_overflow_page(last)?;
+                let mut copy = self.pager().new_page_buffer()?;
+                copy.init();
+                copy.buf_mut().copy_from_slice(page.buf());
+                updated.push((last, copy));
+            }
+
+            if let Some((_, page)) = updated.last_mut() {
+                page.buf_mut()[..8]
+                    .copy_from_slice(&(usize::from(tail_first) as u64).to_le_bytes());
+            }
+        }
+
+        for (overflow_page_id, page) in updated {
+            let version = self.pager().current_version();
+            self.pager()
+                .atomic_update(overflow_page_id, version, page)?;
+        }
+
+        // The reference keeps its length, so the leaf is patched in place.
+        let stored = encode_overflow(new_len, first, checksum.map(|_| new_checksum));
+        let page = self.read_page(leaf_page_id)?;
+        let (value_offset, _) = Node::find_value(leaf_page_id, &page, key)?.ok_or_else(corrupt)?;
+        self.patch_leaf(leaf_page_id, &page, value_offset, &stored)?;
+
+        Ok(true)
+    }
+
+    /// Decode a value stored by `store_value`, reading its overflow pages if
+    /// it has any.
+    pub(super) fn load_value(&mut self, page_id: LogicalPageId, stored: &[u8]) -> Result<Vec<u8>> {
+        match decode(page_id, stored)? {
+            Stored::Inline(value) => Ok(value.to_vec()),
+            Stored::Overflow(len, first, checksum) => {
+                let mut value = Vec::with_capacity(len);
+                let mut next = Some(first);
+
+                while let Some(overflow_page_id) = next {
+                    let page = self.read_overflow_page(overflow_page_id)?;
+                    let chunk_len = CHUNK_LEN.min(len - value.len());
+
+                    next = next_page(page.buf());
+                    value.extend_from_slice(&page.buf()[8..8 + chunk_len]);
+
+                    if value.len() == len {
+                        break;
+                    }
+                }
+
+                if value.len() != len || !matches_checksum(&value, checksum) {
+                    return Err(Error::Corruption {
+                        page: page_id,
+                        reason: "overflow value length or checksum mismatch",
+                    });
+                }
+
+                Ok(value)
+            }
+        }
+    }
+
+    /// Free the overflow pages of a value stored by `store_value` that is
+    /// being replaced or removed.
+    pub(super) fn free_value(&mut self, page_id: LogicalPageId, stored: &[u8]) -> Result<()> {
+        if let Stored::Overflow(_, first, _) = decode(page_id, stored)? {
+            let mut next = Some(first);
+
+            while let Some(overflow_page_id) = next {
+                next = next_page(self.read_overflow_page(overflow_page_id)?.buf());
+
+                let version = self.pager().current_version();
+                self.pager().free(overflow_page_id, version)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The start and end of `bytes` written into a value of `len` bytes at
+/// `offset`, `None` for its end.
+fn patch_bounds(len: usize, offset: Option<usize>, bytes: &[u8]) -> Result<(usize, usize)> {
+    let offset = offset.unwrap_or(len);
+
+    if offset > len {
+        return Err(Error::OffsetOverflow("value offset"));
+    }
+
+    let end = offset
+        .checked_add(bytes.len())
+        .ok_or(Error::OffsetOverflow("value length"))?;
+
+    Ok((offset, end))
+}
+
+/// The reference to an overflow value, without a checksum for values
+/// stored before they had one.
+fn encode_overflow(len: usize, first: LogicalPageId, checksum: Option<u32>) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(OVERFLOW_REF_LEN);
+    stored.push(if checksum.is_some() {
+        OVERFLOW_CHECKED
+    } else {
+        OVERFLOW
+    });
+    stored.extend_from_slice(&(len as u64).to_le_bytes());
+    stored.extend_from_slice(&(usize::from(first) as u64).to_le_bytes());
+    if let Some(checksum) = checksum {
+        stored.extend_from_slice(&checksum.to_le_bytes());
+    }
+
+    stored
+}
+
+/// Whether a value stored by `store_value` is kept inline.
+pub(super) fn is_inline(stored: &[u8]) -> bool {
+    matches!(stored.first(), None | Some(&INLINE))
+}
+
+/// Whether the leaf entry for `key` and `value` is small enough to keep the
+/// value inline.
+fn fits_inline(key: &[u8], value: &[u8]) -> bool {
+    4 + key.len() + 1 + value.len() <= MAX_ENTRY_SIZE
+}
+
+/// Whether `value` matches the checksum of its overflow reference, values
+/// stored without one always do.
+pub(super) fn matches_checksum(value: &[u8], checksum: Option<u32>) -> bool {
+    checksum.is_none() || checksum == Some(crc32(value))
+}
+
+pub(super) enum Stored<'a> {
+    Inline(&'a [u8]),
+    /// The value length, the first overflow page and the value's checksum.
+    Overflow(usize, LogicalPageId, Option<u32>),
+}
+
+pub(super) fn decode(page_id: LogicalPageId, stored: &[u8]) -> Result<Stored<'_>> {
+    let corrupt = || Error::Corruption {
+        page: page_id,
+        reason: "invalid value reference",
+    };
+
+    match stored.split_first() {
+        None => Ok(Stored::Inline(&[])),
+        Some((&INLINE, value)) => Ok(Stored::Inline(value)),
+        Some((&tag, reference))
+            if (tag == OVERFLOW && reference.len() == 8 + 8)
+                || (tag == OVERFLOW_CHECKED && reference.len() == OVERFLOW_REF_LEN - 1) =>
+        {
+            let (len, rest) = reference.split_at(8);
+            let (first, checksum) = rest.split_at(8);
+            let len = usize::try_from(read_u64(len)).map_err(|_| corrupt())?;
+            let first = read_u64(first) as usize;
+
+            if first == 0 {
+                return Err(corrupt());
+            }
+
+            let checksum = match checksum {
+                [] => None,
+                crc => Some(u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]])),
+            };
+
+            Ok(Stored::Overflow(len, LogicalPageId::from(first), checksum))
+        }
+        _ => Err(corrupt()),
+    }
+}
+
+fn next_page(buf: &[u8]) -> Option<LogicalPageId> {
+    match read_u64(&buf[..8]) {
+        0 => None,
+        id => Some(LogicalPageId::from(id as usize)),
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut le = [0; 8];
+    le.copy_from_slice(bytes);
+    u64::from_le_bytes(le)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
+
+    use super::*;
+
+    #[test]
+    fn overflow_chain() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        let value = (0..3 * PAGE_CAPACITY).map(|i| i as u8).collect::<Vec<_>>();
+        let stored = tree.store_value(b"key", &value).unwrap();
+        assert_eq!(stored.len(), OVERFLOW_REF_LEN);
+
+        let root = tree.root_page_id;
+        assert_eq!(tree.load_value(root, &stored).unwrap(), value);
+
+        let stored = tree.store_value(b"key", b"small").unwrap();
+        assert_eq!(stored, b"\0small");
+        assert_eq!(tree.load_value(root, &stored).unwrap(), b"small");
+
+        let stored = tree.store_value(b"key", b"").unwrap();
+        assert!(stored.is_empty());
+        assert!(tree.load_value(root, &stored).unwrap().is_empty());
+
+        assert!(matches!(
+            tree.load_value(root, &[OVERFLOW, 1]),
+            Err(Error::Corruption { .. })
+        ));
+    }
+
+    #[test]
+    fn append_and_update_range() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        let mut value = (0..4 * CHUNK_LEN + 10).map(|i| i as u8).collect::<Vec<_>>();
+        tree.put(b"key", &value).unwrap();
+        tree.commit().unwrap();
+        let snapshot_value = value.clone();
+        let mut snapshot = tree.snapshot().unwrap();
+
+        // A write inside the third page rewrites it and the leaf only.
+        assert!(tree
+            .update_range(b"key", 2 * CHUNK_LEN + 5, b"xyz")
+            .unwrap());
+        value[2 * CHUNK_LEN + 5..2 * CHUNK_LEN + 8].copy_from_slice(b"xyz");
+        tree.commit().unwrap();
+        assert_eq!(tree.last_commit_stats().unwrap().pages.len(), 2);
+        assert_eq!(tree.get(b"key").unwrap().unwrap(), value);
+
+        // Appending fills the last page and links a new one to it.
+        let tail = vec![9; CHUNK_LEN];
+        assert!(tree.append(b"key", &tail).unwrap());
+        value.extend_from_slice(&tail);
+        tree.commit().unwrap();
+        assert_eq!(tree.last_commit_stats().unwrap().pages.len(), 3);
+        assert_eq!(tree.get(b"key").unwrap().unwrap(), value);
+
+        // Straddling the old end both overwrites and grows the value.
+        let offset = value.len() - 2;
+        assert!(tree.update_range(b"key", offset, b"abcd").unwrap());
+        value.truncate(offset);
+        value.extend_from_slice(b"abcd");
+        assert_eq!(tree.get(b"key").unwrap().unwrap(), value);
+
+        // Snapshots still see the value as it was.
+        assert_eq!(snapshot.get(b"key").unwrap().unwrap(), snapshot_value);
+
+        // Small values are rewritten and may move to overflow pages.
+        tree.put(b"small", b"abc").unwrap();
+        assert!(tree.update_range(b"small", 1, b"xy").unwrap());
+        assert_eq!(tree.get(b"small").unwrap().unwrap(), b"axy");
+        assert!(tree.append(b"small", &[1; PAGE_CAPACITY]).unwrap());
+        assert_eq!(
+            tree.get(b"small").unwrap().unwrap().len(),
+            3 + PAGE_CAPACITY
+        );
+
+        assert!(!tree.append(b"missing", b"x").unwrap());
+        assert!(matches!(
+            tree.update_range(b"small", 4 + PAGE_CAPACITY, b"x"),
+            Err(Error::OffsetOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn value_checksum() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+        let root = tree.root_page_id;
+
+        let value = vec![7; 3 * CHUNK_LEN];
+        let stored = tree.store_value(b"key", &value).unwrap();
+        let first = match decode(root, &stored).unwrap() {
+            Stored::Overflow(_, first, checksum) => {
+                assert_eq!(checksum, Some(crc32(&value)));
+                first
+            }
+            Stored::Inline(_) => unreachable!(),
+        };
+
+        // References without a checksum still load.
+        let unchecked = [&[OVERFLOW][..], &stored[1..17]].concat();
+        assert_eq!(tree.load_value(root, &unchecked).unwrap(), value);
+
+        // Damage the middle page of the chain, leaving its link intact.
+        let middle = next_page(tree.read_page(first).unwrap().buf()).unwrap();
+        let mut page = tree.pager().new_page_buffer().unwrap();
+        page.buf_mut()
+            .copy_from_slice(tree.read_page(middle).unwrap().buf());
+        page.buf_mut()[100] ^= 1;
+        tree.pager().update_page(middle, page).unwrap();
+
+        assert!(matches!(
+            tree.load_value(root, &stored),
+            Err(Error::Corruption { .. })
+        ));
+        assert!(tree.load_value(root, &unchecked).is_ok());
+    }
+}
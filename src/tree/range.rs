@@ -0,0 +1,357 @@
+use std::{ops::Bound, vec};
+
+use crate::{
+    cancel::{self, CancelToken},
+    pager::LogicalPageId,
+    Result,
+};
+
+use super::{node::Node, readers::IteratorGuard, Tree};
+
+/// Iterator over the entries of a `Tree` within a range of keys, in key
+/// order. It can also be walked in descending order with `rev`.
+///
+/// Leaves are loaded one at a time as the iterator reaches them by following
+/// the links between leaves.
+pub struct Range<'a> {
+    tree: &'a mut Tree,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    /// The leaves currently being read from each end, loaded on first use.
+    front: Option<Leaf>,
+    back: Option<Leaf>,
+    /// The last keys returned from each end, the two ends must not cross.
+    last_front: Option<Vec<u8>>,
+    last_back: Option<Vec<u8>>,
+    done: bool,
+    /// Checked before each leaf is read.
+    cancel: Option<CancelToken>,
+    /// Leaves read so far, from both ends.
+    leaves_read: u64,
+    _guard: IteratorGuard,
+}
+
+struct Leaf {
+    page_id: LogicalPageId,
+    keys: vec::IntoIter<Vec<u8>>,
+    values: vec::IntoIter<Vec<u8>>,
+    /// The sibling in the direction this end is moving.
+    sibling: Option<LogicalPageId>,
+}
+
+impl Leaf {
+    fn new(page_id: LogicalPageId, node: Node, sibling: Option<LogicalPageId>) -> Self {
+        Self {
+            page_id,
+            keys: node.keys.into_iter(),
+            values: node.values.into_iter(),
+            sibling,
+        }
+    }
+}
+
+impl<'a> Range<'a> {
+    pub(super) fn new(tree: &'a mut Tree, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Self {
+        let guard = IteratorGuard::new(&tree.readers);
+        let cancel = tree.cancel.clone();
+
+        Self {
+            tree,
+            start: start.map(<[u8]>::to_vec),
+            end: end.map(<[u8]>::to_vec),
+            front: None,
+            back: None,
+            last_front: None,
+            last_back: None,
+            done: false,
+            cancel,
+            leaves_read: 0,
+            _guard: guard,
+        }
+    }
+
+    /// Fail with `Error::Cancelled` once `token` is cancelled, it is
+    /// checked before each leaf is read.
+    pub fn cancel_on(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub(super) fn leaves_read(&self) -> u64 {
+        self.leaves_read
+    }
+
+    fn after_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => key >= &start[..],
+            Bound::Excluded(start) => key > &start[..],
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn before_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key <= &end[..],
+            Bound::Excluded(end) => key < &end[..],
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn front_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.front.is_none() {
+            cancel::check(self.cancel.as_ref())?;
+
+            let (page_id, node) = match &self.start {
+                Bound::Included(key) | Bound::Excluded(key) => self.tree.find_leaf(key)?,
+                Bound::Unbounded => self.tree.find_leaf(&[])?,
+            };
+
+            let next = node.metadata.next_leaf_pos;
+            self.front = Some(Leaf::new(page_id, node, next));
+            self.leaves_read += 1;
+        }
+
+        loop {
+            let front = self.front.as_mut().unwrap();
+
+            if let Some(key) = front.keys.next() {
+                let value = front.values.next().unwrap();
+                let page_id = front.page_id;
+
+                // The first leaf may start before the range.
+                if !self.after_start(&key) {
+                    continue;
+                }
+
+                let crossed = matches!(&self.last_back, Some(back) if key >= *back);
+
+                if crossed || !self.before_end(&key) {
+                    return Ok(None);
+                }
+
+                self.last_front = Some(key.clone());
+                return Ok(Some((key, self.tree.load_value(page_id, &value)?)));
+            }
+
+            let page_id = match front.sibling {
+                Some(page_id) => page_id,
+                None => return Ok(None),
+            };
+
+            cancel::check(self.cancel.as_ref())?;
+            let node = self.tree.read_node(page_id)?;
+            let next = node.metadata.next_leaf_pos;
+            self.front = Some(Leaf::new(page_id, node, next));
+            self.leaves_read += 1;
+        }
+    }
+
+    fn back_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.back.is_none() {
+            cancel::check(self.cancel.as_ref())?;
+
+            let (page_id, node) = match &self.end {
+                Bound::Included(key) | Bound::Excluded(key) => self.tree.find_leaf(key)?,
+                Bound::Unbounded => self.tree.last_leaf()?,
+            };
+
+            let prev = node.metadata.prev_leaf_pos;
+            self.back = Some(Leaf::new(page_id, node, prev));
+            self.leaves_read += 1;
+        }
+
+        loop {
+            let back = self.back.as_mut().unwrap();
+
+            if let Some(key) = back.keys.next_back() {
+                let value = back.values.next_back().unwrap();
+                let page_id = back.page_id;
+
+                // The last leaf may end after the range.
+                if !self.before_end(&key) {
+                    continue;
+                }
+
+                let crossed = matches!(&self.last_front, Some(front) if key <= *front);
+
+                if crossed || !self.after_start(&key) {
+                    return Ok(None);
+                }
+
+                self.last_back = Some(key.clone());
+                return Ok(Some((key, self.tree.load_value(page_id, &value)?)));
+            }
+
+            let page_id = match back.sibling {
+                Some(page_id) => page_id,
+                None => return Ok(None),
+            };
+
+            cancel::check(self.cancel.as_ref())?;
+            let node = self.tree.read_node(page_id)?;
+            let prev = node.metadata.prev_leaf_pos;
+            self.back = Some(Leaf::new(page_id, node, prev));
+            self.leaves_read += 1;
+        }
+    }
+
+    /// Stop after the first `None` or error, from either end.
+    fn fuse(
+        &mut self,
+        entry: Result<Option<(Vec<u8>, Vec<u8>)>>,
+    ) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        match entry {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Iterator for Range<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = self.front_entry();
+        self.fuse(entry)
+    }
+}
+
+impl DoubleEndedIterator for Range<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = self.back_entry();
+        self.fuse(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::RangeBounds;
+
+    use super::*;
+    use crate::{
+        pager::{test::mock::MemoryFile, DWALPager},
+        Error,
+    };
+
+    fn range(tree: &mut Tree, range: impl RangeBounds<u64>) -> Range<'_> {
+        let bound = |bound: Bound<&u64>| bound.map(|i| i.to_be_bytes());
+
+        let start = bound(range.start_bound());
+        let end = bound(range.end_bound());
+
+        tree.range((start, end))
+    }
+
+    fn decode(entry: Result<(Vec<u8>, Vec<u8>)>) -> u64 {
+        let (key, value) = entry.unwrap();
+        assert_eq!(key, value);
+
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&key);
+        u64::from_be_bytes(bytes)
+    }
+
+    fn collect(tree: &mut Tree, bounds: impl RangeBounds<u64> + Clone) -> Vec<u64> {
+        let forward: Vec<_> = range(tree, bounds.clone()).map(decode).collect();
+
+        let mut backward: Vec<_> = range(tree, bounds).rev().map(decode).collect();
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        forward
+    }
+
+    #[test]
+    fn range_scan() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        // Only even keys so bounds can fall between entries.
+        for i in (0..4_000u64).step_by(2) {
+            tree.put(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+
+        assert_eq!(
+            collect(&mut tree, ..),
+            (0..4_000).step_by(2).collect::<Vec<_>>()
+        );
+        assert_eq!(collect(&mut tree, 10..16), vec![10, 12, 14]);
+        assert_eq!(collect(&mut tree, 11..=16), vec![12, 14, 16]);
+        assert_eq!(
+            collect(&mut tree, (Bound::Excluded(10), Bound::Included(14))),
+            vec![12, 14]
+        );
+        assert_eq!(
+            collect(&mut tree, 3_000..),
+            (3_000..4_000).step_by(2).collect::<Vec<_>>()
+        );
+        assert_eq!(collect(&mut tree, 5_000..), Vec::<u64>::new());
+        assert_eq!(collect(&mut tree, 7..7), Vec::<u64>::new());
+
+        // Empty leaves left behind by deletes are skipped.
+        for i in (1_000..3_000u64).step_by(2) {
+            tree.delete(&i.to_be_bytes()).unwrap();
+        }
+        assert_eq!(collect(&mut tree, 998..3_004), vec![998, 3_000, 3_002]);
+    }
+
+    #[test]
+    fn cancel_on() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+        for i in 0..2_000u64 {
+            tree.put(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+
+        // The leaf being read is finished, the next one isn't read.
+        let token = CancelToken::new();
+        let mut scan = range(&mut tree, ..).cancel_on(token.clone());
+        assert_eq!(decode(scan.next().unwrap()), 0);
+        token.cancel();
+        let read = scan.by_ref().take_while(Result::is_ok).count();
+        assert!(read > 0 && read < 2_000);
+        assert!(scan.next().is_none());
+
+        assert!(matches!(
+            range(&mut tree, ..).cancel_on(token).next_back(),
+            Some(Err(Error::Cancelled))
+        ));
+    }
+
+    #[test]
+    fn both_ends() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for i in 0..1_000u64 {
+            tree.put(&i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+
+        let mut iter = range(&mut tree, 100..900);
+        let mut seen = Vec::new();
+
+        // Alternate ends so they meet in the middle of a leaf.
+        loop {
+            match (iter.next(), iter.next_back()) {
+                (None, None) => break,
+                (front, back) => seen.extend(front.into_iter().chain(back).map(decode)),
+            }
+        }
+
+        seen.sort_unstable();
+        assert_eq!(seen, (100..900).collect::<Vec<_>>());
+        assert!(iter.next().is_none());
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::{Error, Result};
+
+use super::Tree;
+
+/// Number of snapshots and iterators open on a `Db`, see
+/// `Db::reader_counts`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReaderCounts {
+    /// Snapshots alive, clones included. Each pins the version it reads
+    /// until it is dropped.
+    pub snapshots: usize,
+    /// Ranges and cursors alive, including those of snapshots.
+    pub iterators: usize,
+}
+
+/// Counts of the readers of a tree, shared with its snapshots.
+#[derive(Debug)]
+pub(super) struct Readers {
+    snapshots: AtomicUsize,
+    iterators: AtomicUsize,
+    /// Most snapshots `Tree::snapshot` hands out, `usize::MAX` for no limit.
+    max_snapshots: AtomicUsize,
+}
+
+impl Default for Readers {
+    fn default() -> Self {
+        Self {
+            snapshots: AtomicUsize::new(0),
+            iterators: AtomicUsize::new(0),
+            max_snapshots: AtomicUsize::new(usize::MAX),
+        }
+    }
+}
+
+impl Readers {
+    /// Count a new snapshot, failing if `max_snapshots` are already open.
+    pub(super) fn open_snapshot(&self) -> Result<()> {
+        let max = self.max_snapshots.load(Ordering::Relaxed);
+
+        self.snapshots
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < max).then(|| open + 1)
+            })
+            .map(drop)
+            .map_err(|_| Error::TooManySnapshots(max))
+    }
+
+    /// Count a clone of a snapshot, which can't fail so isn't limited.
+    pub(super) fn clone_snapshot(&self) {
+        self.snapshots.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub(super) fn close_snapshot(&self) {
+        self.snapshots.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    fn counts(&self) -> ReaderCounts {
+        ReaderCounts {
+            snapshots: self.snapshots.load(Ordering::Acquire),
+            iterators: self.iterators.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// Counts an open range or cursor for as long as it is alive.
+#[derive(Debug)]
+pub(super) struct IteratorGuard {
+    readers: Arc<Readers>,
+}
+
+impl IteratorGuard {
+    pub(super) fn new(readers: &Arc<Readers>) -> Self {
+        readers.iterators.fetch_add(1, Ordering::AcqRel);
+
+        Self {
+            readers: readers.clone(),
+        }
+    }
+}
+
+impl Drop for IteratorGuard {
+    fn drop(&mut self) {
+        self.readers.iterators.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Tree {
+    pub(crate) fn reader_counts(&self) -> ReaderCounts {
+        self.readers.counts()
+    }
+
+    /// Fail `snapshot` with `Error::TooManySnapshots` while `max` snapshots
+    /// are open, `None` to lift the limit.
+    pub(crate) fn set_max_snapshots(&self, max: Option<usize>) {
+        self.readers
+            .max_snapshots
+            .store(max.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
+
+    #[test]
+    fn counts() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+        tree.put(b"key", b"value").unwrap();
+        tree.commit().unwrap();
+        // Ranges borrow the tree, read the counts through the shared state.
+        let readers = tree.readers.clone();
+
+        let mut snapshot = tree.snapshot().unwrap();
+        let clone = snapshot.clone();
+        let range = tree.range::<[u8]>(..);
+        assert_eq!(
+            readers.counts(),
+            ReaderCounts {
+                snapshots: 2,
+                iterators: 1,
+            }
+        );
+        drop(range);
+
+        let range = snapshot.range::<[u8]>(..);
+        drop(clone);
+        assert_eq!(readers.counts().iterators, 1);
+        drop(range);
+        assert_eq!(
+            tree.reader_counts(),
+            ReaderCounts {
+                snapshots: 1,
+                iterators: 0,
+            }
+        );
+
+        drop(snapshot);
+        assert_eq!(tree.reader_counts(), ReaderCounts::default());
+    }
+
+    #[test]
+    fn max_snapshots() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+        tree.set_max_snapshots(Some(2));
+
+        let first = tree.snapshot().unwrap();
+        let _second = first.clone();
+        assert!(matches!(tree.snapshot(), Err(Error::TooManySnapshots(2))));
+        // Clones aren't refused, they only count towards the limit.
+        let _third = first.clone();
+
+        drop(first);
+        assert!(matches!(tree.snapshot(), Err(Error::TooManySnapshots(2))));
+
+        tree.set_max_snapshots(None);
+        tree.snapshot().unwrap();
+    }
+}
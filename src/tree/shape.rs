@@ -0,0 +1,274 @@
+use crate::{pager::LogicalPageId, Result};
+
+use super::Tree;
+
+/// Number of children of the internal nodes of a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanoutStats {
+    pub internal_nodes: usize,
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+}
+
+impl Tree {
+    /// Number of levels in the tree, a tree that is a single leaf has a
+    /// height of one.
+    ///
+    /// All leaves are at the same depth so this only walks down the leftmost
+    /// path.
+    pub(crate) fn height(&mut self) -> Result<usize> {
+        let mut page_id = self.root_page_id;
+        let mut height = 1;
+
+        loop {
+            let node = self.read_node(page_id)?;
+
+            if node.is_leaf() {
+                return Ok(height);
+            }
+
+            page_id = node.children[0];
+            height += 1;
+        }
+    }
+
+    /// Number of nodes on each level, starting with the root.
+    ///
+    /// Only internal nodes are read, the number of leaves follows from the
+    /// children of the level above them.
+    pub(crate) fn node_count_by_level(&mut self) -> Result<Vec<usize>> {
+        let mut counts = Vec::new();
+        // Children of the last internal level seen, they are the leaves.
+        let mut leaves = 1;
+
+        self.walk_internal(|level, children| {
+            if counts.len() <= level {
+                counts.push(0);
+                leaves = 0;
+            }
+
+            counts[level] += 1;
+            leaves += children.len();
+        })?;
+
+        counts.push(leaves);
+
+        Ok(counts)
+    }
+
+    /// Fanout of the internal nodes, `None` if the tree is a single leaf.
+    pub(crate) fn fanout_stats(&mut self) -> Result<Option<FanoutStats>> {
+        let mut stats: Option<FanoutStats> = None;
+        let mut total = 0;
+
+        self.walk_internal(|_, children| {
+            let fanout = children.len();
+            total += fanout;
+
+            let stats = stats.get_or_insert(FanoutStats {
+                internal_nodes: 0,
+                min: fanout,
+                max: fanout,
+                mean: 0.0,
+            });
+
+            stats.internal_nodes += 1;
+            stats.min = stats.min.min(fanout);
+            stats.max = stats.max.max(fanout);
+        })?;
+
+        if let Some(stats) = &mut stats {
+            stats.mean = total as f64 / stats.internal_nodes as f64;
+        }
+
+        Ok(stats)
+    }
+
+    /// Up to `n - 1` keys, in order, dividing the tree into `n` ranges of
+    /// about the same number of entries. The first range holds the keys
+    /// before the first point, the next those from it up to the second
+    /// point, and so on.
+    ///
+    /// The subtrees on a level are taken to be the same size, so only the
+    /// levels down to the first with at least `n` nodes are read. A tree
+    /// with fewer leaves than that is split on the keys in its leaves.
+    pub(crate) fn split_points(&mut self, n: usize) -> Result<Vec<Vec<u8>>> {
+        // The nodes of a level along with the lowest key each can hold, once
+        // the leaves are reached only their keys are kept.
+        let mut lows = vec![Vec::new()];
+        let mut pages = vec![self.root_page_id];
+
+        while lows.len() < n && !pages.is_empty() {
+            let mut next_lows = Vec::new();
+            let mut next_pages = Vec::new();
+
+            for (low, page_id) in lows.into_iter().zip(pages) {
+                let node = self.read_node(page_id)?;
+
+                if node.is_leaf() {
+                    next_lows.extend(node.keys);
+                } else {
+                    next_lows.push(low);
+                    next_lows.extend(node.keys);
+                    next_pages.extend(node.children);
+                }
+            }
+
+            lows = next_lows;
+            pages = next_pages;
+        }
+
+        let mut points: Vec<Vec<u8>> = Vec::new();
+
+        for idx in 1..n {
+            // The first node would start an empty range, and with fewer keys
+            // than ranges the same one comes up repeatedly.
+            let low = match idx * lows.len() / n {
+                0 => continue,
+                low => &lows[low],
+            };
+
+            if points.last() != Some(low) {
+                points.push(low.clone());
+            }
+        }
+
+        Ok(points)
+    }
+
+    /// Visit every internal node level by level with its level and children.
+    fn walk_internal(&mut self, mut f: impl FnMut(usize, &[LogicalPageId])) -> Result<()> {
+        let mut level = vec![self.root_page_id];
+        let mut depth = 0;
+
+        while !level.is_empty() {
+            let mut next = Vec::new();
+
+            for page_id in level {
+                let node = self.read_node(page_id)?;
+
+                if node.is_leaf() {
+                    // Leaves are all on the same level.
+                    return Ok(());
+                }
+
+                f(depth, &node.children);
+                next.extend_from_slice(&node.children);
+            }
+
+            // Nodes on the level above leaves don't need their children read.
+            if self.read_node(next[0])?.is_leaf() {
+                return Ok(());
+            }
+
+            level = next;
+            depth += 1;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+    use crate::pager::{test::mock::MemoryFile, DWALPager};
+
+    #[test]
+    fn shape() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        assert_eq!(tree.height().unwrap(), 1);
+        assert_eq!(tree.node_count_by_level().unwrap(), vec![1]);
+        assert_eq!(tree.fanout_stats().unwrap(), None);
+
+        // Long keys keep the fanout small enough for three levels.
+        for i in 0..2_000u64 {
+            let mut key = i.to_be_bytes().to_vec();
+            key.resize(256, 0);
+            tree.put(&key, &[]).unwrap();
+        }
+
+        let height = tree.height().unwrap();
+        assert!(height >= 3);
+
+        let counts = tree.node_count_by_level().unwrap();
+        assert_eq!(counts.len(), height);
+        assert_eq!(counts[0], 1);
+        assert_eq!(counts[height - 1], leaves(&mut tree));
+
+        let stats = tree.fanout_stats().unwrap().unwrap();
+        assert_eq!(
+            stats.internal_nodes,
+            counts[..height - 1].iter().sum::<usize>()
+        );
+        assert!(stats.min >= 2);
+        assert!(stats.min as f64 <= stats.mean && stats.mean <= stats.max as f64);
+        // Every node but the root is counted as a child exactly once.
+        let children = counts[1..].iter().sum::<usize>();
+        assert_eq!(stats.mean, children as f64 / stats.internal_nodes as f64);
+    }
+
+    #[test]
+    fn split_points() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+        assert!(tree.split_points(4).unwrap().is_empty());
+
+        for i in 0..10u64 {
+            tree.put(&i.to_be_bytes(), &[]).unwrap();
+        }
+
+        // A single leaf is split on its keys.
+        let points = tree.split_points(5).unwrap();
+        let expected = (1..5u64)
+            .map(|i| (2 * i).to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(points, expected);
+        assert_eq!(tree.split_points(20).unwrap().len(), 9);
+        assert!(tree.split_points(1).unwrap().is_empty());
+        assert!(tree.split_points(0).unwrap().is_empty());
+
+        for i in 10..20_000u64 {
+            tree.put(&i.to_be_bytes(), &[0; 16]).unwrap();
+        }
+
+        let points = tree.split_points(8).unwrap();
+        assert_eq!(points.len(), 7);
+        assert!(points.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Nodes are between half full and full, so ranges stay within a
+        // factor of a few of each other.
+        let mut bounds = vec![Bound::Unbounded];
+        bounds.extend(points.iter().map(|point| Bound::Included(&point[..])));
+        bounds.push(Bound::Unbounded);
+
+        for pair in bounds.windows(2) {
+            let end = match pair[1] {
+                Bound::Included(key) => Bound::Excluded(key),
+                end => end,
+            };
+            let count = tree.count_range::<[u8]>((pair[0], end)).unwrap();
+            assert!(
+                (20_000 / 8 / 3..20_000 / 8 * 3).contains(&count),
+                "{}",
+                count
+            );
+        }
+    }
+
+    fn leaves(tree: &mut Tree) -> usize {
+        let (page_id, _) = tree.find_leaf(&[]).unwrap();
+
+        let mut count = 0;
+        let mut page_id = Some(page_id);
+        while let Some(id) = page_id {
+            count += 1;
+            page_id = tree.read_node(id).unwrap().metadata.next_leaf_pos;
+        }
+
+        count
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::VecDeque,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::Result;
+
+use super::{Range, Tree, TreeHash, ValueGuard};
+
+/// Entries `IntoRange` reads at a time.
+const INTO_RANGE_BATCH: usize = 256;
+
+/// A read only view of the tree as of the commit it was taken at.
+///
+/// The version is pinned in the pager so writes can continue while the
+/// snapshot is alive without overwriting anything it can read. Dropping the
+/// snapshot releases the version, the pages only it could read are freed by
+/// the next commit.
+///
+/// Snapshots are `Send` and cheap to clone, a clone pins the version once
+/// more and is released on its own. The tree and all of its snapshots read
+/// through the one page cache of the pager, a snapshot keeps no pages of its
+/// own, so dozens of them on as many threads take no more memory than the
+/// old pages they keep from being reused. They take turns on the pager
+/// though, reads on different threads don't run in parallel.
+pub struct Snapshot {
+    tree: Tree,
+}
+
+impl Snapshot {
+    pub(super) fn new(tree: Tree) -> Self {
+        Self { tree }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tree.get(key)
+    }
+
+    /// Look up `key` without copying its value, see `ValueGuard`.
+    pub fn get_ref(&mut self, key: &[u8]) -> Result<Option<ValueGuard<'_>>> {
+        self.tree.get_ref(key)
+    }
+
+    /// Iterate over the entries with keys in `range`, in key order.
+    pub fn range<K: AsRef<[u8]> + ?Sized>(&mut self, range: impl RangeBounds<K>) -> Range<'_> {
+        self.tree.range(range)
+    }
+
+    /// Iterate over the entries with keys in `range` like `range`, but
+    /// owning the snapshot instead of borrowing it.
+    ///
+    /// The iterator can be stored and sent to other threads on its own,
+    /// like an iterator handed to another language through bindings.
+    pub fn into_range<K: AsRef<[u8]> + ?Sized>(self, range: impl RangeBounds<K>) -> IntoRange {
+        let bound = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+
+        IntoRange {
+            next: bound(range.start_bound()),
+            end: bound(range.end_bound()),
+            snapshot: self,
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Number of entries with keys in `range`, see `Db::count_range`.
+    pub fn count_range<K: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        range: impl RangeBounds<K>,
+    ) -> Result<u64> {
+        self.tree.count_range(range)
+    }
+
+    /// Hash of the entries as of the snapshot, comparable with the hash of a
+    /// replica or backup without comparing their files.
+    pub fn root_hash(&mut self) -> Result<TreeHash> {
+        self.tree.root_hash()
+    }
+
+    /// Hashes of the tree nodes `depth` levels below the root, see
+    /// `Db::hashes_at_depth`.
+    pub fn hashes_at_depth(&mut self, depth: usize) -> Result<Vec<(Vec<u8>, TreeHash)>> {
+        self.tree.hashes_at_depth(depth)
+    }
+}
+
+/// Iterator over a range of a snapshot that owns it, see
+/// `Snapshot::into_range`.
+///
+/// Entries are read a batch at a time, each batch looks up where the last
+/// one ended. The snapshot doesn't change in between so nothing is missed.
+pub struct IntoRange {
+    snapshot: Snapshot,
+    /// Where the next batch starts, past the last key returned.
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl IntoRange {
+    fn read_batch(&mut self) -> Result<()> {
+        let range = Range::new(
+            &mut self.snapshot.tree,
+            self.next.as_ref().map(Vec::as_slice),
+            self.end.as_ref().map(Vec::as_slice),
+        );
+
+        for entry in range.take(INTO_RANGE_BATCH) {
+            self.batch.push_back(entry?);
+        }
+
+        self.done = self.batch.len() < INTO_RANGE_BATCH;
+        if let Some((key, _)) = self.batch.back() {
+            self.next = Bound::Excluded(key.clone());
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for IntoRange {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() && !self.done {
+            if let Err(err) = self.read_batch() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Self {
+        if let Some(version) = self.tree.version {
+            self.tree.pager().pin(version);
+        }
+        self.tree.readers.clone_snapshot();
+
+        Self::new(Tree {
+            pager: self.tree.pager.clone(),
+            root_page_id: self.tree.root_page_id,
+            committed_root_page_id: self.tree.committed_root_page_id,
+            version: self.tree.version,
+            frozen: self.tree.frozen.clone(),
+            readers: self.tree.readers.clone(),
+            misses: self.tree.misses.clone(),
+            cancel: self.tree.cancel.clone(),
+            progress: self.tree.progress.clone(),
+        })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Some(version) = self.tree.version {
+            self.tree.pager().unpin(version);
+        }
+        self.tree.readers.close_snapshot();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pager::{test::mock::MemoryFile, DWALPager, File};
+
+    use super::*;
+
+    #[test]
+    fn frozen_view() {
+        let file = MemoryFile::default();
+        let pager = DWALPager::recover(file.clone()).unwrap();
+        let mut tree = Tree::open(pager).unwrap();
+
+        for i in 0..1_000u64 {
+            tree.put(&i.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let before = file.len().unwrap();
+        let mut snapshot = tree.snapshot().unwrap();
+
+        // Splits, merges and collapsing the root all happen after the
+        // snapshot was taken.
+        for i in 1_000..2_000u64 {
+            tree.put(&i.to_be_bytes(), &[2; 32]).unwrap();
+            tree.commit().unwrap();
+        }
+        for i in 0..1_900u64 {
+            tree.delete(&i.to_be_bytes()).unwrap();
+            tree.commit().unwrap();
+        }
+
+        assert_eq!(
+            snapshot.get(&7u64.to_be_bytes()).unwrap(),
+            Some(vec![1; 32])
+        );
+        assert_eq!(snapshot.get(&1_500u64.to_be_bytes()).unwrap(), None);
+        assert_eq!(tree.get(&7u64.to_be_bytes()).unwrap(), None);
+
+        let keys = snapshot
+            .range::<[u8]>(..)
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+        let expected = (0..1_000u64)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+
+        // Everything written since had to go to new pages.
+        let held = file.len().unwrap() - before;
+        let len = file.len().unwrap();
+        drop(snapshot);
+        tree.commit().unwrap();
+
+        // Once released the pages the snapshot held on to are reused, the
+        // file only grows a little for the free list itself.
+        for i in 0..1_000u64 {
+            tree.put(&i.to_be_bytes(), &[3; 32]).unwrap();
+            tree.commit().unwrap();
+        }
+        assert!(file.len().unwrap() - len < held / 100);
+
+        assert_eq!(tree.range::<[u8]>(..).count(), 1_100);
+    }
+
+    #[test]
+    fn into_range() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for i in 0..1_000u64 {
+            tree.put(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let range = 10u64.to_be_bytes()..990u64.to_be_bytes();
+        let mut snapshot = tree.snapshot().unwrap();
+        let expected = snapshot
+            .range(range.clone())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let iter = snapshot.into_range(range);
+
+        // It outlives the writes, and the snapshot it came from, on
+        // another thread.
+        for i in 0..1_000u64 {
+            tree.delete(&i.to_be_bytes()).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let entries = std::thread::spawn(move || iter.collect::<Result<Vec<_>>>())
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(entries.len(), 980);
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn shared_across_threads() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for i in 0..1_000u64 {
+            tree.put(&i.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        tree.commit().unwrap();
+
+        // Reading everything once caches every page a snapshot can read.
+        let snapshot = tree.snapshot().unwrap();
+        assert_eq!(snapshot.clone().range::<[u8]>(..).count(), 1_000);
+        let allocated = tree.cache_usage().allocated_bytes;
+
+        std::thread::scope(|scope| {
+            for _ in 0..48 {
+                let mut snapshot = snapshot.clone();
+                scope.spawn(move || {
+                    for i in 0..1_000u64 {
+                        let value = snapshot.get(&i.to_be_bytes()).unwrap();
+                        assert_eq!(value, Some(vec![1; 32]));
+                    }
+                });
+            }
+
+            // The tree moves on while they read.
+            for i in 0..1_000u64 {
+                tree.put(&i.to_be_bytes(), &[2; 32]).unwrap();
+                tree.commit().unwrap();
+            }
+        });
+
+        // The snapshots shared the pages cached before, only the writes
+        // could grow the cache, up to its limit.
+        let usage = tree.cache_usage();
+        assert!(usage.allocated_bytes <= allocated.max(usage.max_bytes));
+
+        let mut snapshot = snapshot;
+        assert_eq!(
+            snapshot.get(&7u64.to_be_bytes()).unwrap(),
+            Some(vec![1; 32])
+        );
+    }
+
+    #[test]
+    fn stable_root_hash() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        for i in 0..1_000u64 {
+            tree.put(&i.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        tree.commit().unwrap();
+
+        let hash = tree.root_hash().unwrap();
+        let mut snapshot = tree.snapshot().unwrap();
+
+        for i in 500..1_500u64 {
+            tree.put(&i.to_be_bytes(), &[2; 32]).unwrap();
+            tree.commit().unwrap();
+        }
+
+        assert_eq!(snapshot.root_hash().unwrap(), hash);
+        assert_ne!(tree.root_hash().unwrap(), hash);
+    }
+}
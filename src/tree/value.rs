@@ -0,0 +1,149 @@
+use std::{fmt, marker::PhantomData, ops::Deref};
+
+use crate::{pager::PageBuf, Error, Result};
+
+use super::{
+    node::Node,
+    overflow::{self, Stored, CHUNK_LEN},
+    Tree,
+};
+
+/// A value read without copying it out of the page cache, it derefs to the
+/// value's bytes.
+///
+/// The guard holds on to the page the value is stored in. Values that span
+/// several overflow pages are the exception, they are copied into a buffer.
+pub struct ValueGuard<'a> {
+    value: Value,
+    /// Nothing can be written to the tree while the guard is alive.
+    _tree: PhantomData<&'a mut Tree>,
+}
+
+enum Value {
+    Page {
+        page: PageBuf,
+        start: usize,
+        end: usize,
+    },
+    Owned(Vec<u8>),
+}
+
+impl Tree {
+    /// Look up `key` like `get` but without copying its value.
+    pub(crate) fn get_ref(&mut self, key: &[u8]) -> Result<Option<ValueGuard<'_>>> {
+        let mut page_id = self.root_page_id;
+        let mut page = self.read_page(page_id)?;
+
+        // Only internal nodes are decoded, the value is found in the
+        // encoded leaf.
+        while !Node::is_leaf_page(&page) {
+            let node = Node::decode(page_id, &page)?;
+            page_id = node.children[node.child_index(key)];
+            page = self.read_page(page_id)?;
+        }
+
+        let (offset, len) = match Node::find_value(page_id, &page, key)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let value = match overflow::decode(page_id, &page.buf()[offset..offset + len])? {
+            // Skip the tag in front of the value, if there is one.
+            Stored::Inline(value) => {
+                let start = offset + len - value.len();
+
+                Value::Page {
+                    page,
+                    start,
+                    end: offset + len,
+                }
+            }
+            Stored::Overflow(len, first, checksum) if len <= CHUNK_LEN => {
+                let page = self.read_overflow_page(first)?;
+
+                if !overflow::matches_checksum(&page.buf()[8..8 + len], checksum) {
+                    return Err(Error::Corruption {
+                        page: page_id,
+                        reason: "overflow value checksum mismatch",
+                    });
+                }
+
+                Value::Page {
+                    page,
+                    start: 8,
+                    end: 8 + len,
+                }
+            }
+            Stored::Overflow(..) => {
+                let stored = page.buf()[offset..offset + len].to_vec();
+                Value::Owned(self.load_value(page_id, &stored)?)
+            }
+        };
+
+        Ok(Some(ValueGuard {
+            value,
+            _tree: PhantomData,
+        }))
+    }
+}
+
+impl Deref for ValueGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.value {
+            Value::Page { page, start, end } => &page.buf()[*start..*end],
+            Value::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueGuard<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for ValueGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ValueGuard").field(&&**self).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pager::{test::mock::MemoryFile, DWALPager, PAGE_CAPACITY};
+
+    use super::*;
+
+    #[test]
+    fn get_ref() {
+        let mut tree = Tree::open(DWALPager::recover(MemoryFile::default()).unwrap()).unwrap();
+
+        let value = |len: usize| (0..len).map(|i| i as u8).collect::<Vec<_>>();
+        let lens = [0, 10, PAGE_CAPACITY / 2, 3 * PAGE_CAPACITY];
+
+        for i in 0..500u64 {
+            tree.put(&i.to_be_bytes(), &value(8)).unwrap();
+        }
+        for (i, len) in lens.iter().enumerate() {
+            tree.put(&(i as u64).to_be_bytes(), &value(*len)).unwrap();
+        }
+        tree.commit().unwrap();
+
+        for (i, len) in lens.iter().enumerate() {
+            let guard = tree.get_ref(&(i as u64).to_be_bytes()).unwrap().unwrap();
+            assert_eq!(&*guard, &value(*len)[..]);
+
+            // Only values split across overflow pages are copied.
+            let copied = matches!(guard.value, Value::Owned(_));
+            assert_eq!(copied, *len > CHUNK_LEN);
+        }
+
+        assert_eq!(
+            &*tree.get_ref(&499u64.to_be_bytes()).unwrap().unwrap(),
+            &value(8)[..]
+        );
+        assert!(tree.get_ref(b"missing").unwrap().is_none());
+    }
+}
@@ -1,37 +1,837 @@
-// use treedb::tree::Tree;
-//
+use std::{
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-// #[test]
-// fn smoke() {
-//     let mut tmp = std::env::temp_dir();
-//     tmp.push("pager.smoke");
+use treedb::{
+    CancelToken, CommitPolicy, ConflictPolicy, Db, Error, ErrorKind, File, IntoRange, MemoryFile,
+    OpenOptions, ReaderCounts, Snapshot, WriteBatch,
+};
 
-//     let mut tree = Tree::new(tmp).unwrap();
+#[test]
+fn smoke() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("smoke.db");
 
-//     let key = "hello".to_string().into_bytes();
-//     let value = "world".to_string().into_bytes();
+    let mut db = Db::open(&path).unwrap();
 
-//     tree.put(key.clone(), value.clone()).unwrap();
+    db.put(b"hello", b"world").unwrap();
+    db.put(b"key", b"value").unwrap();
 
-//     let res = tree.get(&key).unwrap().unwrap();
+    assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+    assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.get(b"missing").unwrap(), None);
 
-//     assert_eq!(res.0, key);
-//     assert_eq!(res.1, value);
+    db.put(b"hello", b"there").unwrap();
+    assert_eq!(db.get(b"hello").unwrap(), Some(b"there".to_vec()));
 
-//     let key1 = "key".to_string().into_bytes();
-//     let value1 = "value".to_string().into_bytes();
+    assert_eq!(db.delete(b"key").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(db.delete(b"key").unwrap(), None);
+    assert_eq!(db.get(b"key").unwrap(), None);
 
-//     tree.put(key1.clone(), value1.clone()).unwrap();
+    db.put(b"a", b"1").unwrap();
+    db.put(b"b", b"2").unwrap();
 
-//     let res = tree.get(&key1).unwrap().unwrap();
+    let entries = db
+        .range(b"a".as_ref()..b"hello".as_ref())
+        .collect::<treedb::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"2".to_vec())
+        ]
+    );
 
-//     assert_eq!(res.0, key1);
-//     assert_eq!(res.1, value1);
+    db.flush().unwrap();
+}
 
-//     let res = tree.get(&key).unwrap().unwrap();
+#[test]
+fn reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("reopen.db");
 
-//     assert_eq!(res.0, key);
-//     assert_eq!(res.1, value);
+    {
+        let mut db = Db::open(&path).unwrap();
 
-//     tree.flush_all().unwrap();
-// }
+        for i in 0..100u32 {
+            db.put(&i.to_be_bytes(), &[i as u8; 16]).unwrap();
+        }
+        db.delete(&7u32.to_be_bytes()).unwrap();
+
+        db.flush().unwrap();
+    }
+
+    let mut db = Db::open(&path).unwrap();
+
+    for i in 0..100u32 {
+        let expected = if i == 7 {
+            None
+        } else {
+            Some(vec![i as u8; 16])
+        };
+        assert_eq!(db.get(&i.to_be_bytes()).unwrap(), expected);
+    }
+
+    // Pages allocated after reopening must not clobber existing ones.
+    db.put(b"after", b"reopen").unwrap();
+    assert_eq!(db.get(&1u32.to_be_bytes()).unwrap(), Some(vec![1; 16]));
+    drop(db);
+
+    let mut db = OpenOptions::new().read_only(true).open(&path).unwrap();
+    assert_eq!(db.get(b"after").unwrap(), Some(b"reopen".to_vec()));
+    assert!(matches!(db.put(b"a", b"b"), Err(treedb::Error::ReadOnly)));
+}
+
+#[test]
+fn missing_without_create() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let res = OpenOptions::new()
+        .create(false)
+        .open(dir.path().join("missing.db"));
+
+    assert!(matches!(res, Err(treedb::Error::Io(_))));
+}
+
+#[test]
+fn transactions() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("txn.db");
+
+    {
+        let mut db = Db::open(&path).unwrap();
+        db.put(b"before", b"txn").unwrap();
+
+        // Enough keys to split the root, which has to be undone as well.
+        let mut txn = db.begin();
+        for i in 0..500u32 {
+            txn.put(&i.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        txn.delete(b"before").unwrap();
+        assert_eq!(txn.get(&7u32.to_be_bytes()).unwrap(), Some(vec![1; 32]));
+        txn.rollback().unwrap();
+
+        assert_eq!(db.get(&7u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(db.get(b"before").unwrap(), Some(b"txn".to_vec()));
+        assert_eq!(db.height().unwrap(), 1);
+
+        let mut txn = db.begin();
+        txn.put(b"dropped", b"txn").unwrap();
+        drop(txn);
+        assert_eq!(db.get(b"dropped").unwrap(), None);
+
+        let mut txn = db.begin();
+        for i in 0..500u32 {
+            txn.put(&i.to_be_bytes(), &[2; 32]).unwrap();
+        }
+        txn.delete(b"before").unwrap();
+        txn.commit().unwrap();
+    }
+
+    let mut db = Db::open(&path).unwrap();
+    assert_eq!(db.get(b"before").unwrap(), None);
+    assert_eq!(db.get(b"dropped").unwrap(), None);
+    assert_eq!(db.range::<[u8]>(..).count(), 500);
+    assert_eq!(db.get(&7u32.to_be_bytes()).unwrap(), Some(vec![2; 32]));
+}
+
+#[test]
+fn write_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("batch.db");
+
+    {
+        let mut db = Db::open(&path).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"b", b"2")
+            .put(b"c", b"3")
+            .delete(b"a")
+            .put(b"b", b"4");
+        assert_eq!(batch.len(), 4);
+
+        db.write(batch).unwrap();
+
+        // A failing operation discards the whole batch.
+        let mut batch = WriteBatch::new();
+        batch.delete(b"c").put(&[0; 4096], b"big");
+        assert!(matches!(
+            db.write(batch),
+            Err(treedb::Error::KeyTooLarge(4096))
+        ));
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    let mut db = Db::open(&path).unwrap();
+    let entries = db
+        .range::<[u8]>(..)
+        .collect::<treedb::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            (b"b".to_vec(), b"4".to_vec()),
+            (b"c".to_vec(), b"3".to_vec())
+        ]
+    );
+}
+
+#[test]
+fn in_memory() {
+    let mut db = Db::open_in_memory().unwrap();
+    db.put(b"hello", b"world").unwrap();
+    assert_eq!(db.get(b"hello").unwrap(), Some(b"world".to_vec()));
+
+    // The file outlives the database opened on it.
+    let file = MemoryFile::new();
+    {
+        let mut db = OpenOptions::new().open_file(file.clone()).unwrap();
+        for i in 0..1_000u64 {
+            db.put(&i.to_be_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+    }
+
+    let mut db = OpenOptions::new()
+        .read_only(true)
+        .open_file(file.clone())
+        .unwrap();
+    assert_eq!(db.range::<[u8]>(..).count(), 1_000);
+    assert!(db.put(b"key", b"value").is_err());
+}
+
+#[test]
+fn follow_writer() {
+    let file = MemoryFile::new();
+    let mut writer = OpenOptions::new().open_file(file.clone()).unwrap();
+    writer.put(b"a", b"1").unwrap();
+
+    let mut reader = OpenOptions::new()
+        .read_only(true)
+        .open_file(file.clone())
+        .unwrap();
+    assert_eq!(reader.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+    // Enough to split the root.
+    for i in 0..1_000u64 {
+        writer.put(&i.to_be_bytes(), b"value").unwrap();
+    }
+
+    assert!(reader.refresh().unwrap());
+    assert_eq!(reader.range::<[u8]>(..).count(), 1_001);
+    assert!(!reader.refresh().unwrap());
+}
+
+#[test]
+fn group_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("group_commit.db");
+
+    let mut db = OpenOptions::new()
+        .commit_policy(CommitPolicy::Interval(Duration::from_secs(3600)))
+        .open(&path)
+        .unwrap();
+
+    let mut versions = Vec::new();
+    for i in 0..10u64 {
+        let mut txn = db.begin();
+        txn.put(&i.to_be_bytes(), b"value").unwrap();
+        versions.push(txn.commit().unwrap());
+    }
+    assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // One sync covers every commit before it.
+    db.wait_for_durable(versions[9]).unwrap();
+    db.wait_for_durable(versions[0]).unwrap();
+    drop(db);
+
+    let mut db = Db::open(&path).unwrap();
+    assert_eq!(db.range::<[u8]>(..).count(), 10);
+}
+
+#[test]
+fn dup_sort() {
+    let file = MemoryFile::new();
+    {
+        let mut db = OpenOptions::new()
+            .dup_sort(true)
+            .open_file(file.clone())
+            .unwrap();
+        for row_id in [3u64, 1, 2] {
+            assert!(db.put_dup(b"index", &row_id.to_be_bytes()).unwrap());
+        }
+        assert!(db.delete_dup(b"index", &2u64.to_be_bytes()).unwrap());
+        db.flush().unwrap();
+    }
+
+    // The mode is kept by the file, not the options it is reopened with.
+    let mut db = OpenOptions::new().open_file(file).unwrap();
+    assert_eq!(
+        db.get_all(b"index").unwrap(),
+        vec![1u64.to_be_bytes(), 3u64.to_be_bytes()]
+    );
+
+    let (_, stored) = db.range::<[u8]>(..).next().unwrap().unwrap();
+    assert_eq!(treedb::dup_values(&stored).count(), 2);
+
+    let mut plain = Db::open_in_memory().unwrap();
+    assert!(plain.put_dup(b"index", b"row").is_err());
+}
+
+#[test]
+fn key_set() {
+    let mut db = Db::open_in_memory().unwrap();
+
+    for i in 0..1_000u64 {
+        assert!(db.insert(&i.to_be_bytes()).unwrap());
+    }
+    assert!(!db.insert(&7u64.to_be_bytes()).unwrap());
+
+    assert!(db.contains(&7u64.to_be_bytes()).unwrap());
+    assert!(!db.contains(&1_000u64.to_be_bytes()).unwrap());
+    assert_eq!(db.get(&7u64.to_be_bytes()).unwrap(), Some(Vec::new()));
+    assert!(db.get_ref(&7u64.to_be_bytes()).unwrap().unwrap().is_empty());
+    assert_eq!(db.range::<[u8]>(..).count(), 1_000);
+}
+
+#[test]
+fn deterministic() {
+    let run = |cache_budget| {
+        let file = MemoryFile::new();
+
+        for round in 0..3u64 {
+            let mut db = OpenOptions::new()
+                .deterministic(true)
+                .cache_budget(cache_budget)
+                .open_file(file.clone())
+                .unwrap();
+
+            for i in 0..2_000u64 {
+                let key = (i * 7_919 % 2_000).to_be_bytes();
+                db.put(&key, &[round as u8; 40]).unwrap();
+            }
+            for i in (0..2_000u64).step_by(3) {
+                db.delete(&i.to_be_bytes()).unwrap();
+            }
+            db.flush().unwrap();
+        }
+
+        file.to_vec()
+    };
+
+    // The cache size changes which buffers get reused, not the file.
+    assert_eq!(run(1 << 20), run(64 << 20));
+}
+
+#[test]
+fn commit_tags() {
+    let file = MemoryFile::new();
+    let mut db = OpenOptions::new().open_file(file.clone()).unwrap();
+
+    let mut txn = db.begin();
+    txn.put(b"key", b"value").unwrap();
+    txn.set_tag(*b"upstream-txn-042");
+    let version = txn.commit().unwrap();
+
+    db.put(b"other", b"value").unwrap();
+    drop(db);
+
+    let db = OpenOptions::new().open_file(file).unwrap();
+    let info = db.commit_info(version).unwrap();
+    assert_eq!(info.version, version);
+    assert_eq!(&info.tag, b"upstream-txn-042");
+}
+
+#[test]
+fn bitmap_allocator() {
+    let file = MemoryFile::new();
+    let mut db = OpenOptions::new().open_file(file.clone()).unwrap();
+
+    for i in 0..2_000u64 {
+        db.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+    }
+    drop(db);
+
+    let mut db = OpenOptions::new()
+        .bitmap_allocator(true)
+        .open_file(file.clone())
+        .unwrap();
+    // Deleted pages are reused instead of growing the file, once the pages
+    // held back for the commits that aren't synced yet have been added.
+    let mut file_len = 0;
+    for round in 0..4u8 {
+        if round == 1 {
+            file_len = file.to_vec().len();
+        }
+        for i in 0..2_000u64 {
+            db.put(&i.to_be_bytes(), &[round; 100]).unwrap();
+        }
+    }
+    assert!(file.to_vec().len() < file_len * 2);
+    drop(db);
+
+    let mut db = OpenOptions::new().open_file(file).unwrap();
+    assert_eq!(db.get(&7u64.to_be_bytes()).unwrap(), Some(vec![3; 100]));
+    assert_eq!(db.range::<[u8]>(..).count(), 2_000);
+}
+
+#[test]
+fn shadow_paging() {
+    let file = MemoryFile::new();
+    let mut db = OpenOptions::new()
+        .shadow_paging(true)
+        .open_file(file.clone())
+        .unwrap();
+
+    for i in 0..2_000u64 {
+        db.put(&i.to_be_bytes(), &[0; 100]).unwrap();
+    }
+    let mut snapshot = db.snapshot().unwrap();
+    db.put(&10u64.to_be_bytes(), &[1; 100]).unwrap();
+    assert_eq!(
+        snapshot.get(&10u64.to_be_bytes()).unwrap(),
+        Some(vec![0; 100])
+    );
+    drop(snapshot);
+
+    // Copies no reader needs anymore are reused instead of growing the file.
+    let mut file_len = 0;
+    for round in 1..=4u8 {
+        if round == 2 {
+            file_len = file.to_vec().len();
+        }
+        for i in (0..2_000u64).step_by(10) {
+            db.put(&i.to_be_bytes(), &[round; 100]).unwrap();
+        }
+    }
+    assert!(file.to_vec().len() < file_len + file_len / 10);
+    assert_eq!(db.free_list_stats().waiting_jobs, 0);
+    db.close().unwrap();
+
+    let mut db = OpenOptions::new().open_file(file).unwrap();
+    assert!(db.was_closed_cleanly());
+    assert_eq!(db.get(&10u64.to_be_bytes()).unwrap(), Some(vec![4; 100]));
+    assert_eq!(db.get(&11u64.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+    assert_eq!(db.range::<[u8]>(..).count(), 2_000);
+}
+
+#[test]
+fn delta_updates() {
+    let grown = |delta_updates| {
+        let file = MemoryFile::new();
+        let mut db = OpenOptions::new()
+            .delta_updates(delta_updates)
+            .open_file(file.clone())
+            .unwrap();
+
+        for i in 0..1_000u64 {
+            db.put(&i.to_be_bytes(), &0u64.to_be_bytes()).unwrap();
+        }
+        let file_len = file.to_vec().len();
+
+        // The snapshot keeps every version of the counter readable.
+        let mut snapshot = db.snapshot().unwrap();
+        for count in 1..=200u64 {
+            db.put(&7u64.to_be_bytes(), &count.to_be_bytes()).unwrap();
+        }
+        assert_eq!(
+            db.get(&7u64.to_be_bytes()).unwrap(),
+            Some(200u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            snapshot.get(&7u64.to_be_bytes()).unwrap(),
+            Some(0u64.to_be_bytes().to_vec())
+        );
+        let grown = file.to_vec().len() - file_len;
+        drop(snapshot);
+
+        db.put(&8u64.to_be_bytes(), &1u64.to_be_bytes()).unwrap();
+        db.close().unwrap();
+
+        let mut db = OpenOptions::new().open_file(file).unwrap();
+        assert_eq!(
+            db.get(&7u64.to_be_bytes()).unwrap(),
+            Some(200u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            db.get(&8u64.to_be_bytes()).unwrap(),
+            Some(1u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(db.range::<[u8]>(..).count(), 1_000);
+
+        grown
+    };
+
+    // A page per update without deltas, a fraction of that with them.
+    let copied = grown(false);
+    assert!(copied > 100 * 4096);
+    assert!(grown(true) < copied / 10);
+}
+
+#[test]
+fn commit_stats() {
+    let mut db = Db::open_in_memory().unwrap();
+
+    let mut batch = WriteBatch::new();
+    for i in 0..10_000u64 {
+        batch.put(&i.to_be_bytes(), &[1; 32]);
+    }
+    db.write(batch).unwrap();
+
+    let stats = db.last_commit_stats().unwrap();
+    assert!(stats.pages.len() > 100);
+    assert!(stats.pages.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // Updating a key in place writes a copy of its leaf and nothing else.
+    let mut txn = db.begin();
+    txn.put(&7u64.to_be_bytes(), &[2; 32]).unwrap();
+    let version = txn.commit().unwrap();
+
+    let stats = db.last_commit_stats().unwrap();
+    assert_eq!(stats.version, version);
+    assert_eq!(stats.pages.len(), 1);
+    assert_eq!(stats.allocated_pages, 1);
+}
+
+#[test]
+fn close() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("close.db");
+
+    let mut db = Db::open(&path).unwrap();
+    for i in 0..1_000u64 {
+        db.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+    }
+
+    let mut batch = WriteBatch::new();
+    for i in 1_000..10_000u64 {
+        batch.put(&i.to_be_bytes(), &[1; 100]);
+    }
+    db.write(batch).unwrap();
+
+    let mut batch = WriteBatch::new();
+    for i in 1_000..10_000u64 {
+        batch.delete(&i.to_be_bytes());
+    }
+    db.write(batch).unwrap();
+
+    // Later updates copy the nodes they touch to the pages freed before.
+    for i in 0..10u64 {
+        db.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+    }
+    let file_len = std::fs::metadata(&path).unwrap().len();
+    assert!(!db.was_closed_cleanly());
+    db.close().unwrap();
+
+    // The pages freed by the deletes at the end of the file are gone.
+    assert!(std::fs::metadata(&path).unwrap().len() < file_len / 2);
+
+    let mut db = OpenOptions::new()
+        .verify_root_path(true)
+        .open(&path)
+        .unwrap();
+    assert!(db.was_closed_cleanly());
+    assert_eq!(db.range::<[u8]>(..).count(), 1_000);
+    assert_eq!(db.get(&7u64.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+
+    db.put(b"key", b"value").unwrap();
+    drop(db);
+
+    let db = Db::open(&path).unwrap();
+    assert!(!db.was_closed_cleanly());
+}
+
+#[test]
+fn wal_redoes_torn_writes() {
+    let file = MemoryFile::new();
+    let wal = MemoryFile::new();
+
+    let mut db = OpenOptions::new()
+        .open_file_with_wal(file.clone(), wal.clone())
+        .unwrap();
+    for i in 0..1_000u64 {
+        db.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+    }
+    drop(db);
+
+    // Tear both copies of the header, neither is left to fall back on.
+    file.write_at(&[0xff; 64], 0).unwrap();
+    file.write_at(&[0xff; 64], 4096).unwrap();
+    assert!(OpenOptions::new().open_file(file.clone()).is_err());
+
+    let mut db = OpenOptions::new()
+        .open_file_with_wal(file.clone(), wal.clone())
+        .unwrap();
+    assert_eq!(db.range::<[u8]>(..).count(), 1_000);
+
+    // Syncing the file starts the log over.
+    db.flush().unwrap();
+    assert!(wal.to_vec().len() < 100);
+}
+
+#[test]
+fn merge_shards() {
+    let mut db = Db::open_in_memory().unwrap();
+    let mut shard = Db::open_in_memory().unwrap();
+    for i in 0..100u64 {
+        db.put(&i.to_be_bytes(), b"db").unwrap();
+        shard.put(&(i + 50).to_be_bytes(), b"shard").unwrap();
+    }
+
+    let written = db
+        .merge_from(&mut shard, ConflictPolicy::KeepExisting)
+        .unwrap();
+    assert_eq!(written, 50);
+    assert_eq!(db.range::<[u8]>(..).count(), 150);
+    assert_eq!(db.get(&99u64.to_be_bytes()).unwrap(), Some(b"db".to_vec()));
+    assert_eq!(
+        db.get(&149u64.to_be_bytes()).unwrap(),
+        Some(b"shard".to_vec())
+    );
+}
+
+#[test]
+fn replace_range() {
+    let mut db = Db::open_in_memory().unwrap();
+    for key in [b"a", b"b", b"c", b"d"] {
+        db.put(key, b"old").unwrap();
+    }
+
+    let written = db
+        .replace_range(b"b".as_ref()..b"d".as_ref(), vec![(b"bb", b"new")])
+        .unwrap();
+    assert_eq!(written, 3);
+
+    // Out of order entries leave the range as it was.
+    let unordered = vec![(b"c", b"new"), (b"b", b"new")];
+    assert!(matches!(
+        db.replace_range::<[u8], _, _, _>(.., unordered),
+        Err(Error::Unordered)
+    ));
+
+    let entries = db
+        .range::<[u8]>(..)
+        .collect::<treedb::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            (b"a".to_vec(), b"old".to_vec()),
+            (b"bb".to_vec(), b"new".to_vec()),
+            (b"d".to_vec(), b"old".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn with_cancel() {
+    let mut db = Db::open_in_memory().unwrap();
+    for i in 0..1_000u64 {
+        db.put(&i.to_be_bytes(), b"old").unwrap();
+    }
+
+    // Cancelled partway through, the entries replaced so far are rolled
+    // back.
+    let token = CancelToken::new();
+    let entries = (0..1_000u64).map(|i| {
+        if i == 500 {
+            token.cancel();
+        }
+        (i.to_be_bytes(), b"new")
+    });
+    let res = db.with_cancel(&token, |db| db.replace_range::<[u8], _, _, _>(.., entries));
+    assert!(matches!(res, Err(Error::Cancelled)));
+    assert!(db.range::<[u8]>(..).all(|entry| entry.unwrap().1 == b"old"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let res = db.with_cancel(&token, |db| db.snapshot_to_path(dir.path().join("copy.db")));
+    assert!(matches!(res, Err(Error::Cancelled)));
+
+    // Only operations within the closure are cancelled.
+    assert_eq!(db.range::<[u8]>(..).count(), 1_000);
+    let expired = CancelToken::with_timeout(Duration::ZERO);
+    let res = db.with_cancel(&expired, |db| db.root_hash());
+    assert!(matches!(res, Err(Error::Cancelled)));
+}
+
+#[test]
+fn with_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut db = Db::open(dir.path().join("progress.db")).unwrap();
+    let mut other = Db::open_in_memory().unwrap();
+    for i in 0..2_000u64 {
+        db.put(&i.to_be_bytes(), &[0; 32]).unwrap();
+        other.put(&(i * 2).to_be_bytes(), &[1; 32]).unwrap();
+    }
+
+    let sink = Arc::new(Mutex::new(Vec::new()));
+    let run = |db: &mut Db, f: &mut dyn FnMut(&mut Db) -> treedb::Result<()>| {
+        let reported = sink.clone();
+        db.with_progress(move |progress| reported.lock().unwrap().push(progress), f)
+            .unwrap();
+
+        let reports = std::mem::take(&mut *sink.lock().unwrap());
+        let last = *reports.last().unwrap();
+        assert_eq!(last.processed, last.total);
+        assert!(reports
+            .windows(2)
+            .all(|pair| pair[0].processed < pair[1].processed));
+        reports
+    };
+
+    let nodes = db.node_count_by_level().unwrap().iter().sum::<usize>();
+    let reports = run(&mut db, &mut |db| db.root_hash().map(drop));
+    assert_eq!(reports.len(), nodes);
+
+    let leaves = *other.node_count_by_level().unwrap().last().unwrap();
+    let reports = run(&mut db, &mut |db| {
+        db.merge_from(&mut other, ConflictPolicy::KeepExisting)
+            .map(drop)
+    });
+    assert_eq!(reports.last().unwrap().total, leaves as u64);
+
+    run(&mut db, &mut |db| {
+        db.snapshot_to_path(dir.path().join("copy.db"))
+    });
+
+    // Nothing is reported outside of the closure.
+    db.root_hash().unwrap();
+    assert!(sink.lock().unwrap().is_empty());
+}
+
+#[test]
+fn sample_reads() {
+    let sampled = Arc::new(Mutex::new(Vec::new()));
+    let sink = sampled.clone();
+
+    let mut db = OpenOptions::new()
+        .sample_reads(NonZeroU64::new(2).unwrap(), move |key, hit| {
+            sink.lock().unwrap().push((key.to_vec(), hit))
+        })
+        .open_file(MemoryFile::new())
+        .unwrap();
+    db.put(b"a", b"1").unwrap();
+
+    db.get(b"a").unwrap();
+    db.get(b"b").unwrap();
+    db.contains(b"a").unwrap();
+    db.get_ref(b"c").unwrap();
+
+    assert_eq!(
+        *sampled.lock().unwrap(),
+        vec![(b"b".to_vec(), false), (b"c".to_vec(), false)]
+    );
+}
+
+#[test]
+fn snapshot_to_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let copy_path = dir.path().join("copy");
+
+    let mut db = Db::open(dir.path().join("db")).unwrap();
+    for i in 0..500u64 {
+        db.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+    }
+
+    db.snapshot_to_path(&copy_path).unwrap();
+    assert!(db.snapshot_to_path(&copy_path).is_err());
+
+    // Updates after the copy don't show up in it.
+    db.put(&0u64.to_be_bytes(), &[2; 100]).unwrap();
+
+    let mut copy = OpenOptions::new().read_only(true).open(&copy_path).unwrap();
+    assert_eq!(copy.range::<[u8]>(..).count(), 500);
+    assert_eq!(copy.get(&0u64.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+
+    // An in memory database is read out into the copy.
+    let mut db = Db::open_in_memory().unwrap();
+    db.put(b"key", b"value").unwrap();
+    db.snapshot_to_path(dir.path().join("memory")).unwrap();
+
+    let mut copy = Db::open(dir.path().join("memory")).unwrap();
+    assert_eq!(copy.get(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn max_snapshots() {
+    let mut db = OpenOptions::new()
+        .max_snapshots(1)
+        .open_file(MemoryFile::new())
+        .unwrap();
+    db.put(b"key", b"value").unwrap();
+
+    let mut snapshot = db.snapshot().unwrap();
+    let err = db.snapshot().err().unwrap();
+    assert!(matches!(err, Error::TooManySnapshots(1)));
+    assert!(!err.is_fatal());
+
+    assert_eq!(snapshot.range::<[u8]>(..).count(), 1);
+    assert_eq!(
+        db.reader_counts(),
+        ReaderCounts {
+            snapshots: 1,
+            iterators: 0,
+        }
+    );
+
+    drop(snapshot);
+    db.snapshot().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn database_locked() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db");
+
+    let mut db = Db::open(&path).unwrap();
+    db.put(b"key", b"value").unwrap();
+
+    let err = Db::open(&path).err().unwrap();
+    assert!(matches!(err, Error::DatabaseLocked));
+    assert!(!err.is_fatal());
+
+    // Readers don't take the lock.
+    let mut reader = OpenOptions::new().read_only(true).open(&path).unwrap();
+    assert_eq!(reader.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+    drop(db);
+    Db::open(&path).unwrap();
+}
+
+#[test]
+fn binding_handles() {
+    // Bindings hold these behind their own locks and move them between
+    // threads.
+    fn assert_send<T: Send + 'static>() {}
+    assert_send::<Db>();
+    assert_send::<Snapshot>();
+    assert_send::<IntoRange>();
+    assert_send::<Error>();
+
+    let mut db = OpenOptions::new()
+        .max_snapshots(1)
+        .open_file(MemoryFile::default())
+        .unwrap();
+    db.put(b"key", b"value").unwrap();
+
+    let err = db.put(&[0; 4096], b"value").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidArgument);
+
+    let snapshot = db.snapshot().unwrap();
+    assert!(matches!(
+        db.snapshot().map_err(|err| err.kind()),
+        Err(ErrorKind::Unavailable)
+    ));
+
+    let entries = snapshot
+        .into_range::<[u8]>(..)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(entries, vec![(b"key".to_vec(), b"value".to_vec())]);
+}